@@ -20,6 +20,7 @@ use std::comm::{PortOne, oneshot};
 use std::{str, task};
 use std::io;
 use std::io::{File, Decorator};
+use std::io::fs;
 use std::io::mem::MemWriter;
 
 /**
@@ -137,15 +138,28 @@ pub struct Database {
 impl Database {
 
     pub fn new(p: Path) -> Database {
+        match Database::try_new(p) {
+            Ok(db) => db,
+            Err(e) => fail!("{}", e)
+        }
+    }
+
+    /// Like `new`, but if the database file exists and can't be read, or
+    /// isn't a well-formed workcache database, returns an error describing
+    /// the problem instead of failing.
+    pub fn try_new(p: Path) -> Result<Database, ~str> {
         let mut rslt = Database {
             db_filename: p,
             db_cache: TreeMap::new(),
             db_dirty: false
         };
         if rslt.db_filename.exists() {
-            rslt.load();
+            match rslt.load() {
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }
         }
-        rslt
+        Ok(rslt)
     }
 
     pub fn prepare(&self,
@@ -173,30 +187,230 @@ impl Database {
         self.db_dirty = true
     }
 
+    /// Returns the kind, name and value of every output discovered by any
+    /// cached run of `fn_name`, whatever its declared inputs were.
+    pub fn discovered_outputs(&self, fn_name: &str) -> ~[(~str, ~str, ~str)] {
+        let mut result = ~[];
+        for (k, v) in self.db_cache.iter() {
+            let (name, _): (~str, WorkMap) = json_decode(*k);
+            if name.as_slice() != fn_name {
+                continue;
+            }
+            let (_, outputs, _): (WorkMap, WorkMap, ~str) = json_decode(*v);
+            for (output_name, &KindMap(ref kinds)) in outputs.iter() {
+                for (kind, value) in kinds.iter() {
+                    result.push((kind.clone(), output_name.clone(), value.clone()));
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the name of every function that has a cached run, without
+    /// duplicates.
+    pub fn fn_names(&self) -> ~[~str] {
+        let mut names: ~[~str] = ~[];
+        for (k, _) in self.db_cache.iter() {
+            let (name, _): (~str, WorkMap) = json_decode(*k);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Removes every cached run of a function whose name satisfies
+    /// `forget_fn`, so that the next run of each one is treated as stale.
+    /// Returns the number of entries removed.
+    pub fn forget(&mut self, forget_fn: |&str| -> bool) -> uint {
+        let mut stale = ~[];
+        for (k, _) in self.db_cache.iter() {
+            let (name, _): (~str, WorkMap) = json_decode(*k);
+            if forget_fn(name) {
+                stale.push(k.clone());
+            }
+        }
+        for k in stale.iter() {
+            self.db_cache.remove(k);
+        }
+        if !stale.is_empty() {
+            self.db_dirty = true;
+        }
+        stale.len()
+    }
+
+    /// Removes every cached run of `fn_name` except the one with
+    /// `declared_inputs`, if there is one. Returns the number of entries
+    /// removed.
+    pub fn forget_other_runs(&mut self, fn_name: &str, declared_inputs: &WorkMap) -> uint {
+        let keep = json_encode(&(fn_name, declared_inputs));
+        let mut stale = ~[];
+        for (k, _) in self.db_cache.iter() {
+            let (name, _): (~str, WorkMap) = json_decode(*k);
+            if name.as_slice() == fn_name && *k != keep {
+                stale.push(k.clone());
+            }
+        }
+        for k in stale.iter() {
+            self.db_cache.remove(k);
+        }
+        if !stale.is_empty() {
+            self.db_dirty = true;
+        }
+        stale.len()
+    }
+
+    /// Removes every cached run for which `stale_fn`, given the function
+    /// name and the kind and name of each declared input, returns true.
+    /// Returns the function name of each entry removed and the number of
+    /// bytes it took up in the database. If `dry_run` is true, the entries
+    /// are only returned, not removed.
+    pub fn prune(&mut self, dry_run: bool,
+                 stale_fn: |&str, &[(~str, ~str)]| -> bool) -> ~[(~str, uint)] {
+        let mut stale = ~[];
+        for (k, v) in self.db_cache.iter() {
+            let (name, inputs): (~str, WorkMap) = json_decode(*k);
+            let mut declared = ~[];
+            for (input_name, &KindMap(ref kinds)) in inputs.iter() {
+                for (kind, _) in kinds.iter() {
+                    declared.push((kind.clone(), input_name.clone()));
+                }
+            }
+            if stale_fn(name, declared) {
+                stale.push((k.clone(), name, k.len() + v.len()));
+            }
+        }
+        if dry_run {
+            return stale.move_iter().map(|(_, name, size)| (name, size)).collect();
+        }
+        for &(ref k, _, _) in stale.iter() {
+            self.db_cache.remove(k);
+        }
+        if !stale.is_empty() {
+            self.db_dirty = true;
+        }
+        stale.move_iter().map(|(_, name, size)| (name, size)).collect()
+    }
+
+    /// The file that `save` writes the database to before renaming it over
+    /// the database file
+    fn temp_filename(&self) -> Path {
+        let mut p = self.db_filename.clone();
+        p.set_filename(format!("{}.tmp", self.db_filename.filename_display()));
+        p
+    }
+
     // FIXME #4330: This should have &mut self and should set self.db_dirty to false.
+    /// Writes the database to a temporary file, and then renames that over
+    /// the database file, so that if the process dies partway through, the
+    /// database file still holds the complete previous state.
     fn save(&self) {
-        let f = @mut File::create(&self.db_filename);
-        self.db_cache.to_json().to_pretty_writer(f as @mut io::Writer);
+        let tmp = self.temp_filename();
+        {
+            let f = @mut File::create(&tmp);
+            self.db_cache.to_json().to_pretty_writer(f as @mut io::Writer);
+        }
+        fs::rename(&tmp, &self.db_filename);
     }
 
-    fn load(&mut self) {
+    fn load(&mut self) -> Result<(), ~str> {
         assert!(!self.db_dirty);
         assert!(self.db_filename.exists());
         match io::result(|| File::open(&self.db_filename)) {
-            Err(e) => fail!("Couldn't load workcache database {}: {}",
-                            self.db_filename.display(),
-                            e.desc),
+            Err(e) => Err(format!("Couldn't load workcache database {}: {}",
+                                  self.db_filename.display(),
+                                  e.desc)),
             Ok(r) =>
                 match json::from_reader(@mut r.unwrap() as @mut io::Reader) {
-                    Err(e) => fail!("Couldn't parse workcache database (from file {}): {}",
-                                    self.db_filename.display(), e.to_str()),
+                    Err(e) => Err(format!("Couldn't parse workcache database (from file {}): {}",
+                                          self.db_filename.display(), e.to_str())),
                     Ok(r) => {
+                        // Decoding fails, rather than returning an error, if
+                        // anything's the wrong shape, so check first that it's
+                        // a map whose keys and values are what `prepare` and
+                        // `cache` encode
+                        if !Database::well_formed(&r) {
+                            return Err(format!("Workcache database {} is corrupt",
+                                               self.db_filename.display()));
+                        }
                         let mut decoder = json::Decoder(r);
                         self.db_cache = Decodable::decode(&mut decoder);
+                        Ok(())
                     }
             }
         }
     }
+
+    fn well_formed(j: &json::Json) -> bool {
+        match *j {
+            json::Object(ref entries) => entries.iter().all(|(k, v)| {
+                let v_ok = match *v {
+                    json::String(ref s) => match json::from_str(*s) {
+                        Ok(ref v) => Database::well_formed_value(v),
+                        Err(_) => false
+                    },
+                    _ => false
+                };
+                v_ok && match json::from_str(*k) {
+                    Ok(ref k) => Database::well_formed_key(k),
+                    Err(_) => false
+                }
+            }),
+            _ => false
+        }
+    }
+
+    /// A key is an encoded `(~str, WorkMap)`: the function name and its
+    /// declared inputs.
+    fn well_formed_key(j: &json::Json) -> bool {
+        match *j {
+            json::List(ref l) if l.len() == 2 => match l[0] {
+                json::String(_) => Database::well_formed_work_map(&l[1]),
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    /// A value is an encoded `(WorkMap, WorkMap, ~str)`: the discovered
+    /// inputs, the discovered outputs and the result.
+    fn well_formed_value(j: &json::Json) -> bool {
+        match *j {
+            json::List(ref l) if l.len() == 3 => {
+                let result_ok = match l[2] { json::String(_) => true, _ => false };
+                result_ok
+                    && Database::well_formed_work_map(&l[0])
+                    && Database::well_formed_work_map(&l[1])
+            }
+            _ => false
+        }
+    }
+
+    /// `WorkMap` and `KindMap` are newtypes, so each is encoded as an
+    /// object with the wrapped map as its only field.
+    fn well_formed_newtype(j: &json::Json, inner_ok: |&json::Json| -> bool) -> bool {
+        match *j {
+            json::Object(ref o) if o.len() == 1 => match o.find(&~"_field0") {
+                Some(inner) => inner_ok(inner),
+                None => false
+            },
+            _ => false
+        }
+    }
+
+    fn well_formed_work_map(j: &json::Json) -> bool {
+        Database::well_formed_newtype(j, |m| match *m {
+            json::Object(ref names) => names.iter().all(|(_, kinds)| {
+                Database::well_formed_newtype(kinds, |k| match *k {
+                    json::Object(ref digests) => digests.iter().all(|(_, d)| {
+                        match *d { json::String(_) => true, _ => false }
+                    }),
+                    _ => false
+                })
+            }),
+            _ => false
+        })
+    }
 }
 
 #[unsafe_destructor]
@@ -251,7 +465,9 @@ pub struct Exec {
     priv discovered_outputs: WorkMap
 }
 
-enum Work<'self, T> {
+/// The result of a prep's work, which may still be running in another task
+/// (see `Prep::exec_work`)
+pub enum Work<'self, T> {
     WorkValue(T),
     WorkFromTask(&'self Prep<'self>, PortOne<(Exec, T)>),
 }
@@ -308,6 +524,14 @@ impl Context {
 }
 
 impl Exec {
+    /// Returns an Exec that hasn't discovered any inputs or outputs yet
+    pub fn new() -> Exec {
+        Exec {
+            discovered_inputs: WorkMap::new(),
+            discovered_outputs: WorkMap::new()
+        }
+    }
+
     pub fn discover_input(&mut self,
                           dependency_kind: &str,
                           dependency_name: &str,
@@ -346,6 +570,13 @@ impl<'self> Prep<'self> {
         }
     }
 
+    /// Removes the cached runs of this prep's function that had other
+    /// declared inputs, so that only the run for this prep's inputs (if it
+    /// has been cached) is left. Returns the number of entries removed.
+    pub fn forget_other_runs(&self) -> uint {
+        self.ctxt.db.write(|db| db.forget_other_runs(self.fn_name, &self.declared_inputs))
+    }
+
     pub fn lookup_declared_inputs(&self) -> ~[~str] {
         let mut rs = ~[];
         for (_, v) in self.declared_inputs.iter() {
@@ -403,7 +634,11 @@ impl<'self> Prep<'self> {
         self.exec_work(blk).unwrap()
     }
 
-    fn exec_work<T:Send +
+    /// Like `exec`, but if the work isn't cached, returns as soon as the
+    /// task doing it has been spawned, so that several preps can do their
+    /// work at once. Call `unwrap` on the result to wait for the work and
+    /// record it in the database.
+    pub fn exec_work<T:Send +
         Encodable<json::Encoder> +
         Decodable<json::Decoder>>( // FIXME(#5121)
             &'self self, blk: proc(&mut Exec) -> T) -> Work<'self, T> {
@@ -481,6 +716,61 @@ impl<'self, T:Send +
 }
 
 
+#[test]
+fn test_corrupt_database() {
+    use std::os;
+    use std::io::fs;
+
+    let pth = os::self_exe_path().expect("workcache::test_corrupt_database failed")
+        .with_filename("corrupt_db.json");
+    // The last entry is valid JSON, but not an encoded key and value
+    for contents in ["{\"trunc", "[1, 2]", "{\"k\": 3}", "{\"k\": \"{oops\"}",
+                     "{\"\\\"x\\\"\": \"1\"}"].iter() {
+        File::create(&pth).write(contents.as_bytes());
+        assert!(Database::try_new(pth.clone()).is_err());
+    }
+    File::create(&pth).write(bytes!("{}"));
+    assert!(Database::try_new(pth.clone()).is_ok());
+    {
+        let mut inputs = WorkMap::new();
+        inputs.insert_work_key(WorkKey::new("file", "a.rs"), ~"abc");
+        let mut db = Database::try_new(pth.clone()).unwrap();
+        db.cache("f", &inputs, &inputs, &WorkMap::new(), "1");
+    }
+    let db = Database::try_new(pth.clone()).unwrap();
+    let mut inputs = WorkMap::new();
+    inputs.insert_work_key(WorkKey::new("file", "a.rs"), ~"abc");
+    assert!(db.prepare("f", &inputs).is_some());
+    fs::unlink(&pth);
+}
+
+#[test]
+fn test_save_is_atomic() {
+    use std::os;
+
+    let pth = os::self_exe_path().expect("workcache::test_save_is_atomic failed")
+        .with_filename("atomic_db.json");
+    let inputs = WorkMap::new();
+    {
+        let mut db = Database::new(pth.clone());
+        db.cache("f", &inputs, &WorkMap::new(), &WorkMap::new(), "1");
+    }
+    // A save that was interrupted leaves a partial temporary file, but
+    // the database file itself is still whole
+    let tmp = Database::new(pth.clone()).temp_filename();
+    File::create(&tmp).write(bytes!("{\"trunc"));
+    {
+        let mut db = Database::try_new(pth.clone()).unwrap();
+        assert!(db.prepare("f", &inputs).is_some());
+        db.cache("g", &inputs, &WorkMap::new(), &WorkMap::new(), "2");
+    }
+    let db = Database::try_new(pth.clone()).unwrap();
+    assert!(db.prepare("f", &inputs).is_some());
+    assert!(db.prepare("g", &inputs).is_some());
+    assert!(!tmp.exists());
+    fs::unlink(&pth);
+}
+
 #[test]
 #[cfg(not(target_os="android"))] // FIXME(#10455)
 fn test() {
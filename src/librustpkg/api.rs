@@ -16,12 +16,17 @@ use path_util::{platform_library_name, target_build_dir};
 use target::*;
 use version::Version;
 use workspace::pkg_parent_workspaces;
+use source_control::FetcherRegistry;
 use workcache_support::*;
 pub use path_util::default_workspace;
 
 pub use source_control::{safe_git_clone, git_clone_url};
 
 use std::run;
+use std::io;
+use std::io::fs;
+use exit_codes::CORRUPT_WORKCACHE_CODE;
+use messages::{error, warn};
 use extra::arc::{Arc,RWArc};
 use extra::workcache;
 use extra::workcache::{Database, Logger, FreshnessMap};
@@ -47,28 +52,84 @@ pub fn new_default_context(c: workcache::Context, p: Path) -> BuildContext {
     BuildContext {
         context: Context {
             cfgs: ~[],
+            config_args: ~[],
             rustc_flags: RustcFlags::default(),
             use_rust_path_hack: false,
-            sysroot: p
+            sysroot: p,
+            test_timeout: None,
+            test_threads: None,
+            json: false,
+            git_clone_depth: None,
+            profile: None,
+            digest: Sha1Digest,
+            excludes: ~[],
+            jobs: 1,
+            no_default_workspace: false,
+            capture_script_output: false,
+            install_root: None,
+            emit_deps: false,
+            with_docs: false,
+            strip: false,
+            allow_dirty: false,
+            cache_index: false,
+            out_dir: None,
+            tmp_dir: None,
+            strict_cache: false,
+            isolated_tests: false,
+            timings: false,
+            report: None,
+            test_args: ~[],
+            ambiguous: FailIfAmbiguous,
+            variant: None,
+            all_versions: false,
+            print_crate_ids: false,
+            no_wait: false
         },
-        workcache_context: c
+        workcache_context: c,
+        cache_callback: None,
+        copy_progress: None,
+        emitter: None,
+        timings: None,
+        report: None,
+        cancel: None,
+        fetchers: Callback::new(FetcherRegistry::with_defaults(None))
     }
 }
 
 fn file_is_fresh(path: &str, in_hash: &str) -> bool {
-    let path = Path::new(path);
-    path.exists() && in_hash == digest_file_with_date(&path)
+    file_digest_matches(&Path::new(path), in_hash)
 }
 
 fn binary_is_fresh(path: &str, in_hash: &str) -> bool {
-    let path = Path::new(path);
-    path.exists() && in_hash == digest_only_date(&path)
+    date_digest_matches(&Path::new(path), in_hash)
 }
 
 pub fn new_workcache_context(p: &Path) -> workcache::Context {
+    open_workcache_context(p, false)
+}
+
+/// Like `new_workcache_context`, but says what to do if the database file is
+/// corrupt: if `strict` is true, fail with CORRUPT_WORKCACHE_CODE; otherwise,
+/// warn, and start over with an empty database, so everything is rebuilt
+pub fn open_workcache_context(p: &Path, strict: bool) -> workcache::Context {
     let db_file = p.join("rustpkg_db.json"); // ??? probably wrong
     debug!("Workcache database file: {}", db_file.display());
-    let db = RWArc::new(Database::new(db_file));
+    let db = match Database::try_new(db_file.clone()) {
+        Ok(db) => db,
+        Err(e) => {
+            if strict {
+                error(e);
+                fail!(CORRUPT_WORKCACHE_CODE);
+            }
+            warn(format!("{}; discarding it, so everything will be rebuilt", e));
+            if io::result(|| fs::unlink(&db_file)).is_err() {
+                error(format!("Couldn't remove {}", db_file.display()));
+                fail!(CORRUPT_WORKCACHE_CODE);
+            }
+            Database::new(db_file)
+        }
+    };
+    let db = RWArc::new(db);
     let lg = RWArc::new(Logger::new());
     let cfg = Arc::new(TreeMap::new());
     let mut freshness: FreshnessMap = TreeMap::new();
@@ -97,7 +158,10 @@ pub fn build_lib_with_cfgs(sysroot: Path, root: Path, name: ~str,
         libs: ~[mk_crate(lib)],
         mains: ~[],
         tests: ~[],
-        benchs: ~[]
+        benchs: ~[],
+        examples: ~[],
+        binary_names: ~[],
+        script_output: None
     };
     pkg_src.build(&cx, cfgs, []);
 }
@@ -120,23 +184,50 @@ pub fn build_exe_with_cfgs(sysroot: Path, root: Path, name: ~str,
         // n.b. This assumes the package only has one crate
         mains: ~[mk_crate(main)],
         tests: ~[],
-        benchs: ~[]
+        benchs: ~[],
+        examples: ~[],
+        binary_names: ~[],
+        script_output: None
     };
 
     pkg_src.build(&cx, cfgs, []);
 }
 
+/// What `install_pkg` did
+pub struct InstallResult {
+    /// The files that were installed (under the staging root, if there is one)
+    installed_files: ~[Path],
+    /// The source files that the install depends on, as (kind, path) pairs
+    declared_inputs: ~[(~str, ~str)],
+    /// The workspace the package was installed into
+    destination_workspace: Path,
+    /// The package that was installed
+    pkg_id: PkgId
+}
+
 pub fn install_pkg(cx: &BuildContext,
                    workspace: Path,
                    name: ~str,
                    version: Version,
                    // For now, these inputs are assumed to be inputs to each of the crates
-                   more_inputs: ~[(~str, Path)]) { // pairs of Kind and Path
+                   more_inputs: ~[(~str, Path)]) // pairs of Kind and Path
+                   -> BuildResult<InstallResult> {
     let pkgid = PkgId{ version: version, ..PkgId::new(name)};
-    cx.install(PkgSrc::new(workspace.clone(), workspace, false, pkgid),
-               &WhatToBuild{ build_type: Inferred,
-                             inputs_to_discover: more_inputs,
-                             sources: Everything });
+    let pkg_src = PkgSrc::new_with_fetchers(workspace.clone(), workspace, false, pkgid.clone(),
+                                            cx.context.git_clone_depth, &cx.fetchers,
+                                            &cx.context.tmp_dir);
+    let destination_workspace = pkg_src.destination_workspace.clone();
+    match cx.install(pkg_src, &WhatToBuild{ build_type: Inferred,
+                                            inputs_to_discover: more_inputs,
+                                            sources: Everything }) {
+        Built((installed_files, declared_inputs)) => Built(InstallResult {
+            installed_files: installed_files,
+            declared_inputs: declared_inputs,
+            destination_workspace: destination_workspace,
+            pkg_id: pkgid
+        }),
+        Canceled => Canceled
+    }
 }
 
 /// Builds an arbitrary library whose short name is `output`,
@@ -174,7 +265,7 @@ pub fn build_library_in_workspace(exec: &mut workcache::Exec,
         let out_name_str = out_name.as_str().unwrap().to_owned();
         exec.discover_output("binary",
                              out_name_str,
-                             digest_only_date(&out_name));
+                             digest_only_date_using(&out_name, context.digest));
         context.add_library_path(out_name.dir_path());
         out_name_str
     } else {
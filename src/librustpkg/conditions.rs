@@ -52,7 +52,28 @@ condition! {
 }
 
 condition! {
-    pub git_checkout_failed: (~str, Path) -> ();
+    // str is the source, and Path the directory it was being checked out
+    // into; the ProcessExit and the second str are git's exit status and
+    // error output
+    pub git_checkout_failed: (~str, Path, ProcessExit, ~str) -> ();
+}
+
+condition! {
+    // str is the source, and Path the directory it was checked out into,
+    // but not at the requested version; the second str says why
+    pub git_wrong_version: (~str, Path, ~str) -> ();
+}
+
+condition! {
+    // Path is a file that the package script for PkgId
+    // declared as an input, but that doesn't exist
+    pub missing_script_input: (PkgId, Path) -> ();
+}
+
+condition! {
+    // Path is a package script that rustc couldn't parse or expand,
+    // and uint is the number of errors it reported
+    pub bad_pkg_script: (Path, uint) -> ();
 }
 
 condition! {
@@ -10,15 +10,31 @@
 
 // Context data structure used by rustpkg
 
+use extra::arc::{MutexArc, RWArc};
+use extra::json;
+use extra::serialize::{Encodable, Decodable};
+use extra::time::precise_time_ns;
 use extra::workcache;
 use rustc::driver::session;
+use rustc::driver::driver::host_triple;
+use rustc::metadata::filesearch;
+use syntax::diagnostic;
 
+use std::comm::oneshot;
 use std::hashmap::HashSet;
+use std::unstable::atomics::{AtomicBool, SeqCst};
+use std::unstable::sync::UnsafeArc;
+use workcache_support::DigestAlgorithm;
+use source_control::FetcherRegistry;
+use util;
 
 #[deriving(Clone)]
 pub struct Context {
     // Config strings that the user passed in with --cfg
     cfgs: ~[~str],
+    // Extra arguments that the user passed in with --config-arg, which are
+    // appended to the package script's arguments when it's run
+    config_args: ~[~str],
     // Flags to pass to rustc
     rustc_flags: RustcFlags,
     // If use_rust_path_hack is true, rustpkg searches for sources
@@ -27,7 +43,100 @@ pub struct Context {
     // rustpkg stores build artifacts.
     use_rust_path_hack: bool,
     // The root directory containing the Rust standard libraries
-    sysroot: Path
+    sysroot: Path,
+    // If this is Some(n), `rustpkg test` kills test executables that
+    // haven't finished after n seconds
+    test_timeout: Option<u64>,
+    // If this is Some(n), `rustpkg test` has the test harness run n tests
+    // at once (with RUST_TEST_TASKS)
+    test_threads: Option<uint>,
+    // If true, commands that support it (like `targets`) print
+    // their output as JSON
+    json: bool,
+    // If this is Some(n), git sources are fetched with `git clone --depth n`
+    git_clone_depth: Option<uint>,
+    // If this is Some(p), build artifacts go in a subdirectory for
+    // profile p of each package's build directory (set with --profile)
+    profile: Option<Profile>,
+    // The hash function to digest workcache inputs and outputs with
+    // (set with --digest)
+    digest: DigestAlgorithm,
+    // Inferred crates whose paths match any of these glob patterns
+    // aren't built (set with --exclude)
+    excludes: ~[~str],
+    // The most crates to compile at once (set with --jobs; the command
+    // line defaults to the number of CPUs). If this is more than 1,
+    // compiler messages are prefixed with the name of their crate
+    jobs: uint,
+    // If true, operations that would otherwise fall back on the default
+    // workspace when RUST_PATH isn't set fail instead (set with
+    // --no-default-workspace)
+    no_default_workspace: bool,
+    // If true, what package scripts' build hooks print is captured and kept
+    // in the PkgSrc being built (as `script_output`), rather than going to
+    // the terminal (up to MAX_SCRIPT_OUTPUT bytes of each stream). This is
+    // for programs using rustpkg through `api`
+    capture_script_output: bool,
+    // If this is Some(root), installed files go under root, at the same place
+    // relative to it as they'd otherwise have relative to the filesystem root
+    // (set with --root). This is for staging packages
+    install_root: Option<Path>,
+    // If true, installing a package also writes a Makefile-style dependency
+    // file for each crate, next to its build artifacts (set with --emit-deps)
+    emit_deps: bool,
+    // If true, installing a package also installs the documentation in the
+    // `doc` directory of its build directory, if any (set with --with-docs)
+    with_docs: bool,
+    // If true, installing a package strips the debugging symbols from the
+    // installed executables and dynamic libraries (set with --strip)
+    strip: bool,
+    // If true, building or installing a package whose sources are in a git
+    // working tree with uncommitted changes goes ahead anyway, instead of
+    // failing (set with --allow-dirty)
+    allow_dirty: bool,
+    // If true, `list`, `which` and `uninstall` keep each workspace's index of
+    // installed packages in its rustpkg_index.json, and reuse it while it's
+    // current (set with --cache-index)
+    cache_index: bool,
+    // If this is Some(dir), the crates of the package being built are
+    // compiled into dir instead of the package's build directory (set with
+    // --out-dir). Dependencies are still built in their own build directories
+    out_dir: Option<Path>,
+    // If this is Some(dir), git sources are cloned under dir and then moved
+    // into the workspace (set with --tmp-dir). Otherwise, os::tmpdir() is
+    // used, which honors TMPDIR
+    tmp_dir: Option<Path>,
+    // If true, a corrupt workcache database is an error (set with
+    // --strict-cache). Otherwise it's discarded, and everything is rebuilt
+    strict_cache: bool,
+    // If true, `test` runs the test executable with HOME and RUST_PATH
+    // pointing at a temporary directory (set with --isolated)
+    isolated_tests: bool,
+    // If true, rustpkg reports how long compiling each crate, and building
+    // and running the package script, took (set with --timings)
+    timings: bool,
+    // If this is Some(p), rustpkg writes a summary of what the command did
+    // to p as JSON when it finishes, whether or not it succeeded (set with
+    // --report)
+    report: Option<Path>,
+    // Arguments for the test harness: whatever followed `--` on the
+    // command line
+    test_args: ~[~str],
+    // What to do when a package ID given on the command line is in more than
+    // one workspace (set with --ambiguous)
+    ambiguous: AmbiguityPolicy,
+    // If this is Some(v), packages that have a package script named
+    // `pkg-<v>.rs` are built with it instead of `pkg.rs` (set with --variant)
+    variant: Option<~str>,
+    // If true, `which` prints the executable of every installed version of
+    // the package, rather than just the preferred one (set with --all)
+    all_versions: bool,
+    // If true, `targets` also prints each crate's link metadata: its name,
+    // version, package ID and uuid (set with --print-crate-id)
+    print_crate_ids: bool,
+    // If true, commands that change the workspace fail rather than wait when
+    // another rustpkg has it locked (set with --no-wait)
+    no_wait: bool
 }
 
 #[deriving(Clone)]
@@ -35,7 +144,158 @@ pub struct BuildContext {
     // Context for workcache
     workcache_context: workcache::Context,
     // Everything else
-    context: Context
+    context: Context,
+    // If this is Some(c), c is told whether each workcache prep rustpkg
+    // executes was fresh in the cache
+    cache_callback: Option<Callback<~CacheObserver:Send>>,
+    // If this is Some(c), then while installing a file, c is told how much
+    // of it has been copied after each chunk
+    copy_progress: Option<Callback<~CopyObserver:Send>>,
+    // If this is Some(c), c makes the diagnostic emitter for each rustc
+    // session rustpkg creates (for package scripts and for crates), instead
+    // of using the default one that prints to stderr. Crates are compiled
+    // in tasks of their own, so this makes emitters rather than being one.
+    emitter: Option<Callback<~EmitterMaker:Send>>,
+    // If this is Some, `timed` records how long each step it's given takes
+    // here. It's shared by all the clones of this context.
+    timings: Option<Timings>,
+    // If this is Some, `record` records what the command does here, for
+    // --report. It's shared by all the clones of this context.
+    report: Option<Report>,
+    // If this is Some, the build stops, returning `Canceled`, at the next
+    // point where it checks the flag after the flag is set: before each
+    // batch of crates is compiled, and before the package script is run.
+    // A crate that's already being compiled is finished first, along with
+    // any dependencies it has to install.
+    cancel: Option<CancelFlag>,
+    // Fetches the sources of packages that aren't in any workspace, with
+    // the fetcher registered for the URL scheme in the package ID. A tool
+    // embedding rustpkg can register fetchers for more schemes. It's shared
+    // by all the clones of this context.
+    fetchers: Callback<FetcherRegistry>
+}
+
+/// Something that a tool embedding rustpkg can supply to be told, for each
+/// workcache prep rustpkg executes, whether its result came from the cache
+pub trait CacheObserver {
+    /// Called with the prep's key, and true if its result came from the
+    /// cache or false if its work actually ran
+    fn cache_decision(&mut self, key: &str, fresh: bool);
+}
+
+/// Something that a tool embedding rustpkg can supply to follow the
+/// progress of each file rustpkg installs
+pub trait CopyObserver {
+    /// Called before copying starts and after each chunk, with the
+    /// destination, the number of bytes copied so far and the file's size
+    fn copied(&mut self, dest: &str, copied: u64, total: u64);
+}
+
+/// Something that a tool embedding rustpkg can supply to collect rustc's
+/// diagnostics
+pub trait EmitterMaker {
+    /// Called in the task that creates each rustc session, to make its
+    /// emitter
+    fn make_emitter(&mut self) -> @diagnostic::Emitter;
+}
+
+/// A callback object that a tool embedding rustpkg supplies (such as a
+/// `CacheObserver`), shared by all the clones of the context it's in. Crates
+/// are compiled in tasks of their own, so it can be called from any of them,
+/// but it's never called from two tasks at once.
+///
+/// The callback object must not hold a clone of its own `Callback`, even
+/// indirectly (say, a `SourceFetcher` holding a context that holds the
+/// registry it's in): the two would leak, and calling `with` from inside
+/// `with` would deadlock. That's why `with` is unsafe.
+pub struct Callback<T> {
+    priv f: MutexArc<T>
+}
+
+impl<T: Send> Callback<T> {
+    pub fn new(f: T) -> Callback<T> {
+        Callback { f: MutexArc::new(f) }
+    }
+
+    /// Runs `blk` on the callback object, once no other task is using it.
+    /// The caller must make sure that the callback object doesn't hold this
+    /// `Callback` (see above), and that `blk` doesn't call `with` on it.
+    pub unsafe fn with<U>(&self, blk: |&mut T| -> U) -> U {
+        self.f.unsafe_access(blk)
+    }
+}
+
+impl<T: Send> Clone for Callback<T> {
+    fn clone(&self) -> Callback<T> {
+        Callback { f: self.f.clone() }
+    }
+}
+
+/// What was timed, and how long it took in nanoseconds, for --timings
+pub type Timings = RWArc<~[(~str, u64)]>;
+
+/// What a command did, for --report
+#[deriving(Clone)]
+pub struct BuildReport {
+    // The IDs of the packages that were built
+    packages: ~[~str],
+    // The crates that were compiled, rather than found fresh in the cache
+    compiled: ~[~str],
+    // How many workcache lookups found a fresh result, and how many didn't
+    cache_hits: uint,
+    cache_misses: uint,
+    // Each file that was installed, and its digest
+    installed: ~[(~str, ~str)],
+    // Each file that was installed, and the digest of just its contents
+    // (what --record lists)
+    installed_contents: ~[(~str, ~str)],
+    // The source files of the crates that were built (what --watch watches)
+    inputs: ~[Path]
+}
+
+impl BuildReport {
+    pub fn new() -> BuildReport {
+        BuildReport {
+            packages: ~[],
+            compiled: ~[],
+            cache_hits: 0,
+            cache_misses: 0,
+            installed: ~[],
+            installed_contents: ~[],
+            inputs: ~[]
+        }
+    }
+}
+
+pub type Report = RWArc<BuildReport>;
+
+/// A flag that a tool embedding rustpkg can set, from any task, to cancel a
+/// build (see `BuildContext::cancel`). Clones share the same flag.
+#[deriving(Clone)]
+pub struct CancelFlag {
+    priv flag: UnsafeArc<AtomicBool>
+}
+
+/// Whether a build ran to the end or was stopped by its `CancelFlag`, and
+/// what it produced if it ran to the end
+#[deriving(Eq)]
+pub enum BuildResult<T> {
+    Built(T),
+    Canceled
+}
+
+impl CancelFlag {
+    pub fn new() -> CancelFlag {
+        CancelFlag { flag: UnsafeArc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        unsafe { (*self.flag.get()).store(true, SeqCst) }
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        unsafe { (*self.flag.get_immut()).load(SeqCst) }
+    }
 }
 
 impl BuildContext {
@@ -64,13 +324,87 @@ impl BuildContext {
     pub fn additional_library_paths(&self) -> HashSet<Path> {
         self.context.rustc_flags.additional_library_paths.clone()
     }
+
+    /// Returns what makes emitters for rustc sessions (see `make_emitter`)
+    pub fn emitter_maker(&self) -> Option<Callback<~EmitterMaker:Send>> {
+        self.emitter.clone()
+    }
+
+    /// Runs `blk`, recording how long it took as `what` if timings are
+    /// being recorded
+    pub fn timed<T>(&self, what: &str, blk: || -> T) -> T {
+        match self.timings {
+            None => blk(),
+            Some(ref timings) => {
+                let start = precise_time_ns();
+                let result = blk();
+                let elapsed = precise_time_ns() - start;
+                timings.write(|t| t.push((what.to_owned(), elapsed)));
+                result
+            }
+        }
+    }
+
+    /// True if the build has been canceled (see `cancel`)
+    pub fn is_canceled(&self) -> bool {
+        self.cancel.as_ref().map_default(false, |flag| flag.is_canceled())
+    }
+
+    /// Like `prep.exec(blk)`, but also tells the cache callback (if any)
+    /// whether `blk` ran or the result for `key` was fresh in the cache
+    pub fn exec_reporting<T: Send + Encodable<json::Encoder> + Decodable<json::Decoder>>(
+        &self, prep: &workcache::Prep, key: &str, blk: proc(&mut workcache::Exec) -> T) -> T {
+        // workcache only runs blk on a cache miss, so if nothing was
+        // sent by the time exec returns, the result was fresh
+        let (ran_port, ran_chan) = oneshot();
+        let result = prep.exec(proc(exec) {
+            ran_chan.send(());
+            blk(exec)
+        });
+        let fresh = ran_port.try_recv().is_none();
+        self.report_cache_decision(key, fresh);
+        self.record(|r| if fresh { r.cache_hits += 1 } else { r.cache_misses += 1 });
+        result
+    }
+
+    /// Tells the cache callback (if any) whether the work for `key` was
+    /// fresh in the cache
+    pub fn report_cache_decision(&self, key: &str, fresh: bool) {
+        debug!("{} was {}", key, if fresh { "fresh" } else { "rebuilt" });
+        match self.cache_callback {
+            Some(ref c) => unsafe { c.with(|c| c.cache_decision(key, fresh)) },
+            None => ()
+        }
+    }
+
+    /// Runs `f` on the record of what the command did, for --report, if
+    /// there is one
+    pub fn record(&self, f: |&mut BuildReport|) {
+        match self.report {
+            Some(ref report) => report.write(|r| f(r)),
+            None => ()
+        }
+    }
+}
+
+/// Makes the emitter that rustc uses by default, which prints to stderr
+pub fn default_emitter() -> @diagnostic::Emitter {
+    @diagnostic::DefaultEmitter as @diagnostic::Emitter
+}
+
+/// Makes an emitter with the embedder's `maker`, if it supplied one (see
+/// `BuildContext::emitter_maker`), and otherwise with `default_emitter`
+pub fn make_emitter(maker: &Option<Callback<~EmitterMaker:Send>>) -> @diagnostic::Emitter {
+    match *maker {
+        Some(ref m) => unsafe { m.with(|m| m.make_emitter()) },
+        None => default_emitter()
+    }
 }
 
 /*
 Deliberately unsupported rustc flags:
    --bin, --lib           inferred from crate file names
    -L                     inferred from extern mods
-   --out-dir              inferred from RUST_PATH
    --test                 use `rustpkg test`
    -v -h --ls             don't make sense with rustpkg
    -W -A -D -F -          use pragmas instead
@@ -78,28 +412,38 @@ Deliberately unsupported rustc flags:
 rustc flags that aren't implemented yet:
    --passes
    --llvm-arg
-   --target-feature
    --android-cross-path
 */
 pub struct RustcFlags {
     compile_upto: StopBefore,
     // Linker to use with the --linker flag
     linker: Option<~str>,
-    // Extra arguments to pass to rustc with the --link-args flag
-    link_args: Option<~str>,
+    // Linkers to use for particular target triples, from --target-linker
+    // TRIPLE=LINKER. These override `linker` when building for that target.
+    target_linkers: ~[(~str, ~str)],
+    // Extra arguments to pass to the linker, one per --link-arg flag
+    // (or split on spaces from the --link-args flag)
+    link_args: ~[~str],
     // Optimization level. 0 = default. -O = 2.
     optimization_level: session::OptLevel,
     // True if the user passed in --save-temps
     save_temps: bool,
+    // True if the user passed in --deny-warnings, making every warning an error
+    deny_warnings: bool,
     // Target (defaults to rustc's default target)
     target: Option<~str>,
     // Target CPU (defaults to rustc's default target CPU)
     target_cpu: Option<~str>,
+    // CPU features to enable or disable, like `+sse4.2` or `-avx`, one per
+    // --target-feature flag
+    target_features: ~[~str],
     // Additional library directories, which get passed with the -L flag
     // This can't be set with a rustpkg flag, only from package scripts
     additional_library_paths: HashSet<Path>,
     // Any -Z features
-    experimental_features: Option<~[~str]>
+    experimental_features: Option<~[~str]>,
+    // What kind of file to build libraries as (set with --lib-type)
+    lib_type: LibType
 }
 
 impl Clone for RustcFlags {
@@ -107,13 +451,96 @@ impl Clone for RustcFlags {
         RustcFlags {
             compile_upto: self.compile_upto,
             linker: self.linker.clone(),
+            target_linkers: self.target_linkers.clone(),
             link_args: self.link_args.clone(),
             optimization_level: self.optimization_level,
             save_temps: self.save_temps,
+            deny_warnings: self.deny_warnings,
             target: self.target.clone(),
             target_cpu: self.target_cpu.clone(),
+            target_features: self.target_features.clone(),
             additional_library_paths: self.additional_library_paths.clone(),
-            experimental_features: self.experimental_features.clone()
+            experimental_features: self.experimental_features.clone(),
+            lib_type: self.lib_type
+        }
+    }
+}
+
+#[deriving(Eq)]
+pub enum LibType {
+    Dylib, // a dynamic library (the default)
+    Rlib,  // a static Rust library, as built by rustc's `-Z static` mode
+}
+
+impl LibType {
+    /// Parses the argument to --lib-type
+    pub fn from_str(s: &str) -> Option<LibType> {
+        match s {
+            "dylib" => Some(Dylib),
+            "rlib"  => Some(Rlib),
+            _       => None
+        }
+    }
+}
+
+#[deriving(Eq, Clone)]
+pub enum AmbiguityPolicy {
+    FailIfAmbiguous, // report every workspace and fail (the default)
+    FirstWorkspace,  // use the workspace that comes first in the RUST_PATH
+    AllWorkspaces,   // use each of them in turn
+}
+
+impl AmbiguityPolicy {
+    /// Parses the argument to --ambiguous
+    pub fn from_str(s: &str) -> Option<AmbiguityPolicy> {
+        match s {
+            "error" => Some(FailIfAmbiguous),
+            "first" => Some(FirstWorkspace),
+            "all"   => Some(AllWorkspaces),
+            _       => None
+        }
+    }
+
+    /// The argument to --ambiguous that means this policy
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FailIfAmbiguous => "error",
+            FirstWorkspace  => "first",
+            AllWorkspaces   => "all"
+        }
+    }
+}
+
+#[deriving(Eq, Clone)]
+pub enum Profile {
+    Debug,   // unoptimized
+    Release, // optimized, like -O
+}
+
+impl Profile {
+    /// Parses the argument to --profile
+    pub fn from_str(s: &str) -> Option<Profile> {
+        match s {
+            "debug"   => Some(Debug),
+            "release" => Some(Release),
+            _         => None
+        }
+    }
+
+    /// The name of the profile, which is also the name of the
+    /// subdirectory its build artifacts go in
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Debug   => "debug",
+            Release => "release"
+        }
+    }
+
+    /// The optimization level to use when --opt-level or -O isn't given
+    pub fn default_opt_level(&self) -> session::OptLevel {
+        match *self {
+            Debug   => session::No,
+            Release => session::Default
         }
     }
 }
@@ -130,6 +557,48 @@ pub enum StopBefore {
     Analysis, // --parse-only
 }
 
+/// The command-line flags that say where compilation should stop
+pub struct PhaseFlags {
+    no_trans: bool,     // --no-trans
+    no_link: bool,      // --no-link
+    pretty: bool,       // --pretty
+    parse_only: bool,   // --parse-only
+    emit_llvm: bool,    // --emit-llvm
+    generate_asm: bool  // -S or --assembly
+}
+
+/// Returns where compilation should stop, given `flags`. At most one of the
+/// flags may be given, except that `--emit-llvm` and `-S` can be combined
+/// (to get LLVM assembly). Otherwise, returns an error naming two of the
+/// flags that contradict each other.
+pub fn compile_upto(flags: &PhaseFlags) -> Result<StopBefore, ~str> {
+    let named = [(flags.no_trans, "--no-trans"), (flags.no_link, "--no-link"),
+                 (flags.pretty, "--pretty"), (flags.parse_only, "--parse-only"),
+                 (flags.emit_llvm, "--emit-llvm"), (flags.generate_asm, "-S")];
+    let given: ~[&str] = named.iter().filter(|&&(on, _)| on).map(|&(_, name)| name).collect();
+    let llvm_assembly = flags.emit_llvm && flags.generate_asm;
+    if given.len() > 2 || (given.len() == 2 && !llvm_assembly) {
+        return Err(format!("flags `{}` and `{}` can't be used together", given[0], given[1]));
+    }
+    Ok(if flags.no_trans {
+        Trans
+    } else if flags.no_link {
+        Link
+    } else if flags.pretty {
+        Pretty
+    } else if flags.parse_only {
+        Analysis
+    } else if llvm_assembly {
+        LLVMAssemble
+    } else if flags.generate_asm {
+        Assemble
+    } else if flags.emit_llvm {
+        LLVMCompileBitcode
+    } else {
+        Nothing
+    })
+}
+
 impl Context {
     pub fn sysroot(&self) -> Path {
         self.sysroot.clone()
@@ -143,15 +612,7 @@ impl Context {
     // Hack so that rustpkg can run either out of a rustc target dir,
     // or the host dir
     pub fn sysroot_to_use(&self) -> Path {
-        if !in_target(&self.sysroot) {
-            self.sysroot.clone()
-        } else {
-            let mut p = self.sysroot.clone();
-            p.pop();
-            p.pop();
-            p.pop();
-            p
-        }
+        resolve_sysroot(&self.sysroot)
     }
 
     /// Returns the flags to pass to rustc, as a vector of strings
@@ -168,6 +629,36 @@ impl Context {
     }
 }
 
+/// The sysroot to use, given the one rustpkg was given or found: if it's
+/// in a Rust target directory, the sysroot that directory is in
+pub fn resolve_sysroot(sysroot: &Path) -> Path {
+    if !in_target(sysroot) {
+        sysroot.clone()
+    } else {
+        let mut p = sysroot.clone();
+        p.pop();
+        p.pop();
+        p.pop();
+        p
+    }
+}
+
+/// Checks that `sysroot`, given with --sysroot, is a directory that has the
+/// libraries for the host in it, where rustc would look for them. Otherwise,
+/// returns an error saying what's wrong with it.
+pub fn check_sysroot(sysroot: &Path) -> Result<(), ~str> {
+    if !sysroot.is_dir() {
+        return Err(format!("sysroot {} is not a directory", sysroot.display()));
+    }
+    let lib_dir = resolve_sysroot(sysroot)
+        .join(&filesearch::relative_target_lib_path(host_triple()));
+    if !lib_dir.is_dir() {
+        return Err(format!("sysroot {} has no {} directory, so it isn't a Rust sysroot",
+                           sysroot.display(), lib_dir.display()));
+    }
+    Ok(())
+}
+
 /// We assume that if ../../rustc exists, then we're running
 /// rustpkg from a Rust target directory. This is part of a
 /// kludgy hack used to adjust the sysroot.
@@ -180,15 +671,12 @@ pub fn in_target(sysroot: &Path) -> bool {
 
 impl RustcFlags {
     fn flag_strs(&self) -> ~[~str] {
-        let linker_flag = match self.linker {
-            Some(ref l) => ~[~"--linker", l.clone()],
+        let linker_flag = match self.linker_for_target() {
+            Some(l) => ~[~"--linker", l],
             None    => ~[]
         };
-        let link_args_flag = match self.link_args {
-            Some(ref l) => ~[~"--link-args", l.clone()],
-            None        => ~[]
-        };
         let save_temps_flag = if self.save_temps { ~[~"--save-temps"] } else { ~[] };
+        let deny_warnings_flag = if self.deny_warnings { ~[~"-D", ~"warnings"] } else { ~[] };
         let target_flag = match self.target {
             Some(ref l) => ~[~"--target", l.clone()],
             None        => ~[]
@@ -197,15 +685,22 @@ impl RustcFlags {
             Some(ref l) => ~[~"--target-cpu", l.clone()],
             None        => ~[]
         };
+        // rustc takes all the features in one comma-separated list
+        let target_feature_flag = if self.target_features.is_empty() {
+            ~[]
+        } else {
+            ~[~"--target-feature", self.target_features.connect(",")]
+        };
         let z_flags = match self.experimental_features {
             Some(ref ls)    => ls.flat_map(|s| ~[~"-Z", s.clone()]),
             None            => ~[]
         };
         linker_flag
-            + link_args_flag
             + save_temps_flag
+            + deny_warnings_flag
             + target_flag
             + target_cpu_flag
+            + target_feature_flag
             + z_flags + (match self.compile_upto {
             LLVMCompileBitcode => ~[~"--emit-llvm"],
             LLVMAssemble => ~[~"--emit-llvm", ~"-S"],
@@ -219,100 +714,241 @@ impl RustcFlags {
         })
     }
 
+    /// Returns every rustc flag these settings amount to, including the
+    /// optimization level, link arguments and library type, which
+    /// `flag_strs` leaves to the session options
+    pub fn effective_flag_strs(&self) -> ~[~str] {
+        let opt_level = match self.optimization_level {
+            session::No => ~"0",
+            session::Less => ~"1",
+            session::Default => ~"2",
+            session::Aggressive => ~"3"
+        };
+        let mut flags = ~[~"--opt-level", opt_level];
+        flags.push_all_move(self.flag_strs());
+        if !self.link_args.is_empty() {
+            flags.push(~"--link-args");
+            flags.push(self.link_args.connect(" "));
+        }
+        if self.lib_type == Rlib {
+            flags.push(~"-Z");
+            flags.push(~"static");
+        }
+        flags
+    }
+
+    /// Returns the linker to use for the target we're building for
+    /// (the host, if --target wasn't given): the last one given for that
+    /// target with --target-linker, or else the --linker one
+    pub fn linker_for_target(&self) -> Option<~str> {
+        let target = match self.target {
+            Some(ref t) => t.clone(),
+            None => host_triple()
+        };
+        match self.target_linkers.rev_iter().find(|&&(ref t, _)| *t == target) {
+            Some(&(_, ref l)) => Some(l.clone()),
+            None => self.linker.clone()
+        }
+    }
+
     pub fn default() -> RustcFlags {
         RustcFlags {
             linker: None,
-            link_args: None,
+            target_linkers: ~[],
+            link_args: ~[],
             compile_upto: Nothing,
             optimization_level: session::Default,
             save_temps: false,
+            deny_warnings: false,
             target: None,
             target_cpu: None,
+            target_features: ~[],
             additional_library_paths: HashSet::new(),
-            experimental_features: None
+            experimental_features: None,
+            lib_type: Dylib
         }
     }
 }
 
-/// Returns true if any of the flags given are incompatible with the cmd
-pub fn flags_forbidden_for_cmd(flags: &RustcFlags,
-                        cfgs: &[~str],
-                        cmd: &str, user_supplied_opt_level: bool) -> bool {
-    let complain = |s| {
-        println!("The {} option can only be used with the `build` command:
-                  rustpkg [options..] build {} [package-ID]", s, s);
-    };
+/// The commands that each command-specific flag can be used with. `env` takes
+/// the same rustc flags as `build` and `install`, so that it can show what
+/// they resolve to.
+static FLAG_COMMANDS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("--linker", &["build", "install", "reinstall", "env"]),
+    ("--target-linker", &["build", "install", "reinstall", "env"]),
+    ("--link-args", &["build", "install", "reinstall", "env"]),
+    ("--cfg", &["build", "install", "reinstall", "env", "test", "targets", "deps",
+                "run-example"]),
+    ("--opt-level", &["build", "install", "reinstall", "env"]),
+    ("--save-temps", &["build", "install", "reinstall", "env"]),
+    ("--deny-warnings", &["build", "install", "reinstall", "env", "test"]),
+    ("--target", &["build", "install", "reinstall", "env"]),
+    ("--target-cpu", &["build", "install", "reinstall", "env"]),
+    ("--target-feature", &["build", "install", "reinstall", "env"]),
+    ("-Z", &["build", "install", "reinstall", "env"]),
+    ("--lib-type", &["build", "install", "reinstall", "env"]),
+    ("--no-link", &["build"]),
+    ("--no-trans", &["build"]),
+    ("-S", &["build"]),
+    ("--pretty", &["build"]),
+    ("--parse-only", &["build"]),
+    ("--emit-llvm", &["build"]),
+    ("--from-file", &["install"]),
+    ("--config-arg", &["build", "install", "reinstall", "test", "run-example"]),
+    ("--test-timeout", &["test"]),
+    ("--test-threads", &["test"]),
+    ("--isolated", &["test"]),
+    ("--timings", &["build", "install", "reinstall", "test", "run-example"]),
+    ("--report", &["build", "install", "reinstall"]),
+    ("--record", &["install", "reinstall"]),
+    ("--", &["test", "run-example"]),
+    ("--json", &["targets", "deps", "list", "env"]),
+    ("--depth", &["build", "install", "reinstall"]),
+    ("--tmp-dir", &["build", "install", "reinstall", "env"]),
+    ("--keep-going", &["build", "install", "reinstall"]),
+    ("--watch", &["build"]),
+    ("--ambiguous", &["build", "install", "reinstall", "test", "run-example", "env"]),
+    ("--variant", &["build", "install", "reinstall", "test", "run-example", "env"]),
+    ("--profile", &["build", "install", "reinstall", "clean", "test", "run-example",
+                    "env"]),
+    ("--digest", &["build", "install", "reinstall", "test", "env"]),
+    ("--exclude", &["build", "install", "reinstall", "test"]),
+    ("--jobs", &["build", "install", "reinstall", "test", "env"]),
+    ("--root", &["install", "reinstall", "env"]),
+    ("--emit-deps", &["install", "reinstall"]),
+    ("--with-docs", &["install", "reinstall"]),
+    ("--strip", &["install", "reinstall"]),
+    ("--allow-dirty", &["build", "install", "reinstall", "test", "run-example"]),
+    ("--cache-index", &["list", "which", "uninstall"]),
+    ("--out-dir", &["build"]),
+    ("--all", &["clean", "which"]),
+    ("--deep", &["clean"]),
+    ("--dry-run", &["clean-cache"]),
+    ("--print-crate-id", &["targets"])
+];
 
-    if flags.linker.is_some() && cmd != "build" && cmd != "install" {
-        println("The --linker option can only be used with the build or install commands.");
-        return true;
-    }
-    if flags.link_args.is_some() && cmd != "build" && cmd != "install" {
-        println("The --link-args option can only be used with the build or install commands.");
-        return true;
+/// The names of the rustc flags that were given, for `flags_forbidden_for_cmd`
+pub fn rustc_flags_given(flags: &RustcFlags,
+                         cfgs: &[~str],
+                         user_supplied_opt_level: bool) -> ~[&'static str] {
+    let mut given = ~[];
+    if flags.linker.is_some() { given.push("--linker"); }
+    if !flags.target_linkers.is_empty() { given.push("--target-linker"); }
+    if !flags.link_args.is_empty() { given.push("--link-args"); }
+    if !cfgs.is_empty() { given.push("--cfg"); }
+    if user_supplied_opt_level { given.push("--opt-level"); }
+    if flags.save_temps { given.push("--save-temps"); }
+    if flags.deny_warnings { given.push("--deny-warnings"); }
+    if flags.target.is_some() { given.push("--target"); }
+    if flags.target_cpu.is_some() { given.push("--target-cpu"); }
+    if !flags.target_features.is_empty() { given.push("--target-feature"); }
+    if flags.experimental_features.is_some() { given.push("-Z"); }
+    if flags.lib_type != Dylib { given.push("--lib-type"); }
+    match flags.compile_upto {
+        Link => given.push("--no-link"),
+        Trans => given.push("--no-trans"),
+        Assemble => given.push("-S"),
+        Pretty => given.push("--pretty"),
+        Analysis => given.push("--parse-only"),
+        LLVMCompileBitcode | LLVMAssemble => given.push("--emit-llvm"),
+        Nothing => ()
     }
+    given
+}
 
-    if !cfgs.is_empty() && cmd != "build" && cmd != "install" && cmd != "test" {
-        println("The --cfg option can only be used with the build, test, or install commands.");
-        return true;
+/// Returns the name of the first flag in `given` that is incompatible with the
+/// cmd, or None if all of them are allowed
+pub fn flags_forbidden_for_cmd(given: &[&str], cmd: &str) -> Option<~str> {
+    let is_given = |name: &str| given.iter().any(|&f| f == name);
+    for &flag in given.iter() {
+        let allowed = match flag {
+            // Only commands that change the workspace wait for its lock
+            "--no-wait" => util::changes_workspace(cmd),
+            // --timings reports in JSON wherever it can be used
+            "--json" if is_given("--timings") => true,
+            "--deep" if !is_given("--all") => false,
+            _ => match FLAG_COMMANDS.iter().find(|&&(name, _)| name == flag) {
+                Some(&(_, cmds)) => cmds.iter().any(|&c| c == cmd),
+                None => true
+            }
+        };
+        if !allowed {
+            return Some(flag.to_owned());
+        }
     }
+    None
+}
 
-    if user_supplied_opt_level && cmd != "build" && cmd != "install" {
-        println("The -O and --opt-level options can only be used with the build \
-                    or install commands.");
-        return true;
-    }
+#[cfg(test)]
+mod test {
+    use super::{PhaseFlags, compile_upto, Nothing, Link, LLVMCompileBitcode, LLVMAssemble,
+                Assemble, Trans, Pretty, Analysis, flags_forbidden_for_cmd};
 
-    if flags.save_temps  && cmd != "build" && cmd != "install" {
-        println("The --save-temps option can only be used with the build \
-                    or install commands.");
-        return true;
+    fn no_flags() -> PhaseFlags {
+        PhaseFlags {
+            no_trans: false,
+            no_link: false,
+            pretty: false,
+            parse_only: false,
+            emit_llvm: false,
+            generate_asm: false
+        }
     }
 
-    if flags.target.is_some()  && cmd != "build" && cmd != "install" {
-        println("The --target option can only be used with the build \
-                    or install commands.");
-        return true;
+    #[test]
+    fn test_compile_upto() {
+        assert_eq!(compile_upto(&no_flags()), Ok(Nothing));
+        assert_eq!(compile_upto(&PhaseFlags { no_trans: true, ..no_flags() }), Ok(Trans));
+        assert_eq!(compile_upto(&PhaseFlags { no_link: true, ..no_flags() }), Ok(Link));
+        assert_eq!(compile_upto(&PhaseFlags { pretty: true, ..no_flags() }), Ok(Pretty));
+        assert_eq!(compile_upto(&PhaseFlags { parse_only: true, ..no_flags() }), Ok(Analysis));
+        assert_eq!(compile_upto(&PhaseFlags { emit_llvm: true, ..no_flags() }),
+                   Ok(LLVMCompileBitcode));
+        assert_eq!(compile_upto(&PhaseFlags { generate_asm: true, ..no_flags() }),
+                   Ok(Assemble));
+        assert_eq!(compile_upto(&PhaseFlags { emit_llvm: true, generate_asm: true,
+                                              ..no_flags() }),
+                   Ok(LLVMAssemble));
     }
-    if flags.target_cpu.is_some()  && cmd != "build" && cmd != "install" {
-        println("The --target-cpu option can only be used with the build \
-                    or install commands.");
-        return true;
-    }
-    if flags.experimental_features.is_some() && cmd != "build" && cmd != "install" {
-        println("The -Z option can only be used with the build or install commands.");
-        return true;
+
+    #[test]
+    fn test_compile_upto_contradictions() {
+        assert_eq!(compile_upto(&PhaseFlags { parse_only: true, emit_llvm: true,
+                                              ..no_flags() }),
+                   Err(~"flags `--parse-only` and `--emit-llvm` can't be used together"));
+        assert_eq!(compile_upto(&PhaseFlags { no_trans: true, no_link: true, ..no_flags() }),
+                   Err(~"flags `--no-trans` and `--no-link` can't be used together"));
+        assert_eq!(compile_upto(&PhaseFlags { pretty: true, generate_asm: true,
+                                              ..no_flags() }),
+                   Err(~"flags `--pretty` and `-S` can't be used together"));
+        assert_eq!(compile_upto(&PhaseFlags { no_link: true, emit_llvm: true,
+                                              generate_asm: true, ..no_flags() }),
+                   Err(~"flags `--no-link` and `--emit-llvm` can't be used together"));
     }
 
-    match flags.compile_upto {
-        Link if cmd != "build" => {
-            complain("--no-link");
-            true
-        }
-        Trans if cmd != "build" => {
-            complain("--no-trans");
-            true
-        }
-        Assemble if cmd != "build" => {
-            complain("-S");
-            true
-        }
-        Pretty if cmd != "build" => {
-            complain("--pretty");
-            true
-        }
-        Analysis if cmd != "build" => {
-            complain("--parse-only");
-            true
-        }
-        LLVMCompileBitcode if cmd != "build" => {
-            complain("--emit-llvm");
-            true
-        }
-        LLVMAssemble if cmd != "build" => {
-            complain("--emit-llvm");
-            true
-        }
-        _ => false
+    #[test]
+    fn test_flags_forbidden_for_cmd() {
+        assert_eq!(flags_forbidden_for_cmd([], "list"), None);
+        assert_eq!(flags_forbidden_for_cmd(["--linker", "--strip"], "install"), None);
+        assert_eq!(flags_forbidden_for_cmd(["--linker", "--strip"], "reinstall"), None);
+        assert_eq!(flags_forbidden_for_cmd(["--linker", "--strip"], "build"),
+                   Some(~"--strip"));
+        assert_eq!(flags_forbidden_for_cmd(["--emit-llvm"], "clean"), Some(~"--emit-llvm"));
+        assert_eq!(flags_forbidden_for_cmd(["--from-file"], "reinstall"),
+                   Some(~"--from-file"));
+        assert_eq!(flags_forbidden_for_cmd(["--allow-dirty"], "run-example"), None);
+        // Flags that aren't command-specific are allowed anywhere
+        assert_eq!(flags_forbidden_for_cmd(["--strict-cache"], "list"), None);
+        // --no-wait goes with anything that takes the workspace lock
+        assert_eq!(flags_forbidden_for_cmd(["--no-wait"], "uninstall"), None);
+        assert_eq!(flags_forbidden_for_cmd(["--no-wait"], "list"), Some(~"--no-wait"));
+        // --json is allowed with --timings, wherever --timings is
+        assert_eq!(flags_forbidden_for_cmd(["--json"], "build"), Some(~"--json"));
+        assert_eq!(flags_forbidden_for_cmd(["--timings", "--json"], "build"), None);
+        assert_eq!(flags_forbidden_for_cmd(["--timings", "--json"], "list"),
+                   Some(~"--timings"));
+        // --deep only makes sense for `clean --all`
+        assert_eq!(flags_forbidden_for_cmd(["--deep"], "clean"), Some(~"--deep"));
+        assert_eq!(flags_forbidden_for_cmd(["--all", "--deep"], "clean"), None);
     }
 }
@@ -36,7 +36,7 @@ impl Crate {
         }
     }
 
-    fn flags(&self, flags: ~[~str]) -> Crate {
+    pub fn flags(&self, flags: ~[~str]) -> Crate {
         Crate {
             flags: vec::append(self.flags.clone(), flags),
             .. (*self).clone()
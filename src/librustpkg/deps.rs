@@ -0,0 +1,183 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Listing the dependencies between a package's crates (`rustpkg deps`)
+
+use std::vec;
+use rustc::driver::{driver, session};
+use syntax::{ast, diagnostic, visit};
+use syntax::visit::Visitor;
+use crate::Crate;
+use package_source::PkgSrc;
+
+/// One of a package's crates, and what its `extern mod`s refer to
+pub struct CrateDeps {
+    // `lib`, `main`, `test` or `bench`
+    kind: &'static str,
+    // The crate file, relative to the package directory
+    file: Path,
+    // The package's own libraries that this crate uses, as indices into
+    // the list of the package's crates
+    local: ~[uint],
+    // The names of the other libraries that this crate uses
+    external: ~[~str]
+}
+
+struct ExternModVisitor {
+    sess: session::Session,
+    names: ~[~str]
+}
+
+impl Visitor<()> for ExternModVisitor {
+    fn visit_view_item(&mut self, vi: &ast::view_item, env: ()) {
+        match vi.node {
+            ast::view_item_extern_mod(lib_ident, path_opt, _, _) => {
+                let lib_name = match path_opt {
+                    Some((p, _)) => p,
+                    None => self.sess.str_of(lib_ident)
+                };
+                self.names.push(lib_name.to_owned());
+            }
+            _ => ()
+        }
+        visit::walk_view_item(self, vi, env)
+    }
+}
+
+/// Parses (but doesn't expand) `crate_file`, and returns the names of the
+/// libraries it declares with `extern mod`, in order. For
+/// `extern mod foo = "github.com/bar/foo";`, the name is the path.
+pub fn extern_mods(sysroot: &Path, crate_file: &Path) -> ~[~str] {
+    let options = @session::options {
+        maybe_sysroot: Some(@sysroot.clone()),
+        .. (*session::basic_options()).clone()
+    };
+    let sess = driver::build_session(options,
+                                     @diagnostic::DefaultEmitter as @diagnostic::Emitter);
+    let cfg = driver::build_configuration(sess);
+    let input = driver::file_input(crate_file.clone());
+    let crate = driver::phase_1_parse_input(sess, cfg, &input);
+    let mut visitor = ExternModVisitor { sess: sess, names: ~[] };
+    visit::walk_crate(&mut visitor, &crate, ());
+    visitor.names
+}
+
+/// The name that other crates `extern mod` a library with `file` (relative
+/// to the package directory) by: the package's short name for a library
+/// at the top of the package, otherwise the name of its directory
+fn lib_name(pkg_src: &PkgSrc, file: &Path) -> ~str {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    match file.dir_path().filename_str() {
+        Some(dir) if dir != "." => dir.to_owned(),
+        _ => pkg_src.id.short_name.clone()
+    }
+}
+
+/// Finds the crates in `pkg_src` (which must have had `find_crates` called
+/// on it already) and works out which of each one's `extern mod`s refer to
+/// the package's own libraries and which refer to other packages. The
+/// crates are listed libraries first, then mains, tests and benches, each in
+/// the order `pkg_src` has them.
+pub fn crate_deps(pkg_src: &PkgSrc, sysroot: &Path) -> ~[CrateDeps] {
+    let crate_sets = [("lib", &pkg_src.libs), ("main", &pkg_src.mains),
+                      ("test", &pkg_src.tests), ("bench", &pkg_src.benchs)];
+    deps_among(pkg_src, sysroot, crate_sets)
+}
+
+fn deps_among(pkg_src: &PkgSrc, sysroot: &Path,
+              crate_sets: &[(&'static str, &~[Crate])]) -> ~[CrateDeps] {
+    let mut crates = ~[];
+    let mut names = ~[];
+    for &(kind, ref cs) in crate_sets.iter() {
+        for c in cs.iter() {
+            let mods = extern_mods(sysroot, &pkg_src.start_dir.join(&c.file));
+            crates.push((kind, c.file.clone(), mods));
+            names.push(if kind == "lib" { Some(lib_name(pkg_src, &c.file)) } else { None });
+        }
+    }
+
+    crates.move_iter().map(|(kind, file, mods)| {
+        let mut local = ~[];
+        let mut external = ~[];
+        for m in mods.move_iter() {
+            let libs: ~[uint] = range(0, names.len())
+                .filter(|&i| names[i] == Some(m.clone())).collect();
+            if libs.is_empty() {
+                external.push(m);
+            } else {
+                local.push_all(libs);
+            }
+        }
+        CrateDeps { kind: kind, file: file, local: local, external: external }
+    }).collect()
+}
+
+#[deriving(Clone, Eq)]
+enum Mark {
+    Unvisited,
+    OnStack,
+    Done
+}
+
+/// Finds the cycles among `crates`' dependencies on each other. Each cycle
+/// is a list of indices into `crates` that starts and ends with the same crate.
+pub fn find_cycles(crates: &[CrateDeps]) -> ~[~[uint]] {
+    fn visit(crates: &[CrateDeps], i: uint, marks: &mut [Mark],
+             stack: &mut ~[uint], cycles: &mut ~[~[uint]]) {
+        marks[i] = OnStack;
+        stack.push(i);
+        for &j in crates[i].local.iter() {
+            match marks[j] {
+                Unvisited => visit(crates, j, marks, stack, cycles),
+                OnStack => {
+                    let start = stack.iter().position(|&k| k == j).unwrap();
+                    let mut cycle = stack.slice_from(start).to_owned();
+                    cycle.push(j);
+                    cycles.push(cycle);
+                }
+                Done => ()
+            }
+        }
+        stack.pop();
+        marks[i] = Done;
+    }
+
+    let mut marks = vec::from_elem(crates.len(), Unvisited);
+    let mut stack = ~[];
+    let mut cycles = ~[];
+    for i in range(0, crates.len()) {
+        if marks[i] == Unvisited {
+            visit(crates, i, marks.as_mut_slice(), &mut stack, &mut cycles);
+        }
+    }
+    cycles
+}
+
+/// Groups `crates` (which must not depend on each other in a cycle) into
+/// levels, each a list of indices into `crates`, such that every crate's
+/// dependencies are in earlier levels than it is. The crates in each level
+/// are in the same order as in `crates`.
+pub fn build_levels(crates: &[CrateDeps]) -> ~[~[uint]] {
+    let mut levels: ~[~[uint]] = ~[];
+    let mut placed = vec::from_elem(crates.len(), false);
+    let mut remaining = crates.len();
+    while remaining > 0 {
+        let level: ~[uint] = range(0, crates.len()).filter(|&i| {
+            !placed[i] && crates[i].local.iter().all(|&j| placed[j])
+        }).collect();
+        assert!(!level.is_empty());
+        for &i in level.iter() {
+            placed[i] = true;
+        }
+        remaining -= level.len();
+        levels.push(level);
+    }
+    levels
+}
@@ -0,0 +1,124 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Checking workspaces for layout problems (`rustpkg doctor`)
+
+use std::os;
+use std::io;
+use std::io::fs;
+use path_util::{rust_path, dir_has_crate_file, target_build_dir, target_lib_dir};
+use installed_packages::library_short_name;
+
+/// Checks the RUST_PATH and the layout of `workspace`, printing a checklist
+/// of what was checked and what's wrong. Returns the number of problems found.
+pub fn check_workspace(workspace: &Path) -> uint {
+    println!("Checking workspace {}", workspace.display());
+    let checks = [("All RUST_PATH entries exist", check_rust_path(workspace)),
+                  ("The workspace has the standard directories", check_standard_dirs(workspace)),
+                  ("Every package in src/ has a crate file", check_packages(workspace)),
+                  ("Everything in build/ and lib/ has a source", check_orphans(workspace))];
+    let mut problems = 0;
+    for &(ref what, ref found) in checks.iter() {
+        println!("[{}] {}", if found.is_empty() { "x" } else { " " }, *what);
+        for problem in found.iter() {
+            println!("      {}", *problem);
+        }
+        problems += found.len();
+    }
+    problems
+}
+
+fn check_rust_path(workspace: &Path) -> ~[~str] {
+    // cwd/.rust is always in the RUST_PATH, whether or not it exists,
+    // so only complain about it if it's the workspace we're checking
+    let cwd_dot_rust = os::getcwd().join(".rust");
+    rust_path().iter()
+        .filter(|p| !p.is_dir() && (**p != cwd_dot_rust || *p == workspace))
+        .map(|p| format!("{} doesn't exist", p.display()))
+        .collect()
+}
+
+fn check_standard_dirs(workspace: &Path) -> ~[~str] {
+    ["src", "lib", "bin", "build"].iter()
+        .map(|d| workspace.join(*d))
+        .filter(|d| !d.is_dir())
+        .map(|d| format!("{} is missing", d.display()))
+        .collect()
+}
+
+fn check_packages(workspace: &Path) -> ~[~str] {
+    let mut crateless = ~[];
+    for dir in subdirs(&workspace.join("src")).iter() {
+        find_crateless_packages(dir, &mut crateless);
+    }
+    crateless.map(|d| format!("{} has no crate file", d.display()))
+}
+
+/// Pushes onto `result` each leaf directory under `dir` that has neither a
+/// crate file nor a package script, unless some directory above it has one
+fn find_crateless_packages(dir: &Path, result: &mut ~[Path]) {
+    if dir_has_crate_file(dir) || dir.join("pkg.rs").exists() {
+        return;
+    }
+    let children = subdirs(dir);
+    if children.is_empty() {
+        result.push(dir.clone());
+    }
+    for child in children.iter() {
+        find_crateless_packages(child, result);
+    }
+}
+
+fn check_orphans(workspace: &Path) -> ~[~str] {
+    let mut names = ~[];
+    let build_dir = target_build_dir(workspace);
+    for src in [workspace.join("src"), build_dir.join("src")].iter() {
+        package_names(src, &mut names);
+    }
+    let mut orphans = ~[];
+    // build/<triple> has a directory for each package path, plus src/,
+    // which has the sources for packages fetched from elsewhere
+    for entry in subdirs(&build_dir).iter() {
+        let name = entry.filename_str().unwrap_or("");
+        if name != "src" && !names.iter().any(|n| n.as_slice() == name) {
+            orphans.push(format!("{} has no corresponding source", entry.display()));
+        }
+    }
+    let libs = io::ignore_io_error(|| fs::readdir(&target_lib_dir(workspace)));
+    for lib in libs.iter() {
+        match library_short_name(lib) {
+            Some(name) if !names.contains(&name) =>
+                orphans.push(format!("{} has no corresponding source", lib.display())),
+            _ => ()
+        }
+    }
+    orphans
+}
+
+/// Pushes onto `result` the name (minus any version) of every directory under `dir`
+fn package_names(dir: &Path, result: &mut ~[~str]) {
+    for child in subdirs(dir).iter() {
+        let name = child.filename_str().unwrap_or("");
+        let unversioned = match name.rfind('-') {
+            Some(i) if name.slice_from(i + 1).chars().next()
+                           .map_default(false, |c| c.is_digit()) => name.slice_to(i),
+            _ => name
+        };
+        result.push(unversioned.to_owned());
+        package_names(child, result);
+    }
+}
+
+/// The subdirectories of `dir`, not counting hidden ones like `.git`
+fn subdirs(dir: &Path) -> ~[Path] {
+    io::ignore_io_error(|| fs::readdir(dir)).move_iter()
+        .filter(|p| p.is_dir() && !p.filename_str().map_default(false, |s| s.starts_with(".")))
+        .collect()
+}
@@ -11,4 +11,11 @@
 pub static COPY_FAILED_CODE: int = 65;
 pub static BAD_FLAG_CODE: int    = 67;
 pub static NONEXISTENT_PACKAGE_CODE: int = 68;
-
+pub static WORKSPACE_PROBLEMS_CODE: int = 69;
+pub static VERIFY_FAILED_CODE: int = 70;
+pub static BAD_PKG_SCRIPT_CODE: int = 71;
+pub static WORKSPACE_LOCKED_CODE: int = 72;
+pub static CORRUPT_WORKCACHE_CODE: int = 73;
+pub static DEPENDENCY_CYCLE_CODE: int = 75;
+pub static DIRTY_SOURCES_CODE: int = 76;
+pub static BAD_SYSROOT_CODE: int = 77;
@@ -12,72 +12,297 @@
 
 use rustc::metadata::filesearch::rust_path;
 use path_util::*;
-use std::os;
+use std::{os, str};
 use std::io;
 use std::io::fs;
+use std::io::File;
+use extra::json;
+use extra::treemap::TreeMap;
+
+/// Whether an installed package has all of its artifacts installed
+#[deriving(Eq)]
+pub enum InstallStatus {
+    InstallOk,
+    /// The package builds an executable, but it isn't installed
+    MissingBin,
+    /// The package builds a library, but it isn't installed
+    MissingLib
+}
+
+impl ToStr for InstallStatus {
+    fn to_str(&self) -> ~str {
+        match *self {
+            InstallOk => ~"ok",
+            MissingBin => ~"missing-bin",
+            MissingLib => ~"missing-lib"
+        }
+    }
+}
+
+/// Checks that each artifact `id` has in `workspace`'s build directory is
+/// also installed in `workspace`
+pub fn install_status(id: &PkgId, workspace: &Path) -> InstallStatus {
+    if built_executable_in_workspace(id, workspace).is_some()
+        && !target_executable_in_workspace(id, workspace).exists() {
+        MissingBin
+    } else if built_library_in_workspace(id, workspace).is_some()
+        && installed_library_in_workspace(&id.path, workspace).is_none() {
+        MissingLib
+    } else {
+        InstallOk
+    }
+}
 
 pub fn list_installed_packages(f: |&PkgId| -> bool) -> bool  {
-    let workspaces = rust_path();
-    for p in workspaces.iter() {
-        let binfiles = io::ignore_io_error(|| fs::readdir(&p.join("bin")));
+    each_installed_package(|id, _| f(id))
+}
+
+/// Like `list_installed_packages`, but also passes `f` the workspace that
+/// each package is installed in
+pub fn each_installed_package(f: |&PkgId, &Path| -> bool) -> bool  {
+    InstalledIndex::new(rust_path(), false).each_installed(f)
+}
+
+/// The packages installed in one workspace, with the modification times of
+/// the directories they were found by reading, which say whether the list
+/// is still current
+struct WorkspaceEntry {
+    workspace: Path,
+    // Directories relative to `workspace`, and their modification times
+    // (None if they didn't exist)
+    stamps: ~[(~str, Option<u64>)],
+    packages: ~[~str]
+}
+
+/// Maps the packages installed in a list of workspaces to the workspaces
+/// they're installed in, so that commands that only need to know what's
+/// installed where (`list`, `which` and `uninstall`) scan each workspace at
+/// most once. With --cache-index, each workspace's part of the index is kept
+/// in its `rustpkg_index.json`, and only rebuilt when the workspace's `src`,
+/// `bin` or `lib` directories (or those under `lib`) have been modified.
+pub struct InstalledIndex {
+    priv entries: ~[WorkspaceEntry]
+}
+
+impl InstalledIndex {
+    /// Indexes `workspaces`. If `persist` is true, a workspace's saved index
+    /// is used if it's current, and saved again if it wasn't.
+    pub fn new(workspaces: &[Path], persist: bool) -> InstalledIndex {
+        let entries = workspaces.map(|ws| {
+            let saved = if persist { WorkspaceEntry::load(ws) } else { None };
+            match saved {
+                Some(entry) => {
+                    debug!("Using the saved index for {}", ws.display());
+                    entry
+                }
+                None => {
+                    let entry = WorkspaceEntry::scan(ws);
+                    if persist {
+                        entry.save();
+                    }
+                    entry
+                }
+            }
+        });
+        InstalledIndex { entries: entries }
+    }
+
+    /// Calls `f` on each installed package and the workspace it's installed
+    /// in, in RUST_PATH order, until `f` returns false
+    pub fn each_installed(&self, f: |&PkgId, &Path| -> bool) -> bool {
+        for entry in self.entries.iter() {
+            for p in entry.packages.iter() {
+                if !f(&PkgId::new(*p), &entry.workspace) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the workspaces that `pkgid` is installed in, without duplicates
+    pub fn workspaces_for(&self, pkgid: &PkgId) -> ~[Path] {
+        let mut workspaces: ~[Path] = ~[];
+        self.each_installed(|id, ws| {
+            if id == pkgid && !workspaces.contains(ws) {
+                workspaces.push(ws.clone());
+            }
+            true
+        });
+        workspaces
+    }
+
+    pub fn is_installed(&self, pkgid: &PkgId) -> bool {
+        !self.each_installed(|id, _| id != pkgid)
+    }
+}
+
+/// Where `workspace`'s part of the installed package index is saved
+pub fn index_file(workspace: &Path) -> Path {
+    workspace.join("rustpkg_index.json")
+}
+
+fn modified(p: &Path) -> Option<u64> {
+    match io::result(|| p.stat()) {
+        Ok(s) => Some(s.modified),
+        Err(*) => None
+    }
+}
+
+impl WorkspaceEntry {
+    /// Reads `workspace`'s `bin` and `lib` directories for installed packages
+    fn scan(workspace: &Path) -> WorkspaceEntry {
+        let mut stamps = ~[];
+        let mut packages = ~[];
+        for dir in ["src", "bin", "lib"].iter() {
+            stamps.push((dir.to_owned(), modified(&workspace.join(*dir))));
+        }
+        let binfiles = io::ignore_io_error(|| fs::readdir(&workspace.join("bin")));
         for exec in binfiles.iter() {
             // FIXME (#9639): This needs to handle non-utf8 paths
             match exec.filestem_str() {
                 None => (),
-                Some(exec_path) => {
-                    if !f(&PkgId::new(exec_path)) {
-                        return false;
-                    }
-                }
+                Some(exec_path) => packages.push(exec_path.to_owned())
             }
         }
-        let libfiles = io::ignore_io_error(|| fs::readdir(&p.join("lib")));
+        let parent = workspace.join("lib");
+        let libfiles = io::ignore_io_error(|| fs::readdir(&parent));
         for lib in libfiles.iter() {
             debug!("Full name: {}", lib.display());
+            let rel_p = lib.path_relative_from(&parent).unwrap();
+            if lib.is_dir() {
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                stamps.push((Path::new("lib").join(&rel_p).as_str().unwrap().to_owned(),
+                             modified(lib)));
+            }
             match has_library(lib) {
                 Some(basename) => {
-                    let parent = p.join("lib");
                     debug!("parent = {}, child = {}",
                             parent.display(), lib.display());
-                    let rel_p = lib.path_relative_from(&parent).unwrap();
                     debug!("Rel: {}", rel_p.display());
                     let rel_path = rel_p.join(basename);
-                    rel_path.display().with_str(|s| {
-                        debug!("Rel name: {}", s);
-                        f(&PkgId::new(s));
-                    });
+                    debug!("Rel name: {}", rel_path.display());
+                    packages.push(rel_path.display().to_str());
                 }
                 None => ()
             }
+        }
+        WorkspaceEntry { workspace: workspace.clone(), stamps: stamps, packages: packages }
+    }
+
+    /// True if none of the directories this entry was built from have been
+    /// modified since
+    fn is_current(&self) -> bool {
+        self.stamps.iter().all(|&(ref dir, stamp)| modified(&self.workspace.join(*dir)) == stamp)
+    }
+
+    /// Reads `workspace`'s saved index, if it has one and it's current
+    fn load(workspace: &Path) -> Option<WorkspaceEntry> {
+        let file = index_file(workspace);
+        if !file.exists() {
+            return None;
+        }
+        let contents = match io::result(|| File::open(&file).read_to_end()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                debug!("Couldn't read {}: {}", file.display(), e.desc);
+                return None;
+            }
+        };
+        let entry = match json::from_str(str::from_utf8(contents)) {
+            Ok(json::Object(ref obj)) => {
+                let strings = |key: &str| match obj.find(&key.to_owned()) {
+                    Some(&json::List(ref l)) => Some(l.iter().filter_map(|j| match *j {
+                        json::String(ref s) => Some(s.clone()),
+                        _ => None
+                    }).collect::<~[~str]>()),
+                    _ => None
+                };
+                let stamps = match obj.find(&~"stamps") {
+                    Some(&json::List(ref l)) => l.iter().filter_map(|j| match *j {
+                        json::List(ref pair) => match pair.as_slice() {
+                            [json::String(ref dir), json::Number(n)] =>
+                                Some((dir.clone(), Some(n as u64))),
+                            [json::String(ref dir), json::Null] => Some((dir.clone(), None)),
+                            _ => None
+                        },
+                        _ => None
+                    }).collect(),
+                    _ => ~[]
+                };
+                strings("packages").map(|packages| WorkspaceEntry {
+                    workspace: workspace.clone(),
+                    stamps: stamps,
+                    packages: packages
+                })
+            }
+            _ => None
         };
+        match entry {
+            Some(entry) => if entry.is_current() && !entry.stamps.is_empty() {
+                Some(entry)
+            } else {
+                debug!("The saved index for {} is out of date", workspace.display());
+                None
+            },
+            None => {
+                debug!("Ignoring malformed index {}", file.display());
+                None
+            }
+        }
+    }
+
+    /// Saves this entry in its workspace, if the workspace can be written to
+    fn save(&self) {
+        let stamps = self.stamps.map(|&(ref dir, stamp)| {
+            json::List(~[json::String(dir.clone()), match stamp {
+                Some(m) => json::Number(m as f64),
+                None => json::Null
+            }])
+        });
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"stamps", json::List(stamps));
+        obj.insert(~"packages", json::List(self.packages.map(|p| json::String(p.clone()))));
+        let file = index_file(&self.workspace);
+        match io::result(|| File::create(&file).write(json::Object(obj).to_str().as_bytes())) {
+            Ok(()) => (),
+            Err(e) => debug!("Couldn't save the index {}: {}", file.display(), e.desc)
+        }
     }
-    true
 }
 
 pub fn has_library(p: &Path) -> Option<~str> {
     let files = io::ignore_io_error(|| fs::readdir(p));
     for path in files.iter() {
-        if path.extension_str() == Some(os::consts::DLL_EXTENSION) {
-            let stuff : &str = path.filestem_str().expect("has_library: weird path");
-            let mut stuff2 = stuff.split_str("-");
-            let stuff3: ~[&str] = stuff2.collect();
-            // argh
-            let chars_to_drop = os::consts::DLL_PREFIX.len();
-            return Some(stuff3[0].slice(chars_to_drop, stuff3[0].len()).to_owned());
+        match library_short_name(path) {
+            Some(name) => return Some(name),
+            None => ()
         }
     }
     None
 }
 
-pub fn package_is_installed(p: &PkgId) -> bool {
-    let mut is_installed = false;
-    list_installed_packages(|installed| {
-        if installed == p {
-            is_installed = true;
-            false
-        } else {
-            true
-        }
-    });
-    is_installed
+/// If `path` names a library that rustpkg installed, returns the
+/// library's short name (for example, `foo` for `libfoo-<hash>-0.1.so`)
+pub fn library_short_name(path: &Path) -> Option<~str> {
+    // rlibs always have the `lib` prefix, even where dynamic libraries don't
+    let prefix = match path.extension_str() {
+        Some(s) if s == os::consts::DLL_EXTENSION => os::consts::DLL_PREFIX,
+        Some("rlib") => "lib",
+        _ => return None
+    };
+    let stem = match path.filestem_str() {
+        Some(s) if s.starts_with(prefix) => s.slice_from(prefix.len()),
+        _ => return None
+    };
+    // The short name is followed by the hash and the version, if there are any
+    let name = match stem.split('-').next() {
+        Some(name) => name,
+        None => return None
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
 }
@@ -26,51 +26,80 @@ extern mod rustc;
 extern mod syntax;
 
 use std::{os, result, run, str, task};
+use std::comm::{SharedChan, oneshot};
 use std::io::process;
 use std::hashmap::HashSet;
 use std::io;
 use std::io::fs;
+use std::io::File;
+use std::io::signal::{Listener, Interrupt};
+use std::io::timer::sleep;
 pub use std::path::Path;
 
+use extra::arc::RWArc;
+use extra::glob::Pattern;
+use extra::json;
+use extra::sort::merge_sort;
+use extra::treemap::TreeMap;
+use extra::tempfile::TempDir;
+use extra::time::precise_time_ns;
 use extra::workcache;
 use rustc::driver::{driver, session};
+use rustc::middle::lint;
 use rustc::metadata::filesearch;
 use rustc::metadata::filesearch::rust_path;
 use extra::{getopts};
-use syntax::{ast, diagnostic};
+use syntax::{ast, codemap, diagnostic};
 use messages::{error, warn, note};
-use path_util::{build_pkg_id_in_workspace, built_test_in_workspace};
-use path_util::in_rust_path;
-use path_util::{built_executable_in_workspace, built_library_in_workspace, default_workspace};
+use path_util::{build_pkg_id_in_workspace, built_test_in_profile, profile_build_dir};
+use path_util::built_bench_in_profile;
+use path_util::{in_rust_path, target_build_dir, target_lib_dir};
+use path_util::{built_executable_in_profile, built_library_in_profile, default_workspace};
+use path_util::built_example_in_profile;
 use path_util::{target_executable_in_workspace, target_library_in_workspace, dir_has_crate_file};
-use source_control::{CheckedOutSources, is_git_dir, make_read_only};
+use path_util::{path_under_root, installed_executable_in_workspace};
+use path_util::{built_docs_in_profile, target_doc_dir, checked_out_source_in_workspace};
+use source_control::{CheckedOutSources, CloneFailed, DirToUse, WrongVersion};
+use source_control::{is_git_dir, make_read_only, FetcherRegistry};
 use workspace::{each_pkg_parent_workspace, pkg_parent_workspaces, cwd_to_workspace};
-use workspace::determine_destination;
-use context::{Context, BuildContext,
-                       RustcFlags, Trans, Link, Nothing, Pretty, Analysis, Assemble,
-                       LLVMAssemble, LLVMCompileBitcode};
+use workspace::{each_chosen_pkg_parent_workspace, choose_workspaces};
+use workspace::{determine_destination, fallback_workspace, is_workspace};
+use workspace::is_rust_path_hack_dir;
+use installed_packages::InstalledIndex;
+use context::{Context, BuildContext, LibType, Dylib, Profile, RustcFlags, Nothing,
+              PhaseFlags, Timings, AmbiguityPolicy, FailIfAmbiguous, BuildReport, Report};
+use context::{Callback, EmitterMaker, make_emitter, BuildResult, Built, Canceled};
 use package_id::PkgId;
 use package_source::PkgSrc;
-use target::{WhatToBuild, Everything, is_lib, is_main, is_test, is_bench};
-use target::{Tests, MaybeCustom, Inferred, JustOne};
-use workcache_support::digest_only_date;
-use exit_codes::{COPY_FAILED_CODE, BAD_FLAG_CODE};
+use target::{WhatToBuild, Everything, is_test};
+use target::{Tests, MaybeCustom, Inferred, JustOne, Example};
+use target::{OutputType, Lib, Main, Test, Bench};
+use workcache_support::{DigestAlgorithm, Sha1Digest, digest_only_date_using};
+use version::{Version, Tagged, split_version, try_parsing_version};
+use exit_codes::{COPY_FAILED_CODE, BAD_FLAG_CODE, WORKSPACE_PROBLEMS_CODE};
+use exit_codes::{NONEXISTENT_PACKAGE_CODE, VERIFY_FAILED_CODE, BAD_PKG_SCRIPT_CODE};
+use exit_codes::{WORKSPACE_LOCKED_CODE, DIRTY_SOURCES_CODE, BAD_SYSROOT_CODE};
+use lock::WorkspaceLock;
 
 pub mod api;
 mod conditions;
 pub mod context;
 mod crate;
+mod deps;
+mod doctor;
 pub mod exit_codes;
 mod installed_packages;
+mod lock;
 mod messages;
 mod package_id;
 mod package_source;
 mod path_util;
 mod search;
 mod sha1;
+mod sha2;
 mod source_control;
 mod target;
-#[cfg(not(windows), test)] // FIXME test failure on windows: #10471
+#[cfg(test)]
 mod tests;
 mod util;
 pub mod version;
@@ -79,6 +108,10 @@ mod workspace;
 
 pub mod usage;
 
+/// The most bytes of a package script's output, per stream, that rustpkg keeps
+/// when it captures the output, so a runaway script can't exhaust its memory
+static MAX_SCRIPT_OUTPUT: uint = 16 * 1024 * 1024;
+
 /// A PkgScript represents user-supplied custom logic for
 /// special build hooks. This only exists for packages with
 /// an explicit package script.
@@ -95,33 +128,69 @@ struct PkgScript<'self> {
     /// The crate for the custom build script
     crate: Option<ast::Crate>,
     /// Directory in which to store build output
-    build_dir: Path
+    build_dir: Path,
+    /// The sysroot to pass to the package script when running its hooks
+    sysroot: Path
+}
+
+/// Passes diagnostics on to `inner`, and sends a message on `errors` for
+/// each error, so that another task can count them
+struct CountingEmitter {
+    errors: SharedChan<()>,
+    inner: @diagnostic::Emitter
+}
+
+impl diagnostic::Emitter for CountingEmitter {
+    fn emit(&self,
+            cmsp: Option<(@codemap::CodeMap, codemap::Span)>,
+            msg: &str,
+            lvl: diagnostic::level) {
+        match lvl {
+            diagnostic::fatal | diagnostic::error => self.errors.send(()),
+            diagnostic::warning | diagnostic::note => ()
+        }
+        self.inner.emit(cmsp, msg, lvl);
+    }
 }
 
 impl<'self> PkgScript<'self> {
+    /// The rustc options for compiling a package script; with
+    /// `deny_warnings`, its warnings are errors
+    fn session_options(sysroot: &Path, deny_warnings: bool) -> @session::options {
+        // Get the executable name that was invoked
+        let binary = os::args()[0].to_managed();
+        @session::options {
+            binary: binary,
+            maybe_sysroot: Some(@sysroot.clone()),
+            crate_type: session::bin_crate,
+            lint_opts: if deny_warnings { ~[(lint::warnings, lint::deny)] } else { ~[] },
+            .. (*session::basic_options()).clone()
+        }
+    }
+
     /// Given the path name for a package script
     /// and a package ID, parse the package script into
-    /// a PkgScript that we can then execute
+    /// a PkgScript that we can then execute. The session's diagnostics
+    /// go to an emitter made by `emitter_maker`, and a message is sent on
+    /// `errors` for each error; with `deny_warnings`, its warnings are errors.
+    /// rustc fails if the script doesn't parse, so this should be called in
+    /// a task of its own.
     fn parse<'a>(sysroot: Path,
                  script: Path,
                  workspace: &Path,
-                 id: &'a PkgId) -> PkgScript<'a> {
-        // Get the executable name that was invoked
-        let binary = os::args()[0].to_managed();
+                 id: &'a PkgId,
+                 emitter_maker: Option<Callback<~EmitterMaker:Send>>,
+                 errors: SharedChan<()>,
+                 deny_warnings: bool) -> PkgScript<'a> {
         // Build the rustc session data structures to pass
         // to the compiler
         debug!("pkgscript parse: {}", sysroot.display());
-        let options = @session::options {
-            binary: binary,
-            maybe_sysroot: Some(@sysroot),
-            crate_type: session::bin_crate,
-            .. (*session::basic_options()).clone()
-        };
+        let options = PkgScript::session_options(&sysroot, deny_warnings);
         let input = driver::file_input(script.clone());
-        let sess = driver::build_session(options,
-                                         @diagnostic::DefaultEmitter as
-                                            @diagnostic::Emitter);
+        let emitter = @CountingEmitter { errors: errors, inner: make_emitter(&emitter_maker) };
+        let sess = driver::build_session(options, emitter as @diagnostic::Emitter);
         let cfg = driver::build_configuration(sess);
+        let script_sysroot = sysroot.clone();
         let crate = driver::phase_1_parse_input(sess, cfg.clone(), &input);
         let crate = driver::phase_2_configure_and_expand(sess, cfg.clone(), crate);
         let work_dir = build_pkg_id_in_workspace(id, workspace);
@@ -134,11 +203,25 @@ impl<'self> PkgScript<'self> {
             sess: sess,
             cfg: cfg,
             crate: Some(crate),
-            build_dir: work_dir
+            build_dir: work_dir,
+            sysroot: script_sysroot
         }
     }
 
-    fn build_custom(&mut self, exec: &mut workcache::Exec) -> ~str {
+    /// Raises the bad_pkg_script condition for the package script `script`,
+    /// which didn't parse, with the number of errors rustc reported; by
+    /// default, that prints an error and fails with BAD_PKG_SCRIPT_CODE.
+    fn parse_failed(script: &Path, errors: uint) {
+        use conditions::bad_pkg_script::cond;
+
+        cond.raise_default((script.clone(), errors), || {
+            error(format!("package script {} failed to parse ({} error{})",
+                          script.display(), errors, if errors == 1 { "" } else { "s" }));
+            fail!(BAD_PKG_SCRIPT_CODE)
+        });
+    }
+
+    fn build_custom(&mut self, exec: &mut workcache::Exec, digest: DigestAlgorithm) -> ~str {
         let sess = self.sess;
 
         debug!("Working directory = {}", self.build_dir.display());
@@ -146,50 +229,151 @@ impl<'self> PkgScript<'self> {
         let crate = util::ready_crate(sess, self.crate.take_unwrap());
         debug!("Building output filenames with script name {}",
                driver::source_name(&driver::file_input(self.input.clone())));
-        let exe = self.build_dir.join("pkg" + util::exe_suffix());
+        let exe = script_exe(&self.build_dir, &self.input);
         util::compile_crate_from_input(&self.input,
                                        exec,
                                        Nothing,
                                        &self.build_dir,
                                        sess,
-                                       crate);
+                                       crate,
+                                       digest,
+                                       false);
         // Discover the output
         // FIXME (#9639): This needs to handle non-utf8 paths
         // Discover the output
-        exec.discover_output("binary", exe.as_str().unwrap().to_owned(), digest_only_date(&exe));
+        exec.discover_output("binary", exe.as_str().unwrap().to_owned(),
+                             digest_only_date_using(&exe, digest));
+        // Discover any other files the package script says it reads, so that
+        // changing them causes the script to be rebuilt
+        for input in self.script_inputs(&exe).iter() {
+            debug!("Package script declared input {}", input.display());
+            exec.discover_input("file",
+                                input.as_str().unwrap(),
+                                workcache_support::digest_file_with_date_using(input, digest));
+        }
         exe.as_str().unwrap().to_owned()
     }
 
+    /// Runs the package script's inputs() hook, which prints the paths of
+    /// any files (other than the script itself) that the script depends on,
+    /// each on a line of its own starting with `input:`. Other lines are
+    /// ignored, so scripts that don't know the hook can print what they like.
+    /// Relative paths are relative to the directory containing the package
+    /// script. Raises the missing_script_input condition for any paths that
+    /// don't exist.
+    fn script_inputs(&self, exe: &Path) -> ~[Path] {
+        use conditions::missing_script_input::cond;
+
+        debug!("Running program (inputs): {} {} {}",
+               exe.display(), self.sysroot.display(), "inputs");
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let output = PkgScript::run_hook(exe, [self.sysroot.as_str().unwrap().to_owned(),
+                                               ~"inputs"]);
+        if !output.status.success() {
+            debug!("script_inputs: inputs hook failed with {:?}", output.status);
+            return ~[];
+        }
+        let script_dir = self.input.dir_path();
+        let mut inputs = ~[];
+        for line in str::from_utf8_slice(output.output).lines_any() {
+            if !line.starts_with("input:") {
+                continue;
+            }
+            let path = line.slice_from("input:".len()).trim();
+            if path.is_empty() {
+                continue;
+            }
+            let input = script_dir.join(path);
+            if input.exists() {
+                inputs.push(input);
+            } else {
+                cond.raise((self.id.clone(), input));
+            }
+        }
+        inputs
+    }
+
 
     /// Run the contents of this package script, where <what>
     /// is the command to pass to it (e.g., "build", "clean", "install")
-    /// Returns a pair of an exit code and list of configs (obtained by
-    /// calling the package script's configs() function if it exists
-    fn run_custom(exe: &Path, sysroot: &Path) -> (~[~str], process::ProcessExit) {
-        debug!("Running program: {} {} {}", exe.as_str().unwrap().to_owned(),
-               sysroot.display(), "install");
+    /// followed by `config_args`, both times it's run.
+    /// Returns a triple of a list of configs (obtained by
+    /// calling the package script's configs() function if it exists), an exit
+    /// code, and, if `capture` is true, what the build hook printed to stdout
+    /// and stderr (otherwise, its output goes to rustpkg's)
+    fn run_custom(exe: &Path, sysroot: &Path, config_args: &[~str], capture: bool)
+                  -> (~[~str], process::ProcessExit, Option<(~[u8], ~[u8])>) {
+        debug!("Running program: {} {} {} {}", exe.as_str().unwrap().to_owned(),
+               sysroot.display(), "install", config_args.connect(" "));
         // FIXME #7401 should support commands besides `install`
         // FIXME (#9639): This needs to handle non-utf8 paths
-        let status = run::process_status(exe.as_str().unwrap(),
-                                         [sysroot.as_str().unwrap().to_owned(), ~"install"]);
+        let args = ~[sysroot.as_str().unwrap().to_owned(), ~"install"] + config_args;
+        let (status, captured) = if capture {
+            let output = run::process_capped_output(exe.as_str().unwrap(), args,
+                                                    MAX_SCRIPT_OUTPUT);
+            for &(truncated, stream) in [(output.output_truncated, "stdout"),
+                                         (output.error_truncated, "stderr")].iter() {
+                if truncated {
+                    warn(format!("The package script {} wrote more than {} bytes to {}; \
+                                  only the first {} were kept",
+                                 exe.display(), MAX_SCRIPT_OUTPUT, stream, MAX_SCRIPT_OUTPUT));
+                }
+            }
+            (output.status, Some((output.output, output.error)))
+        } else {
+            (run::process_status(exe.as_str().unwrap(), args), None)
+        };
         if !status.success() {
             debug!("run_custom: first pkg command failed with {:?}", status);
-            (~[], status)
+            (~[], status, captured)
         }
         else {
             debug!("Running program (configs): {} {} {}",
                    exe.display(), sysroot.display(), "configs");
             // FIXME (#9639): This needs to handle non-utf8 paths
-            let output = run::process_output(exe.as_str().unwrap(),
-                                             [sysroot.as_str().unwrap().to_owned(), ~"configs"]);
+            let output = PkgScript::run_hook(exe, ~[sysroot.as_str().unwrap().to_owned(),
+                                                    ~"configs"] + config_args);
             debug!("run_custom: second pkg command did {:?}", output.status);
             // Run the configs() function to get the configs
             let cfgs = str::from_utf8_slice(output.output).words()
                 .map(|w| w.to_owned()).collect();
-            (cfgs, output.status)
+            (cfgs, output.status, captured)
         }
     }
 
+    /// Runs the package script's binaries() hook, which prints a line of the
+    /// form `<crate path> <binary name>` for each main crate that should be
+    /// installed under a name other than the package's short name.
+    /// Crate paths are relative to the package's source directory.
+    fn binary_names(exe: &Path, sysroot: &Path) -> ~[(Path, ~str)] {
+        debug!("Running program (binaries): {} {} {}",
+               exe.display(), sysroot.display(), "binaries");
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let output = PkgScript::run_hook(exe, [sysroot.as_str().unwrap().to_owned(),
+                                               ~"binaries"]);
+        if !output.status.success() {
+            debug!("binary_names: binaries hook failed with {:?}", output.status);
+            return ~[];
+        }
+        util::parse_binary_names(str::from_utf8_slice(output.output))
+    }
+
+    /// Runs the package script `exe` with `args` to call one of its hooks
+    /// (`args[1]`), keeping at most MAX_SCRIPT_OUTPUT bytes of what it prints.
+    /// What the hook prints to stdout is what rustpkg acts on, so if that was
+    /// cut off, prints an error and fails with BAD_PKG_SCRIPT_CODE rather than
+    /// act on part of it.
+    fn run_hook(exe: &Path, args: &[~str]) -> run::CappedProcessOutput {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let output = run::process_capped_output(exe.as_str().unwrap(), args, MAX_SCRIPT_OUTPUT);
+        if output.output_truncated {
+            error(format!("The package script {} wrote more than {} bytes to stdout for its \
+                           {} hook", exe.display(), MAX_SCRIPT_OUTPUT, args[1]));
+            fail!(BAD_PKG_SCRIPT_CODE);
+        }
+        output
+    }
+
     fn hash(&self) -> ~str {
         self.id.hash()
     }
@@ -198,30 +382,58 @@ impl<'self> PkgScript<'self> {
 pub trait CtxMethods {
     fn run(&self, cmd: &str, args: ~[~str]);
     fn do_cmd(&self, _cmd: &str, _pkgname: &str);
-    /// Returns a pair of the selected package ID, and the destination workspace
-    fn build_args(&self, args: ~[~str], what: &WhatToBuild) -> Option<(PkgId, Path)>;
-    /// Returns the destination workspace
-    fn build(&self, pkg_src: &mut PkgSrc, what: &WhatToBuild);
+    /// Returns the package source that was built (with --ambiguous all, the
+    /// last one), which says what `test` and `run-example` should look for
+    /// and where
+    fn build_args(&self, args: ~[~str], what: &WhatToBuild) -> Option<PkgSrc>;
+    /// Returns `Canceled` if the build was canceled (see `BuildContext::cancel`)
+    /// before it finished
+    fn build(&self, pkg_src: &mut PkgSrc, what: &WhatToBuild) -> BuildResult<()>;
     fn clean(&self, workspace: &Path, id: &PkgId);
     fn info(&self);
     /// Returns a pair. First component is a list of installed paths,
-    /// second is a list of declared and discovered inputs
-    fn install(&self, src: PkgSrc, what: &WhatToBuild) -> (~[Path], ~[(~str, ~str)]);
-    /// Returns a list of installed files
+    /// second is a list of declared and discovered inputs. If the build was
+    /// canceled, returns `Canceled` without installing anything.
+    fn install(&self, src: PkgSrc, what: &WhatToBuild)
+               -> BuildResult<(~[Path], ~[(~str, ~str)])>;
+    /// Like `install`, but first removes the package's build output and
+    /// forgets everything the workcache knows about building and installing it
+    fn reinstall(&self, src: PkgSrc, what: &WhatToBuild)
+                 -> BuildResult<(~[Path], ~[(~str, ~str)])>;
+    /// Returns a list of installed files. `renamed_ids` are the IDs of any
+    /// executables that were built under a name other than id's short name.
     fn install_no_build(&self,
                         build_workspace: &Path,
                         build_inputs: &[Path],
                         target_workspace: &Path,
-                        id: &PkgId) -> ~[~str];
+                        id: &PkgId,
+                        renamed_ids: &[PkgId]) -> ~[~str];
     fn prefer(&self, _id: &str, _vers: Option<~str>);
-    fn test(&self, id: &PkgId, workspace: &Path);
+    /// Prints the crates that rustpkg would build for the given package
+    fn targets(&self, pkg_src: PkgSrc);
+    /// Prints which libraries each of the given package's crates uses
+    fn deps(&self, pkg_src: PkgSrc);
+    /// Runs the test executables built for the test crates in `pkg_src`
+    fn test(&self, pkg_src: &PkgSrc);
+    /// Returns the test crates in the given package's sources, and the ones
+    /// among them that --exclude doesn't exclude
+    fn test_crates(&self, id: &PkgId) -> (~[Path], ~[Path]);
+    /// Runs the example `name` of the package `id`, which must already be
+    /// built in `workspace`, passing it `args`
+    fn run_example(&self, id: &PkgId, workspace: &Path, name: &str, args: &[~str]);
+    /// Prints the executable installed for the preferred version of the given
+    /// package (or for each installed version, with --all)
+    fn which(&self, id: &str);
+    /// Updates the git clone of the given package's sources, and reinstalls
+    /// the package from it
+    fn update(&self, id: &PkgId);
     fn uninstall(&self, _id: &str, _vers: Option<~str>);
     fn unprefer(&self, _id: &str, _vers: Option<~str>);
     fn init(&self);
 }
 
 impl CtxMethods for BuildContext {
-    fn build_args(&self, args: ~[~str], what: &WhatToBuild) -> Option<(PkgId, Path)> {
+    fn build_args(&self, args: ~[~str], what: &WhatToBuild) -> Option<PkgSrc> {
         let cwd = os::getcwd();
 
         if args.len() < 1 {
@@ -229,47 +441,36 @@ impl CtxMethods for BuildContext {
                 None  if dir_has_crate_file(&cwd) => {
                     // FIXME (#9639): This needs to handle non-utf8 paths
                     let pkgid = PkgId::new(cwd.filename_str().unwrap());
-                    let mut pkg_src = PkgSrc::new(cwd, default_workspace(), true, pkgid);
+                    let mut pkg_src = PkgSrc::new(cwd, fallback_workspace(&self.context),
+                                                  true, pkgid);
                     self.build(&mut pkg_src, what);
-                    match pkg_src {
-                        PkgSrc { destination_workspace: ws,
-                                 id: id, _ } => {
-                            Some((id, ws))
-                        }
-                    }
+                    Some(pkg_src)
                 }
                 None => { usage::build(); None }
                 Some((ws, pkgid)) => {
                     let mut pkg_src = PkgSrc::new(ws.clone(), ws, false, pkgid);
                     self.build(&mut pkg_src, what);
-                    match pkg_src {
-                        PkgSrc { destination_workspace: ws,
-                                 id: id, _ } => {
-                            Some((id, ws))
-                        }
-                    }
+                    Some(pkg_src)
                 }
             }
         } else {
             // The package id is presumed to be the first command-line
             // argument
             let pkgid = PkgId::new(args[0].clone());
-            let mut dest_ws = default_workspace();
-            each_pkg_parent_workspace(&self.context, &pkgid, |workspace| {
+            let mut built = None;
+            each_chosen_pkg_parent_workspace(&self.context, &pkgid, |workspace| {
                 debug!("found pkg {} in workspace {}, trying to build",
                        pkgid.to_str(), workspace.display());
-                dest_ws = determine_destination(os::getcwd(),
-                                                self.context.use_rust_path_hack,
-                                                workspace);
-                let mut pkg_src = PkgSrc::new(workspace.clone(), dest_ws.clone(),
-                                              false, pkgid.clone());
+                let dest_ws = determine_destination(os::getcwd(), &self.context, workspace);
+                let hack_dir = is_rust_path_hack_dir(&self.context, &pkgid, workspace);
+                let mut pkg_src = PkgSrc::new(workspace.clone(), dest_ws, hack_dir,
+                                              pkgid.clone());
                 self.build(&mut pkg_src, what);
-                true
+                built = Some(pkg_src);
             });
-            // n.b. If this builds multiple packages, it only returns the workspace for
-            // the last one. The whole building-multiple-packages-with-the-same-ID is weird
-            // anyway and there are no tests for it, so maybe take it out
-            Some((pkgid, dest_ws))
+            // n.b. With --ambiguous all, this builds the package in each
+            // workspace that has it, but only returns the last one
+            built
         }
     }
     fn run(&self, cmd: &str, args: ~[~str]) {
@@ -295,6 +496,31 @@ impl CtxMethods for BuildContext {
                     self.clean(&cwd, &pkgid); // tjc: should use workspace, not cwd
                 }
             }
+            "deps" => {
+                if args.len() < 1 {
+                    match cwd_to_workspace() {
+                        None if dir_has_crate_file(&cwd) => {
+                            // FIXME (#9639): This needs to handle non-utf8 paths
+                            let pkgid = PkgId::new(cwd.filename_str().unwrap());
+                            self.deps(PkgSrc::new(cwd, default_workspace(), true, pkgid));
+                        }
+                        None => { usage::deps(); return; }
+                        Some((ws, pkgid)) => {
+                            self.deps(PkgSrc::new(ws.clone(), ws, false, pkgid));
+                        }
+                    }
+                }
+                else {
+                    let pkgid = PkgId::new(args[0]);
+                    each_pkg_parent_workspace(&self.context, &pkgid, |workspace| {
+                        self.deps(PkgSrc::new(workspace.clone(),
+                                              workspace.clone(),
+                                              self.context.use_rust_path_hack,
+                                              pkgid.clone()));
+                        true
+                    });
+                }
+            }
             "do" => {
                 if args.len() < 2 {
                     return usage::do_cmd();
@@ -305,7 +531,14 @@ impl CtxMethods for BuildContext {
             "info" => {
                 self.info();
             }
-            "install" => {
+            "install" | "reinstall" => {
+                let install = |src: PkgSrc, what: &WhatToBuild| {
+                    if cmd == "reinstall" {
+                        self.reinstall(src, what);
+                    } else {
+                        self.install(src, what);
+                    }
+                };
                if args.len() < 1 {
                     match cwd_to_workspace() {
                         None if dir_has_crate_file(&cwd) => {
@@ -313,15 +546,14 @@ impl CtxMethods for BuildContext {
 
                             let inferred_pkgid =
                                 PkgId::new(cwd.filename_str().unwrap());
-                            self.install(PkgSrc::new(cwd, default_workspace(),
-                                                     true, inferred_pkgid),
-                                         &WhatToBuild::new(MaybeCustom, Everything));
+                            install(PkgSrc::new(cwd, fallback_workspace(&self.context),
+                                                true, inferred_pkgid),
+                                    &WhatToBuild::new(MaybeCustom, Everything));
                         }
-                        None  => { usage::install(); return; }
+                        None  => { usage::command(cmd); return; }
                         Some((ws, pkgid))                => {
                             let pkg_src = PkgSrc::new(ws.clone(), ws.clone(), false, pkgid);
-                            self.install(pkg_src, &WhatToBuild::new(MaybeCustom,
-                                                                    Everything));
+                            install(pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
                       }
                   }
                 }
@@ -332,31 +564,54 @@ impl CtxMethods for BuildContext {
                     let workspaces = pkg_parent_workspaces(&self.context, &pkgid);
                     debug!("package ID = {}, found it in {:?} workspaces",
                            pkgid.to_str(), workspaces.len());
+                    let workspaces = choose_workspaces(&self.context, &pkgid, workspaces);
                     if workspaces.is_empty() {
-                        let d = default_workspace();
-                        let src = PkgSrc::new(d.clone(), d, false, pkgid.clone());
-                        self.install(src, &WhatToBuild::new(MaybeCustom, Everything));
+                        let d = fallback_workspace(&self.context);
+                        let src = PkgSrc::new_with_fetchers(d.clone(), d, false,
+                                                            pkgid.clone(),
+                                                            self.context.git_clone_depth,
+                                                            &self.fetchers,
+                                                            &self.context.tmp_dir);
+                        install(src, &WhatToBuild::new(MaybeCustom, Everything));
                     }
                     else {
                         for workspace in workspaces.iter() {
-                            let dest = determine_destination(os::getcwd(),
-                                                             self.context.use_rust_path_hack,
+                            let dest = determine_destination(os::getcwd(), &self.context,
                                                              workspace);
-                            let src = PkgSrc::new(workspace.clone(),
-                                                  dest,
-                                                  self.context.use_rust_path_hack,
+                            let hack_dir = is_rust_path_hack_dir(&self.context, &pkgid,
+                                                                 workspace);
+                            let src = PkgSrc::new(workspace.clone(), dest, hack_dir,
                                                   pkgid.clone());
-                            self.install(src, &WhatToBuild::new(MaybeCustom, Everything));
+                            install(src, &WhatToBuild::new(MaybeCustom, Everything));
                         };
                     }
                 }
             }
             "list" => {
-                println("Installed packages:");
-                installed_packages::list_installed_packages(|pkg_id| {
-                    pkg_id.path.display().with_str(|s| println(s));
+                let mut installed = ~[];
+                let index = InstalledIndex::new(rust_path(), self.context.cache_index);
+                index.each_installed(|pkg_id, workspace| {
+                    let status = installed_packages::install_status(pkg_id, workspace);
+                    installed.push((pkg_id.clone(), workspace.clone(), status));
                     true
                 });
+                if self.context.json {
+                    let packages = installed.map(|&(ref pkg_id, ref workspace, status)| {
+                        let mut obj = ~TreeMap::new();
+                        obj.insert(~"path", json::String(pkg_id.path.display().to_str()));
+                        obj.insert(~"workspace", json::String(workspace.display().to_str()));
+                        obj.insert(~"status", json::String(status.to_str()));
+                        json::Object(obj)
+                    });
+                    let mut obj = ~TreeMap::new();
+                    obj.insert(~"packages", json::List(packages));
+                    println(json::Object(obj).to_pretty_str());
+                } else {
+                    println("Installed packages:");
+                    for &(ref pkg_id, _, status) in installed.iter() {
+                        println(format!("{} ({})", pkg_id.path.display(), status.to_str()));
+                    }
+                }
             }
             "prefer" => {
                 if args.len() < 1 {
@@ -365,20 +620,77 @@ impl CtxMethods for BuildContext {
 
                 self.prefer(args[0], None);
             }
+            "targets" => {
+                if args.len() < 1 {
+                    match cwd_to_workspace() {
+                        None if dir_has_crate_file(&cwd) => {
+                            // FIXME (#9639): This needs to handle non-utf8 paths
+                            let pkgid = PkgId::new(cwd.filename_str().unwrap());
+                            self.targets(PkgSrc::new(cwd, default_workspace(), true, pkgid));
+                        }
+                        None => { usage::targets(); return; }
+                        Some((ws, pkgid)) => {
+                            self.targets(PkgSrc::new(ws.clone(), ws, false, pkgid));
+                        }
+                    }
+                }
+                else {
+                    // The package id is presumed to be the first command-line
+                    // argument
+                    let pkgid = PkgId::new(args[0]);
+                    each_pkg_parent_workspace(&self.context, &pkgid, |workspace| {
+                        self.targets(PkgSrc::new(workspace.clone(),
+                                                 workspace.clone(),
+                                                 self.context.use_rust_path_hack,
+                                                 pkgid.clone()));
+                        true
+                    });
+                }
+            }
             "test" => {
                 // Build the test executable
-                let maybe_id_and_workspace = self.build_args(args,
-                                                             &WhatToBuild::new(MaybeCustom, Tests));
-                match maybe_id_and_workspace {
-                    Some((pkg_id, workspace)) => {
+                let maybe_pkg_src = self.build_args(args, &WhatToBuild::new(MaybeCustom, Tests));
+                match maybe_pkg_src {
+                    Some(pkg_src) => {
                         // Assuming it's built, run the tests
-                        self.test(&pkg_id, &workspace);
+                        self.test(&pkg_src);
                     }
                     None => {
                         error("Testing failed because building the specified package failed.");
                     }
                 }
             }
+            "run-example" => {
+                if args.len() < 2 {
+                    return usage::run_example();
+                }
+                let name = args[1].clone();
+                let what = WhatToBuild::new(MaybeCustom, Example(name.clone()));
+                let built = {
+                    // The example may run for as long as it likes, so only
+                    // building it holds the workspace lock
+                    let ws = default_workspace();
+                    let _lock = match WorkspaceLock::acquire(&ws, !self.context.no_wait) {
+                        Some(lock) => lock,
+                        None => {
+                            error(format!("Another rustpkg is using the workspace {}",
+                                          ws.display()));
+                            fail!(WORKSPACE_LOCKED_CODE);
+                        }
+                    };
+                    self.build_args(~[args[0].clone()], &what)
+                };
+                match built {
+                    Some(pkg_src) => {
+                        self.run_example(&pkg_src.id, pkg_src.build_workspace(), name,
+                                         args.slice_from(2));
+                    }
+                    None => {
+                        error("Couldn't run the example because building the specified \
+                               package failed.");
+                    }
+                }
+            }
             "init" => {
                 if args.len() != 0 {
                     return usage::init();
@@ -386,26 +698,49 @@ impl CtxMethods for BuildContext {
                     self.init();
                 }
             }
+            "which" => {
+                if args.len() != 1 {
+                    return usage::which();
+                }
+                self.which(args[0]);
+            }
+            "update" => {
+                if args.len() != 1 {
+                    return usage::update();
+                }
+                self.update(&PkgId::new(args[0]));
+            }
             "uninstall" => {
                 if args.len() < 1 {
                     return usage::uninstall();
                 }
 
                 let pkgid = PkgId::new(args[0]);
-                if !installed_packages::package_is_installed(&pkgid) {
+                let index = InstalledIndex::new(rust_path(), self.context.cache_index);
+                let workspaces = index.workspaces_for(&pkgid);
+                if workspaces.is_empty() {
                     warn(format!("Package {} doesn't seem to be installed! \
                                   Doing nothing.", args[0]));
                     return;
                 }
                 else {
-                    let rp = rust_path();
-                    assert!(!rp.is_empty());
-                    each_pkg_parent_workspace(&self.context, &pkgid, |workspace| {
-                        path_util::uninstall_package_from(workspace, &pkgid);
+                    let install_tag = pkgid.install_tag();
+                    let recorded: ~[Path] = self.workcache_context.db.read(|db| {
+                        db.discovered_outputs(install_tag.as_slice())
+                            + db.discovered_outputs(format!("{} stripped", install_tag))
+                    }).move_iter().map(|(_, name, _)| Path::new(name)).collect();
+                    for workspace in workspaces.iter() {
+                        path_util::uninstall_package_from(workspace, &pkgid, recorded);
                         note(format!("Uninstalled package {} (was installed in {})",
                                   pkgid.to_str(), workspace.display()));
-                        true
-                    });
+                    }
+                    // Otherwise a later install would think the files it
+                    // copied are still in place
+                    let forgotten = self.workcache_context.db.write(|db| db.forget(|fn_name| {
+                        is_install_tag(fn_name, install_tag.as_slice())
+                    }));
+                    debug!("Removed {} workcache entries for package {}",
+                           forgotten, pkgid.to_str());
                 }
             }
             "unprefer" => {
@@ -424,7 +759,7 @@ impl CtxMethods for BuildContext {
         fail!("`do` not yet implemented");
     }
 
-    fn build(&self, pkg_src: &mut PkgSrc, what_to_build: &WhatToBuild) {
+    fn build(&self, pkg_src: &mut PkgSrc, what_to_build: &WhatToBuild) -> BuildResult<()> {
         use conditions::git_checkout_failed::cond;
 
         let workspace = pkg_src.source_workspace.clone();
@@ -439,17 +774,33 @@ impl CtxMethods for BuildContext {
         // If workspace isn't in the RUST_PATH, and it's a git repo,
         // then clone it into the first entry in RUST_PATH, and repeat
         if !in_rust_path(&workspace) && is_git_dir(&workspace.join(&pkgid.path)) {
-            let mut out_dir = default_workspace().join("src");
+            let mut out_dir = fallback_workspace(&self.context).join("src");
             out_dir.push(&pkgid.path);
-            let git_result = source_control::safe_git_clone(&workspace.join(&pkgid.path),
-                                                            &pkgid.version,
-                                                            &out_dir);
-            match git_result {
-                CheckedOutSources => make_read_only(&out_dir),
-                // FIXME (#9639): This needs to handle non-utf8 paths
-                _ => cond.raise((pkgid.path.as_str().unwrap().to_owned(), out_dir.clone()))
-            };
-            let default_ws = default_workspace();
+            let source = workspace.join(&pkgid.path);
+            // If a previous build already cloned this version, just rebuild it
+            if source_control::clone_is_current(&source, &out_dir, &pkgid.version) {
+                debug!("{} is already checked out at the right version; not cloning it",
+                       out_dir.display());
+            } else {
+                let git_result = source_control::safe_git_clone(&source,
+                                                                &pkgid.version,
+                                                                &out_dir,
+                                                                self.context.git_clone_depth,
+                                                                &self.context.tmp_dir);
+                match git_result {
+                    CheckedOutSources => make_read_only(&out_dir),
+                    // FIXME (#9639): This needs to handle non-utf8 paths
+                    CloneFailed(_, status, git_error) =>
+                        cond.raise((pkgid.path.as_str().unwrap().to_owned(), out_dir.clone(),
+                                    status, git_error)),
+                    WrongVersion(_, error) =>
+                        conditions::git_wrong_version::cond.raise(
+                            (pkgid.path.as_str().unwrap().to_owned(), out_dir.clone(), error)),
+                    DirToUse(_) => fail!("{} disappeared before it could be cloned",
+                                         source.display())
+                };
+            }
+            let default_ws = fallback_workspace(&self.context);
             debug!("Calling build recursively with {:?} and {:?}", default_ws.display(),
                    pkgid.to_str());
             return self.build(&mut PkgSrc::new(default_ws.clone(),
@@ -458,42 +809,183 @@ impl CtxMethods for BuildContext {
                                                pkgid.clone()), what_to_build);
         }
 
+        // Don't build sources that don't match any commit, unless asked to
+        if !self.context.allow_dirty {
+            match source_control::uncommitted_changes(&pkg_src.start_dir) {
+                Some(ref changed) if !changed.is_empty() => {
+                    error(format!("{} has uncommitted changes to: {}", pkgid.to_str(),
+                                  changed.connect(", ")));
+                    note("commit them, or pass --allow-dirty to build them anyway");
+                    fail!(DIRTY_SOURCES_CODE);
+                }
+                _ => ()
+            }
+        }
+
         // Is there custom build logic? If so, use it
         let mut custom = false;
+        // Crates that the package script's configs() hook asked for
+        let mut script_targets = ~[];
+        // Link arguments from the package script's static configs file, and
+        // the file itself, which the crates' builds depend on
+        let mut static_link_args = ~[];
+        let mut static_inputs = ~[];
         debug!("Package source directory = {}", pkg_src.to_str());
-        let opt = pkg_src.package_script_option();
+        let opt = pkg_src.package_script_option(&self.context.variant);
         debug!("Calling pkg_script_option on {:?}", opt);
-        let cfgs = match (pkg_src.package_script_option(), what_to_build.build_type) {
+        for v in self.context.variant.iter() {
+            let variant_script = pkg_src.variant_script(*v);
+            if opt != Some(variant_script.clone()) {
+                warn(format!("Package {} has no package script for variant `{}` ({}), so it's \
+                              built as usual", pkgid.to_str(), *v, variant_script.display()));
+            }
+        }
+        let cfgs = match (opt, what_to_build.build_type) {
+            // If the package declares its configs statically, the package
+            // script isn't built or run at all, so that building the package
+            // doesn't mean running its code
+            (Some(ref package_script_path), MaybeCustom)
+                    if util::static_configs_path(package_script_path).exists() => {
+                let configs_path = util::static_configs_path(package_script_path);
+                debug!("Reading configs from {} instead of running {}",
+                       configs_path.display(), package_script_path.display());
+                let contents = io::result(|| File::open(&configs_path).read_to_end());
+                let parsed = match contents {
+                    Ok(bytes) => util::parse_static_configs(str::from_utf8(bytes)),
+                    Err(e) => Err(e.desc.to_owned())
+                };
+                match parsed {
+                    Ok((cfgs, link_args, binary_names)) => {
+                        static_link_args = link_args;
+                        pkg_src.binary_names = binary_names;
+                        static_inputs.push((~"file", configs_path.clone()));
+                        cfgs
+                    }
+                    Err(e) => {
+                        error(format!("Couldn't read the configs in {}: {}",
+                                      configs_path.display(), e));
+                        fail!(BAD_PKG_SCRIPT_CODE);
+                    }
+                }
+            }
             (Some(package_script_path), MaybeCustom)  => {
                 let sysroot = self.sysroot_to_use();
-                // Build the package script if needed
-                let script_build = format!("build_package_script({})",
-                                           package_script_path.display());
-                let pkg_exe = self.workcache_context.with_prep(script_build, |prep| {
-                    let subsysroot = sysroot.clone();
-                    let psp = package_script_path.clone();
-                    let ws = workspace.clone();
-                    let pid = pkgid.clone();
-                    prep.exec(proc(exec) {
-                        let mut pscript = PkgScript::parse(subsysroot.clone(),
-                                                           psp.clone(),
-                                                           &ws,
-                                                           &pid);
-                        pscript.build_custom(exec)
+                // If the script doesn't build, the workcache task that builds
+                // it says so on `failed_chan`, with the number of errors rustc
+                // reported and whether the script got as far as parsing
+                let (failed_port, failed_chan) = oneshot();
+                let mut failed_chan = Some(failed_chan);
+                // Build the package script if needed. A build with
+                // --deny-warnings is cached separately, so that a script
+                // built without it isn't taken to pass.
+                let deny_warnings = self.context.rustc_flags.deny_warnings;
+                let script_build = format!("build_package_script({}){}",
+                                           package_script_path.display(),
+                                           if deny_warnings { " with --deny-warnings" }
+                                           else { "" });
+                let pkg_exe = self.timed(script_build, || {
+                    self.workcache_context.with_prep(script_build, |prep| {
+                        declare_package_script_dependency(prep, &package_script_path,
+                                                          self.context.digest);
+                        let subsysroot = sysroot.clone();
+                        let psp = package_script_path.clone();
+                        let ws = workspace.clone();
+                        let pid = pkgid.clone();
+                        let digest = self.context.digest;
+                        let emitter_maker = self.emitter_maker();
+                        let failed_chan = failed_chan.take_unwrap();
+                        self.exec_reporting(prep, script_build, proc(exec) {
+                            // The script is parsed and built in a task of its
+                            // own, so that if it has errors, rustc's failure
+                            // is contained
+                            let (errors_port, errors_chan) = stream();
+                            let errors_chan = SharedChan::new(errors_chan);
+                            let (parsed_port, parsed_chan) = oneshot();
+                            let sub_exec = std::util::replace(exec, workcache::Exec::new());
+                            let built = do task::try {
+                                let mut sub_exec = sub_exec;
+                                let mut pscript = PkgScript::parse(subsysroot.clone(),
+                                                                   psp.clone(),
+                                                                   &ws,
+                                                                   &pid,
+                                                                   emitter_maker,
+                                                                   errors_chan,
+                                                                   deny_warnings);
+                                parsed_chan.send(());
+                                let exe = pscript.build_custom(&mut sub_exec, digest);
+                                (sub_exec, exe)
+                            };
+                            match built {
+                                Ok((sub_exec, exe)) => {
+                                    *exec = sub_exec;
+                                    exe
+                                }
+                                Err(_) => {
+                                    let mut errors = 0u;
+                                    while errors_port.try_recv().is_some() {
+                                        errors += 1;
+                                    }
+                                    failed_chan.send((errors, parsed_port.try_recv().is_some()));
+                                    ~""
+                                }
+                            }
+                        })
                     })
                 });
-                // We always *run* the package script
-                let (cfgs, hook_result) = PkgScript::run_custom(&Path::new(pkg_exe), &sysroot);
+                match failed_port.try_recv() {
+                    Some((errors, parsed)) => {
+                        // The failed build mustn't be taken for a fresh one
+                        // next time
+                        self.workcache_context.db.write(|db| {
+                            db.forget(|fn_name| fn_name == script_build.as_slice())
+                        });
+                        if parsed {
+                            fail!("Error building package script {}",
+                                  package_script_path.display());
+                        }
+                        PkgScript::parse_failed(&package_script_path, errors);
+                    }
+                    None => ()
+                }
+                // We always *run* the package script, unless the build has
+                // been canceled in the meantime
+                if self.is_canceled() {
+                    return Canceled;
+                }
+                let (cfgs, hook_result, captured) =
+                    self.timed(format!("run_package_script({})", package_script_path.display()),
+                               || PkgScript::run_custom(&Path::new(pkg_exe.clone()), &sysroot,
+                                                        self.context.config_args,
+                                                        self.context.capture_script_output));
                 debug!("Command return code = {:?}", hook_result);
                 if !hook_result.success() {
+                    // Don't swallow the output that explains the failure
+                    for &(ref out, ref err) in captured.iter() {
+                        io::stdout().write(*out);
+                        io::stderr().write(*err);
+                    }
                     fail!("Error running custom build command")
                 }
+                pkg_src.script_output = captured;
+                // The crates the script asked for get their binary names
+                // from it too
+                pkg_src.binary_names = PkgScript::binary_names(&Path::new(pkg_exe.clone()),
+                                                               &sysroot);
                 custom = true;
                 // otherwise, the package script succeeded
+                let (cfgs, targets) = util::parse_script_configs(cfgs);
+                script_targets = targets;
                 cfgs
             }
-            (Some(_), Inferred) => {
+            (Some(package_script_path), Inferred) => {
                 debug!("There is a package script, but we're ignoring it");
+                // ...except to ask what to name the executables, if it's been built
+                let pkg_exe = script_exe(&build_pkg_id_in_workspace(&pkgid, &workspace),
+                                         &package_script_path);
+                if pkg_exe.exists() {
+                    pkg_src.binary_names = PkgScript::binary_names(&pkg_exe,
+                                                                   &self.sysroot_to_use());
+                }
                 ~[]
             }
             (None, _) => {
@@ -502,37 +994,82 @@ impl CtxMethods for BuildContext {
             }
         } + self.context.cfgs;
 
-        // If there was a package script, it should have finished
-        // the build already. Otherwise...
-        if !custom {
+        // If there was a package script, it should have finished the build
+        // already, except for the crates it asked for, whose cfgs are set
+        if custom {
+            let mut wanted = false;
+            for &(ref p, ref gate) in script_targets.iter() {
+                match *gate {
+                    Some(ref cfg) if !cfgs.contains(cfg) => {
+                        debug!("Not building {}, since {} isn't set", p.display(), *cfg);
+                        continue;
+                    }
+                    _ => ()
+                }
+                if !pkg_src.start_dir.join(p).exists() {
+                    error(format!("The package script for {} asked for the crate {}, \
+                                   which doesn't exist", pkgid.to_str(), p.display()));
+                    fail!(BAD_PKG_SCRIPT_CODE);
+                }
+                if pkg_src.push_crate_by_kind(p) {
+                    wanted = true;
+                } else {
+                    warn(format!("Not building {}, since it isn't a lib.rs, main.rs, \
+                                  test.rs or bench.rs, and has no #[crate_type] attribute",
+                                 p.display()));
+                }
+            }
+            if wanted {
+                match pkg_src.build(self, cfgs.clone(), []) {
+                    Built(_) => (),
+                    Canceled => return Canceled
+                }
+            }
+        } else {
             match what_to_build.sources {
                 // Find crates inside the workspace
-                Everything => pkg_src.find_crates(),
+                Everything | Example(_) => pkg_src.find_crates(cfgs),
                 // Find only tests
-                Tests => pkg_src.find_crates_with_filter(|s| { is_test(&Path::new(s)) }),
+                Tests => pkg_src.find_crates_with_filter(cfgs, |s| { is_test(&Path::new(s)) }),
                 // Don't infer any crates -- just build the one that was requested
                 JustOne(ref p) => {
                     // We expect that p is relative to the package source's start directory,
                     // so check that assumption
                     debug!("JustOne: p = {}", p.display());
                     assert!(pkg_src.start_dir.join(p).exists());
-                    if is_lib(p) {
-                        PkgSrc::push_crate(&mut pkg_src.libs, 0, p);
-                    } else if is_main(p) {
-                        PkgSrc::push_crate(&mut pkg_src.mains, 0, p);
-                    } else if is_test(p) {
-                        PkgSrc::push_crate(&mut pkg_src.tests, 0, p);
-                    } else if is_bench(p) {
-                        PkgSrc::push_crate(&mut pkg_src.benchs, 0, p);
-                    } else {
+                    if !pkg_src.push_crate_by_kind(p) {
                         warn(format!("Not building any crates for dependency {}", p.display()));
-                        return;
+                        return Built(());
                     }
                 }
             }
+            let excludes = self.context.excludes.map(|e| Pattern::new(*e));
+            pkg_src.exclude_crates(excludes);
+            pkg_src.add_link_args(static_link_args);
             // Build it!
-            pkg_src.build(self, cfgs, []);
+            match pkg_src.build(self, cfgs.clone(), static_inputs) {
+                Built(_) => (),
+                Canceled => return Canceled
+            }
+            // ...and then the requested example, which may use the package's libraries
+            match what_to_build.sources {
+                Example(ref name) => {
+                    match pkg_src.build_example(self, cfgs, *name) {
+                        Some(result) => return result,
+                        None => {
+                            error(format!("Package {} has no example named {} (expected {})",
+                                          pkg_src.id.to_str(), *name,
+                                          pkg_src.start_dir.join_many(["examples",
+                                                                       *name + ".rs"])
+                                                           .display()));
+                            fail!(NONEXISTENT_PACKAGE_CODE);
+                        }
+                    }
+                }
+                _ => ()
+            }
         }
+        Built(())
     }
 
     fn clean(&self, workspace: &Path, id: &PkgId)  {
@@ -540,7 +1077,8 @@ impl CtxMethods for BuildContext {
         // script for cleaning files rustpkg doesn't know about.
         // Do something reasonable for now
 
-        let dir = build_pkg_id_in_workspace(id, workspace);
+        // With --profile, only that profile's build artifacts are removed
+        let dir = profile_build_dir(id, workspace, self.context.profile);
         note(format!("Cleaning package {} (removing directory {})",
                         id.to_str(), dir.display()));
         if dir.exists() {
@@ -556,7 +1094,8 @@ impl CtxMethods for BuildContext {
         fail!("info not yet implemented");
     }
 
-    fn install(&self, mut pkg_src: PkgSrc, what: &WhatToBuild) -> (~[Path], ~[(~str, ~str)]) {
+    fn install(&self, mut pkg_src: PkgSrc, what: &WhatToBuild)
+               -> BuildResult<(~[Path], ~[(~str, ~str)])> {
 
         let id = pkg_src.id.clone();
 
@@ -568,32 +1107,90 @@ impl CtxMethods for BuildContext {
 
         // workcache only knows about *crates*. Building a package
         // just means inferring all the crates in it, then building each one.
-        self.build(&mut pkg_src, what);
+        match self.build(&mut pkg_src, what) {
+            Built(()) => (),
+            Canceled => {
+                note(format!("The build of package {} was canceled, so it wasn't installed",
+                             id.to_str()));
+                return Canceled;
+            }
+        }
 
         debug!("Done building package source {}", pkg_src.to_str());
 
-        let to_do = ~[pkg_src.libs.clone(), pkg_src.mains.clone(),
-                      pkg_src.tests.clone(), pkg_src.benchs.clone()];
+        let to_do = ~[(Lib, pkg_src.libs.clone()), (Main, pkg_src.mains.clone()),
+                      (Test, pkg_src.tests.clone()), (Bench, pkg_src.benchs.clone())];
         debug!("In declare inputs for {}", id.to_str());
-        for cs in to_do.iter() {
+        for &(what, ref cs) in to_do.iter() {
             for c in cs.iter() {
                 let path = pkg_src.start_dir.join(&c.file);
                 debug!("Recording input: {}", path.display());
                 // FIXME (#9639): This needs to handle non-utf8 paths
                 inputs.push((~"file", path.as_str().unwrap().to_owned()));
+                if self.context.emit_deps {
+                    write_crate_deps(self, &pkg_src, what, &c.file, [path.clone()]);
+                }
                 build_inputs.push(path);
             }
         }
 
+        let renamed_ids: ~[PkgId] = pkg_src.mains.iter()
+            .map(|c| pkg_src.executable_id(&c.file))
+            .filter(|exe_id| exe_id.short_name != id.short_name)
+            .collect();
         let result = self.install_no_build(pkg_src.build_workspace(),
                                            build_inputs,
                                            &pkg_src.destination_workspace,
-                                           &id).map(|s| Path::new(s.as_slice()));
+                                           &id,
+                                           renamed_ids).map(|s| Path::new(s.as_slice()));
+        let digest = self.context.digest;
+        let root = self.context.install_root.clone();
+        self.record(|r| {
+            for p in result.iter() {
+                // With --root, the file is under the staging root for now
+                let on_disk = match root {
+                    Some(ref root) => path_under_root(root, p),
+                    None => p.clone()
+                };
+                if on_disk.exists() {
+                    r.installed.push((p.display().to_str(),
+                                      workcache_support::digest_file_with_date_using(&on_disk,
+                                                                                     digest)));
+                    r.installed_contents.push((p.display().to_str(),
+                                               workcache_support::digest_file_contents_using(
+                                                   &on_disk, digest)));
+                }
+            }
+        });
         installed_files = installed_files + result;
         note(format!("Installed package {} to {}",
                      id.to_str(),
                      pkg_src.destination_workspace.display()));
-        (installed_files, inputs)
+        Built((installed_files, inputs))
+    }
+
+    fn reinstall(&self, pkg_src: PkgSrc, what: &WhatToBuild)
+                 -> BuildResult<(~[Path], ~[(~str, ~str)])> {
+        let id = pkg_src.id.clone();
+        note(format!("Reinstalling package {}: cleaning it first", id.to_str()));
+        self.clean(pkg_src.build_workspace(), &id);
+
+        // Forget the package's installs (with or without --root or --strip),
+        // its crates' builds (in every profile) and its package script's
+        // builds (with or without --deny-warnings)
+        let install_tag = id.install_tag();
+        let script_tag = pkg_src.package_script_option(&self.context.variant)
+            .map(|p| format!("build_package_script({})", p.display()));
+        let start_dir = pkg_src.start_dir.clone();
+        let forgotten = self.workcache_context.db.write(|db| db.forget(|fn_name| {
+            is_install_tag(fn_name, install_tag.as_slice())
+                || script_tag.as_ref().map_default(false, |t| fn_name.starts_with(*t))
+                || start_dir.is_ancestor_of(&Path::new(fn_name))
+        }));
+        note(format!("Removed {} workcache entries for package {}", forgotten, id.to_str()));
+
+        note(format!("Reinstalling package {}: installing it", id.to_str()));
+        self.install(pkg_src, what)
     }
 
     // again, working around lack of Encodable for Path
@@ -601,14 +1198,16 @@ impl CtxMethods for BuildContext {
                         build_workspace: &Path,
                         build_inputs: &[Path],
                         target_workspace: &Path,
-                        id: &PkgId) -> ~[~str] {
+                        id: &PkgId,
+                        renamed_ids: &[PkgId]) -> ~[~str] {
 
         debug!("install_no_build: assuming {} comes from {} with target {}",
                id.to_str(), build_workspace.display(), target_workspace.display());
 
         // Now copy stuff into the install dirs
-        let maybe_executable = built_executable_in_workspace(id, build_workspace);
-        let maybe_library = built_library_in_workspace(id, build_workspace);
+        let profile = self.context.profile;
+        let maybe_executable = built_executable_in_profile(id, build_workspace, profile);
+        let maybe_library = built_library_in_profile(id, build_workspace, profile);
         let target_exec = target_executable_in_workspace(id, target_workspace);
         let target_lib = maybe_library.as_ref()
             .map(|_| target_library_in_workspace(id, target_workspace));
@@ -618,54 +1217,159 @@ impl CtxMethods for BuildContext {
                target_exec.display(), target_lib,
                maybe_executable, maybe_library);
 
-        self.workcache_context.with_prep(id.install_tag(), |prep| {
-            for ee in maybe_executable.iter() {
+        // Pairs of a built executable and where to install it
+        let mut executables: ~[(Path, Path)] = maybe_executable.iter()
+            .map(|ee| (ee.clone(), target_exec.clone())).collect();
+        for exe_id in renamed_ids.iter() {
+            match built_executable_in_profile(exe_id, build_workspace, profile) {
+                Some(ee) => executables.push((ee,
+                                              target_executable_in_workspace(exe_id,
+                                                                             target_workspace))),
+                None => debug!("install_no_build: no executable {} was built",
+                               exe_id.short_name)
+            }
+        }
+
+        // With --with-docs, pairs of a generated documentation file and where
+        // to install it
+        let docs: ~[(Path, Path)] = if self.context.with_docs {
+            match built_docs_in_profile(id, build_workspace, profile) {
+                Some(doc_dir) => {
+                    let target_docs = target_doc_dir(id, target_workspace);
+                    fs::walk_dir(&doc_dir).filter(|p| p.is_file()).map(|p| {
+                        let dest = target_docs.join(p.path_relative_from(&doc_dir).unwrap());
+                        (p, dest)
+                    }).collect()
+                }
+                None => {
+                    note(format!("Package {} has no documentation in {} to install",
+                                 id.to_str(),
+                                 profile_build_dir(id, build_workspace, profile)
+                                     .join("doc").display()));
+                    ~[]
+                }
+            }
+        } else {
+            ~[]
+        };
+
+        // Installing under a staging root doesn't make the package installed
+        // in the workspace proper, so the two are cached separately
+        let install_tag = match self.context.install_root {
+            Some(ref root) => format!("{} under {}", id.install_tag(), root.display()),
+            None => id.install_tag()
+        };
+        // So are stripped and unstripped installs
+        let (install_tag, other_install_tag) = if self.context.strip {
+            (format!("{} stripped", install_tag), install_tag)
+        } else {
+            (install_tag.clone(), format!("{} stripped", install_tag))
+        };
+        // The binaries that the last install stripped, if it did
+        let stripped_tag = if self.context.strip { &install_tag } else { &other_install_tag };
+        let stripped_before: ~[~str] = self.workcache_context.db.read(|db| {
+            db.discovered_outputs(stripped_tag.as_slice()).move_iter()
+                .filter(|&(ref kind, _, _)| *kind == ~"binary")
+                .map(|(_, name, _)| name)
+                .collect()
+        });
+        let digest = self.context.digest;
+        self.workcache_context.with_prep(install_tag.as_slice(), |prep| {
+            for &(ref ee, _) in executables.iter() {
                 // FIXME (#9639): This needs to handle non-utf8 paths
                 prep.declare_input("binary",
                                    ee.as_str().unwrap(),
-                                   workcache_support::digest_only_date(ee));
+                                   workcache_support::digest_only_date_using(ee, digest));
             }
             for ll in maybe_library.iter() {
                 // FIXME (#9639): This needs to handle non-utf8 paths
                 prep.declare_input("binary",
                                    ll.as_str().unwrap(),
-                                   workcache_support::digest_only_date(ll));
+                                   workcache_support::digest_only_date_using(ll, digest));
+            }
+            for &(ref doc, _) in docs.iter() {
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                prep.declare_input("file",
+                                   doc.as_str().unwrap(),
+                                   workcache_support::digest_file_with_date_using(doc, digest));
             }
-            let subex = maybe_executable.clone();
+            let subex = executables.clone();
+            let subdocs = docs.clone();
             let sublib = maybe_library.clone();
-            let sub_target_ex = target_exec.clone();
             let sub_target_lib = target_lib.clone();
             let sub_build_inputs = build_inputs.to_owned();
-            prep.exec(proc(exe_thing) {
+            let copy_progress = self.copy_progress.clone();
+            let sub_root = self.context.install_root.clone();
+            let strip = self.context.strip;
+            let stripped_before = stripped_before.clone();
+            let sub_id = id.clone();
+            let sub_build_workspace = build_workspace.clone();
+            let sub_target_workspace = target_workspace.clone();
+            let outputs = self.exec_reporting(prep, install_tag.as_slice(), proc(exe_thing) {
                 let mut outputs = ~[];
                 // Declare all the *inputs* to the declared input too, as inputs
-                for executable in subex.iter() {
+                for &(ref executable, _) in subex.iter() {
                     exe_thing.discover_input("binary",
                                              executable.as_str().unwrap().to_owned(),
-                                             workcache_support::digest_only_date(executable));
+                                             workcache_support::digest_only_date_using(executable,
+                                                                                       digest));
                 }
                 for library in sublib.iter() {
                     exe_thing.discover_input("binary",
                                              library.as_str().unwrap().to_owned(),
-                                             workcache_support::digest_only_date(library));
+                                             workcache_support::digest_only_date_using(library,
+                                                                                       digest));
                 }
 
                 for transitive_dependency in sub_build_inputs.iter() {
                     exe_thing.discover_input(
                         "file",
                         transitive_dependency.as_str().unwrap().to_owned(),
-                        workcache_support::digest_file_with_date(transitive_dependency));
+                        workcache_support::digest_file_with_date_using(transitive_dependency,
+                                                                       digest));
                 }
 
 
-                for exec in subex.iter() {
-                    debug!("Copying: {} -> {}", exec.display(), sub_target_ex.display());
-                    fs::mkdir_recursive(&sub_target_ex.dir_path(), io::UserRWX);
-                    fs::copy(exec, &sub_target_ex);
+                // Files are written under the staging root, if there is one,
+                // but the outputs recorded are where they'll be once it's
+                // unpacked, with the digests of the files written. (So an
+                // install under a staging root is never fresh.)
+                let staged = |p: &Path| match sub_root {
+                    Some(ref root) => path_under_root(root, p),
+                    None => p.clone()
+                };
+
+                // Installing into a read-only tree (like sources rustpkg
+                // checked out, used as a workspace with the RUST_PATH hack)
+                // would only fail with a permission error when copying, so
+                // say what's wrong first
+                for ro in util::read_only_ancestor(&staged(&sub_target_workspace)).iter() {
+                    let id = sub_id.to_str();
+                    let ws = sub_target_workspace.display();
+                    if sub_build_workspace == sub_target_workspace {
+                        error(format!("Can't install {} into {}, because {} is read-only. \
+                                       {} is also the workspace {} is built from; \
+                                       install it into a different workspace than its \
+                                       read-only sources (for example, by putting a \
+                                       writable workspace first in the RUST_PATH)",
+                                      id, ws, ro.display(), ws, id));
+                    } else {
+                        error(format!("Can't install {} into {}, because {} is read-only",
+                                      id, ws, ro.display()));
+                    }
+                    fail!(COPY_FAILED_CODE);
+                }
+                for &(ref exec, ref sub_target_ex) in subex.iter() {
+                    let dest = staged(sub_target_ex);
+                    debug!("Copying: {} -> {}", exec.display(), dest.display());
                     // FIXME (#9639): This needs to handle non-utf8 paths
+                    let was_stripped = stripped_before.iter()
+                        .any(|p| p.as_slice() == sub_target_ex.as_str().unwrap());
+                    util::install_binary_or_fail(exec, &dest, strip, was_stripped,
+                                                 &copy_progress, digest);
                     exe_thing.discover_output("binary",
                         sub_target_ex.as_str().unwrap(),
-                        workcache_support::digest_only_date(&sub_target_ex));
+                        workcache_support::digest_only_date_using(&dest, digest));
                     outputs.push(sub_target_ex.as_str().unwrap().to_owned());
                 }
                 for lib in sublib.iter() {
@@ -673,16 +1377,40 @@ impl CtxMethods for BuildContext {
                         .clone().expect(format!("I built {} but apparently \
                                              didn't install it!", lib.display()));
                     target_lib.set_filename(lib.filename().expect("weird target lib"));
-                    fs::mkdir_recursive(&target_lib.dir_path(), io::UserRWX);
-                    fs::copy(lib, &target_lib);
+                    let dest = staged(&target_lib);
+                    // Static libraries (rlibs) are left alone
+                    let dylib = dest.extension_str() == Some(os::consts::DLL_EXTENSION);
+                    let was_stripped = dylib && stripped_before.iter()
+                        .any(|p| p.as_slice() == target_lib.as_str().unwrap());
+                    util::install_binary_or_fail(lib, &dest, strip && dylib, was_stripped,
+                                                 &copy_progress, digest);
                     debug!("3. discovering output {}", target_lib.display());
                     exe_thing.discover_output("binary",
                                               target_lib.as_str().unwrap(),
-                                              workcache_support::digest_only_date(&target_lib));
+                                              workcache_support::digest_only_date_using(&dest,
+                                                                                        digest));
                     outputs.push(target_lib.as_str().unwrap().to_owned());
                 }
+                for &(ref doc, ref target_doc) in subdocs.iter() {
+                    let dest = staged(target_doc);
+                    util::install_file_or_fail(doc, &dest, &copy_progress, digest);
+                    // Recorded by contents, so that `verify` notices edits
+                    exe_thing.discover_output("file",
+                                              target_doc.as_str().unwrap(),
+                                              workcache_support::digest_file_with_date_using(
+                                                  &dest, digest));
+                    outputs.push(target_doc.as_str().unwrap().to_owned());
+                }
                 outputs
-            })
+            });
+            // What's installed now is what this install put there, so earlier
+            // installs of the package (which `verify` would check against
+            // too) are forgotten
+            prep.forget_other_runs();
+            self.workcache_context.db.write(|db| db.forget(|fn_name| {
+                fn_name == other_install_tag.as_slice()
+            }));
+            outputs
         })
     }
 
@@ -690,20 +1418,346 @@ impl CtxMethods for BuildContext {
         fail!("prefer not yet implemented");
     }
 
-    fn test(&self, pkgid: &PkgId, workspace: &Path)  {
-        match built_test_in_workspace(pkgid, workspace) {
-            Some(test_exec) => {
+    fn targets(&self, mut pkg_src: PkgSrc) {
+        // If there's a package script, it does the building, so
+        // the crates we infer might not be the ones that get built
+        let inferred_only = pkg_src.package_script_option(&self.context.variant).is_some();
+        pkg_src.find_crates(self.context.cfgs);
+
+        let crate_sets = [("lib", Lib, &pkg_src.libs), ("main", Main, &pkg_src.mains),
+                          ("test", Test, &pkg_src.tests), ("bench", Bench, &pkg_src.benchs),
+                          ("example", Main, &pkg_src.examples)];
+        // With --print-crate-id, each crate's link metadata, from parsing it
+        let crate_id = |kind: &str, what: OutputType, file: &Path| {
+            if !self.context.print_crate_ids {
+                return None;
+            }
+            // Examples are built under their own names
+            let id = if kind == "example" {
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                PkgId { short_name: file.filestem_str().unwrap().to_owned(),
+                        ..pkg_src.id.clone() }
+            } else {
+                pkg_src.crate_pkg_id(file, what)
+            };
+            let crate_id = util::CrateId::of_crate(&pkg_src.start_dir.join(file), &id, what);
+            if crate_id.is_none() {
+                warn(format!("Couldn't parse {}, so its crate ID is unknown", file.display()));
+            }
+            crate_id
+        };
+        if self.context.json {
+            let mut crates = ~[];
+            for &(kind, what, ref cs) in crate_sets.iter() {
+                for c in cs.iter() {
+                    let mut obj = ~TreeMap::new();
+                    obj.insert(~"kind", json::String(kind.to_owned()));
+                    obj.insert(~"path", json::String(c.file.display().to_str()));
+                    if self.context.print_crate_ids {
+                        obj.insert(~"crate_id", match crate_id(kind, what, &c.file) {
+                            Some(id) => id.to_json(),
+                            None => json::Null
+                        });
+                    }
+                    crates.push(json::Object(obj));
+                }
+            }
+            let mut obj = ~TreeMap::new();
+            obj.insert(~"package", json::String(pkg_src.id.to_str()));
+            obj.insert(~"inferred_only", json::Boolean(inferred_only));
+            obj.insert(~"targets", json::List(crates));
+            println(json::Object(obj).to_pretty_str());
+        } else {
+            if inferred_only {
+                note(format!("Package {} has a package script, which does its own building; \
+                              these crates were inferred for listing purposes only",
+                             pkg_src.id.to_str()));
+            }
+            for &(kind, what, ref cs) in crate_sets.iter() {
+                for c in cs.iter() {
+                    match crate_id(kind, what, &c.file) {
+                        Some(id) => println!("{:<6} {} {}", kind, c.file.display(), id.to_str()),
+                        None => println!("{:<6} {}", kind, c.file.display())
+                    }
+                }
+            }
+        }
+    }
+
+    fn deps(&self, mut pkg_src: PkgSrc) {
+        pkg_src.find_crates(self.context.cfgs);
+        let crates = deps::crate_deps(&pkg_src, &self.sysroot());
+        let cycles = deps::find_cycles(crates);
+
+        if self.context.json {
+            let paths: ~[json::Json] = crates.iter()
+                .map(|c| json::String(c.file.display().to_str())).collect();
+            let mut objs = ~[];
+            for (i, c) in crates.iter().enumerate() {
+                let mut obj = ~TreeMap::new();
+                obj.insert(~"kind", json::String(c.kind.to_owned()));
+                obj.insert(~"path", paths[i].clone());
+                obj.insert(~"local", json::List(c.local.map(|&j| paths[j].clone())));
+                obj.insert(~"external", json::List(c.external.map(|e| json::String(e.clone()))));
+                objs.push(json::Object(obj));
+            }
+            let mut obj = ~TreeMap::new();
+            obj.insert(~"package", json::String(pkg_src.id.to_str()));
+            obj.insert(~"crates", json::List(objs));
+            obj.insert(~"cycles",
+                       json::List(cycles.map(|cycle| {
+                           json::List(cycle.map(|&j| paths[j].clone()))
+                       })));
+            println(json::Object(obj).to_pretty_str());
+        } else {
+            for c in crates.iter() {
+                println!("{:<6} {}", c.kind, c.file.display());
+                for &i in c.local.iter() {
+                    println!("    -> {}", crates[i].file.display());
+                }
+                for e in c.external.iter() {
+                    println!("    -> {} (external)", *e);
+                }
+            }
+            for cycle in cycles.iter() {
+                let names: ~[~str] = cycle.iter().map(|&i| crates[i].file.display().to_str())
+                                                 .collect();
+                warn(format!("crates in {} depend on each other in a cycle: {}",
+                             pkg_src.id.to_str(), names.connect(" -> ")));
+            }
+        }
+    }
+
+    fn test(&self, pkg_src: &PkgSrc)  {
+        let pkgid = &pkg_src.id;
+        let workspace = pkg_src.build_workspace();
+        // There's a test executable for each test crate that was built, and
+        // only those are run, whatever else is in the build directory
+        let test_execs: ~[Path] = pkg_src.tests.iter().map(|crate| {
+            let test_id = pkg_src.test_id(&crate.file);
+            match built_test_in_profile(&test_id, workspace, self.context.profile) {
+                Some(test_exec) => test_exec,
+                None => {
+                    error(format!("Internal error: the test crate {} of package ID {} was \
+                                   built, but its test executable isn't in workspace {}! \
+                                   Please report this as a bug.", crate.file.display(),
+                                  pkgid.to_str(), workspace.display()));
+                    fail!("Test executables weren't built");
+                }
+            }
+        }).collect();
+        if test_execs.is_empty() {
+            // That's expected if the package has no test crates to build
+            let (found, kept) = self.test_crates(pkgid);
+            if found.is_empty() {
+                note(format!("Package {} has no test crates, so there are no tests to run",
+                             pkgid.to_str()));
+                return;
+            }
+            if kept.is_empty() {
+                note(format!("All of package {}'s test crates were excluded with --exclude, \
+                              so there are no tests to run", pkgid.to_str()));
+                return;
+            }
+            let names: ~[~str] = kept.iter().map(|p| p.display().to_str()).collect();
+            error(format!("Internal error: package ID {} has test crates ({}), but no test \
+                           executables for them were built in workspace {}! Please report \
+                           this as a bug.", pkgid.to_str(), names.connect(", "),
+                          workspace.display()));
+            fail!("Test executables weren't built");
+        }
+        // With --isolated, the tests get a HOME and RUST_PATH of their own,
+        // so they can't touch the user's workspaces. It's removed afterward.
+        let test_home = if self.context.isolated_tests {
+            Some(TempDir::new("rustpkg-test-home")
+                 .expect("couldn't create a temporary HOME for the tests"))
+        } else {
+            None
+        };
+        let env = test_home.as_ref().map(|home| util::isolated_env(home.path()));
+        // The test harness reads how many tests to run at once from
+        // RUST_TEST_TASKS
+        let env = match self.context.test_threads {
+            Some(n) => Some(util::env_with(env.unwrap_or_else(|| os::env()), "RUST_TEST_TASKS",
+                                           n.to_str())),
+            None => env
+        };
+        let test_args = ~[~"--test"] + self.context.test_args;
+        let timeout = self.context.test_timeout;
+        // Run up to `jobs` test executables at once, each waited on in a
+        // task of its own
+        let mut failed = ~[];
+        for batch in test_execs.chunks(self.context.jobs) {
+            let ports = batch.map(|test_exec| {
                 debug!("test: test_exec = {}", test_exec.display());
                 // FIXME (#9639): This needs to handle non-utf8 paths
-                let status = run::process_status(test_exec.as_str().unwrap(), [~"--test"]);
+                let test_exec_str = test_exec.as_str().unwrap().to_owned();
+                let (args, env) = (test_args.clone(), env.clone());
+                let (port, chan) = oneshot();
+                do task::spawn {
+                    chan.send(util::process_status_in_env(test_exec_str, args, env, timeout));
+                }
+                port
+            });
+            for (test_exec, port) in batch.iter().zip(ports.move_iter()) {
+                let name = test_exec.filename_display().to_str();
+                match port.recv() {
+                    Some(status) if status.success() => (),
+                    Some(_) => failed.push(name),
+                    None => {
+                        error(format!("Tests for package {} ({}) timed out after {} seconds",
+                                      pkgid.to_str(), name, timeout.unwrap()));
+                        failed.push(name);
+                    }
+                }
+            }
+        }
+        if test_execs.len() > 1 {
+            note(format!("{} of {} test crates for package {} passed",
+                         test_execs.len() - failed.len(), test_execs.len(), pkgid.to_str()));
+        }
+        if !failed.is_empty() {
+            error(format!("Tests failed in {}", failed.connect(", ")));
+            fail!("Some tests failed");
+        }
+    }
+
+    /// So that `test` can explain why it found no test executables to run
+    fn test_crates(&self, pkgid: &PkgId) -> (~[Path], ~[Path]) {
+        let cwd = os::getcwd();
+        let mut srcs: ~[PkgSrc] = pkg_parent_workspaces(&self.context, pkgid).move_iter()
+            .map(|ws| {
+                let hack_dir = is_rust_path_hack_dir(&self.context, pkgid, &ws);
+                PkgSrc::new(ws.clone(), ws, hack_dir, pkgid.clone())
+            }).collect();
+        if srcs.is_empty() && dir_has_crate_file(&cwd) {
+            // The package in the current directory, as in build_args
+            srcs.push(PkgSrc::new(cwd, fallback_workspace(&self.context), true, pkgid.clone()));
+        }
+        let excludes = self.context.excludes.map(|e| Pattern::new(*e));
+        let mut found = ~[];
+        let mut kept = ~[];
+        for src in srcs.mut_iter() {
+            src.find_crates_with_filter(self.context.cfgs, |s| { is_test(&Path::new(s)) });
+            for crate in src.tests.iter() {
+                let full = src.start_dir.join(&crate.file);
+                if !excludes.iter().any(|p| p.matches_path(&crate.file) || p.matches_path(&full)) {
+                    kept.push(full.clone());
+                }
+                found.push(full);
+            }
+        }
+        (found, kept)
+    }
+
+    /// Prints the executable installed for the version of `id` given in it,
+    /// or if it doesn't give one, for the latest version installed, which is
+    /// the one `prefer` prefers. With --all, prints the executable of every
+    /// installed version, oldest first. Fails if there aren't any.
+    fn which(&self, id: &str) {
+        let pkgid = PkgId::new(id);
+        let requested = split_version(id).map(|(_, v)| v);
+        // Executables are installed under the package's short name, so each
+        // workspace has at most one, whatever its version
+        let index = InstalledIndex::new(rust_path(), self.context.cache_index);
+        let exes: ~[Path] = index.workspaces_for(&PkgId::new(pkgid.short_name)).move_iter()
+            .filter_map(|ws| installed_executable_in_workspace(&pkgid, &ws)).collect();
+        // The install records say which version each of them is: the one
+        // whose install wrote the file that's there now
+        let prefix = format!("install({}-", pkgid.path.as_str().unwrap());
+        let mut installed: ~[(Version, Path)] = ~[];
+        self.workcache_context.db.read(|db| {
+            for tag in db.fn_names().iter().filter(|t| t.starts_with(prefix.as_slice())) {
+                let rest = tag.slice_from(prefix.len());
+                let (vers, suffix) = match rest.find(')') {
+                    Some(i) => (rest.slice_to(i), rest.slice_from(i + 1)),
+                    None => continue
+                };
+                // Installs under a staging root didn't install anything here
+                if suffix != "" && suffix != " stripped" {
+                    continue;
+                }
+                let vers = try_parsing_version(vers).unwrap_or_else(|| Tagged(vers.to_owned()));
+                for &(_, ref name, ref digest) in db.discovered_outputs(tag.as_slice()).iter() {
+                    let exe = Path::new(name.as_slice());
+                    if exes.contains(&exe)
+                        && workcache_support::date_digest_matches(&exe, *digest)
+                        && !installed.iter().any(|&(_, ref p)| *p == exe) {
+                        installed.push((vers.clone(), exe));
+                    }
+                }
+            }
+        });
+        let installed = merge_sort(installed, |&(ref v1, _), &(ref v2, _)| v1 <= v2);
+        let chosen: ~[Path] = if self.context.all_versions {
+            installed.move_iter().map(|(_, exe)| exe).collect()
+        } else {
+            match requested {
+                Some(ref v) => installed.move_iter().filter(|&(ref iv, _)| iv == v)
+                    .map(|(_, exe)| exe).take(1).collect(),
+                None => installed.move_iter().last().map(|(_, exe)| exe).move_iter().collect()
+            }
+        };
+        if chosen.is_empty() {
+            fail!(NONEXISTENT_PACKAGE_CODE);
+        }
+        for exe in chosen.iter() {
+            println(exe.display().to_str());
+        }
+    }
+
+    fn update(&self, pkgid: &PkgId) {
+        use conditions::git_checkout_failed::cond;
+
+        // Use the clone in the first workspace in the RUST_PATH that has
+        // one, rather than cloning it again
+        let found = rust_path().move_iter().filter_map(|ws| {
+            checked_out_source_in_workspace(pkgid, &ws).map(|dir| (ws.clone(), dir))
+        }).next();
+        let (workspace, dir) = match found {
+            Some(found) => found,
+            None => {
+                error(format!("There are no sources for {} in any workspace in the RUST_PATH \
+                               to update; `rustpkg install {}` fetches them",
+                              pkgid.to_str(), pkgid.to_str()));
+                fail!(NONEXISTENT_PACKAGE_CODE);
+            }
+        };
+        if !is_git_dir(&dir) {
+            warn(format!("The sources for {} in {} aren't a git clone, so there's nothing to \
+                          update", pkgid.to_str(), dir.display()));
+            return;
+        }
+        match source_control::git_update(&dir, &pkgid.version) {
+            Ok(commit) => note(format!("Updated the sources for {} in {} to commit {}",
+                                       pkgid.to_str(), dir.display(), commit)),
+            // FIXME (#9639): This needs to handle non-utf8 paths
+            Err((status, git_error)) => cond.raise((dir.as_str().unwrap().to_owned(),
+                                                    dir.clone(), status, git_error))
+        }
+        // Checking out made the changed files writable again
+        make_read_only(&dir);
+        let pkg_src = PkgSrc::new(workspace.clone(), workspace, false, pkgid.clone());
+        self.reinstall(pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    }
+
+    fn run_example(&self, pkgid: &PkgId, workspace: &Path, name: &str, args: &[~str]) {
+        match built_example_in_profile(pkgid, name, workspace, self.context.profile) {
+            Some(example_exec) => {
+                debug!("run_example: example_exec = {}", example_exec.display());
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                let status = run::process_status(example_exec.as_str().unwrap(), args);
                 if !status.success() {
-                    fail!("Some tests failed");
+                    error(format!("Example {} of package {} failed: {}",
+                                  name, pkgid.to_str(), status));
+                    fail!("Example failed");
                 }
             }
             None => {
-                error(format!("Internal error: test executable for package ID {} in workspace {} \
+                error(format!("Internal error: example {} for package ID {} in workspace {} \
                            wasn't built! Please report this as a bug.",
-                           pkgid.to_str(), workspace.display()));
+                           name, pkgid.to_str(), workspace.display()));
+                fail!(NONEXISTENT_PACKAGE_CODE);
             }
         }
     }
@@ -729,7 +1783,21 @@ pub fn main() {
     os::set_exit_status(main_args(os::args()));
 }
 
+/// The command named by the argument immediately following `-h` or `--help`,
+/// if there is one
+fn help_command<'a>(args: &'a [~str]) -> Option<&'a ~str> {
+    args.windows(2)
+        .find(|w| (w[0] == ~"-h" || w[0] == ~"--help") && util::is_cmd(w[1]))
+        .map(|w| &w[1])
+}
+
 pub fn main_args(args: &[~str]) -> int {
+    // Everything after a literal `--` is for the test harness (or, with
+    // run-example, the example), even if it looks like one of rustpkg's flags
+    let (args, passthrough_args) = match args.iter().position(|a| *a == ~"--") {
+        Some(i) => (args.slice_to(i), args.slice_from(i + 1).to_owned()),
+        None => (args, ~[])
+    };
     let opts = ~[getopts::optflag("h"), getopts::optflag("help"),
                                         getopts::optflag("no-link"),
                                         getopts::optflag("no-trans"),
@@ -738,17 +1806,58 @@ pub fn main_args(args: &[~str]) -> int {
                                         getopts::optflag("parse-only"),
                  getopts::optflag("S"), getopts::optflag("assembly"),
                  getopts::optmulti("c"), getopts::optmulti("cfg"),
+                 getopts::optmulti("config-arg"),
                  getopts::optflag("v"), getopts::optflag("version"),
                  getopts::optflag("r"), getopts::optflag("rust-path-hack"),
                                         getopts::optopt("sysroot"),
+                                        getopts::optflag("print-sysroot"),
                                         getopts::optflag("emit-llvm"),
                                         getopts::optopt("linker"),
-                                        getopts::optopt("link-args"),
+                                        getopts::optmulti("target-linker"),
+                                        getopts::optmulti("link-args"),
+                                        getopts::optmulti("link-arg"),
                                         getopts::optopt("opt-level"),
                  getopts::optflag("O"),
                                         getopts::optflag("save-temps"),
+                                        getopts::optflag("deny-warnings"),
                                         getopts::optopt("target"),
                                         getopts::optopt("target-cpu"),
+                                        getopts::optmulti("target-feature"),
+                                        getopts::optopt("from-file"),
+                                        getopts::optopt("test-timeout"),
+                                        getopts::optopt("test-threads"),
+                                        getopts::optflag("json"),
+                                        getopts::optopt("depth"),
+                                        getopts::optopt("lib-type"),
+                                        getopts::optopt("profile"),
+                                        getopts::optopt("digest"),
+                                        getopts::optmulti("exclude"),
+                                        getopts::optopt("jobs"),
+                                        getopts::optflag("no-default-workspace"),
+                                        getopts::optflag("no-wait"),
+                                        getopts::optflag("quiet"),
+                                        getopts::optflag("silent"),
+                                        getopts::optopt("root"),
+                                        getopts::optflag("emit-deps"),
+                                        getopts::optflag("with-docs"),
+                                        getopts::optflag("strip"),
+                                        getopts::optflag("allow-dirty"),
+                                        getopts::optflag("cache-index"),
+                                        getopts::optopt("out-dir"),
+                                        getopts::optopt("tmp-dir"),
+                                        getopts::optflag("strict-cache"),
+                                        getopts::optflag("isolated"),
+                                        getopts::optflag("timings"),
+                                        getopts::optopt("report"),
+                                        getopts::optopt("record"),
+                                        getopts::optflag("keep-going"),
+                                        getopts::optflag("watch"),
+                                        getopts::optopt("ambiguous"),
+                                        getopts::optopt("variant"),
+                                        getopts::optflag("all"),
+                                        getopts::optflag("print-crate-id"),
+                                        getopts::optflag("deep"),
+                                        getopts::optflag("dry-run"),
                  getopts::optmulti("Z")                                   ];
     let matches = &match getopts::getopts(args, opts) {
         result::Ok(m) => m,
@@ -768,6 +1877,14 @@ pub fn main_args(args: &[~str]) -> int {
     let parse_only = matches.opt_present("parse-only");
     let pretty = matches.opt_present("pretty");
     let emit_llvm = matches.opt_present("emit-llvm");
+    let phase_flags = PhaseFlags {
+        no_trans: no_trans,
+        no_link: no_link,
+        pretty: pretty,
+        parse_only: parse_only,
+        emit_llvm: emit_llvm,
+        generate_asm: generate_asm
+    };
 
     if matches.opt_present("v") ||
        matches.opt_present("version") {
@@ -775,12 +1892,113 @@ pub fn main_args(args: &[~str]) -> int {
         return 0;
     }
 
+    // A bad --sysroot would otherwise only show up as a failure to find
+    // libraries partway through compiling something
+    let sroot = match supplied_sysroot {
+        Some(s) => {
+            let sroot = Path::new(s);
+            match context::check_sysroot(&sroot) {
+                Ok(()) => sroot,
+                Err(e) => {
+                    error(e);
+                    return BAD_SYSROOT_CODE;
+                }
+            }
+        }
+        None => filesearch::get_or_default_sysroot()
+    };
+    debug!("Using sysroot: {}", sroot.display());
+    if matches.opt_present("print-sysroot") {
+        println(context::resolve_sysroot(&sroot).display().to_str());
+        return 0;
+    }
+
+    let compile_upto = match context::compile_upto(&phase_flags) {
+        Ok(c) => c,
+        Err(e) => {
+            error(e);
+            return BAD_FLAG_CODE;
+        }
+    };
+
     let use_rust_path_hack = matches.opt_present("r") ||
                              matches.opt_present("rust-path-hack");
+    let no_default_workspace = matches.opt_present("no-default-workspace");
+    let no_wait = matches.opt_present("no-wait");
+    messages::set_verbosity(if matches.opt_present("silent") {
+        messages::Silent
+    } else if matches.opt_present("quiet") {
+        messages::Quiet
+    } else {
+        messages::Normal
+    });
+    let install_root = matches.opt_str("root").map(|r| os::make_absolute(&Path::new(r)));
+    let emit_deps = matches.opt_present("emit-deps");
+    let with_docs = matches.opt_present("with-docs");
+    let strip = matches.opt_present("strip");
+    let allow_dirty = matches.opt_present("allow-dirty");
+    let cache_index = matches.opt_present("cache-index");
+    let out_dir = matches.opt_str("out-dir").map(|d| os::make_absolute(&Path::new(d)));
+    let tmp_dir = matches.opt_str("tmp-dir").map(|d| os::make_absolute(&Path::new(d)));
+    let strict_cache = matches.opt_present("strict-cache");
+    let isolated_tests = matches.opt_present("isolated");
+    let timings = matches.opt_present("timings");
+    let report = matches.opt_str("report").map(|r| os::make_absolute(&Path::new(r)));
+    let record = matches.opt_str("record").map(|r| os::make_absolute(&Path::new(r)));
+    let keep_going = matches.opt_present("keep-going");
+    let watch = matches.opt_present("watch");
 
     let linker = matches.opt_str("linker");
-    let link_args = matches.opt_str("link-args");
+    // --link-args is split on spaces, for compatibility; each --link-arg is one argument
+    let mut link_args = ~[];
+    for args in matches.opt_strs("link-args").iter() {
+        link_args.push_all_move(args.words().map(|a| a.to_owned()).collect());
+    }
+    link_args.push_all(matches.opt_strs("link-arg"));
+    let mut target_linkers = ~[];
+    for s in matches.opt_strs("target-linker").iter() {
+        match s.find('=') {
+            Some(i) if i > 0 && i + 1 < s.len() =>
+                target_linkers.push((s.slice_to(i).to_owned(), s.slice_from(i + 1).to_owned())),
+            _ => {
+                error(format!("--target-linker expects TRIPLE=LINKER, not `{}`", *s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    }
     let cfgs = matches.opt_strs("cfg") + matches.opt_strs("c");
+    let config_args = matches.opt_strs("config-arg");
+    let profile = match matches.opt_str("profile") {
+        None => None,
+        Some(s) => match Profile::from_str(s) {
+            Some(p) => Some(p),
+            None => {
+                error(format!("--profile expects `debug` or `release`, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let digest = match matches.opt_str("digest") {
+        None => None,
+        Some(s) => match DigestAlgorithm::from_str(s) {
+            Some(d) => Some(d),
+            None => {
+                error(format!("--digest expects `sha1` or `sha256`, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let excludes = matches.opt_strs("exclude");
+    let jobs = match matches.opt_str("jobs") {
+        None => None,
+        Some(s) => match from_str::<uint>(s) {
+            Some(n) if n > 0 => Some(n),
+            _ => {
+                error(format!("--jobs expects a positive number of crates, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
     let mut user_supplied_opt_level = true;
     let opt_level = match matches.opt_str("opt-level") {
         Some(~"0") => session::No,
@@ -790,13 +2008,86 @@ pub fn main_args(args: &[~str]) -> int {
         _ if matches.opt_present("O") => session::Default,
         _ => {
             user_supplied_opt_level = false;
-            session::No
+            profile.map_default(session::No, |p| p.default_opt_level())
         }
     };
 
+    let from_file = matches.opt_str("from-file");
+    let all = matches.opt_present("all");
+    let print_crate_ids = matches.opt_present("print-crate-id");
+    let deep_clean = matches.opt_present("deep");
+    let dry_run = matches.opt_present("dry-run");
+    let json = matches.opt_present("json");
+    let test_timeout = match matches.opt_str("test-timeout") {
+        None => None,
+        Some(s) => match from_str::<u64>(s) {
+            Some(secs) if secs > 0 => Some(secs),
+            _ => {
+                error(format!("--test-timeout expects a positive number of seconds, \
+                               not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let test_threads = match matches.opt_str("test-threads") {
+        None => None,
+        Some(s) => match from_str::<uint>(s) {
+            Some(n) if n > 0 => Some(n),
+            _ => {
+                error(format!("--test-threads expects a positive number of threads, not `{}`",
+                              s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let git_clone_depth = match matches.opt_str("depth") {
+        None => None,
+        Some(s) => match from_str::<uint>(s) {
+            Some(n) if n > 0 => Some(n),
+            _ => {
+                error(format!("--depth expects a positive number of commits, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let ambiguous = match matches.opt_str("ambiguous") {
+        None => None,
+        Some(s) => match AmbiguityPolicy::from_str(s) {
+            Some(p) => Some(p),
+            None => {
+                error(format!("--ambiguous expects `error`, `first` or `all`, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
+    let variant = matches.opt_str("variant");
+    for v in variant.iter() {
+        if v.is_empty() || !v.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            error(format!("--variant expects a name made of letters, digits, `-` and `_`, \
+                           not `{}`", *v));
+            return BAD_FLAG_CODE;
+        }
+    }
+    let lib_type = match matches.opt_str("lib-type") {
+        None => Dylib,
+        Some(s) => match LibType::from_str(s) {
+            Some(t) => t,
+            None if s == ~"staticlib" => {
+                error("--lib-type staticlib isn't supported by this version of rustc; \
+                       try --lib-type rlib");
+                return BAD_FLAG_CODE;
+            }
+            None => {
+                error(format!("--lib-type expects `dylib` or `rlib`, not `{}`", s));
+                return BAD_FLAG_CODE;
+            }
+        }
+    };
     let save_temps = matches.opt_present("save-temps");
+    let deny_warnings = matches.opt_present("deny-warnings");
     let target     = matches.opt_str("target");
     let target_cpu = matches.opt_str("target-cpu");
+    let target_features = matches.opt_strs("target-feature");
     let experimental_features = {
         let strs = matches.opt_strs("Z");
         if matches.opt_present("Z") {
@@ -807,76 +2098,86 @@ pub fn main_args(args: &[~str]) -> int {
         }
     };
 
+    let help_cmd = if help { help_command(args) } else { None };
     let mut args = matches.free.clone();
     args.shift();
 
     if (args.len() < 1) {
         usage::general();
-        return 1;
+        return if help { 0 } else { 1 };
     }
 
     let rustc_flags = RustcFlags {
         linker: linker,
+        target_linkers: target_linkers,
         link_args: link_args,
         optimization_level: opt_level,
-        compile_upto: if no_trans {
-            Trans
-        } else if no_link {
-            Link
-        } else if pretty {
-            Pretty
-        } else if parse_only {
-            Analysis
-        } else if emit_llvm && generate_asm {
-            LLVMAssemble
-        } else if generate_asm {
-            Assemble
-        } else if emit_llvm {
-            LLVMCompileBitcode
-        } else {
-            Nothing
-        },
+        compile_upto: compile_upto,
         save_temps: save_temps,
+        deny_warnings: deny_warnings,
         target: target,
         target_cpu: target_cpu,
+        target_features: target_features,
         additional_library_paths:
             HashSet::new(), // No way to set this from the rustpkg command line
-        experimental_features: experimental_features
+        experimental_features: experimental_features,
+        lib_type: lib_type
     };
 
-    let mut cmd_opt = None;
-    for a in args.iter() {
-        if util::is_cmd(*a) {
-            cmd_opt = Some(a);
-            break;
-        }
-    }
+    // A command word right after `-h`/`--help` is what the user wants help
+    // for, wherever it appears; otherwise, use the first command word
+    let cmd_opt = help_cmd.or_else(|| args.iter().find(|a| util::is_cmd(**a)));
     let cmd = match cmd_opt {
         None => {
             usage::general();
             return 0;
         }
         Some(cmd) => {
-            let bad_option = context::flags_forbidden_for_cmd(&rustc_flags,
-                                                              cfgs,
-                                                              *cmd,
-                                                              user_supplied_opt_level);
-            if help || bad_option {
-                match *cmd {
-                    ~"build" => usage::build(),
-                    ~"clean" => usage::clean(),
-                    ~"do" => usage::do_cmd(),
-                    ~"info" => usage::info(),
-                    ~"install" => usage::install(),
-                    ~"list"    => usage::list(),
-                    ~"prefer" => usage::prefer(),
-                    ~"test" => usage::test(),
-                    ~"init" => usage::init(),
-                    ~"uninstall" => usage::uninstall(),
-                    ~"unprefer" => usage::unprefer(),
-                    _ => usage::general()
-                };
-                if bad_option {
+            let mut given_flags = context::rustc_flags_given(&rustc_flags, cfgs,
+                                                             user_supplied_opt_level);
+            for &(flag, present) in [("--from-file", from_file.is_some()),
+                                     ("--config-arg", !config_args.is_empty()),
+                                     ("--test-timeout", test_timeout.is_some()),
+                                     ("--test-threads", test_threads.is_some()),
+                                     ("--isolated", isolated_tests),
+                                     ("--timings", timings),
+                                     ("--report", report.is_some()),
+                                     ("--record", record.is_some()),
+                                     ("--", !passthrough_args.is_empty()),
+                                     ("--json", json),
+                                     ("--depth", git_clone_depth.is_some()),
+                                     ("--tmp-dir", tmp_dir.is_some()),
+                                     ("--keep-going", keep_going),
+                                     ("--watch", watch),
+                                     ("--ambiguous", ambiguous.is_some()),
+                                     ("--variant", variant.is_some()),
+                                     ("--profile", profile.is_some()),
+                                     ("--digest", digest.is_some()),
+                                     ("--exclude", !excludes.is_empty()),
+                                     ("--jobs", jobs.is_some()),
+                                     ("--root", install_root.is_some()),
+                                     ("--emit-deps", emit_deps),
+                                     ("--with-docs", with_docs),
+                                     ("--strip", strip),
+                                     ("--allow-dirty", allow_dirty),
+                                     ("--cache-index", cache_index),
+                                     ("--out-dir", out_dir.is_some()),
+                                     ("--no-wait", no_wait),
+                                     ("--all", all),
+                                     ("--deep", deep_clean),
+                                     ("--dry-run", dry_run),
+                                     ("--print-crate-id", print_crate_ids)].iter() {
+                if present {
+                    given_flags.push(flag);
+                }
+            }
+            let bad_option = context::flags_forbidden_for_cmd(given_flags, *cmd);
+            for flag in bad_option.iter() {
+                error(format!("flag `{}` is not valid for command `{}`", *flag, *cmd));
+            }
+            if help || bad_option.is_some() {
+                usage::command(*cmd);
+                if bad_option.is_some() {
                     return BAD_FLAG_CODE;
                 }
                 else {
@@ -893,43 +2194,676 @@ pub fn main_args(args: &[~str]) -> int {
     // I had to add this type annotation to get the code to typecheck
     let mut remaining_args: ~[~str] = remaining_args.map(|s| (*s).clone()).collect();
     remaining_args.shift();
-    let sroot = match supplied_sysroot {
-        Some(s) => Path::new(s),
-        _ => filesearch::get_or_default_sysroot()
+    let test_args = if *cmd == ~"run-example" {
+        remaining_args.push_all(passthrough_args);
+        ~[]
+    } else {
+        passthrough_args
     };
-
-    debug!("Using sysroot: {}", sroot.display());
+    // --all means every workspace to `clean`, and every installed version
+    // to `which`
+    let clean_everything = all && *cmd == ~"clean";
+    let all_versions = all && *cmd == ~"which";
     let ws = default_workspace();
     debug!("Will store workcache in {}", ws.display());
 
-    let rm_args = remaining_args.clone();
-    let sub_cmd = cmd.clone();
+    let context = Context {
+        cfgs: cfgs,
+        config_args: config_args,
+        rustc_flags: rustc_flags,
+        use_rust_path_hack: use_rust_path_hack,
+        sysroot: sroot, // Currently, only tests override this
+        test_timeout: test_timeout,
+        test_threads: test_threads,
+        json: json,
+        git_clone_depth: git_clone_depth,
+        profile: profile,
+        digest: digest.unwrap_or(Sha1Digest),
+        excludes: excludes,
+        jobs: jobs.unwrap_or(os::num_cpus()),
+        no_default_workspace: no_default_workspace,
+        capture_script_output: false,
+        install_root: install_root,
+        emit_deps: emit_deps,
+        with_docs: with_docs,
+        strip: strip,
+        allow_dirty: allow_dirty,
+        cache_index: cache_index,
+        out_dir: out_dir,
+        tmp_dir: tmp_dir,
+        strict_cache: strict_cache,
+        isolated_tests: isolated_tests,
+        timings: timings,
+        report: report.clone(),
+        test_args: test_args,
+        ambiguous: ambiguous.unwrap_or(FailIfAmbiguous),
+        variant: variant,
+        all_versions: all_versions,
+        print_crate_ids: print_crate_ids,
+        no_wait: no_wait
+    };
+
+    if watch {
+        if report.is_some() {
+            error("--watch can't be combined with --report");
+            usage::build();
+            return BAD_FLAG_CODE;
+        }
+        return watch_and_build(remaining_args, context, &ws, !no_wait, keep_going);
+    }
+
+    // Commands that change the workspace hold its lock until they're done,
+    // so that two of them don't update the workcache database at once.
+    // run-example only holds it while it builds the example.
+    let _lock = if util::changes_workspace(*cmd) && *cmd != ~"run-example" {
+        match WorkspaceLock::acquire(&ws, !no_wait) {
+            Some(lock) => Some(lock),
+            None => {
+                error(format!("Another rustpkg is using the workspace {}", ws.display()));
+                return WORKSPACE_LOCKED_CODE;
+            }
+        }
+    } else {
+        None
+    };
+
+    match from_file {
+        Some(ref list_file) => {
+            if !remaining_args.is_empty() {
+                error("--from-file can't be combined with a package ID argument");
+                usage::install();
+                return BAD_FLAG_CODE;
+            }
+            let list_file = Path::new(list_file.as_slice());
+            return with_report(report, record, |r| {
+                install_from_file(&list_file, context.clone(), r)
+            });
+        }
+        None => ()
+    }
+
+    if *cmd == ~"doctor" {
+        return doctor(remaining_args);
+    }
+    if *cmd == ~"verify" {
+        return verify(remaining_args, context);
+    }
+    if *cmd == ~"clean-cache" {
+        return clean_cache(remaining_args, context, dry_run);
+    }
+    if *cmd == ~"env" {
+        return print_env(remaining_args, &context, &ws);
+    }
+    if clean_everything {
+        return clean_all(remaining_args, deep_clean);
+    }
+
+    let flag_cmd = if *cmd == ~"reinstall" { ~"install" } else { cmd.clone() };
+    if (flag_cmd == ~"build" || flag_cmd == ~"install") && remaining_args.len() > 1 {
+        return with_report(report, record, |r| {
+            run_each(*cmd, remaining_args.clone(), context.clone(), keep_going, r)
+        });
+    }
+
+    with_report(report, record, |r| {
+        run_in_task(*cmd, remaining_args.clone(), context.clone(), r)
+    })
+}
+
+/// Runs `blk`, which returns an exit code, and returns its exit code. With
+/// --report (if `path` is Some), `blk` is given a report to record what it
+/// does in, which is then written to `path` along with how long `blk` took
+/// and its exit code. With --record (if `record` is Some), the files that
+/// `blk` installed are listed in an install manifest written to `record`.
+fn with_report(path: Option<Path>, record: Option<Path>,
+               blk: |Option<Report>| -> int) -> int {
+    if path.is_none() && record.is_none() {
+        return blk(None);
+    }
+    let report = RWArc::new(BuildReport::new());
+    let start = precise_time_ns();
+    let mut code = blk(Some(report.clone()));
+    let seconds = ((precise_time_ns() - start) as f64) / 1e9;
+    match record {
+        Some(ref record) => {
+            let manifest = report.read(|r| install_manifest(r.installed_contents));
+            match io::result(|| File::create(record).write(manifest.as_bytes())) {
+                Ok(()) => (),
+                Err(e) => {
+                    error(format!("Couldn't write the install record to {}: {}",
+                                  record.display(), e.desc));
+                    if code == 0 { code = COPY_FAILED_CODE; }
+                }
+            }
+        }
+        None => ()
+    }
+    let path = match path {
+        Some(p) => p,
+        None => return code
+    };
+    let strs = |v: &~[~str]| json::List(v.map(|s| json::String(s.clone())));
+    let mut obj = ~TreeMap::new();
+    report.read(|r| {
+        obj.insert(~"packages", strs(&r.packages));
+        obj.insert(~"compiled", strs(&r.compiled));
+        obj.insert(~"cache_hits", json::Number(r.cache_hits as f64));
+        obj.insert(~"cache_misses", json::Number(r.cache_misses as f64));
+        obj.insert(~"installed", json::List(r.installed.map(|&(ref p, ref digest)| {
+            let mut file = ~TreeMap::new();
+            file.insert(~"path", json::String(p.clone()));
+            file.insert(~"digest", json::String(digest.clone()));
+            json::Object(file)
+        })));
+    });
+    obj.insert(~"seconds", json::Number(seconds));
+    obj.insert(~"exit_code", json::Number(code as f64));
+    match io::result(|| File::create(&path).write(json::Object(obj).to_pretty_str().as_bytes())) {
+        Ok(()) => code,
+        Err(e) => {
+            error(format!("Couldn't write the report to {}: {}", path.display(), e.desc));
+            if code == 0 { COPY_FAILED_CODE } else { code }
+        }
+    }
+}
+
+/// Returns the install manifest written by --record for the files in
+/// `installed` (pairs of a path and the digest of its contents), as JSON:
+/// `{"version": 1, "files": [{"path": ..., "digest": ...}, ...]}`, with
+/// the files sorted by path so that the same install gives the same manifest
+fn install_manifest(installed: &[(~str, ~str)]) -> ~str {
+    let mut files = merge_sort(installed, |&(ref a, _), &(ref b, _)| a <= b);
+    files.dedup();
+    let mut obj = ~TreeMap::new();
+    obj.insert(~"version", json::Number(1.0));
+    obj.insert(~"files", json::List(files.map(|&(ref p, ref digest)| {
+        let mut file = ~TreeMap::new();
+        file.insert(~"path", json::String(p.clone()));
+        file.insert(~"digest", json::String(digest.clone()));
+        json::Object(file)
+    })));
+    json::Object(obj).to_pretty_str()
+}
+
+/// Runs `cmd` with `args`, returning the exit code for how it went
+fn run_in_task(cmd: &str, args: ~[~str], context: Context, report: Option<Report>) -> int {
+    let sub_cmd = cmd.to_owned();
     // Wrap the rest in task::try in case of a condition failure in a task
     let result = do task::try {
-        BuildContext {
-            context: Context {
-                cfgs: cfgs.clone(),
-                rustc_flags: rustc_flags.clone(),
-                use_rust_path_hack: use_rust_path_hack,
-                sysroot: sroot.clone(), // Currently, only tests override this
-            },
-            workcache_context: api::default_context(sroot.clone(),
-                                                    default_workspace()).workcache_context
-        }.run(sub_cmd, rm_args.clone())
+        conditions::git_checkout_failed::cond.trap(|(source, target, status, git_error)| {
+            error(format!("Couldn't check out {} into {} (git failed with {}):\n{}", source,
+                          target.display(), status, git_error.trim()));
+            fail!("git checkout failed")
+        }).inside(|| {
+            conditions::git_wrong_version::cond.trap(|(source, target, why)| {
+                error(format!("Couldn't check out {} into {}: {}", source,
+                              target.display(), why));
+                fail!("git checked out the wrong version")
+            }).inside(|| {
+                let cx = build_context(context.clone(), report.clone());
+                cx.run(sub_cmd, args.clone());
+                for timings in cx.timings.iter() {
+                    print_timings(timings, cx.context.json);
+                }
+            })
+        })
     };
     // FIXME #9262: This is using the same error code for all errors,
     // and at least one test case succeeds if rustpkg returns COPY_FAILED_CODE,
     // when actually, it might set the exit code for that even if a different
-    // unhandled condition got raised.
-    if result.is_err() { return COPY_FAILED_CODE; }
-    return 0;
+    // unhandled condition got raised. Failures that know better fail with
+    // their exit code.
+    match result {
+        Ok(()) => 0,
+        Err(cause) => match cause.as_ref::<int>() {
+            Some(&code) => code,
+            None => COPY_FAILED_CODE
+        }
+    }
 }
 
-fn declare_package_script_dependency(prep: &mut workcache::Prep, pkg_src: &PkgSrc) {
-    match pkg_src.package_script_option() {
-        // FIXME (#9639): This needs to handle non-utf8 paths
-        Some(ref p) => prep.declare_input("file", p.as_str().unwrap(),
-                                      workcache_support::digest_file_with_date(p)),
-        None => ()
+/// For --emit-deps: writes `<crate>.d` in `pkg_src`'s build directory,
+/// saying that the artifact built from `crate` (relative to the package
+/// directory) depends on `inputs`
+fn write_crate_deps(cx: &BuildContext, pkg_src: &PkgSrc, what: OutputType, crate: &Path,
+                    inputs: &[Path]) {
+    let id = &pkg_src.id;
+    let ws = pkg_src.build_workspace();
+    let profile = cx.context.profile;
+    let built = match what {
+        Lib => built_library_in_profile(id, ws, profile),
+        Main => built_executable_in_profile(&pkg_src.executable_id(crate), ws, profile),
+        Test => built_test_in_profile(id, ws, profile),
+        Bench => built_bench_in_profile(id, ws, profile)
+    };
+    match built {
+        Some(target) => {
+            let dep_file = profile_build_dir(id, ws, profile).join(crate.with_extension("d"));
+            debug!("Writing dependencies of {} to {}", target.display(), dep_file.display());
+            util::write_dep_file(&dep_file, &target, inputs);
+        }
+        None => debug!("write_crate_deps: {} wasn't built", crate.display())
+    }
+}
+
+/// Installs each package listed in `list_file` (see `util::read_pkgid_list`)
+/// with `run_each`, always carrying on past failures. Returns the exit code
+/// of the first package that failed to install, or BAD_FLAG_CODE if the list
+/// couldn't be read.
+fn install_from_file(list_file: &Path, context: Context, report: Option<Report>) -> int {
+    let pkgids = match util::read_pkgid_list(list_file) {
+        Ok(ids) => ids,
+        Err(why) => {
+            error(format!("Couldn't read the package list {}: {}", list_file.display(), why));
+            return BAD_FLAG_CODE;
+        }
+    };
+    note(format!("Installing the {} packages listed in {}", pkgids.len(),
+                 list_file.display()));
+    run_each("install", pkgids, context, true, report)
+}
+
+/// Builds or installs (as `cmd` says) each of `pkgids` in turn. Stops at the
+/// first package that fails, with its exit code, unless `keep_going` is set,
+/// in which case the rest are still tried, and the exit code is that of the
+/// first failure.
+fn run_each(cmd: &str, pkgids: ~[~str], context: Context, keep_going: bool,
+            report: Option<Report>) -> int {
+    let mut first_failure = None;
+    let mut failed = ~[];
+    for pkgid in pkgids.iter() {
+        let code = run_in_task(cmd, ~[pkgid.clone()], context.clone(), report.clone());
+        if code == 0 {
+            note(format!("{}: package {} succeeded", cmd, *pkgid));
+            continue;
+        }
+        error(format!("{}: package {} failed", cmd, *pkgid));
+        failed.push(pkgid.clone());
+        if first_failure.is_none() {
+            first_failure = Some(code);
+        }
+        if !keep_going {
+            break;
+        }
     }
+    match first_failure {
+        None => {
+            note(format!("{}: all {} packages succeeded", cmd, pkgids.len()));
+            0
+        }
+        Some(code) => {
+            error(format!("{}: {} of {} packages failed: {}", cmd, failed.len(),
+                          pkgids.len(), failed.connect(", ")));
+            code
+        }
+    }
+}
+
+/// How often `build --watch` checks whether any sources have changed, in ms
+static WATCH_POLL_MS: u64 = 500;
+
+/// For `build --watch`: builds `pkgids`, then waits until one of the source
+/// files of the crates that were built changes, and builds again, and so on
+/// until the user presses Ctrl-C while it's waiting. Each build holds `ws`'s
+/// lock only while it runs. Returns the last build's exit code.
+fn watch_and_build(pkgids: ~[~str], context: Context, ws: &Path, wait_for_lock: bool,
+                   keep_going: bool) -> int {
+    let mut listener = Listener::new();
+    let mut builds = 0u;
+    loop {
+        builds += 1;
+        let report = RWArc::new(BuildReport::new());
+        let code = {
+            let _lock = match WorkspaceLock::acquire(ws, wait_for_lock) {
+                Some(lock) => lock,
+                None => {
+                    error(format!("Another rustpkg is using the workspace {}", ws.display()));
+                    return WORKSPACE_LOCKED_CODE;
+                }
+            };
+            if pkgids.len() > 1 {
+                run_each("build", pkgids.clone(), context.clone(), keep_going,
+                         Some(report.clone()))
+            } else {
+                run_in_task("build", pkgids.clone(), context.clone(), Some(report.clone()))
+            }
+        };
+        if code == 0 {
+            note(format!("watch: build {} succeeded", builds));
+        } else {
+            error(format!("watch: build {} failed", builds));
+        }
+
+        let inputs = report.read(|r| r.inputs.clone());
+        if inputs.is_empty() {
+            warn("watch: no source files to watch, so stopping");
+            return code;
+        }
+        let dates = inputs.map(|p| util::datestamp(p));
+        // Ctrl-C only stops rustpkg cleanly between builds; during a build, it
+        // interrupts the build as it would without --watch
+        listener.register(Interrupt);
+        note(format!("watch: waiting for changes to {} source files (press Ctrl-C to stop)",
+                     inputs.len()));
+        loop {
+            sleep(WATCH_POLL_MS);
+            if listener.port.try_recv().is_some() {
+                note("watch: stopped");
+                return code;
+            }
+            if inputs.map(|p| util::datestamp(p)) != dates {
+                break;
+            }
+        }
+        listener.unregister(Interrupt);
+    }
+}
+
+/// Runs `rustpkg doctor` on the workspace named in `args`, or if there isn't
+/// one, the workspace containing the current directory (or the default
+/// workspace). Returns WORKSPACE_PROBLEMS_CODE if any problems were found.
+fn doctor(args: &[~str]) -> int {
+    let cwd = os::getcwd();
+    let workspace = match args {
+        [] => match cwd_to_workspace() {
+            Some((ws, _)) => ws,
+            None if is_workspace(&cwd) => cwd,
+            None => {
+                let rp = rust_path();
+                match rp.head_opt() {
+                    Some(ws) => ws.clone(),
+                    None => {
+                        error("There's no workspace to check; give one, or set RUST_PATH");
+                        return BAD_FLAG_CODE;
+                    }
+                }
+            }
+        },
+        [ref ws] => os::make_absolute(&Path::new(ws.as_slice())),
+        _ => {
+            usage::doctor();
+            return BAD_FLAG_CODE;
+        }
+    };
+    let problems = doctor::check_workspace(&workspace);
+    if problems == 0 {
+        note("No problems found");
+        0
+    } else {
+        error(format!("Found {} problem{}", problems, if problems == 1 { "" } else { "s" }));
+        WORKSPACE_PROBLEMS_CODE
+    }
+}
+
+/// Removes the build output of every package in every workspace on the
+/// RUST_PATH, and if `deep` is true, everything installed there as well.
+/// Prints how much space was freed in each workspace, and in total.
+fn clean_all(args: &[~str], deep: bool) -> int {
+    if !args.is_empty() {
+        error("--all can't be combined with a package ID argument");
+        usage::clean();
+        return BAD_FLAG_CODE;
+    }
+    // cwd/.rust is always in the RUST_PATH, whether or not it exists,
+    // so there's no point warning about it
+    let cwd_dot_rust = os::getcwd().join(".rust");
+    let mut total_freed = 0u64;
+    for workspace in rust_path().iter() {
+        if !workspace.is_dir() {
+            if *workspace != cwd_dot_rust {
+                warn(format!("Skipping {}, which is on the RUST_PATH but doesn't exist",
+                             workspace.display()));
+            }
+            continue;
+        }
+        // Likewise for the current directory, which is in the RUST_PATH
+        // even if it isn't a workspace
+        if !is_workspace(workspace) {
+            debug!("clean_all: {} isn't a workspace", workspace.display());
+            continue;
+        }
+        // Each entry in the build directory holds one package's build
+        // output (or, for src/, the sources of packages fetched from elsewhere)
+        let mut dirs = ~[target_build_dir(workspace)];
+        if deep {
+            dirs.push(target_lib_dir(workspace));
+            dirs.push(workspace.join("bin"));
+        }
+        let mut freed = 0u64;
+        for dir in dirs.iter() {
+            for entry in io::ignore_io_error(|| fs::readdir(dir)).iter() {
+                freed += disk_usage(entry);
+                if entry.is_dir() {
+                    fs::rmdir_recursive(entry);
+                } else {
+                    fs::unlink(entry);
+                }
+                note(format!("Removed {}", entry.display()));
+            }
+        }
+        note(format!("Cleaned workspace {}, freeing {} bytes", workspace.display(), freed));
+        total_freed += freed;
+    }
+    note(format!("Freed {} bytes in total", total_freed));
+    0
+}
+
+/// The total size of the files in `path`, if it's a directory, or of `path`
+fn disk_usage(path: &Path) -> u64 {
+    if path.is_dir() {
+        fs::walk_dir(path).filter(|p| p.is_file()).fold(0, |total, p| total + p.stat().size)
+    } else {
+        path.stat().size
+    }
+}
+
+/// Prints the configuration in `context`, along with the RUST_PATH and
+/// `workspace`, the default workspace, as `KEY=value` lines or (with --json)
+/// as a JSON object
+fn print_env(args: &[~str], context: &Context, workspace: &Path) -> int {
+    if !args.is_empty() {
+        usage::env();
+        return BAD_FLAG_CODE;
+    }
+    let rust_path = rust_path().map(|p| p.display().to_str());
+    let workcache_db = workspace.join("rustpkg_db.json");
+    let rustc_flags = context.rustc_flags.effective_flag_strs();
+    // Scratch directories go under os::tmpdir() (which honors TMPDIR)
+    // without --tmp-dir
+    let tmp_dir = context.tmp_dir.clone().unwrap_or_else(|| os::tmpdir());
+    let profile = context.profile.map(|p| p.name().to_owned());
+    let install_root = context.install_root.as_ref().map(|r| r.display().to_str());
+    if context.json {
+        fn strings(ss: &[~str]) -> json::Json {
+            json::List(ss.map(|s| json::String(s.clone())))
+        }
+        fn string_or_null(s: Option<~str>) -> json::Json {
+            s.map_default(json::Null, |s| json::String(s))
+        }
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"sysroot", json::String(context.sysroot.display().to_str()));
+        obj.insert(~"rust_path", strings(rust_path));
+        obj.insert(~"default_workspace", json::String(workspace.display().to_str()));
+        obj.insert(~"workcache_db", json::String(workcache_db.display().to_str()));
+        obj.insert(~"cfgs", strings(context.cfgs));
+        obj.insert(~"rustc_flags", strings(rustc_flags));
+        obj.insert(~"profile", string_or_null(profile));
+        obj.insert(~"digest", json::String(context.digest.name().to_owned()));
+        obj.insert(~"jobs", json::Number(context.jobs as f64));
+        obj.insert(~"tmp_dir", json::String(tmp_dir.display().to_str()));
+        obj.insert(~"install_root", string_or_null(install_root));
+        obj.insert(~"ambiguous", json::String(context.ambiguous.name().to_owned()));
+        obj.insert(~"strict_cache", json::Boolean(context.strict_cache));
+        obj.insert(~"variant", string_or_null(context.variant.clone()));
+        println(json::Object(obj).to_pretty_str());
+    } else {
+        let separator = if cfg!(windows) { ";" } else { ":" };
+        println!("SYSROOT={}", context.sysroot.display());
+        println!("RUST_PATH={}", rust_path.connect(separator));
+        println!("DEFAULT_WORKSPACE={}", workspace.display());
+        println!("WORKCACHE_DB={}", workcache_db.display());
+        println!("CFGS={}", context.cfgs.connect(" "));
+        println!("RUSTC_FLAGS={}", rustc_flags.connect(" "));
+        println!("PROFILE={}", profile.unwrap_or(~""));
+        println!("DIGEST={}", context.digest.name());
+        println!("JOBS={}", context.jobs);
+        println!("TMP_DIR={}", tmp_dir.display());
+        println!("INSTALL_ROOT={}", install_root.unwrap_or(~""));
+        println!("AMBIGUOUS={}", context.ambiguous.name());
+        println!("STRICT_CACHE={}", context.strict_cache);
+        println!("VARIANT={}", context.variant.clone().unwrap_or(~""));
+    }
+    0
+}
+
+/// Checks each file that the workcache says was installed for the package
+/// named by `args` against the digest recorded for it when it was installed.
+fn verify(args: &[~str], context: Context) -> int {
+    let pkgid = match args {
+        [ref id] => PkgId::new(*id),
+        _ => {
+            usage::verify();
+            return BAD_FLAG_CODE;
+        }
+    };
+    let outputs = build_context(context, None).workcache_context.db.read(|db| {
+        db.discovered_outputs(pkgid.install_tag())
+            + db.discovered_outputs(format!("{} stripped", pkgid.install_tag()))
+    });
+    if outputs.is_empty() {
+        error(format!("No installed files are recorded for {}", pkgid.to_str()));
+        return NONEXISTENT_PACKAGE_CODE;
+    }
+    // Installing forgets earlier installs, so these are only the files the
+    // most recent install put there
+    let mut verified: TreeMap<~str, bool> = TreeMap::new();
+    for &(ref kind, ref name, ref digest) in outputs.iter() {
+        let path = Path::new(name.as_slice());
+        let ok = match kind.as_slice() {
+            "file" => workcache_support::file_digest_matches(&path, *digest),
+            _      => workcache_support::date_digest_matches(&path, *digest)
+        };
+        verified.insert(name.clone(), ok);
+    }
+    let mut failures = 0;
+    for (name, &ok) in verified.iter() {
+        if !ok {
+            failures += 1;
+            if Path::new(name.as_slice()).exists() {
+                error(format!("{} has changed since it was installed", *name));
+            } else {
+                error(format!("{} is missing", *name));
+            }
+        }
+    }
+    if failures == 0 {
+        note(format!("All {} installed files for {} are intact", verified.len(),
+                     pkgid.to_str()));
+        0
+    } else {
+        error(format!("{} of {} installed files for {} failed verification", failures,
+                      verified.len(), pkgid.to_str()));
+        VERIFY_FAILED_CODE
+    }
+}
+
+/// Removes the workcache entries that can never be used again, because a
+/// source file or binary declared as one of their inputs no longer exists,
+/// and reports how many were removed and how many bytes of the database they
+/// took up. With `dry_run`, lists the entries instead of removing them.
+fn clean_cache(args: &[~str], context: Context, dry_run: bool) -> int {
+    if !args.is_empty() {
+        usage::clean_cache();
+        return BAD_FLAG_CODE;
+    }
+    let pruned = build_context(context, None).workcache_context.db.write(|db| {
+        db.prune(dry_run, |_, inputs| inputs.iter().any(|&(ref kind, ref name)| {
+            let path = Path::new(name.as_slice());
+            // Inputs that aren't files are never missing, and relative paths
+            // can't be checked, since they depend on where rustpkg was run
+            (*kind == ~"file" || *kind == ~"binary") && path.is_absolute() && !path.exists()
+        }))
+    });
+    let freed = pruned.iter().fold(0, |total, &(_, size)| total + size);
+    for &(ref fn_name, _) in pruned.iter() {
+        if dry_run {
+            note(format!("Would remove {}", *fn_name));
+        } else {
+            note(format!("Removed {}", *fn_name));
+        }
+    }
+    if dry_run {
+        note(format!("Would remove {} workcache entries, freeing {} bytes",
+                     pruned.len(), freed));
+    } else {
+        note(format!("Removed {} workcache entries, freeing {} bytes", pruned.len(), freed));
+    }
+    0
+}
+
+/// Whether `fn_name` is the workcache entry for installing the package whose
+/// install tag is `install_tag`, with or without --root or --strip
+fn is_install_tag(fn_name: &str, install_tag: &str) -> bool {
+    fn_name == install_tag || fn_name == format!("{} stripped", install_tag)
+        || fn_name.starts_with(format!("{} under ", install_tag))
+}
+
+/// For --timings: prints what was timed, slowest first, either as a table
+/// or (with --json) as a JSON object
+fn print_timings(timings: &Timings, json: bool) {
+    let entries = timings.read(|t| t.clone());
+    let entries = merge_sort(entries, |&(_, a), &(_, b)| a >= b);
+    let seconds = |ns: u64| (ns as f64) / 1e9;
+    if json {
+        let mut list = ~[];
+        for &(ref what, ns) in entries.iter() {
+            let mut obj = ~TreeMap::new();
+            obj.insert(~"what", json::String(what.clone()));
+            obj.insert(~"seconds", json::Number(seconds(ns)));
+            list.push(json::Object(obj));
+        }
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"timings", json::List(list));
+        println(json::Object(obj).to_pretty_str());
+    } else {
+        println("Timings (slowest first):");
+        for &(ref what, ns) in entries.iter() {
+            println!("{:>9.3f}s  {}", seconds(ns), *what);
+        }
+    }
+}
+
+/// Returns a BuildContext for `context`, keeping the workcache database
+/// in the default workspace, and recording what it does in `report`
+fn build_context(context: Context, report: Option<Report>) -> BuildContext {
+    let workcache_context = api::open_workcache_context(&default_workspace(),
+                                                        context.strict_cache);
+    let timings = if context.timings { Some(RWArc::new(~[])) } else { None };
+    // Remote git clones get --depth too
+    let fetchers = FetcherRegistry::with_defaults(context.git_clone_depth);
+    BuildContext {
+        context: context,
+        workcache_context: workcache_context,
+        cache_callback: None,
+        copy_progress: None,
+        emitter: None,
+        timings: timings,
+        report: report,
+        cancel: None,
+        fetchers: Callback::new(fetchers)
+    }
+}
+
+/// The executable that the package script `script` is compiled to in
+/// `build_dir`, named after the script (`pkg`, or `pkg-<variant>`)
+fn script_exe(build_dir: &Path, script: &Path) -> Path {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    build_dir.join(script.filestem_str().unwrap() + util::exe_suffix())
+}
+
+fn declare_package_script_dependency(prep: &mut workcache::Prep, script: &Path,
+                                     digest: DigestAlgorithm) {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    prep.declare_input("file", script.as_str().unwrap(),
+                       workcache_support::digest_file_with_date_using(script, digest));
 }
@@ -0,0 +1,132 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Advisory locks on workspaces, so that rustpkg commands that change the
+// same workspace (and its workcache database) don't run at the same time
+
+use std::libc;
+use std::libc::c_int;
+use messages::{note, warn};
+
+/// The file in a workspace that rustpkg locks
+pub fn lock_file(workspace: &Path) -> Path {
+    workspace.join(".rustpkg-lock")
+}
+
+/// A lock on a workspace, which is released when this is dropped (or when
+/// the process exits, however it exits)
+pub struct WorkspaceLock {
+    // -1 if the lock file couldn't be opened, in which case nothing is locked
+    priv fd: c_int
+}
+
+impl WorkspaceLock {
+    /// Locks `workspace`. If another process has it locked, then if `wait`
+    /// is true, says so and waits for it to finish; otherwise, returns None.
+    pub fn acquire(workspace: &Path, wait: bool) -> Option<WorkspaceLock> {
+        let path = lock_file(workspace);
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let mode = libc::S_IRUSR | libc::S_IWUSR;
+        let fd = path.with_c_str(|p| unsafe { libc::open(p, flags, mode as c_int) });
+        if fd < 0 {
+            warn(format!("Couldn't create {}, so {} won't be locked",
+                         path.display(), workspace.display()));
+            return Some(WorkspaceLock { fd: fd });
+        }
+        imp::set_cloexec(fd);
+        let lock = WorkspaceLock { fd: fd };
+        if imp::try_lock(fd) {
+            return Some(lock);
+        }
+        if !wait {
+            return None;
+        }
+        note(format!("Waiting for another rustpkg to finish with {}", workspace.display()));
+        imp::lock(fd);
+        Some(lock)
+    }
+}
+
+impl Drop for WorkspaceLock {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            imp::unlock(self.fd);
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::libc;
+    use std::libc::c_int;
+    use std::os;
+
+    static LOCK_EX: c_int = 2;
+    static LOCK_NB: c_int = 4;
+    static LOCK_UN: c_int = 8;
+    static F_GETFD: c_int = 1;
+    static F_SETFD: c_int = 2;
+    static FD_CLOEXEC: c_int = 1;
+
+    extern {
+        fn flock(fd: c_int, operation: c_int) -> c_int;
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    /// Keeps the processes rustpkg runs from inheriting `fd`: the lock is
+    /// held as long as any process has the file open, so one that outlived
+    /// rustpkg would keep the workspace locked
+    pub fn set_cloexec(fd: c_int) {
+        unsafe {
+            let flags = fcntl(fd, F_GETFD);
+            if flags >= 0 {
+                fcntl(fd, F_SETFD, flags | FD_CLOEXEC);
+            }
+        }
+    }
+
+    /// Takes the lock without waiting; returns false if another process has it
+    pub fn try_lock(fd: c_int) -> bool {
+        loop {
+            if unsafe { flock(fd, LOCK_EX | LOCK_NB) } == 0 {
+                return true;
+            }
+            match os::errno() as c_int {
+                libc::EINTR => (),
+                libc::EWOULDBLOCK => return false,
+                _ => fail!("couldn't lock the workspace: {}", os::last_os_error())
+            }
+        }
+    }
+
+    pub fn lock(fd: c_int) {
+        while unsafe { flock(fd, LOCK_EX) } != 0 {
+            if os::errno() as c_int != libc::EINTR {
+                fail!("couldn't lock the workspace: {}", os::last_os_error());
+            }
+        }
+    }
+
+    pub fn unlock(fd: c_int) {
+        unsafe { flock(fd, LOCK_UN); }
+    }
+}
+
+// FIXME: Use LockFileEx. Until then, workspaces aren't locked on Windows.
+#[cfg(windows)]
+mod imp {
+    use std::libc::c_int;
+
+    pub fn set_cloexec(_fd: c_int) {}
+    pub fn try_lock(_fd: c_int) -> bool { true }
+    pub fn lock(_fd: c_int) {}
+    pub fn unlock(_fd: c_int) {}
+}
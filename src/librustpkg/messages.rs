@@ -10,13 +10,44 @@
 
 use extra::term;
 use std::io;
+use std::unstable::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+/// Which messages get printed: all of them (`Normal`), only warnings and
+/// errors (`Quiet`, set with --quiet), or only errors (`Silent`, set with
+/// --silent). Errors are always printed.
+#[deriving(Eq, Clone)]
+pub enum Verbosity {
+    Normal,
+    Quiet,
+    Silent
+}
+
+// This is process-wide rather than task-local, because messages are also
+// printed from the tasks that build crates
+static mut VERBOSITY: AtomicUint = INIT_ATOMIC_UINT;
+
+pub fn set_verbosity(v: Verbosity) {
+    unsafe { VERBOSITY.store(v as uint, SeqCst) }
+}
+
+pub fn verbosity() -> Verbosity {
+    match unsafe { VERBOSITY.load(SeqCst) } {
+        0 => Normal,
+        1 => Quiet,
+        _ => Silent
+    }
+}
 
 pub fn note(msg: &str) {
-    pretty_message(msg, "note: ", term::color::GREEN);
+    if verbosity() == Normal {
+        pretty_message(msg, "note: ", term::color::GREEN);
+    }
 }
 
 pub fn warn(msg: &str) {
-    pretty_message(msg, "warning: ", term::color::YELLOW);
+    if verbosity() != Silent {
+        pretty_message(msg, "warning: ", term::color::YELLOW);
+    }
 }
 
 pub fn error(msg: &str) {
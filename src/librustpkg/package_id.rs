@@ -97,6 +97,20 @@ impl PkgId {
         format!("{}{}", self.short_name, self.version.to_str())
     }
 
+    /// The URL that the package's sources are fetched from when they aren't
+    /// in any workspace: the path, with `https://` in front, unless its first
+    /// component names a URL scheme, as in `hg://example.com/foo`
+    pub fn remote_url(&self) -> ~str {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let path = self.path.as_str().unwrap();
+        match path.find_str(":/") {
+            // Paths are normalized, so `hg://` is now `hg:/`
+            Some(i) if !path.slice_to(i).contains_char('/') =>
+                format!("{}://{}", path.slice_to(i), path.slice_from(i + 2)),
+            _ => format!("https://{}", path)
+        }
+    }
+
     /// True if the ID has multiple components
     pub fn is_complex(&self) -> bool {
         self.short_name.as_bytes() != self.path.as_vec()
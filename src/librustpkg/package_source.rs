@@ -12,19 +12,24 @@ extern mod extra;
 
 use target::*;
 use package_id::PkgId;
-use std::io;
+use std::comm::oneshot;
 use std::io::fs;
 use std::os;
 use context::*;
 use crate::Crate;
 use messages::*;
-use source_control::{safe_git_clone, git_clone_url, DirToUse, CheckedOutSources};
-use source_control::make_read_only;
+use source_control::{safe_git_clone, DirToUse, CheckedOutSources, CloneFailed, WrongVersion};
+use source_control::FetcherRegistry;
+use source_control::{make_read_only, move_dir};
 use path_util::{find_dir_using_rust_path_hack, make_dir_rwx_recursive, default_workspace};
 use path_util::{target_build_dir, versionize, dir_has_crate_file};
-use util::{compile_crate, DepMap};
+use util::{compile_crate, crate_type_attr, link_crate, DepMap, LinkCommand, NeedsLink};
+use deps;
+use exit_codes::DEPENDENCY_CYCLE_CODE;
 use workcache_support;
-use workcache_support::{digest_only_date, digest_file_with_date, crate_tag};
+use workcache_support::{DigestAlgorithm, digest_only_date_using, digest_file_with_date_using,
+                        crate_tag};
+use extra::glob::Pattern;
 use extra::workcache;
 use extra::treemap::TreeMap;
 
@@ -54,6 +59,18 @@ pub struct PkgSrc {
     mains: ~[Crate],
     tests: ~[Crate],
     benchs: ~[Crate],
+    /// Example programs: the `.rs` files directly in the package's
+    /// `examples` directory. These are only built when asked for by name
+    /// (see `build_example`), and never installed.
+    examples: ~[Crate],
+    /// Names to build and install executables under, instead of the
+    /// package's short name, keyed by the path of the main crate
+    /// (relative to start_dir). Filled in from the package script's
+    /// `binaries` hook.
+    binary_names: ~[(Path, ~str)],
+    /// What the package script's build hook printed to stdout and stderr,
+    /// if it ran with its output captured (see `Context::capture_script_output`)
+    script_output: Option<(~[u8], ~[u8])>
 }
 
 pub enum BuildSort { InPlace, Discovered }
@@ -74,10 +91,27 @@ condition! {
 
 impl PkgSrc {
 
-    pub fn new(mut source_workspace: Path,
+    pub fn new(source_workspace: Path,
                destination_workspace: Path,
                use_rust_path_hack: bool,
                id: PkgId) -> PkgSrc {
+        let fetchers = Callback::new(FetcherRegistry::with_defaults(None));
+        PkgSrc::new_with_fetchers(source_workspace, destination_workspace,
+                                  use_rust_path_hack, id, None, &fetchers, &None)
+    }
+
+    /// Like `new`, but if the sources have to be fetched, fetch them with
+    /// `fetchers`. If they're cloned from a local git repository, only clone
+    /// the last `clone_depth` commits. Either way, fetch them under `tmp_dir`
+    /// before moving them into the workspace (see
+    /// `source_control::safe_git_clone`).
+    pub fn new_with_fetchers(mut source_workspace: Path,
+                             destination_workspace: Path,
+                             use_rust_path_hack: bool,
+                             id: PkgId,
+                             clone_depth: Option<uint>,
+                             fetchers: &Callback<FetcherRegistry>,
+                             tmp_dir: &Option<Path>) -> PkgSrc {
         use conditions::nonexistent_package::cond;
 
         debug!("Checking package source for package ID {}, \
@@ -137,10 +171,13 @@ impl PkgSrc {
                     let path = build_dir.join(&package_id.path);
                     debug!("in loop: checking if {} is a directory", path.display());
                     if path.is_dir() {
-                        let ps = PkgSrc::new(source_workspace,
-                                             destination_workspace,
-                                             use_rust_path_hack,
-                                             package_id);
+                        let ps = PkgSrc::new_with_fetchers(source_workspace,
+                                                           destination_workspace,
+                                                           use_rust_path_hack,
+                                                           package_id,
+                                                           clone_depth,
+                                                           fetchers,
+                                                           tmp_dir);
                         match ps {
                             PkgSrc {
                                 source_workspace: source,
@@ -156,7 +193,10 @@ impl PkgSrc {
                                     libs: ~[],
                                     mains: ~[],
                                     tests: ~[],
-                                    benchs: ~[]
+                                    benchs: ~[],
+                                    examples: ~[],
+                                    binary_names: ~[],
+                                    script_output: None
                                 };
                                 debug!("pkgsrc: Returning {}", result.to_str());
                                 return result;
@@ -170,7 +210,8 @@ impl PkgSrc {
                 let mut ok_d = None;
                 for w in output_names.iter() {
                     debug!("Calling fetch_git on {}", w.display());
-                    let target_dir_opt = PkgSrc::fetch_git(w, &id);
+                    let target_dir_opt = PkgSrc::fetch_git(w, &id, clone_depth, fetchers,
+                                                           tmp_dir);
                     for p in target_dir_opt.iter() {
                         ok_d = Some(p.clone());
                         build_in_destination = true;
@@ -215,7 +256,10 @@ impl PkgSrc {
                                 libs: ~[],
                                 mains: ~[],
                                 benchs: ~[],
-                                tests: ~[]
+                                examples: ~[],
+                                tests: ~[],
+                                binary_names: ~[],
+                                script_output: None
                             }
                         } else if use_rust_path_hack {
                             match find_dir_using_rust_path_hack(&id) {
@@ -255,7 +299,10 @@ impl PkgSrc {
             libs: ~[],
             mains: ~[],
             tests: ~[],
-            benchs: ~[]
+            benchs: ~[],
+            examples: ~[],
+            binary_names: ~[],
+            script_output: None
         }
     }
 
@@ -263,9 +310,12 @@ impl PkgSrc {
     /// fetching it and caching it in a local directory. Return the cached directory
     /// if this was successful, None otherwise. Similarly, if the package id
     /// refers to a git repo on the local version, also check it out.
-    /// (right now we only support git)
-    pub fn fetch_git(local: &Path, pkgid: &PkgId) -> Option<Path> {
-        use conditions::git_checkout_failed::cond;
+    /// Remote repositories are fetched from with the fetcher in `fetchers`
+    /// for the scheme of the package's URL (see `PkgId::remote_url`).
+    pub fn fetch_git(local: &Path, pkgid: &PkgId, clone_depth: Option<uint>,
+                     fetchers: &Callback<FetcherRegistry>,
+                     tmp_dir: &Option<Path>) -> Option<Path> {
+        use conditions::git_wrong_version;
 
         let cwd = os::getcwd();
         debug!("Checking whether {} (path = {}) exists locally. Cwd = {}, does it? {:?}",
@@ -273,45 +323,73 @@ impl PkgSrc {
                 cwd.display(),
                 pkgid.path.exists());
 
-        match safe_git_clone(&pkgid.path, &pkgid.version, local) {
+        match safe_git_clone(&pkgid.path, &pkgid.version, local, clone_depth, tmp_dir) {
             CheckedOutSources => {
                 make_read_only(local);
                 Some(local.clone())
             }
-            DirToUse(clone_target) => {
+            // The sources are there, but not at the requested version, so
+            // fetching them remotely wouldn't help
+            WrongVersion(_, error) => {
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                git_wrong_version::cond.raise((pkgid.path.as_str().unwrap().to_owned(),
+                                               local.clone(), error));
+                None
+            }
+            // If a local clone failed, try fetching it remotely
+            DirToUse(clone_target) | CloneFailed(clone_target, _, _) => {
                 if pkgid.path.components().nth(1).is_none() {
                     // If a non-URL, don't bother trying to fetch
                     return None;
                 }
 
-                // FIXME (#9639): This needs to handle non-utf8 paths
-                let url = format!("https://{}", pkgid.path.as_str().unwrap());
-                debug!("Fetching package: git clone {} {} [version={}]",
+                let url = pkgid.remote_url();
+                debug!("Fetching package: {} into {} [version={}]",
                         url, clone_target.display(), pkgid.version.to_str());
 
-                let mut failed = false;
-
-                cond.trap(|_| {
-                    failed = true;
-                }).inside(|| git_clone_url(url, &clone_target, &pkgid.version));
-
-                if failed {
-                    return None;
+                let fetched = unsafe {
+                    fetchers.with(|f| f.fetch(url.as_slice(), &pkgid.version, &clone_target))
+                };
+                match fetched {
+                    Some(CheckedOutSources) => (),
+                    Some(CloneFailed(_, _, error)) => {
+                        debug!("Couldn't fetch {}: {}", url, error);
+                        return None;
+                    }
+                    Some(WrongVersion(_, error)) => {
+                        git_wrong_version::cond.raise((url.clone(), local.clone(), error));
+                        return None;
+                    }
+                    Some(DirToUse(_)) | None => return None
                 }
 
                 // Move clone_target to local.
                 // First, create all ancestor directories.
                 let moved = make_dir_rwx_recursive(&local.dir_path())
-                    && io::result(|| fs::rename(&clone_target, local)).is_ok();
+                    && move_dir(&clone_target, local);
                 if moved { Some(local.clone()) }
                     else { None }
             }
         }
     }
 
-    // If a file named "pkg.rs" in the start directory exists,
-    // return the path for it. Otherwise, None
-    pub fn package_script_option(&self) -> Option<Path> {
+    /// The package script for the variant `variant` (see --variant): the
+    /// file named `pkg-<variant>.rs` in the start directory
+    pub fn variant_script(&self, variant: &str) -> Path {
+        self.start_dir.join(format!("pkg-{}.rs", variant))
+    }
+
+    // If `variant` is given and the package has a script for it, return
+    // the path for that. Otherwise, if a file named "pkg.rs" in the start
+    // directory exists, return the path for it. Otherwise, None
+    pub fn package_script_option(&self, variant: &Option<~str>) -> Option<Path> {
+        for v in variant.iter() {
+            let variant_path = self.variant_script(*v);
+            debug!("package_script_option: checking whether {} exists", variant_path.display());
+            if variant_path.exists() {
+                return Some(variant_path);
+            }
+        }
         let maybe_path = self.start_dir.join("pkg.rs");
         debug!("package_script_option: checking whether {} exists", maybe_path.display());
         if maybe_path.exists() {
@@ -321,6 +399,18 @@ impl PkgSrc {
         }
     }
 
+    /// Has every crate found so far linked with `link_args` too
+    pub fn add_link_args(&mut self, link_args: &[~str]) {
+        if link_args.is_empty() {
+            return;
+        }
+        let flags = link_args.flat_map(|a| ~[~"--link-args", a.clone()]);
+        self.libs = self.libs.map(|c| c.flags(flags.clone()));
+        self.mains = self.mains.map(|c| c.flags(flags.clone()));
+        self.tests = self.tests.map(|c| c.flags(flags.clone()));
+        self.benchs = self.benchs.map(|c| c.flags(flags.clone()));
+    }
+
     /// True if the given path's stem is self's pkg ID's stem
     fn stem_matches(&self, p: &Path) -> bool {
         p.filestem().map_default(false, |p| { p == self.id.short_name.as_bytes() })
@@ -340,85 +430,271 @@ impl PkgSrc {
         cs.push(Crate::new(&sub));
     }
 
-    /// Infers crates to build. Called only in the case where there
-    /// is no custom build logic
-    pub fn find_crates(&mut self) {
-        self.find_crates_with_filter(|_| true);
+    /// What kind of crate `p` (relative to the package's source directory)
+    /// is: a test or benchmark if it's named `test.rs` or `bench.rs`, and
+    /// otherwise a library or executable if its `#[crate_type]` attribute
+    /// says so, or failing that if it's named `lib.rs` or `main.rs`
+    fn crate_kind(&self, p: &Path) -> Option<OutputType> {
+        if is_test(p) {
+            return Some(Test);
+        } else if is_bench(p) {
+            return Some(Bench);
+        }
+        match crate_type_attr(&self.start_dir.join(p)) {
+            Some(ref t) if t.as_slice() == "lib" => return Some(Lib),
+            Some(ref t) if t.as_slice() == "bin" => return Some(Main),
+            _ => ()
+        }
+        if is_lib(p) {
+            Some(Lib)
+        } else if is_main(p) {
+            Some(Main)
+        } else {
+            None
+        }
+    }
+
+    /// The crates of kind `kind` found so far
+    fn crates_of_kind<'a>(&'a mut self, kind: OutputType) -> &'a mut ~[Crate] {
+        match kind {
+            Lib => &mut self.libs,
+            Main => &mut self.mains,
+            Test => &mut self.tests,
+            Bench => &mut self.benchs
+        }
     }
 
-    pub fn find_crates_with_filter(&mut self, filter: |&str| -> bool) {
+    /// Adds the crate `p` (relative to the package's source directory) to
+    /// the libraries, mains, tests or benchmarks, depending on its kind (see
+    /// `crate_kind`). Returns false, and adds nothing, if its kind is unknown.
+    pub fn push_crate_by_kind(&mut self, p: &Path) -> bool {
+        match self.crate_kind(p) {
+            Some(kind) => {
+                PkgSrc::push_crate(self.crates_of_kind(kind), 0, p);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Infers crates to build. Called only in the case where there
+    /// is no custom build logic. `cfgs` are the active cfgs, which
+    /// determine whether crates under `cfg-` directories are included
+    /// (see `cfg_dirs_active`). Raises missing_pkg_files if there
+    /// aren't any.
+    pub fn find_crates(&mut self, cfgs: &[~str]) {
         use conditions::missing_pkg_files::cond;
 
+        self.find_crates_with_filter(cfgs, |_| true);
+        let crate_sets = [&self.libs, &self.mains, &self.tests, &self.benchs];
+        if crate_sets.iter().all(|crate_set| crate_set.is_empty()) {
+
+            note(format!("Couldn't infer any crates to build.\n\
+                          Try naming a crate `main.rs`, `lib.rs`, `test.rs`, \
+                          `bench.rs`, or `{}.rs`.", self.id.short_name));
+            cond.raise(self.id.clone());
+        }
+    }
+
+    /// Like `find_crates`, but only finds the crates whose file names
+    /// `filter` accepts, and finding none of them isn't an error.
+    /// A package with no `lib.rs` or `main.rs` can have a crate named after
+    /// the package instead, like `foo.rs` in package `foo`.
+    pub fn find_crates_with_filter(&mut self, cfgs: &[~str], filter: |&str| -> bool) {
         let prefix = self.start_dir.components().len();
         debug!("Matching against {}", self.id.short_name);
+        let mut named_crate = None;
         for pth in fs::walk_dir(&self.start_dir) {
-            let maybe_known_crate_set = match pth.filename_str() {
+            if !PkgSrc::cfg_dirs_active(cfgs, prefix, &pth) {
+                debug!("Skipping {}, since it's in a directory for an inactive cfg",
+                       pth.display());
+                continue;
+            }
+            let rel = pth.path_relative_from(&self.start_dir).unwrap_or(pth.clone());
+            if in_examples_dir(&rel) {
+                // Nothing under examples/ is one of the package's own crates;
+                // each .rs file directly in it is an example
+                let is_example_file = rel.components().len() == 2
+                    && rel.extension_str() == Some("rs");
+                let wanted = match pth.filename_str() {
+                    Some(filename) => filter(filename),
+                    None => false
+                };
+                if is_example_file && wanted {
+                    PkgSrc::push_crate(&mut self.examples, prefix, &pth);
+                }
+                continue;
+            }
+            let is_named_crate = rel.components().len() == 1
+                && rel.extension_str() == Some("rs") && self.stem_matches(&rel);
+            let maybe_kind = match pth.filename_str() {
                 Some(filename) if filter(filename) => match filename {
-                    "lib.rs" => Some(&mut self.libs),
-                    "main.rs" => Some(&mut self.mains),
-                    "test.rs" => Some(&mut self.tests),
-                    "bench.rs" => Some(&mut self.benchs),
-                    _ => None
+                    "lib.rs" | "main.rs" | "test.rs" | "bench.rs" => self.crate_kind(&rel),
+                    _ => {
+                        if is_named_crate {
+                            named_crate = Some(pth.clone());
+                        }
+                        None
+                    }
                 },
                 _ => None
             };
 
-            match maybe_known_crate_set {
-                Some(crate_set) => PkgSrc::push_crate(crate_set, prefix, &pth),
+            match maybe_kind {
+                Some(kind) => PkgSrc::push_crate(self.crates_of_kind(kind), prefix, &pth),
                 None => ()
             }
         }
 
-        let crate_sets = [&self.libs, &self.mains, &self.tests, &self.benchs];
-        if crate_sets.iter().all(|crate_set| crate_set.is_empty()) {
-
-            note("Couldn't infer any crates to build.\n\
-                         Try naming a crate `main.rs`, `lib.rs`, \
-                         `test.rs`, or `bench.rs`.");
-            cond.raise(self.id.clone());
+        if self.libs.is_empty() && self.mains.is_empty() {
+            for pth in named_crate.iter() {
+                let rel = pth.path_relative_from(&self.start_dir).unwrap_or(pth.clone());
+                // Like rustc, take a crate that doesn't say it's a library
+                // to be an executable
+                let kind = match self.crate_kind(&rel) {
+                    Some(Lib) => Lib,
+                    _ => Main
+                };
+                debug!("Taking {} to be the package's crate", pth.display());
+                PkgSrc::push_crate(self.crates_of_kind(kind), prefix, pth);
+            }
         }
 
-        debug!("In {}, found {} libs, {} mains, {} tests, {} benchs",
+        debug!("In {}, found {} libs, {} mains, {} tests, {} benchs, {} examples",
                self.start_dir.display(),
                self.libs.len(),
                self.mains.len(),
                self.tests.len(),
-               self.benchs.len())
+               self.benchs.len(),
+               self.examples.len())
     }
 
+    /// Removes the crates whose paths match any of `excludes`, noting each
+    /// one. Paths are matched both relative to the start directory (for
+    /// example, `experimental/main.rs`) and in full, so a pattern like
+    /// `*/experimental/*` also works.
+    pub fn exclude_crates(&mut self, excludes: &[Pattern]) {
+        if excludes.is_empty() {
+            return;
+        }
+        let start_dir = self.start_dir.clone();
+        for crate_set in [&mut self.libs, &mut self.mains, &mut self.tests,
+                          &mut self.benchs, &mut self.examples].mut_iter() {
+            let crates = (**crate_set).clone();
+            let (excluded, kept) = crates.partition(|crate| {
+                let full = start_dir.join(&crate.file);
+                excludes.iter().any(|p| p.matches_path(&crate.file) || p.matches_path(&full))
+            });
+            for crate in excluded.iter() {
+                note(format!("Excluding {}", start_dir.join(&crate.file).display()));
+            }
+            **crate_set = kept;
+        }
+    }
+
+    /// Crates in a directory named `cfg-NAME` (at any depth below the
+    /// package's start directory) are only built if NAME is an active cfg:
+    /// either one of `cfgs`, or one of the host's defaults (such as `unix`,
+    /// `linux` or `x86_64`). Returns true if every such directory in `p`
+    /// (ignoring the first `prefix` components) names an active cfg.
+    fn cfg_dirs_active(cfgs: &[~str], prefix: uint, p: &Path) -> bool {
+        let host_cfgs = [os::consts::FAMILY, os::consts::SYSNAME, os::consts::ARCH];
+        let dir = p.dir_path();
+        for c in dir.str_components().skip(prefix) {
+            match c {
+                Some(c) if c.starts_with("cfg-") => {
+                    let name = c.slice_from(4);
+                    if !cfgs.iter().any(|cfg| cfg.as_slice() == name)
+                        && !host_cfgs.iter().any(|cfg| *cfg == name) {
+                        return false;
+                    }
+                }
+                _ => ()
+            }
+        }
+        true
+    }
+
+    /// Builds each of `crates`, which is built as the kind of crate paired
+    /// with it, unless the build is canceled first
     fn build_crates(&self,
                     ctx: &BuildContext,
                     deps: &mut DepMap,
-                    crates: &[Crate],
+                    crates: &[(Crate, OutputType)],
                     cfgs: &[~str],
-                    what: OutputType,
-                    inputs_to_discover: &[(~str, Path)]) {
-        for crate in crates.iter() {
-            let path = self.start_dir.join(&crate.file);
-            debug!("build_crates: compiling {}", path.display());
-            let cfgs = crate.cfgs + cfgs;
-
-            ctx.workcache_context.with_prep(crate_tag(&path), |prep| {
+                    inputs_to_discover: &[(~str, Path)]) -> BuildResult<()> {
+        // Build up to `jobs` crates at a time: each crate that isn't cached
+        // is compiled in a task of its own, and the batch is waited for
+        // before starting on the next one
+        for batch in crates.chunks(ctx.context.jobs) {
+            if ctx.is_canceled() {
+                debug!("The build of {} was canceled", self.id.to_str());
+                return Canceled;
+            }
+            let paths = batch.map(|&(ref crate, _)| self.start_dir.join(&crate.file));
+            // Each profile's build of a crate is cached separately, since
+            // they have the same inputs but different outputs, and so is
+            // each --out-dir's. So is a build with --deny-warnings, so that
+            // a crate built without it isn't taken to pass, and a build with
+            // --config-arg, since the package script may choose other cfgs.
+            let tags = paths.map(|path| {
+                let tag = match ctx.context.profile {
+                    Some(p) => format!("{}#{}", crate_tag(path), p.name()),
+                    None    => crate_tag(path)
+                };
+                let tag = match ctx.context.out_dir {
+                    Some(ref dir) => format!("{} in {}", tag, dir.display()),
+                    None => tag
+                };
+                let tag = if ctx.context.rustc_flags.deny_warnings {
+                    format!("{} with --deny-warnings", tag)
+                } else {
+                    tag
+                };
+                if ctx.context.config_args.is_empty() {
+                    tag
+                } else {
+                    format!("{} with --config-arg {}", tag, ctx.context.config_args.connect(" "))
+                }
+            });
+            let mut preps = ~[];
+            for (path, tag) in paths.iter().zip(tags.iter()) {
                 debug!("Building crate {}, declaring it as an input", path.display());
+                let mut prep = ctx.workcache_context.prep(*tag);
                 // FIXME (#9639): This needs to handle non-utf8 paths
                 prep.declare_input("file", path.as_str().unwrap(),
-                                   workcache_support::digest_file_with_date(&path));
+                                   workcache_support::digest_file_with_date_using(
+                                       path, ctx.context.digest));
+                preps.push(prep);
+            }
+
+            let mut works = ~[];
+            // For each crate, a port that gets a message if it had to be
+            // compiled, rather than found fresh in the cache
+            let mut ran_ports = ~[];
+            for ((&(ref crate, what), path), prep) in batch.iter().zip(paths.iter())
+                                                            .zip(preps.iter()) {
+                debug!("build_crates: compiling {}", path.display());
                 let subpath = path.clone();
-                let subcfgs = cfgs.clone();
+                let subcfgs = crate.cfgs + cfgs;
                 let subcx = ctx.clone();
-                let id = self.id.clone();
+                let id = self.crate_pkg_id(&crate.file, what);
+                let example = self.examples.iter().any(|c| c.file == crate.file);
                 let sub_dir = self.build_workspace().clone();
                 let sub_flags = crate.flags.clone();
                 let sub_deps = deps.clone();
                 let inputs = inputs_to_discover.map(|&(ref k, ref p)|
                                                     (k.clone(), p.as_str().unwrap().to_owned()));
-                prep.exec(proc(exec) {
+                let (ran_port, ran_chan) = oneshot();
+                ran_ports.push(ran_port);
+                works.push(prep.exec_work(proc(exec) {
+                    ran_chan.send(());
                     for &(ref kind, ref p) in inputs.iter() {
                         let pth = Path::new(p.clone());
                         exec.discover_input(*kind, *p, if *kind == ~"file" {
-                                digest_file_with_date(&pth)
+                                digest_file_with_date_using(&pth, subcx.context.digest)
                             } else if *kind == ~"binary" {
-                                digest_only_date(&Path::new(p.clone()))
+                                digest_only_date_using(&Path::new(p.clone()), subcx.context.digest)
                             } else {
                                 fail!("Bad kind in build_crates")
                             });
@@ -426,31 +702,125 @@ impl PkgSrc {
                     debug!("Compiling crate {}; its output will be in {}",
                            subpath.display(), sub_dir.display());
                     let opt: session::OptLevel = subcx.context.rustc_flags.optimization_level;
-                    let result = compile_crate(&subcx,
-                                               exec,
-                                               &id,
-                                               &subpath,
-                                               &sub_dir,
-                                               &mut (sub_deps.clone()),
-                                               sub_flags,
-                                               subcfgs,
-                                               opt,
-                                               what);
-                    // XXX: result is an Option<Path>. The following code did not take that
-                    // into account. I'm not sure if the workcache really likes seeing the
-                    // output as "Some(\"path\")". But I don't know what to do about it.
-                    // FIXME (#9639): This needs to handle non-utf8 paths
-                    let result = result.as_ref().map(|p|p.as_str().unwrap());
-                    debug!("Result of compiling {} was {}", subpath.display(), result.to_str());
-                    result.to_str()
-                })
-            });
+                    let result = subcx.timed(subpath.display().to_str(), || {
+                        compile_crate(&subcx,
+                                      exec,
+                                      &id,
+                                      &subpath,
+                                      &sub_dir,
+                                      &mut (sub_deps.clone()),
+                                      sub_flags,
+                                      subcfgs,
+                                      opt,
+                                      what,
+                                      example)
+                    });
+                    // The result is how to link the crate's object file, if
+                    // it has one, so that it can be linked again without
+                    // compiling it when only the link settings change
+                    match result {
+                        NeedsLink(cmd) => cmd.to_json_str(),
+                        _ => ~""
+                    }
+                }));
+            }
+            for (((work, ran_port), path), tag) in works.move_iter().zip(ran_ports.move_iter())
+                                                        .zip(paths.iter()).zip(tags.iter()) {
+                let link_cmd = work.unwrap();
+                let fresh = ran_port.try_recv().is_none();
+                ctx.report_cache_decision(*tag, fresh);
+                if fresh {
+                    ctx.record(|r| r.cache_hits += 1);
+                } else {
+                    ctx.record(|r| {
+                        r.cache_misses += 1;
+                        r.compiled.push(path.display().to_str());
+                    });
+                }
+                match LinkCommand::from_json_str(link_cmd) {
+                    Some(cmd) => self.link(ctx, *tag, cmd),
+                    None => ()
+                }
+            }
+        }
+        Built(())
+    }
+
+    /// Links the crate whose compilation is cached under `tag`, unless it
+    /// was already linked with the same settings. The linker and link
+    /// arguments rustpkg was given are part of this work's key rather
+    /// than the compilation's, so changing them only relinks the crate.
+    fn link(&self, ctx: &BuildContext, tag: &str, cmd: LinkCommand) {
+        let flags = &ctx.context.rustc_flags;
+        let link_tag = format!("{} linked by {} with [{}]",
+                               tag,
+                               flags.linker_for_target().unwrap_or(cmd.linker.clone()),
+                               flags.link_args.connect(" "));
+        let mut prep = ctx.workcache_context.prep(link_tag);
+        // So that compiling the crate again means linking it again
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        prep.declare_input("binary", cmd.object.as_str().unwrap(),
+                           digest_only_date_using(&cmd.object, ctx.context.digest));
+        let (ran_port, ran_chan) = oneshot();
+        let subcx = ctx.clone();
+        let _: ~str = prep.exec(proc(exec) {
+            ran_chan.send(());
+            link_crate(&subcx, exec, &cmd);
+            // FIXME (#9639): This needs to handle non-utf8 paths
+            cmd.output.as_str().unwrap().to_owned()
+        });
+        ctx.report_cache_decision(link_tag, ran_port.try_recv().is_none());
+    }
+
+    /// The package ID to use for the executable built from the main crate
+    /// `crate`: self's ID, with the short name replaced if the package script
+    /// asked for a different binary name
+    pub fn executable_id(&self, crate: &Path) -> PkgId {
+        match self.binary_names.iter().find(|&&(ref p, _)| p == crate) {
+            Some(&(_, ref name)) => PkgId { short_name: name.clone(), ..self.id.clone() },
+            None => self.id.clone()
+        }
+    }
+
+    /// The package ID to build `crate`, a `what`, under. A main crate with its
+    /// own binary name gets built under that name, and so does a test crate
+    /// in a subdirectory, so that they don't clobber the package's other
+    /// executables
+    pub fn crate_pkg_id(&self, crate: &Path, what: OutputType) -> PkgId {
+        match what {
+            Main => self.executable_id(crate),
+            Test => self.test_id(crate),
+            _    => self.id.clone()
+        }
+    }
+
+    /// The package ID to build the test crate `crate` under: self's ID for the
+    /// `test.rs` in the package's top directory, and otherwise self's ID with
+    /// the crate's directories appended to the short name, separated by `-`
+    /// (so `more/test.rs` in package `foo` is built as `foo-moretest`, and
+    /// `a/b/test.rs` as `foo-a-btest`). So that no two directories give the
+    /// same name, `_` and `-` in a directory's name become `__` and `_-`
+    /// (so `a-b/test.rs` is built as `foo-a_-btest`).
+    pub fn test_id(&self, crate: &Path) -> PkgId {
+        let dir = crate.dir_path();
+        let dirs: ~[~str] = dir.str_components()
+            .filter_map(|c| c)
+            .filter(|&c| c != ".")
+            .map(|c| c.replace("_", "__").replace("-", "_-"))
+            .collect();
+        if dirs.is_empty() {
+            self.id.clone()
+        } else {
+            PkgId {
+                short_name: format!("{}-{}", self.id.short_name, dirs.connect("-")),
+                ..self.id.clone()
+            }
         }
     }
 
     /// Declare all the crate files in the package source as inputs
     /// (to the package)
-    pub fn declare_inputs(&self, prep: &mut workcache::Prep) {
+    pub fn declare_inputs(&self, prep: &mut workcache::Prep, digest: DigestAlgorithm) {
         let to_do = ~[self.libs.clone(), self.mains.clone(),
                       self.tests.clone(), self.benchs.clone()];
         debug!("In declare inputs, self = {}", self.to_str());
@@ -460,7 +830,8 @@ impl PkgSrc {
                 debug!("Declaring input: {}", path.display());
                 // FIXME (#9639): This needs to handle non-utf8 paths
                 prep.declare_input("file", path.as_str().unwrap(),
-                                   workcache_support::digest_file_with_date(&path.clone()));
+                                   workcache_support::digest_file_with_date_using(&path.clone(),
+                                                                                  digest));
             }
         }
     }
@@ -470,43 +841,83 @@ impl PkgSrc {
                  // DepMap is a map from str (crate name) to (kind, name) --
                  // it tracks discovered dependencies per-crate
                  cfgs: ~[~str],
-                 inputs_to_discover: &[(~str, Path)]) -> DepMap {
+                 inputs_to_discover: &[(~str, Path)]) -> BuildResult<DepMap> {
+        let id = self.id.to_str();
+        build_context.record(|r| {
+            if !r.packages.contains(&id) { r.packages.push(id.clone()) }
+            for c in self.libs.iter().chain(self.mains.iter())
+                         .chain(self.tests.iter()).chain(self.benchs.iter()) {
+                let input = self.start_dir.join(&c.file);
+                if !r.inputs.contains(&input) { r.inputs.push(input) }
+            }
+        });
         let mut deps = TreeMap::new();
-        let libs = self.libs.clone();
-        let mains = self.mains.clone();
-        let tests = self.tests.clone();
-        let benchs = self.benchs.clone();
-        debug!("Building libs in {}, destination = {}",
+        debug!("Building crates in {}, destination = {}",
                self.source_workspace.display(),
                self.build_workspace().display());
-        self.build_crates(build_context,
-                          &mut deps,
-                          libs,
-                          cfgs,
-                          Lib,
-                          inputs_to_discover);
-        debug!("Building mains");
-        self.build_crates(build_context,
-                          &mut deps,
-                          mains,
-                          cfgs,
-                          Main,
-                          inputs_to_discover);
-        debug!("Building tests");
-        self.build_crates(build_context,
-                          &mut deps,
-                          tests,
-                          cfgs,
-                          Test,
-                          inputs_to_discover);
-        debug!("Building benches");
-        self.build_crates(build_context,
-                          &mut deps,
-                          benchs,
-                          cfgs,
-                          Bench,
-                          inputs_to_discover);
-        deps
+        for level in self.build_order(build_context).iter() {
+            match self.build_crates(build_context,
+                                    &mut deps,
+                                    *level,
+                                    cfgs,
+                                    inputs_to_discover) {
+                Built(()) => (),
+                Canceled => return Canceled
+            }
+        }
+        Built(deps)
+    }
+
+    /// Groups the package's crates, each paired with the kind of crate it's
+    /// built as, so that each group only uses (with `extern mod`) libraries
+    /// of the package's in earlier groups, and so can be built once they
+    /// have been. Fails with DEPENDENCY_CYCLE_CODE if some of the libraries
+    /// depend on each other in a cycle.
+    fn build_order(&self, build_context: &BuildContext) -> ~[~[(Crate, OutputType)]] {
+        let kinds = [(Lib, &self.libs), (Main, &self.mains),
+                     (Test, &self.tests), (Bench, &self.benchs)];
+        let mut all: ~[(Crate, OutputType)] = ~[];
+        for &(what, cs) in kinds.iter() {
+            for c in cs.iter() {
+                all.push((c.clone(), what));
+            }
+        }
+        // With one library at most, there's nothing to work out: only the
+        // other crates can use it
+        if self.libs.len() < 2 {
+            let (libs, others) = all.partition(|&(_, what)| what == Lib);
+            return ~[libs, others].move_iter().filter(|l| !l.is_empty()).collect();
+        }
+        let crates = deps::crate_deps(self, &build_context.sysroot());
+        let cycles = deps::find_cycles(crates);
+        if !cycles.is_empty() {
+            for cycle in cycles.iter() {
+                let names: ~[~str] = cycle.iter().map(|&i| crates[i].file.display().to_str())
+                                                 .collect();
+                error(format!("libraries in {} depend on each other in a cycle: {}",
+                              self.id.to_str(), names.connect(" -> ")));
+            }
+            fail!(DEPENDENCY_CYCLE_CODE);
+        }
+        deps::build_levels(crates).map(|level| level.map(|&i| all[i].clone()))
+    }
+
+    /// Builds the example `name` (`examples/<name>.rs`, found by `find_crates`)
+    /// into an executable called `name`, in the `examples` directory of the
+    /// package's build directory.
+    /// Returns None if the package has no such example.
+    pub fn build_example(&mut self,
+                         build_context: &BuildContext,
+                         cfgs: &[~str],
+                         name: &str) -> Option<BuildResult<()>> {
+        let crate = match self.examples.iter().find(|c| c.file.filestem_str() == Some(name)) {
+            Some(c) => c.clone(),
+            None => return None
+        };
+        debug!("Building example {}", crate.file.display());
+        self.binary_names.push((crate.file.clone(), name.to_owned()));
+        let mut deps = TreeMap::new();
+        Some(self.build_crates(build_context, &mut deps, [(crate, Main)], cfgs, []))
     }
 
     /// Return the workspace to put temporary files in. See the comment on `PkgSrc`
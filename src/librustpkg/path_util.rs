@@ -15,6 +15,7 @@ pub use target::{OutputType, Main, Lib, Test, Bench, Target, Build, Install};
 pub use version::{Version, NoVersion, split_version_general, try_parsing_version};
 pub use rustc::metadata::filesearch::rust_path;
 use rustc::driver::driver::host_triple;
+use context::Profile;
 
 use std::libc;
 use std::libc::consts::os::posix88::{S_IRUSR, S_IWUSR, S_IXUSR};
@@ -23,6 +24,9 @@ use std::io;
 use std::io::fs;
 use messages::*;
 
+/// Returns the default workspace (the first entry in the RUST_PATH).
+/// If it doesn't exist yet, creates it with the standard workspace
+/// layout, and tells the user where it is.
 pub fn default_workspace() -> Path {
     let p = rust_path();
     if p.is_empty() {
@@ -30,7 +34,11 @@ pub fn default_workspace() -> Path {
     }
     let result = p[0];
     if !result.is_dir() {
-        fs::mkdir_recursive(&result, io::UserRWX);
+        note(format!("Creating the default workspace in {} \
+                      (set RUST_PATH to use a different workspace)", result.display()));
+        for dir in ["src", "lib", "bin", "build"].iter() {
+            fs::mkdir_recursive(&result.join(*dir), io::UserRWX);
+        }
     }
     result
 }
@@ -110,7 +118,7 @@ pub fn target_build_dir(workspace: &Path) -> Path {
 
 /// Return the target-specific lib subdirectory, pushed onto `base`;
 /// doesn't check that it exists or create it
-fn target_lib_dir(workspace: &Path) -> Path {
+pub fn target_lib_dir(workspace: &Path) -> Path {
     let mut dir = workspace.join("lib");
     dir.push(host_triple());
     dir
@@ -123,11 +131,29 @@ fn target_bin_dir(workspace: &Path) -> Path {
     workspace.join("bin")
 }
 
+/// Return the directory that <pkgid>'s build artifacts for `profile` go in:
+/// <pkgid>'s subdirectory of <workspace>'s build directory, plus the
+/// profile's own subdirectory if there is a profile;
+/// doesn't check that it exists or create it
+pub fn profile_build_dir(pkgid: &PkgId, workspace: &Path, profile: Option<Profile>) -> Path {
+    let mut dir = target_build_dir(workspace).join(&pkgid.path);
+    for p in profile.iter() {
+        dir.push(p.name());
+    }
+    dir
+}
+
 /// Figure out what the executable name for <pkgid> in <workspace>'s build
 /// directory is, and if the file exists, return it.
 pub fn built_executable_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Path> {
-    let mut result = target_build_dir(workspace);
-    result = mk_output_path(Main, Build, pkgid, result);
+    built_executable_in_profile(pkgid, workspace, None)
+}
+
+/// Like `built_executable_in_workspace`, but looks in the build directory
+/// for `profile`
+pub fn built_executable_in_profile(pkgid: &PkgId, workspace: &Path,
+                                   profile: Option<Profile>) -> Option<Path> {
+    let result = output_file_in(Main, pkgid, profile_build_dir(pkgid, workspace, profile));
     debug!("built_executable_in_workspace: checking whether {} exists",
            result.display());
     if result.exists() {
@@ -139,22 +165,52 @@ pub fn built_executable_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<
     }
 }
 
+/// The executable that the example `name` of <pkgid> is built as in the
+/// `examples` directory of <workspace>'s build directory for `profile`, if
+/// it exists
+pub fn built_example_in_profile(pkgid: &PkgId, name: &str, workspace: &Path,
+                                profile: Option<Profile>) -> Option<Path> {
+    // Examples are built under their own names, like renamed executables
+    let example_id = PkgId { short_name: name.to_owned(), ..pkgid.clone() };
+    let result = output_file_in(Main, &example_id,
+                                profile_build_dir(pkgid, workspace, profile).join("examples"));
+    if result.exists() {
+        Some(result)
+    } else {
+        debug!("built_example_in_profile: {} does not exist", result.display());
+        None
+    }
+}
+
 /// Figure out what the test name for <pkgid> in <workspace>'s build
 /// directory is, and if the file exists, return it.
 pub fn built_test_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Path> {
-    output_in_workspace(pkgid, workspace, Test)
+    built_test_in_profile(pkgid, workspace, None)
+}
+
+/// Like `built_test_in_workspace`, but looks in the build directory
+/// for `profile`
+pub fn built_test_in_profile(pkgid: &PkgId, workspace: &Path,
+                             profile: Option<Profile>) -> Option<Path> {
+    output_in_workspace(pkgid, workspace, profile, Test)
 }
 
 /// Figure out what the test name for <pkgid> in <workspace>'s build
 /// directory is, and if the file exists, return it.
 pub fn built_bench_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Path> {
-    output_in_workspace(pkgid, workspace, Bench)
+    built_bench_in_profile(pkgid, workspace, None)
 }
 
-fn output_in_workspace(pkgid: &PkgId, workspace: &Path, what: OutputType) -> Option<Path> {
-    let mut result = target_build_dir(workspace);
-    // should use a target-specific subdirectory
-    result = mk_output_path(what, Build, pkgid, result);
+/// Like `built_bench_in_workspace`, but looks in the build directory
+/// for `profile`
+pub fn built_bench_in_profile(pkgid: &PkgId, workspace: &Path,
+                              profile: Option<Profile>) -> Option<Path> {
+    output_in_workspace(pkgid, workspace, profile, Bench)
+}
+
+fn output_in_workspace(pkgid: &PkgId, workspace: &Path, profile: Option<Profile>,
+                       what: OutputType) -> Option<Path> {
+    let result = output_file_in(what, pkgid, profile_build_dir(pkgid, workspace, profile));
     debug!("output_in_workspace: checking whether {} exists",
            result.display());
     if result.exists() {
@@ -172,6 +228,64 @@ pub fn built_library_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Pat
     library_in_workspace(&pkgid.path, pkgid.short_name, Build, workspace, "build", &pkgid.version)
 }
 
+/// Like `built_library_in_workspace`, but looks in the build directory
+/// for `profile`
+pub fn built_library_in_profile(pkgid: &PkgId, workspace: &Path,
+                                profile: Option<Profile>) -> Option<Path> {
+    library_in(pkgid.short_name, &pkgid.version,
+               &profile_build_dir(pkgid, workspace, profile))
+}
+
+/// Returns the directory that rustpkg checked <pkgid>'s sources out into for
+/// <workspace>, if there is one: under <workspace>'s build directory, where
+/// sources fetched from a remote repository go, or its `src` directory, where
+/// clones of local repositories go. The latter may be the user's own sources,
+/// so the result isn't necessarily a git clone.
+pub fn checked_out_source_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Path> {
+    let versioned = format!("{}-{}", pkgid.short_name, pkgid.version.to_str());
+    for src_dir in [target_build_dir(workspace).join("src"), workspace.join("src")].iter() {
+        let candidates = [src_dir.join(&pkgid.path.dir_path()).join(versioned.as_slice()),
+                          src_dir.join(&pkgid.path)];
+        for dir in candidates.iter() {
+            if dir.is_dir() {
+                return Some(dir.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Returns the `doc` directory in <pkgid>'s build directory for `profile`,
+/// where generated documentation for <pkgid> goes, if it exists
+pub fn built_docs_in_profile(pkgid: &PkgId, workspace: &Path,
+                             profile: Option<Profile>) -> Option<Path> {
+    let dir = profile_build_dir(pkgid, workspace, profile).join("doc");
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+/// Returns the directory that `install --with-docs` installs <pkgid>'s
+/// documentation in: <workspace>/doc/<pkgid's path>;
+/// doesn't check that it exists or create it
+pub fn target_doc_dir(pkgid: &PkgId, workspace: &Path) -> Path {
+    workspace.join("doc").join(&pkgid.path)
+}
+
+/// Returns the executable installed for <pkgid> in <workspace>, if there is
+/// one. Unlike `target_executable_in_workspace`, this doesn't create the
+/// workspace's `bin` directory.
+pub fn installed_executable_in_workspace(pkgid: &PkgId, workspace: &Path) -> Option<Path> {
+    let exe = mk_output_path(Main, Install, pkgid, target_bin_dir(workspace));
+    if exe.is_file() {
+        Some(exe)
+    } else {
+        None
+    }
+}
+
 /// Does the actual searching stuff
 pub fn installed_library_in_workspace(pkg_path: &Path, workspace: &Path) -> Option<Path> {
     // This could break once we're handling multiple versions better -- I should add a test for it
@@ -213,29 +327,31 @@ pub fn system_library(sysroot: &Path, lib_name: &str) -> Option<Path> {
     library_in(lib_name, &NoVersion, &sysroot.join("lib"))
 }
 
-fn library_in(short_name: &str, version: &Version, dir_to_search: &Path) -> Option<Path> {
+pub fn library_in(short_name: &str, version: &Version, dir_to_search: &Path) -> Option<Path> {
     debug!("Listing directory {}", dir_to_search.display());
     let dir_contents = io::ignore_io_error(|| fs::readdir(dir_to_search));
     debug!("dir has {:?} entries", dir_contents.len());
 
-    let lib_prefix = format!("{}{}", os::consts::DLL_PREFIX, short_name);
-    let lib_filetype = os::consts::DLL_EXTENSION;
+    // Libraries may be dynamic or static (rlibs), which have different prefixes
+    let dylib_prefix = format!("{}{}", os::consts::DLL_PREFIX, short_name);
+    let rlib_prefix = format!("lib{}", short_name);
 
-    debug!("lib_prefix = {} and lib_filetype = {}", lib_prefix, lib_filetype);
+    debug!("dylib_prefix = {} and rlib_prefix = {}", dylib_prefix, rlib_prefix);
 
     // Find a filename that matches the pattern:
     // (lib_prefix)-hash-(version)(lib_suffix)
-    let mut libraries = dir_contents.iter().filter(|p| {
+    let mut libraries = dir_contents.iter().filter_map(|p| {
         let extension = p.extension_str();
         debug!("p = {}, p's extension is {:?}", p.display(), extension);
         match extension {
-            None => false,
-            Some(ref s) => lib_filetype == *s
+            Some(s) if s == os::consts::DLL_EXTENSION => Some((p, dylib_prefix.as_slice())),
+            Some("rlib") => Some((p, rlib_prefix.as_slice())),
+            _ => None
         }
     });
 
     let mut result_filename = None;
-    for p_path in libraries {
+    for (p_path, lib_prefix) in libraries {
         // Find a filename that matches the pattern: (lib_prefix)-hash-(version)(lib_suffix)
         // and remember what the hash was
         let mut f_name = match p_path.filestem_str() {
@@ -307,6 +423,16 @@ pub fn target_library_in_workspace(pkgid: &PkgId, workspace: &Path) -> Path {
     target_file_in_workspace(pkgid, workspace, Lib, Install)
 }
 
+/// Returns where the file that would be installed at `p` goes instead when
+/// installing under the staging directory `root` (set with --root): at
+/// the same place relative to `root` as `p` is to the filesystem root
+pub fn path_under_root(root: &Path, p: &Path) -> Path {
+    let p = os::make_absolute(p);
+    let rel = p.root_path().and_then(|r| p.path_relative_from(&r))
+        .expect(format!("path_under_root: {} has no root", p.display()));
+    root.join(rel)
+}
+
 /// Returns the test executable that would be installed for <pkgid>
 /// in <workspace>
 /// note that we *don't* install test executables, so this is just for unit testing
@@ -361,8 +487,6 @@ pub fn build_pkg_id_in_workspace(pkgid: &PkgId, workspace: &Path) -> Path {
 /// given whether we're building a library and whether we're building tests
 pub fn mk_output_path(what: OutputType, where: Target,
                       pkg_id: &PkgId, workspace: Path) -> Path {
-    let short_name_with_version = format!("{}-{}", pkg_id.short_name,
-                                          pkg_id.version.to_str());
     // Not local_path.dir_path()! For package foo/bar/blat/, we want
     // the executable blat-0.5 to live under blat/
     let dir = match where {
@@ -372,8 +496,14 @@ pub fn mk_output_path(what: OutputType, where: Target,
         Build => workspace.join(&pkg_id.path)
     };
     debug!("[{:?}:{:?}] mk_output_path: short_name = {}, path = {}", what, where,
-           if what == Lib { short_name_with_version.clone() } else { pkg_id.short_name.clone() },
-           dir.display());
+           pkg_id.short_name, dir.display());
+    output_file_in(what, pkg_id, dir)
+}
+
+/// Return the output file for <pkg_id> in the directory `dir`
+fn output_file_in(what: OutputType, pkg_id: &PkgId, dir: Path) -> Path {
+    let short_name_with_version = format!("{}-{}", pkg_id.short_name,
+                                          pkg_id.version.to_str());
     let mut output_path = match what {
         // this code is duplicated from elsewhere; fix this
         Lib => dir.join(os::dll_filename(short_name_with_version)),
@@ -389,12 +519,15 @@ pub fn mk_output_path(what: OutputType, where: Target,
     if !output_path.is_absolute() {
         output_path = os::getcwd().join(&output_path);
     }
-    debug!("mk_output_path: returning {}", output_path.display());
+    debug!("output_file_in: returning {}", output_path.display());
     output_path
 }
 
-/// Removes files for the package `pkgid`, assuming it's installed in workspace `workspace`
-pub fn uninstall_package_from(workspace: &Path, pkgid: &PkgId) {
+/// Removes files for the package `pkgid`, assuming it's installed in workspace `workspace`.
+/// `recorded` is the files that installing `pkgid` recorded; of those in
+/// its documentation directory, only these are removed, so that anything
+/// else in it is left alone
+pub fn uninstall_package_from(workspace: &Path, pkgid: &PkgId, recorded: &[Path]) {
     let mut did_something = false;
     let installed_bin = target_executable_in_workspace(pkgid, workspace);
     if installed_bin.exists() {
@@ -406,6 +539,21 @@ pub fn uninstall_package_from(workspace: &Path, pkgid: &PkgId) {
         fs::unlink(&installed_lib);
         did_something = true;
     }
+    let installed_docs = target_doc_dir(pkgid, workspace);
+    let doc_root = workspace.join("doc");
+    for doc in recorded.iter().filter(|p| installed_docs.is_ancestor_of(*p)) {
+        if !doc.is_file() {
+            continue;
+        }
+        fs::unlink(doc);
+        did_something = true;
+        // Remove the directories this leaves empty, but not `doc` itself
+        let mut dir = doc.dir_path();
+        while dir != doc_root && dir.is_dir() && fs::readdir(&dir).is_empty() {
+            fs::rmdir(&dir);
+            dir.pop();
+        }
+    }
     if !did_something {
         warn(format!("Warning: there don't seem to be any files for {} installed in {}",
              pkgid.to_str(), workspace.display()));
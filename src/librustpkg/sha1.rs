@@ -33,7 +33,7 @@ use extra::hex::ToHex;
 
 /// Write a u32 into a vector, which must be 4 bytes long. The value is written in big-endian
 /// format.
-fn write_u32_be(dst: &mut[u8], input: u32) {
+pub fn write_u32_be(dst: &mut[u8], input: u32) {
     use std::cast::transmute;
     use std::unstable::intrinsics::to_be32;
     assert!(dst.len() == 4);
@@ -44,7 +44,7 @@ fn write_u32_be(dst: &mut[u8], input: u32) {
 }
 
 /// Read a vector of bytes into a vector of u32s. The values are read in big-endian format.
-fn read_u32v_be(dst: &mut[u32], input: &[u8]) {
+pub fn read_u32v_be(dst: &mut[u32], input: &[u8]) {
     use std::cast::transmute;
     use std::unstable::intrinsics::to_be32;
     assert!(dst.len() * 4 == input.len());
@@ -59,7 +59,7 @@ fn read_u32v_be(dst: &mut[u32], input: &[u8]) {
     }
 }
 
-trait ToBits {
+pub trait ToBits {
     /// Convert the value in bytes to the number of bits, a tuple where the 1st item is the
     /// high-order value and the 2nd item is the low order value.
     fn to_bits(self) -> (Self, Self);
@@ -73,7 +73,7 @@ impl ToBits for u64 {
 
 /// Adds the specified number of bytes to the bit count. fail!() if this would cause numeric
 /// overflow.
-fn add_bytes_to_bits<T: Int + CheckedAdd + ToBits>(bits: T, bytes: T) -> T {
+pub fn add_bytes_to_bits<T: Int + CheckedAdd + ToBits>(bits: T, bytes: T) -> T {
     let (new_high_bits, new_low_bits) = bytes.to_bits();
 
     if new_high_bits > Zero::zero() {
@@ -91,7 +91,7 @@ fn add_bytes_to_bits<T: Int + CheckedAdd + ToBits>(bits: T, bytes: T) -> T {
 /// automatically. However, other methods do not and require the caller to process the buffer. Any
 /// method that modifies the buffer directory or provides the caller with bytes that can be modifies
 /// results in those bytes being marked as used by the buffer.
-trait FixedBuffer {
+pub trait FixedBuffer {
     /// Input a vector of bytes. If the buffer becomes full, process it with the provided
     /// function and then clear the buffer.
     fn input(&mut self, input: &[u8], func: |&[u8]|);
@@ -121,14 +121,14 @@ trait FixedBuffer {
 }
 
 /// A fixed size buffer of 64 bytes useful for cryptographic operations.
-struct FixedBuffer64 {
+pub struct FixedBuffer64 {
     priv buffer: [u8, ..64],
     priv buffer_idx: uint,
 }
 
 impl FixedBuffer64 {
     /// Create a new buffer
-    fn new() -> FixedBuffer64 {
+    pub fn new() -> FixedBuffer64 {
         return FixedBuffer64 {
             buffer: [0u8, ..64],
             buffer_idx: 0
@@ -212,7 +212,7 @@ impl FixedBuffer for FixedBuffer64 {
 
 /// The StandardPadding trait adds a method useful for various hash algorithms to a FixedBuffer
 /// struct.
-trait StandardPadding {
+pub trait StandardPadding {
     /// Add standard padding to the buffer. The buffer must not be full when this method is called
     /// and is guaranteed to have exactly rem remaining bytes when it returns. If there are not at
     /// least rem bytes available, the buffer will be zero padded, processed, cleared, and then
@@ -0,0 +1,191 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/*!
+ * An implementation of the SHA-256 cryptographic hash, built on the same
+ * buffering and padding helpers as the `sha1` module.
+ *
+ * This implementation has not been reviewed for cryptographic uses.
+ * As such, all cryptographic uses of this implementation are strongly
+ * discouraged.
+ */
+
+use sha1::{Digest, FixedBuffer, FixedBuffer64, StandardPadding,
+           add_bytes_to_bits, read_u32v_be, write_u32_be};
+
+static K32: [u32, ..64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
+    0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+    0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
+    0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+static H256: [u32, ..8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+/// Structure representing the state of a Sha256 computation
+pub struct Sha256 {
+    priv h: [u32, ..8],
+    priv length_bits: u64,
+    priv buffer: FixedBuffer64,
+    priv computed: bool,
+}
+
+fn rotate_right(word: u32, bits: u32) -> u32 {
+    word >> bits | word << 32u32 - bits
+}
+
+fn process_msg_block(data: &[u8], h: &mut [u32, ..8]) {
+    let mut w = [0u32, ..64];
+    read_u32v_be(w.mut_slice(0, 16), data);
+    for t in range(16u, 64) {
+        let s0 = rotate_right(w[t - 15], 7) ^ rotate_right(w[t - 15], 18) ^ (w[t - 15] >> 3);
+        let s1 = rotate_right(w[t - 2], 17) ^ rotate_right(w[t - 2], 19) ^ (w[t - 2] >> 10);
+        w[t] = w[t - 16] + s0 + w[t - 7] + s1;
+    }
+
+    let mut a = h[0];
+    let mut b = h[1];
+    let mut c = h[2];
+    let mut d = h[3];
+    let mut e = h[4];
+    let mut f = h[5];
+    let mut g = h[6];
+    let mut hh = h[7];
+    for t in range(0u, 64) {
+        let s1 = rotate_right(e, 6) ^ rotate_right(e, 11) ^ rotate_right(e, 25);
+        let ch = (e & f) ^ (!e & g);
+        let temp1 = hh + s1 + ch + K32[t] + w[t];
+        let s0 = rotate_right(a, 2) ^ rotate_right(a, 13) ^ rotate_right(a, 22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0 + maj;
+        hh = g;
+        g = f;
+        f = e;
+        e = d + temp1;
+        d = c;
+        c = b;
+        b = a;
+        a = temp1 + temp2;
+    }
+    h[0] += a;
+    h[1] += b;
+    h[2] += c;
+    h[3] += d;
+    h[4] += e;
+    h[5] += f;
+    h[6] += g;
+    h[7] += hh;
+}
+
+impl Sha256 {
+    /// Construct a `Sha256` object
+    pub fn new() -> Sha256 {
+        let mut st = Sha256 {
+            h: H256,
+            length_bits: 0u64,
+            buffer: FixedBuffer64::new(),
+            computed: false,
+        };
+        st.reset();
+        st
+    }
+}
+
+impl Digest for Sha256 {
+    fn reset(&mut self) {
+        self.length_bits = 0;
+        self.h = H256;
+        self.buffer.reset();
+        self.computed = false;
+    }
+
+    fn input(&mut self, msg: &[u8]) {
+        assert!(!self.computed);
+        // Assumes that msg.len() can be converted to u64 without overflow
+        self.length_bits = add_bytes_to_bits(self.length_bits, msg.len() as u64);
+        let h = &mut self.h;
+        self.buffer.input(msg, |d: &[u8]| { process_msg_block(d, h); });
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        if !self.computed {
+            let h = &mut self.h;
+            self.buffer.standard_padding(8, |d: &[u8]| { process_msg_block(d, h) });
+            write_u32_be(self.buffer.next(4), (self.length_bits >> 32) as u32);
+            write_u32_be(self.buffer.next(4), self.length_bits as u32);
+            process_msg_block(self.buffer.full_buffer(), h);
+            self.computed = true;
+        }
+
+        for i in range(0u, 8) {
+            write_u32_be(out.mut_slice(i * 4, i * 4 + 4), self.h[i]);
+        }
+    }
+
+    fn output_bits(&self) -> uint { 256 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use sha1::Digest;
+    use super::Sha256;
+
+    #[test]
+    fn test() {
+        // Test messages from FIPS 180-2, plus a couple of others
+        let tests = [
+            ("", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            ("abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            ("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+             "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+            ("The quick brown fox jumps over the lazy dog",
+             "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592")];
+
+        let mut sh = Sha256::new();
+        for &(input, output) in tests.iter() {
+            // All at once
+            sh.input_str(input);
+            assert_eq!(sh.result_str().as_slice(), output);
+            sh.reset();
+
+            // and one byte at a time
+            for b in input.bytes() {
+                sh.input(&[b]);
+            }
+            assert_eq!(sh.result_str().as_slice(), output);
+            sh.reset();
+        }
+    }
+
+    #[test]
+    fn test_1million_a() {
+        let mut sh = Sha256::new();
+        let buffer = vec::from_elem(1000, 'a' as u8);
+        1000u.times(|| sh.input(buffer));
+        assert_eq!(sh.result_str().as_slice(),
+                   "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+}
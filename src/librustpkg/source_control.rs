@@ -8,59 +8,81 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-// Utils for working with version control repositories. Just git right now.
+// Utils for working with version control repositories. Git is built in;
+// other kinds of repository can be fetched from with a `SourceFetcher`.
 
-use std::{run, str};
+use std::{os, run, str};
 use std::run::{ProcessOutput, ProcessOptions, Process};
+use std::io;
 use std::io::fs;
+use std::io::process::{ProcessExit, ExitStatus};
 use extra::tempfile::TempDir;
 use version::*;
 use path_util::chmod_read_only;
+use messages::warn;
 
 /// Attempts to clone `source`, a local git repository, into `target`, a local
-/// directory that doesn't exist.
+/// directory that doesn't exist. If `source` is a plain directory rather than a
+/// git repository, it's copied into `target` instead (replacing anything
+/// there), and `v` is ignored.
 /// Returns `DirToUse(p)` if the clone fails, where `p` is a newly created temporary
 /// directory (that the callee may use, for example, to check out remote sources into).
 /// Returns `CheckedOutSources` if the clone succeeded.
-pub fn safe_git_clone(source: &Path, v: &Version, target: &Path) -> CloneResult {
+/// If `depth` is `Some(n)`, only the last `n` commits are fetched, falling back
+/// to a full clone if the requested version isn't reachable that way.
+/// Clones are made in a scratch directory under `tmp_dir` (or `os::tmpdir()`,
+/// which honors `TMPDIR`, if that's `None`) and then moved to `target`; the
+/// directory returned in `DirToUse` is made there too.
+pub fn safe_git_clone(source: &Path, v: &Version, target: &Path,
+                      depth: Option<uint>, tmp_dir: &Option<Path>) -> CloneResult {
     if source.exists() {
         debug!("{} exists locally! Cloning it into {}",
                 source.display(), target.display());
         // Ok to use target here; we know it will succeed
         assert!(source.is_dir());
-        assert!(is_git_dir(source));
+        if !is_git_dir(source) {
+            if target.exists() {
+                fs::rmdir_recursive(target);
+            }
+            copy_local_source(source, v, target);
+            return CheckedOutSources;
+        }
 
         if !target.exists() {
-            debug!("Running: git clone {} {}", source.display(), target.display());
+            use conditions::{copy_failed, failed_to_create_temp_dir};
+
+            let scratch = new_scratch_dir(tmp_dir);
+            let clone_dir = match scratch {
+                Some(ref d) => d.path().join("rustpkg_temp"),
+                None => failed_to_create_temp_dir::cond.raise(
+                    ~"Failed to create temporary directory for cloning git sources")
+            };
+            // git ignores --depth for plain local paths, so use a file:// URL
             // FIXME (#9639): This needs to handle non-utf8 paths
-            let outp = run::process_output("git", [~"clone",
-                                                   source.as_str().unwrap().to_owned(),
-                                                   target.as_str().unwrap().to_owned()]);
-            if !outp.status.success() {
-                println(str::from_utf8_owned(outp.output.clone()));
-                println(str::from_utf8_owned(outp.error));
-                return DirToUse(target.clone());
-            }
-                else {
-                match v {
-                    &ExactRevision(ref s) => {
-                        let git_dir = target.join(".git");
-                        debug!("`Running: git --work-tree={} --git-dir={} checkout {}",
-                                *s, target.display(), git_dir.display());
-                        // FIXME (#9639: This needs to handle non-utf8 paths
-                        let outp = run::process_output("git",
-                            [format!("--work-tree={}", target.as_str().unwrap().to_owned()),
-                             format!("--git-dir={}", git_dir.as_str().unwrap().to_owned()),
-                             ~"checkout", format!("{}", *s)]);
-                        if !outp.status.success() {
-                            println(str::from_utf8_owned(outp.output.clone()));
-                            println(str::from_utf8_owned(outp.error));
-                            return DirToUse(target.clone());
-                        }
-                    }
-                    _ => ()
+            let source_url = format!("file://{}",
+                                     os::make_absolute(source).as_str().unwrap());
+            if !try_shallow_clone(source_url, &clone_dir, v, depth) {
+                debug!("Running: git clone {} {}", source.display(), clone_dir.display());
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                let outp = run::process_output("git", [~"clone",
+                                                       source.as_str().unwrap().to_owned(),
+                                                       clone_dir.as_str().unwrap().to_owned()]);
+                if !outp.status.success() {
+                    println(str::from_utf8_owned(outp.output.clone()));
+                    return CloneFailed(target.clone(), outp.status,
+                                       str::from_utf8_owned(outp.error));
                 }
             }
+            // Only hand over sources that are confirmed to be the version
+            // that was asked for
+            match check_out_version(&clone_dir, target, v) {
+                Ok(()) => (),
+                Err(failed) => return failed
+            }
+            debug!("Moving the clone in {} to {}", clone_dir.display(), target.display());
+            if !move_dir(&clone_dir, target) {
+                copy_failed::cond.raise((clone_dir.clone(), target.clone()));
+            }
         } else {
             // Check that no version was specified. There's no reason to not handle the
             // case where a version was requested, but I haven't implemented it.
@@ -79,8 +101,7 @@ pub fn safe_git_clone(source: &Path, v: &Version, target: &Path) -> CloneResult
     } else {
         use conditions::failed_to_create_temp_dir::cond;
 
-        let scratch_dir = TempDir::new("rustpkg");
-        let clone_target = match scratch_dir {
+        let clone_target = match new_scratch_dir(tmp_dir) {
             Some(d) => d.unwrap().join("rustpkg_temp"),
             None    => cond.raise(~"Failed to create temporary directory for fetching git sources")
         };
@@ -89,9 +110,121 @@ pub fn safe_git_clone(source: &Path, v: &Version, target: &Path) -> CloneResult
     }
 }
 
+/// Creates a new temporary directory under `tmp_dir` (creating `tmp_dir`
+/// itself if need be), or under `os::tmpdir()` if `tmp_dir` is `None`
+fn new_scratch_dir(tmp_dir: &Option<Path>) -> Option<TempDir> {
+    match *tmp_dir {
+        Some(ref d) => {
+            if !d.is_dir()
+                && io::result(|| fs::mkdir_recursive(d, io::UserRWX)).is_err() {
+                return None;
+            }
+            TempDir::new_in(d, "rustpkg")
+        }
+        None => TempDir::new("rustpkg")
+    }
+}
+
+/// Moves the directory `from` to `to`, which mustn't exist yet, creating
+/// `to`'s parent directories if need be. If the two are on different
+/// filesystems, so that `from` can't just be renamed, it's copied to `to`
+/// and then removed. Returns true if the move succeeded.
+pub fn move_dir(from: &Path, to: &Path) -> bool {
+    if io::result(|| fs::mkdir_recursive(&to.dir_path(), io::UserRWX)).is_err() {
+        return false;
+    }
+    if io::result(|| fs::rename(from, to)).is_ok() {
+        return true;
+    }
+    debug!("Couldn't rename {} to {}; copying it instead", from.display(), to.display());
+    let copied = io::result(|| {
+        fs::mkdir_recursive(to, io::UserRWX);
+        for p in fs::walk_dir(from) {
+            let dest = to.join(p.path_relative_from(from).unwrap());
+            if p.is_dir() {
+                fs::mkdir_recursive(&dest, io::UserRWX);
+            } else {
+                fs::mkdir_recursive(&dest.dir_path(), io::UserRWX);
+                fs::copy(&p, &dest);
+            }
+        }
+    });
+    if copied.is_err() {
+        let _ = io::result(|| fs::rmdir_recursive(to));
+        return false;
+    }
+    io::result(|| fs::rmdir_recursive(from)).is_ok()
+}
+
+/// Returns true if `target` is a clone of `source`, the local git repository,
+/// that's already checked out at the commit that version `v` names in `source`
+/// (`source`'s HEAD if no version was requested), so cloning again would be
+/// pointless.
+pub fn clone_is_current(source: &Path, target: &Path, v: &Version) -> bool {
+    if !is_git_dir(target) {
+        return false;
+    }
+    let wanted = match v {
+        &ExactRevision(ref s) | &Tagged(ref s) => s.as_slice(),
+        _ => "HEAD"
+    };
+    match (git_commit_for(source, wanted), git_commit_for(target, "HEAD")) {
+        (Some(ref want), Some(ref have)) => {
+            debug!("{} wants commit {}; {} has {}", source.display(), *want,
+                   target.display(), *have);
+            want == have
+        }
+        _ => false
+    }
+}
+
+/// Returns the hash of the commit that `rev` names in the repository `repo`,
+/// or None if git can't resolve it
+fn git_commit_for(repo: &Path, rev: &str) -> Option<~str> {
+    let outp = process_output_in_cwd("git", [~"rev-parse", ~"--verify", ~"--quiet",
+                                             rev + "^{commit}"], repo);
+    if outp.status.success() {
+        Some(str::from_utf8_owned(outp.output).trim().to_owned())
+    } else {
+        None
+    }
+}
+
+/// If `dir` is inside a git working tree, returns the tracked files under
+/// `dir` that have uncommitted changes (an empty vector if there are none).
+/// Returns None if `dir` isn't under git's control, or if git can't tell.
+/// Untracked files don't count, since build products and editor droppings
+/// usually are.
+pub fn uncommitted_changes(dir: &Path) -> Option<~[~str]> {
+    let mut d = os::make_absolute(dir);
+    // Don't spawn git at all for sources that can't be in a repository
+    loop {
+        if is_git_dir(&d) { break; }
+        if !d.pop() { return None; }
+    }
+    let outp = process_output_in_cwd("git", [~"status", ~"--porcelain",
+                                             ~"--untracked-files=no", ~"."], dir);
+    if !outp.status.success() {
+        debug!("git status failed in {}: {}", dir.display(),
+               str::from_utf8(outp.error));
+        return None;
+    }
+    // Each line is a two-character status, a space, and the path
+    Some(str::from_utf8(outp.output).lines()
+         .filter(|l| l.len() > 3)
+         .map(|l| l.slice_from(3).to_owned())
+         .collect())
+}
+
 pub enum CloneResult {
     DirToUse(Path), // Created this empty directory to use as the temp dir for git
-    CheckedOutSources // Successfully checked sources out into the given target dir
+    CheckedOutSources, // Successfully checked sources out into the given target dir
+    // git failed to clone a local source into this directory, exiting with
+    // this status and printing this error output
+    CloneFailed(Path, ProcessExit, ~str),
+    // git cloned the sources for this directory, but couldn't put them at
+    // the requested version, for the reason given
+    WrongVersion(Path, ~str)
 }
 
 pub fn make_read_only(target: &Path) {
@@ -104,29 +237,284 @@ pub fn make_read_only(target: &Path) {
 }
 
 /// Source can be either a URL or a local file path.
-pub fn git_clone_url(source: &str, target: &Path, v: &Version) {
+/// `depth` is as for `safe_git_clone`.
+/// A local source (a path or `file://` URL) that isn't a git repository
+/// is copied into `target` instead.
+pub fn git_clone_url(source: &str, target: &Path, v: &Version, depth: Option<uint>) {
     use conditions::git_checkout_failed::cond;
+    use conditions::git_wrong_version;
 
-    // FIXME (#9639): This needs to handle non-utf8 paths
-    let outp = run::process_output("git", [~"clone", source.to_owned(),
-                                           target.as_str().unwrap().to_owned()]);
+    match local_source(source) {
+        Some(ref dir) if !is_git_dir(dir) => {
+            copy_local_source(dir, v, target);
+            return;
+        }
+        _ => ()
+    }
+
+    if !try_shallow_clone(source, target, v, depth) {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let outp = run::process_output("git", [~"clone", source.to_owned(),
+                                               target.as_str().unwrap().to_owned()]);
+        if !outp.status.success() {
+            debug!("{}", str::from_utf8_owned(outp.output.clone()));
+            cond.raise((source.to_owned(), target.clone(), outp.status,
+                        str::from_utf8_owned(outp.error)));
+            return;
+        }
+    }
+    match check_out_version(target, target, v) {
+        Ok(()) => (),
+        Err(CloneFailed(_, status, error)) =>
+            cond.raise((source.to_owned(), target.clone(), status, error)),
+        Err(WrongVersion(_, error)) =>
+            git_wrong_version::cond.raise((source.to_owned(), target.clone(), error)),
+        Err(_) => fail!("check_out_version returned neither CloneFailed nor WrongVersion")
+    }
+}
+
+/// Fetches packages' sources from remote repositories. Each implementation
+/// is registered in a `FetcherRegistry` under the URL schemes it handles.
+pub trait SourceFetcher {
+    /// A short name for the kind of repository this fetches from, like `git`
+    fn name(&self) -> &'static str;
+
+    /// Fetches version `v` of the sources at `url` into `target`, a directory
+    /// that doesn't exist yet. Returns `CheckedOutSources` if that worked, or
+    /// `CloneFailed` or `WrongVersion` saying why it didn't.
+    fn fetch(&self, url: &str, v: &Version, target: &Path) -> CloneResult;
+}
+
+/// Fetches sources by cloning a git repository (see `git_clone_url`)
+pub struct GitFetcher {
+    // As for `safe_git_clone`
+    depth: Option<uint>
+}
+
+impl SourceFetcher for GitFetcher {
+    fn name(&self) -> &'static str { "git" }
+
+    fn fetch(&self, url: &str, v: &Version, target: &Path) -> CloneResult {
+        use conditions::git_checkout_failed::cond;
+        use conditions::git_wrong_version;
+
+        let mut failed = None;
+        let mut wrong = None;
+        cond.trap(|(_, _, status, git_error)| {
+            failed = Some(CloneFailed(target.clone(), status, git_error));
+        }).inside(|| {
+            git_wrong_version::cond.trap(|(_, _, error)| {
+                wrong = Some(WrongVersion(target.clone(), error));
+            }).inside(|| git_clone_url(url, target, v, self.depth))
+        });
+        failed.or(wrong).unwrap_or(CheckedOutSources)
+    }
+}
+
+/// The source fetcher to use for each URL scheme
+pub struct FetcherRegistry {
+    priv fetchers: ~[(~str, ~SourceFetcher:Send)]
+}
+
+impl FetcherRegistry {
+    /// A registry with no fetchers in it
+    pub fn new() -> FetcherRegistry {
+        FetcherRegistry { fetchers: ~[] }
+    }
+
+    /// A registry that fetches `https`, `http`, `git`, `ssh` and `file` URLs
+    /// with git, cloning only the last `depth` commits if that's given
+    pub fn with_defaults(depth: Option<uint>) -> FetcherRegistry {
+        let mut registry = FetcherRegistry::new();
+        for scheme in ["https", "http", "git", "ssh", "file"].iter() {
+            registry.register(*scheme, ~GitFetcher { depth: depth } as ~SourceFetcher:Send);
+        }
+        registry
+    }
+
+    /// Makes `fetcher` the one for URLs with the scheme `scheme`, in place
+    /// of any that was registered for it before
+    pub fn register(&mut self, scheme: &str, fetcher: ~SourceFetcher:Send) {
+        self.fetchers.retain(|&(ref s, _)| s.as_slice() != scheme);
+        self.fetchers.push((scheme.to_owned(), fetcher));
+    }
+
+    /// The name of the fetcher for `url`'s scheme, or None if there isn't one
+    pub fn fetcher_name(&self, url: &str) -> Option<&'static str> {
+        self.find(url).map(|f| f.name())
+    }
+
+    /// Fetches version `v` of the sources at `url` into `target` with the
+    /// fetcher for `url`'s scheme (see `SourceFetcher::fetch`). Returns None,
+    /// without fetching anything, if there's no fetcher for the scheme.
+    pub fn fetch(&self, url: &str, v: &Version, target: &Path) -> Option<CloneResult> {
+        self.find(url).map(|f| {
+            debug!("Fetching {} with the {} fetcher", url, f.name());
+            f.fetch(url, v, target)
+        })
+    }
+
+    fn find<'a>(&'a self, url: &str) -> Option<&'a ~SourceFetcher:Send> {
+        let scheme = match url.find_str("://") {
+            Some(i) => url.slice_to(i),
+            None => return None
+        };
+        self.fetchers.iter().find(|&&(ref s, _)| s.as_slice() == scheme).map(|&(_, ref f)| f)
+    }
+}
+
+/// Brings `repo`, a clone that rustpkg made earlier, up to date: fetches
+/// what's new from the repository it was cloned from, and then checks out
+/// the newest commit that version `v` names there (the remote's HEAD if no
+/// version was requested), discarding the old checkout. Unlike
+/// `safe_git_clone`, this reuses the objects already in `repo`.
+/// Returns the hash of the commit checked out, or if a git command fails,
+/// its exit status and an error saying what went wrong.
+pub fn git_update(repo: &Path, v: &Version) -> Result<~str, (ProcessExit, ~str)> {
+    // Plain `fetch` doesn't update tags that moved, and in older gits,
+    // `fetch --tags` fetches only tags, so do both
+    for args in [~[~"fetch", ~"origin"], ~[~"fetch", ~"--tags", ~"origin"]].iter() {
+        debug!("Running: git {} in {}", args.connect(" "), repo.display());
+        let outp = process_output_in_cwd("git", *args, repo);
+        if !outp.status.success() {
+            return Err((outp.status, format!("couldn't fetch into {}:\n{}", repo.display(),
+                                             str::from_utf8_owned(outp.error))));
+        }
+    }
+    // A branch name means the remote's branch, which `fetch` updated,
+    // rather than the local branch of the same name, which it didn't
+    let wanted = match v {
+        &ExactRevision(ref s) | &Tagged(ref s) => s.to_owned(),
+        _ => ~"HEAD"
+    };
+    let commit = match git_commit_for(repo, format!("origin/{}", wanted).as_slice())
+        .or_else(|| git_commit_for(repo, wanted.as_slice())) {
+        Some(c) => c,
+        None => {
+            let head = git_commit_for(repo, "HEAD").unwrap_or(~"no commit");
+            return Err((ExitStatus(1),
+                        format!("requested version {} not found, got {}", wanted, head)));
+        }
+    };
+    debug!("Running: git checkout --force {} in {}", commit, repo.display());
+    let outp = process_output_in_cwd("git", [~"checkout", ~"--quiet", ~"--force",
+                                             commit.clone()], repo);
+    if !outp.status.success() {
+        return Err((outp.status, format!("couldn't check out {} in {}:\n{}", commit,
+                                         repo.display(), str::from_utf8_owned(outp.error))));
+    }
+    Ok(commit)
+}
+
+/// Checks out the tag or revision that `v` names in `repo`, a fresh clone
+/// that's going to be `target`, and confirms that HEAD is then the commit it
+/// names, so that a version that doesn't exist isn't silently built from
+/// whatever was checked out before. Does nothing if `v` doesn't name a tag or
+/// revision. Returns `CloneFailed` if git couldn't check the version out, or
+/// `WrongVersion` if it could, but HEAD isn't the commit that `v` names.
+fn check_out_version(repo: &Path, target: &Path, v: &Version) -> Result<(), CloneResult> {
+    let wanted = match v {
+        &ExactRevision(ref s) | &Tagged(ref s) => s.as_slice(),
+        _ => return Ok(())
+    };
+    let head = || git_commit_for(repo, "HEAD").unwrap_or(~"no commit");
+    debug!("Running: git checkout {} in {}", wanted, repo.display());
+    let outp = process_output_in_cwd("git", [~"checkout", wanted.to_owned()], repo);
     if !outp.status.success() {
-         debug!("{}", str::from_utf8_owned(outp.output.clone()));
-         debug!("{}", str::from_utf8_owned(outp.error));
-         cond.raise((source.to_owned(), target.clone()))
-    }
-    else {
-        match v {
-            &ExactRevision(ref s) | &Tagged(ref s) => {
-                    let outp = process_output_in_cwd("git", [~"checkout", s.to_owned()],
-                                                         target);
-                    if !outp.status.success() {
-                        debug!("{}", str::from_utf8_owned(outp.output.clone()));
-                        debug!("{}", str::from_utf8_owned(outp.error));
-                        cond.raise((source.to_owned(), target.clone()))
-                    }
+        debug!("{}", str::from_utf8_owned(outp.output.clone()));
+        return Err(CloneFailed(target.clone(), outp.status,
+                               format!("requested version {} not found, got {}\n{}",
+                                       wanted, head(), str::from_utf8_owned(outp.error))));
+    }
+    // A branch that's only on the remote is checked out as a new local branch
+    // of the same name, so `wanted` names the new HEAD either way. If `wanted`
+    // doesn't name a commit at all, git took it for a file to check out.
+    let outp = process_output_in_cwd("git", [~"rev-parse", ~"--verify", ~"--quiet",
+                                             wanted + "^{commit}"], repo);
+    let want = str::from_utf8_owned(outp.output).trim().to_owned();
+    let have = head();
+    if !outp.status.success() || want != have {
+        return Err(WrongVersion(target.clone(), format!("requested version {} not found, got {}",
+                                                        wanted, have)));
+    }
+    Ok(())
+}
+
+/// If `depth` is `Some(n)`, tries to clone the last `n` commits of `source` into
+/// `target`, with the tag or branch named by `v` checked out.
+/// Returns true if that worked. Otherwise, removes anything the attempt left
+/// behind, warns if a shallow clone was asked for, and returns false, in which
+/// case the caller should do a full clone.
+fn try_shallow_clone(source: &str, target: &Path, v: &Version, depth: Option<uint>) -> bool {
+    let depth = match depth {
+        Some(d) => d,
+        None    => return false
+    };
+    let mut args = ~[~"clone", ~"--depth", depth.to_str()];
+    match v {
+        &ExactRevision(ref s) | &Tagged(ref s) => {
+            args.push(~"--branch");
+            args.push(s.to_owned());
+        }
+        _ => ()
+    }
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    args.push(source.to_owned());
+    args.push(target.as_str().unwrap().to_owned());
+    debug!("Running: git {}", args.connect(" "));
+    let outp = run::process_output("git", args);
+    if outp.status.success() {
+        return true;
+    }
+    debug!("{}", str::from_utf8_owned(outp.output.clone()));
+    debug!("{}", str::from_utf8_owned(outp.error));
+    if target.exists() {
+        let _ = io::result(|| fs::rmdir_recursive(target));
+    }
+    warn(format!("Couldn't make a shallow clone of {} at version {}; \
+                  falling back to a full clone", source, v.to_str()));
+    false
+}
+
+/// If `source` is a `file://` URL or a path naming an existing local
+/// directory, returns that directory
+pub fn local_source(source: &str) -> Option<Path> {
+    let path = if source.starts_with("file://") {
+        Path::new(source.slice_from("file://".len()))
+    } else {
+        Path::new(source)
+    };
+    if path.is_dir() { Some(path) } else { None }
+}
+
+/// Copies `source`, a local directory that isn't a git repository, into
+/// `target`. There's no history to check a version out of, so if `v` asks
+/// for one, warns that it's being ignored. Raises copy_failed for
+/// anything that can't be copied.
+fn copy_local_source(source: &Path, v: &Version, target: &Path) {
+    use conditions::copy_failed::cond;
+
+    if *v != NoVersion {
+        warn(format!("{} isn't a git repository, so ignoring version {}",
+                     source.display(), v.to_str()));
+    }
+    debug!("Copying {} to {}", source.display(), target.display());
+    if io::result(|| fs::mkdir_recursive(target, io::UserRWX)).is_err() {
+        cond.raise((source.clone(), target.clone()));
+        return;
+    }
+    for p in fs::walk_dir(source) {
+        let dest = target.join(p.path_relative_from(source).unwrap());
+        let copied = io::result(|| {
+            if p.is_dir() {
+                fs::mkdir_recursive(&dest, io::UserRWX);
+            } else {
+                fs::mkdir_recursive(&dest.dir_path(), io::UserRWX);
+                fs::copy(&p, &dest);
             }
-            _ => ()
+        });
+        if copied.is_err() {
+            cond.raise((p.clone(), dest));
         }
     }
 }
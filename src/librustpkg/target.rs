@@ -53,7 +53,9 @@ pub enum SourceType {
     /// Build any test.rs files that can be recursively found in the active workspace
     Tests,
     /// Build everything
-    Everything
+    Everything,
+    /// Build everything, and also the example crate with this name
+    Example(~str)
 }
 
 pub fn is_lib(p: &Path) -> bool {
@@ -72,6 +74,12 @@ pub fn is_bench(p: &Path) -> bool {
     file_is(p, "bench")
 }
 
+/// True if `p`, relative to a package's start directory, is inside the
+/// package's `examples` directory
+pub fn in_examples_dir(p: &Path) -> bool {
+    p.str_components().next() == Some(Some("examples"))
+}
+
 fn file_is(p: &Path, stem: &str) -> bool {
     match p.filestem() {
         Some(s) if s == stem.as_bytes() => true,
@@ -10,13 +10,20 @@
 
 // rustpkg unit tests
 
-use context::{BuildContext, Context, RustcFlags};
-use std::{os, run, str, task};
+use CtxMethods;
+use context::{BuildContext, Context, RustcFlags, Debug, Release, CancelFlag, default_emitter};
+use context::{Built, Canceled};
+use context::{Callback, CacheObserver, CopyObserver, EmitterMaker};
+use context::{FailIfAmbiguous, resolve_sysroot};
+use workcache_support::{Sha1Digest, crate_tag, date_digest_matches, digest_file_with_date,
+                        digest_file_contents_using, digest_only_date, file_digest_matches};
+use std::{os, run, str, task, vec};
 use std::io;
 use std::io::fs;
 use std::io::File;
 use std::io::process;
 use std::io::process::ProcessExit;
+use std::io::timer;
 use extra::arc::Arc;
 use extra::arc::RWArc;
 use extra::tempfile::TempDir;
@@ -24,8 +31,9 @@ use extra::workcache;
 use extra::workcache::{Database, Logger};
 use extra::treemap::TreeMap;
 use extra::getopts::groups::getopts;
+use extra::test::BenchHarness;
 use std::run::ProcessOutput;
-use installed_packages::list_installed_packages;
+use installed_packages::{list_installed_packages, InstalledIndex, index_file};
 use package_id::{PkgId};
 use version::{ExactRevision, NoVersion, Version, Tagged};
 use path_util::{target_executable_in_workspace, target_test_in_workspace,
@@ -33,15 +41,24 @@ use path_util::{target_executable_in_workspace, target_test_in_workspace,
                library_in_workspace, installed_library_in_workspace,
                built_bench_in_workspace, built_test_in_workspace,
                built_library_in_workspace, built_executable_in_workspace, target_build_dir,
-               chmod_read_only, platform_library_name};
+               built_executable_in_profile, built_library_in_profile,
+               built_example_in_profile, chmod_read_only,
+               platform_library_name, library_in};
 use rustc::back::link::get_cc_prog;
 use rustc::metadata::filesearch::rust_path;
 use rustc::driver::driver::{build_session, build_session_options, host_triple, optgroups};
-use syntax::diagnostic;
+use syntax::{codemap, diagnostic};
 use target::*;
 use package_source::PkgSrc;
-use source_control::{CheckedOutSources, DirToUse, safe_git_clone};
-use exit_codes::{BAD_FLAG_CODE, COPY_FAILED_CODE};
+use source_control::{CheckedOutSources, CloneFailed, safe_git_clone, git_clone_url};
+use source_control::{clone_is_current, WrongVersion};
+use source_control::{CloneResult, FetcherRegistry, SourceFetcher};
+use source_control::{is_git_dir, make_read_only};
+use exit_codes::{BAD_FLAG_CODE, COPY_FAILED_CODE, WORKSPACE_PROBLEMS_CODE};
+use exit_codes::{NONEXISTENT_PACKAGE_CODE, VERIFY_FAILED_CODE, BAD_PKG_SCRIPT_CODE};
+use exit_codes::{WORKSPACE_LOCKED_CODE, CORRUPT_WORKCACHE_CODE};
+use exit_codes::{DEPENDENCY_CYCLE_CODE, DIRTY_SOURCES_CODE, BAD_SYSROOT_CODE};
+use lock;
 
 fn fake_ctxt(sysroot: Path, workspace: &Path) -> BuildContext {
     let context = workcache::Context::new(
@@ -52,11 +69,47 @@ fn fake_ctxt(sysroot: Path, workspace: &Path) -> BuildContext {
         workcache_context: context,
         context: Context {
             cfgs: ~[],
+            config_args: ~[],
             rustc_flags: RustcFlags::default(),
 
             use_rust_path_hack: false,
-            sysroot: sysroot
-        }
+            sysroot: sysroot,
+            test_timeout: None,
+            test_threads: None,
+            json: false,
+            git_clone_depth: None,
+            profile: None,
+            digest: Sha1Digest,
+            excludes: ~[],
+            jobs: 1,
+            no_default_workspace: false,
+            capture_script_output: false,
+            install_root: None,
+            emit_deps: false,
+            with_docs: false,
+            strip: false,
+            allow_dirty: false,
+            cache_index: false,
+            out_dir: None,
+            tmp_dir: None,
+            strict_cache: false,
+            isolated_tests: false,
+            timings: false,
+            report: None,
+            test_args: ~[],
+            ambiguous: FailIfAmbiguous,
+            variant: None,
+            all_versions: false,
+            print_crate_ids: false,
+            no_wait: false
+        },
+        cache_callback: None,
+        copy_progress: None,
+        emitter: None,
+        timings: None,
+        report: None,
+        cancel: None,
+        fetchers: Callback::new(FetcherRegistry::with_defaults(None))
     }
 }
 
@@ -198,16 +251,43 @@ fn add_git_tag(repo: &Path, tag: ~str) {
             format!("Couldn't add git tag {} in {}", tag, repo.display()));
 }
 
+#[cfg(not(windows))]
 fn is_rwx(p: &Path) -> bool {
     if !p.exists() { return false }
     p.stat().perm & io::UserRWX == io::UserRWX
 }
 
+// Windows has no execute permission, and a file's only permission bit is
+// whether it's read-only
+#[cfg(windows)]
+fn is_rwx(p: &Path) -> bool {
+    if !p.exists() { return false }
+    p.stat().perm & io::UserWrite == io::UserWrite
+}
+
+#[cfg(not(windows))]
 fn is_read_only(p: &Path) -> bool {
     if !p.exists() { return false }
     p.stat().perm & io::UserRWX == io::UserRead
 }
 
+#[cfg(windows)]
+fn is_read_only(p: &Path) -> bool {
+    if !p.exists() { return false }
+    p.stat().perm & io::UserWrite == 0
+}
+
+/// Returns the value of RUST_PATH that makes rustpkg search `paths`, in order
+fn rust_path_value(paths: &[Path]) -> ~str {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    paths.map(|p| p.as_str().unwrap().to_owned()).connect(RUST_PATH_SEP)
+}
+
+#[cfg(windows)]
+static RUST_PATH_SEP: &'static str = ";";
+#[cfg(not(windows))]
+static RUST_PATH_SEP: &'static str = ":";
+
 fn test_sysroot() -> Path {
     // Totally gross hack but it's just for test cases.
     // Infer the sysroot from the exe name and pray that it's right.
@@ -220,13 +300,14 @@ fn test_sysroot() -> Path {
 // Returns the path to rustpkg
 fn rustpkg_exec() -> Path {
     // Ugh
+    let exe_name = format!("rustpkg{}", os::EXE_SUFFIX);
     let first_try = test_sysroot().join_many(
-        [~"lib", ~"rustc", host_triple(), ~"bin", ~"rustpkg"]);
+        [~"lib", ~"rustc", host_triple(), ~"bin", exe_name.clone()]);
     if is_executable(&first_try) {
         first_try
     }
     else {
-        let second_try = test_sysroot().join_many(["bin", "rustpkg"]);
+        let second_try = test_sysroot().join_many([~"bin", exe_name]);
         if is_executable(&second_try) {
             second_try
         }
@@ -492,39 +573,43 @@ fn output_file_name(workspace: &Path, short_name: ~str) -> Path {
                                                                          os::EXE_SUFFIX))
 }
 
-#[cfg(target_os = "linux")]
-fn touch_source_file(workspace: &Path, pkgid: &PkgId) {
-    use conditions::bad_path::cond;
-    let pkg_src_dir = workspace.join_many([~"src", pkgid.to_str()]);
-    let contents = fs::readdir(&pkg_src_dir);
-    for p in contents.iter() {
-        if p.extension_str() == Some("rs") {
-            // should be able to do this w/o a process
-            // FIXME (#9639): This needs to handle non-utf8 paths
-            // n.b. Bumps time up by 2 seconds to get around granularity issues
-            if !run::process_output("touch", [~"--date",
-                                             ~"+2 seconds",
-                                             p.as_str().unwrap().to_owned()]).status.success() {
-                let _ = cond.raise((pkg_src_dir.clone(), ~"Bad path"));
-            }
-        }
+/// Reads the JSON object that rustpkg wrote to `path` (with --report or
+/// --record), failing if it isn't one
+fn read_json_object(path: &Path) -> ~extra::json::Object {
+    use extra::json;
+
+    match json::from_str(str::from_utf8(File::open(path).read_to_end())) {
+        Ok(json::Object(obj)) => obj,
+        Ok(j) => fail!("{} holds {}, not a JSON object", path.display(), j.to_str()),
+        Err(e) => fail!("{} isn't JSON: {}", path.display(), e.to_str())
+    }
+}
+
+/// Returns the `{"path": ..., "digest": ...}` objects listed under `key` in
+/// `obj`, as pairs of a path and a digest
+fn path_digest_list(obj: &extra::json::Object, key: &str) -> ~[(~str, ~str)] {
+    use extra::json;
+
+    match obj.find(&key.to_owned()) {
+        Some(&json::List(ref files)) => files.map(|f| match *f {
+            json::Object(ref file) => match (file.find(&~"path"), file.find(&~"digest")) {
+                (Some(&json::String(ref p)), Some(&json::String(ref d))) => (p.clone(), d.clone()),
+                _ => fail!("bad file in {}: {}", key, f.to_str())
+            },
+            _ => fail!("bad file in {}: {}", key, f.to_str())
+        }),
+        _ => fail!("no list of files in {}", key)
     }
 }
 
-#[cfg(not(target_os = "linux"))]
 fn touch_source_file(workspace: &Path, pkgid: &PkgId) {
-    use conditions::bad_path::cond;
     let pkg_src_dir = workspace.join_many([~"src", pkgid.to_str()]);
     let contents = fs::readdir(&pkg_src_dir);
     for p in contents.iter() {
         if p.extension_str() == Some("rs") {
-            // should be able to do this w/o a process
-            // FIXME (#9639): This needs to handle non-utf8 paths
             // n.b. Bumps time up by 2 seconds to get around granularity issues
-            if !run::process_output("touch", [~"-A02",
-                                             p.as_str().unwrap().to_owned()]).status.success() {
-                let _ = cond.raise((pkg_src_dir.clone(), ~"Bad path"));
-            }
+            let stat = p.stat();
+            fs::change_file_times(p, stat.accessed, stat.modified + 2000);
         }
     }
 }
@@ -633,6 +718,248 @@ fn test_install_invalid() {
             .to_str().contains("supplied path for package dir does not exist"));
 }
 
+/// Records each cache decision it's told about in `decisions`
+struct CacheDecisionRecorder {
+    decisions: RWArc<~[(~str, bool)]>
+}
+
+impl CacheObserver for CacheDecisionRecorder {
+    fn cache_decision(&mut self, key: &str, fresh: bool) {
+        self.decisions.write(|d| d.push((key.to_owned(), fresh)));
+    }
+}
+
+/// Makes `ctxt` record its cache decisions in the vector returned
+fn record_cache_decisions(ctxt: &mut BuildContext) -> RWArc<~[(~str, bool)]> {
+    let decisions = RWArc::new(~[]);
+    let recorder = ~CacheDecisionRecorder { decisions: decisions.clone() };
+    ctxt.cache_callback = Some(Callback::new(recorder as ~CacheObserver:Send));
+    decisions
+}
+
+#[test]
+fn test_workcache_survives_crash() {
+    use api::{new_workcache_context, open_workcache_context};
+
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+
+    // Finish one piece of work, and then die after declaring the inputs of
+    // the next, before it discovers any outputs. The database is saved as
+    // the task unwinds.
+    let ws = workspace.clone();
+    let crashed = do task::try {
+        let cx = new_workcache_context(&ws);
+        cx.with_prep("finished", |prep| {
+            prep.exec(proc(exec) {
+                exec.discover_output("file", "finished.out", "digest");
+                ~"ok"
+            })
+        });
+        cx.with_prep("crashed", |prep| {
+            prep.declare_input("file", "crashed.rs", "digest");
+            let _: ~str = prep.exec(proc(_exec) { fail!("simulated crash") });
+        });
+    };
+    assert!(crashed.is_err());
+
+    // The database still loads, strictly, and has the finished work in it
+    let cx = open_workcache_context(workspace, true);
+    assert!(cx.db.read(|db| !db.discovered_outputs("finished").is_empty()));
+    assert!(cx.db.read(|db| db.discovered_outputs("crashed").is_empty()));
+    assert!(!workspace.join("rustpkg_db.json.tmp").exists());
+}
+
+#[test]
+fn test_cache_callback_reports_hits() {
+    use api::{new_default_context, new_workcache_context};
+
+    let sysroot = test_sysroot();
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let mut ctxt = new_default_context(new_workcache_context(workspace), sysroot);
+    let decisions = record_cache_decisions(&mut ctxt);
+
+    for _ in range(0, 2) {
+        let pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+        ctxt.install(pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    }
+
+    let decisions = decisions.read(|d| d.clone());
+    let installs: ~[bool] = decisions.iter()
+        .filter(|&&(ref key, _)| *key == p_id.install_tag())
+        .map(|&(_, fresh)| fresh)
+        .collect();
+    // The first install does the work; the second is served from the cache
+    assert_eq!(installs, ~[false, true]);
+}
+
+#[test]
+fn test_changing_link_args_only_relinks() {
+    use api::{new_default_context, new_workcache_context};
+
+    let sysroot = test_sysroot();
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let mut ctxt = new_default_context(new_workcache_context(workspace), sysroot);
+    let decisions = record_cache_decisions(&mut ctxt);
+
+    let link_args = [~[], ~[~"-L", workspace.as_str().unwrap().to_owned()]];
+    for args in link_args.iter() {
+        ctxt.context.rustc_flags.link_args = args.clone();
+        let mut pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+        ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    }
+
+    let main_tag = crate_tag(&workspace.join_many([~"src", p_id.to_str(), ~"main.rs"]));
+    let decisions = decisions.read(|d| d.clone());
+    let compiles: ~[bool] = decisions.iter()
+        .filter(|&&(ref key, _)| *key == main_tag)
+        .map(|&(_, fresh)| fresh)
+        .collect();
+    let links: ~[bool] = decisions.iter()
+        .filter(|&&(ref key, _)| key.starts_with(format!("{} linked by ", main_tag)))
+        .map(|&(_, fresh)| fresh)
+        .collect();
+    // The second build is served from the cache, but links main.rs again
+    assert_eq!(compiles, ~[false, true]);
+    assert_eq!(links, ~[false, false]);
+    assert_built_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_install_pkg_result() {
+    use api::{install_pkg, new_default_context, new_workcache_context};
+    use path_util::installed_library_in_workspace;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let ctxt = new_default_context(new_workcache_context(workspace), test_sysroot());
+
+    let result = match install_pkg(&ctxt, workspace.clone(), ~"foo", NoVersion, ~[]) {
+        Built(result) => result,
+        Canceled => fail!("test_install_pkg_result: the install was canceled without a CancelFlag")
+    };
+    assert_eq!(result.pkg_id.short_name, ~"foo");
+    assert_eq!(&result.destination_workspace, workspace);
+    // The executable and the library, and nothing else
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let lib = installed_library_in_workspace(&p_id.path, workspace)
+        .expect("test_install_pkg_result: library wasn't installed");
+    assert_eq!(result.installed_files.len(), 2);
+    assert!(result.installed_files.contains(&exec));
+    assert!(result.installed_files.contains(&lib));
+    for f in result.installed_files.iter() {
+        assert!(f.exists());
+    }
+    // Each of the package's crates is an input
+    let src_dir = workspace.join_many(["src", "foo-0.1"]);
+    for f in ["lib.rs", "main.rs", "test.rs", "bench.rs"].iter() {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let path = src_dir.join(*f).as_str().unwrap().to_owned();
+        assert!(result.declared_inputs.contains(&(~"file", path)));
+    }
+}
+
+/// Records each error it's given in `errors`
+struct CapturingEmitter {
+    errors: RWArc<~[~str]>
+}
+
+impl diagnostic::Emitter for CapturingEmitter {
+    fn emit(&self,
+            _cmsp: Option<(@codemap::CodeMap, codemap::Span)>,
+            msg: &str,
+            lvl: diagnostic::level) {
+        match lvl {
+            diagnostic::fatal | diagnostic::error => {
+                self.errors.write(|e| e.push(msg.to_owned()));
+            }
+            diagnostic::warning | diagnostic::note => ()
+        }
+    }
+}
+
+/// Makes `CapturingEmitter`s that all record errors in `errors`
+struct CapturingEmitterMaker {
+    errors: RWArc<~[~str]>
+}
+
+impl EmitterMaker for CapturingEmitterMaker {
+    fn make_emitter(&mut self) -> @diagnostic::Emitter {
+        @CapturingEmitter { errors: self.errors.clone() } as @diagnostic::Emitter
+    }
+}
+
+#[test]
+fn test_custom_emitter_captures_errors() {
+    use api::{new_default_context, new_workcache_context};
+
+    let sysroot = test_sysroot();
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "lib.rs"]),
+              "pub fn f() -> int { \"not an int\" }");
+    let mut ctxt = new_default_context(new_workcache_context(workspace), sysroot);
+    let errors = RWArc::new(~[]);
+    let maker = ~CapturingEmitterMaker { errors: errors.clone() };
+    ctxt.emitter = Some(Callback::new(maker as ~EmitterMaker:Send));
+
+    // Uses task::try because the build fails
+    let ws = workspace.clone();
+    let result = do task::try {
+        let pkg_src = PkgSrc::new(ws.clone(), ws.clone(), false, p_id.clone());
+        ctxt.install(pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    };
+    assert!(result.is_err());
+
+    let errors = errors.read(|e| e.clone());
+    debug!("Captured errors: {:?}", errors);
+    assert!(errors.iter().any(|e| e.contains("mismatched types")));
+}
+
+/// Cancels `build` when it makes the emitter for the first crate it
+/// compiles, and otherwise makes the default emitter
+struct CancelingEmitterMaker {
+    build: CancelFlag
+}
+
+impl EmitterMaker for CancelingEmitterMaker {
+    fn make_emitter(&mut self) -> @diagnostic::Emitter {
+        self.build.cancel();
+        default_emitter()
+    }
+}
+
+#[test]
+fn test_cancel_build() {
+    use api::{new_default_context, new_workcache_context};
+
+    let sysroot = test_sysroot();
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let flag = CancelFlag::new();
+    let mut ctxt = new_default_context(new_workcache_context(workspace), sysroot);
+    ctxt.cancel = Some(flag.clone());
+    let maker = ~CancelingEmitterMaker { build: flag.clone() };
+    ctxt.emitter = Some(Callback::new(maker as ~EmitterMaker:Send));
+
+    let mut pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+    let result = ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    assert!(result == Canceled);
+    // The library was already being compiled when the flag was set, so it
+    // was finished, but nothing was compiled after it
+    assert!(flag.is_canceled());
+    assert_built_library_exists(workspace, "foo");
+    assert!(!built_executable_exists(workspace, "foo"));
+}
+
 #[test]
 fn test_install_valid_external() {
     let temp_pkg_id = PkgId::new("foo");
@@ -987,16 +1314,6 @@ fn rust_path_contents() {
     }
 }
 
-#[test]
-fn rust_path_parse() {
-    os::setenv("RUST_PATH", "/a/b/c:/d/e/f:/g/h/i");
-    let paths = rust_path();
-    assert!(paths.contains(&Path::new("/g/h/i")));
-    assert!(paths.contains(&Path::new("/d/e/f")));
-    assert!(paths.contains(&Path::new("/a/b/c")));
-    os::unsetenv("RUST_PATH");
-}
-
 #[test]
 fn test_list() {
     let dir = TempDir::new("test_list").expect("test_list failed");
@@ -1053,6 +1370,154 @@ fn install_remove() {
     assert!(list_output.iter().any(|x| x.starts_with("quux")));
 }
 
+#[test]
+fn test_which() {
+    let p_id = PkgId::new("foo");
+    let a = create_local_package(&PkgId::new("foo#0.1"));
+    let b = create_local_package(&PkgId::new("foo#0.2"));
+    let (a, b) = (a.path(), b.path());
+    // Both installs are recorded in the first workspace's workcache
+    let env = ~[(~"RUST_PATH", rust_path_value([a.clone(), b.clone()]))];
+    for &(ref id, ws) in [(~"foo#0.1", a), (~"foo#0.2", b)].iter() {
+        match command_line_test_with_env([~"install", id.clone()], ws, Some(env.clone())) {
+            Success(_) => (),
+            Fail(_) => fail!("Couldn't install {}", *id)
+        }
+    }
+    let a_exe = target_executable_in_workspace(&p_id, a).display().to_str();
+    let b_exe = target_executable_in_workspace(&p_id, b).display().to_str();
+
+    // The latest version is preferred, even though it's in the second workspace
+    let output = command_line_test_output_with_env([~"which", ~"foo"], env.clone());
+    assert!(output.iter().any(|l| *l == b_exe));
+    assert!(!output.iter().any(|l| *l == a_exe));
+
+    let output = command_line_test_output_with_env([~"which", ~"foo#0.1"], env.clone());
+    assert!(output.iter().any(|l| *l == a_exe));
+    assert!(!output.iter().any(|l| *l == b_exe));
+
+    let output = command_line_test_output_with_env([~"which", ~"--all", ~"foo"], env.clone());
+    let a_line = output.iter().position(|l| *l == a_exe).expect("no line for foo#0.1");
+    let b_line = output.iter().position(|l| *l == b_exe).expect("no line for foo#0.2");
+    assert!(a_line < b_line);
+
+    command_line_test_expect_fail([~"which", ~"foo#0.3"], &os::getcwd(), Some(env.clone()),
+                                  NONEXISTENT_PACKAGE_CODE);
+    command_line_test_expect_fail([~"which", ~"bar"], &os::getcwd(), Some(env),
+                                  NONEXISTENT_PACKAGE_CODE);
+}
+
+#[test]
+fn test_list_status() {
+    use extra::json;
+
+    let dir = TempDir::new("test_list_status").expect("test_list_status failed");
+    let dir = dir.path();
+    let foo = PkgId::new("foo");
+    create_local_package_in(&foo, dir);
+    command_line_test([~"install", ~"foo"], dir);
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env_arg = ~[(~"RUST_PATH", dir.as_str().unwrap().to_owned())];
+    let list_output = command_line_test_output_with_env([~"list"], env_arg.clone());
+    assert!(list_output.iter().any(|x| *x == ~"foo (ok)"));
+
+    // Break the install by removing its library
+    let lib = installed_library_in_workspace(&foo.path, dir).expect("no library");
+    fs::unlink(&lib);
+    let list_output = command_line_test_output_with_env([~"list"], env_arg.clone());
+    assert!(list_output.iter().any(|x| *x == ~"foo (missing-lib)"));
+    assert!(!list_output.iter().any(|x| *x == ~"foo (ok)"));
+
+    let output = command_line_test_output_with_env([~"list", ~"--json"], env_arg).connect("\n");
+    let obj = match json::from_str(output) {
+        Ok(json::Object(obj)) => obj,
+        _ => fail!("test_list_status: expected a JSON object, got {}", output)
+    };
+    let packages = match obj.find(&~"packages") {
+        Some(&json::List(ref ps)) => ps.clone(),
+        _ => fail!("test_list_status: no list of packages")
+    };
+    let mut expected = ~TreeMap::new();
+    expected.insert(~"path", json::String(~"foo"));
+    expected.insert(~"workspace", json::String(dir.display().to_str()));
+    expected.insert(~"status", json::String(~"missing-lib"));
+    assert!(packages.contains(&json::Object(expected)));
+}
+
+#[test]
+fn test_env() {
+    use extra::json;
+
+    let dir = TempDir::new("test_env").expect("test_env failed");
+    let dir = dir.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env_arg = ~[(~"RUST_PATH", dir.as_str().unwrap().to_owned())];
+    let output = command_line_test_output_with_env([~"env", ~"--cfg", ~"quux",
+                                                    ~"--opt-level", ~"3"], env_arg.clone());
+    let default_ws = format!("DEFAULT_WORKSPACE={}", dir.display());
+    assert!(output.iter().any(|x| *x == default_ws));
+    assert!(output.iter().any(|x| x.starts_with(format!("RUST_PATH={}", dir.display()))));
+    let db = format!("WORKCACHE_DB={}", dir.join("rustpkg_db.json").display());
+    assert!(output.iter().any(|x| *x == db));
+    assert!(output.iter().any(|x| *x == ~"CFGS=quux"));
+    assert!(output.iter().any(|x| x.starts_with("RUSTC_FLAGS=--opt-level 3")));
+    assert!(output.iter().any(|x| x.starts_with("SYSROOT=")));
+
+    let output = command_line_test_output_with_env([~"env", ~"--json"], env_arg).connect("\n");
+    let obj = match json::from_str(output) {
+        Ok(json::Object(obj)) => obj,
+        _ => fail!("test_env: expected a JSON object, got {}", output)
+    };
+    assert_eq!(obj.find(&~"default_workspace"),
+               Some(&json::String(dir.display().to_str())));
+    assert_eq!(obj.find(&~"cfgs"), Some(&json::List(~[])));
+    assert_eq!(obj.find(&~"profile"), Some(&json::Null));
+    assert_eq!(obj.find(&~"strict_cache"), Some(&json::Boolean(false)));
+}
+
+#[test]
+fn test_env_overrides() {
+    use extra::json;
+
+    let dir = TempDir::new("test_env_overrides").expect("test_env_overrides failed");
+    let dir = dir.path();
+    let tmp = dir.join("tmp");
+    let root = dir.join("root");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env_arg = ~[(~"RUST_PATH", dir.as_str().unwrap().to_owned()),
+                    (~"TMPDIR", tmp.as_str().unwrap().to_owned())];
+    let output = command_line_test_output_with_env([~"env"], env_arg.clone());
+    // TMPDIR is where scratch directories go without --tmp-dir
+    assert!(output.iter().any(|x| *x == format!("TMP_DIR={}", tmp.display())));
+    assert!(output.iter().any(|x| *x == ~"PROFILE="));
+    assert!(output.iter().any(|x| *x == ~"DIGEST=sha1"));
+    assert!(output.iter().any(|x| *x == ~"AMBIGUOUS=error"));
+
+    let flags = ~[~"env", ~"--profile", ~"release", ~"--digest", ~"sha256", ~"--jobs", ~"3",
+                  ~"--tmp-dir", dir.join("other-tmp").as_str().unwrap().to_owned(),
+                  ~"--root", root.as_str().unwrap().to_owned(), ~"--ambiguous", ~"first",
+                  ~"--strict-cache", ~"--variant", ~"ci"];
+    let output = command_line_test_output_with_env(flags, env_arg.clone());
+    for expected in [~"PROFILE=release", ~"DIGEST=sha256", ~"JOBS=3",
+                     format!("TMP_DIR={}", dir.join("other-tmp").display()),
+                     format!("INSTALL_ROOT={}", root.display()), ~"AMBIGUOUS=first",
+                     ~"STRICT_CACHE=true", ~"VARIANT=ci"].iter() {
+        assert!(output.iter().any(|x| x == expected),
+                "test_env_overrides: expected {} in {:?}", *expected, output);
+    }
+
+    let output = command_line_test_output_with_env(flags + ~[~"--json"], env_arg).connect("\n");
+    let obj = match json::from_str(output) {
+        Ok(json::Object(obj)) => obj,
+        _ => fail!("test_env_overrides: expected a JSON object, got {}", output)
+    };
+    assert_eq!(obj.find(&~"profile"), Some(&json::String(~"release")));
+    assert_eq!(obj.find(&~"jobs"), Some(&json::Number(3.0)));
+    assert_eq!(obj.find(&~"install_root"), Some(&json::String(root.display().to_str())));
+    assert_eq!(obj.find(&~"strict_cache"), Some(&json::Boolean(true)));
+    assert_eq!(obj.find(&~"variant"), Some(&json::String(~"ci")));
+}
+
 #[test]
 fn install_check_duplicates() {
     // should check that we don't install two packages with the same full name *and* version
@@ -1078,6 +1543,74 @@ fn install_check_duplicates() {
     list_installed_packages(check_dups);
 }
 
+/// Makes a workspace that looks like `n` packages, named `pkg0` and so on,
+/// are installed in it, each with an executable and a library
+fn mk_installed_workspace(n: uint) -> TempDir {
+    let workspace = mk_emptier_workspace("installed_index");
+    let bin = workspace.path().join("bin");
+    let lib = workspace.path().join_many([~"lib", host_triple()]);
+    fs::mkdir_recursive(&bin, io::UserRWX);
+    fs::mkdir_recursive(&lib, io::UserRWX);
+    for i in range(0, n) {
+        writeFile(&bin.join(format!("pkg{}", i)), "");
+        writeFile(&lib.join(os::dll_filename(format!("pkg{}-abcdef-0.1", i))), "");
+    }
+    workspace
+}
+
+#[test]
+fn test_installed_index_invalidation() {
+    let workspace = mk_installed_workspace(2);
+    let workspaces = ~[workspace.path().clone()];
+    let index = InstalledIndex::new(workspaces, true);
+    assert!(index.is_installed(&PkgId::new("pkg0")));
+    assert!(index.is_installed(&PkgId::new("pkg1")));
+    assert!(!index.is_installed(&PkgId::new("pkg2")));
+    let saved = index_file(workspace.path());
+    assert!(saved.exists());
+
+    // While nothing has changed, the saved index is used as it is
+    let contents = str::from_utf8_owned(File::open(&saved).read_to_end());
+    writeFile(&saved, contents.replace("\"pkg1\"", "\"pkg2\"").as_slice());
+    let index = InstalledIndex::new(workspaces, true);
+    assert!(index.is_installed(&PkgId::new("pkg2")));
+    assert!(!index.is_installed(&PkgId::new("pkg1")));
+
+    // Installing something else modifies `bin`, so the workspace is scanned
+    // again. Wait first, in case the file system only keeps whole seconds.
+    timer::sleep(1100);
+    writeFile(&workspace.path().join_many(["bin", "pkg3"]), "");
+    let index = InstalledIndex::new(workspaces, true);
+    assert!(index.is_installed(&PkgId::new("pkg1")));
+    assert!(index.is_installed(&PkgId::new("pkg3")));
+    assert!(!index.is_installed(&PkgId::new("pkg2")));
+    assert_eq!(index.workspaces_for(&PkgId::new("pkg3")), workspaces);
+
+    // Without persistence, the saved index is never used
+    writeFile(&saved, contents.replace("\"pkg1\"", "\"pkg2\"").as_slice());
+    assert!(!InstalledIndex::new(workspaces, false).is_installed(&PkgId::new("pkg2")));
+}
+
+/// Many workspaces with many packages installed in each, and nothing else
+fn mk_many_installed_workspaces() -> (~[TempDir], ~[Path]) {
+    let dirs = vec::from_fn(50, |_| mk_installed_workspace(40));
+    let paths = dirs.map(|d| d.path().clone());
+    (dirs, paths)
+}
+
+#[bench]
+fn bench_installed_index_scan(bh: &mut BenchHarness) {
+    let (_dirs, workspaces) = mk_many_installed_workspaces();
+    bh.iter(|| { InstalledIndex::new(workspaces, false); });
+}
+
+#[bench]
+fn bench_installed_index_saved(bh: &mut BenchHarness) {
+    let (_dirs, workspaces) = mk_many_installed_workspaces();
+    InstalledIndex::new(workspaces, true);
+    bh.iter(|| { InstalledIndex::new(workspaces, true); });
+}
+
 #[test]
 fn no_rebuilding() {
     let p_id = PkgId::new("foo");
@@ -1118,72 +1651,1196 @@ fn no_recopying() {
 }
 
 #[test]
-fn no_rebuilding_dep() {
-    let p_id = PkgId::new("foo");
-    let dep_id = PkgId::new("bar");
-    let workspace = create_local_package_with_dep(&p_id, &dep_id);
-    let workspace = workspace.path();
-    command_line_test([~"build", ~"foo"], workspace);
-    let bar_lib = lib_output_file_name(workspace, "bar");
-    frob_source_file(workspace, &p_id, "main.rs");
-    // Now make `bar` read-only so that subsequent rebuilds of it will fail
-    assert!(chmod_read_only(&bar_lib));
-    match command_line_test_partial([~"build", ~"foo"], workspace) {
-        Success(*) => (), // ok
-        Fail(ref r) if r.status.matches_exit_status(65) =>
-            fail!("no_rebuilding_dep failed: it tried to rebuild bar"),
-        Fail(_) => fail!("no_rebuilding_dep failed for some other reason")
+fn interrupted_install_keeps_old_artifact() {
+    use util::{install_file, stage_install};
+
+    let dir = TempDir::new("interrupted_install").expect("interrupted_install failed");
+    let dir = dir.path();
+    let built = dir.join("foo-built");
+    let installed = dir.join("foo");
+    writeFile(&built, "new");
+    writeFile(&installed, "old");
+
+    // Copy, then fail before the copy gets renamed into place
+    let (sub_built, sub_installed) = (built.clone(), installed.clone());
+    let result = do task::try {
+        stage_install(&sub_built, &sub_installed);
+        fail!("interrupted")
+    };
+    assert!(result.is_err());
+    assert_eq!(File::open(&installed).read_to_end(), "old".as_bytes().to_owned());
+
+    install_file(&built, &installed);
+    assert_eq!(File::open(&installed).read_to_end(), "new".as_bytes().to_owned());
+    assert!(built.exists());
+
+    // Each staged copy gets its own name, and a finished install leaves
+    // none behind (unlike the interrupted one)
+    let staged_files = || -> ~[Path] {
+        fs::readdir(dir).move_iter()
+            .filter(|p| p.filename_str().map_default(false, |s| s.ends_with(".rustpkg-tmp")))
+            .collect()
+    };
+    let before = staged_files();
+    let staged = [stage_install(&built, &installed), stage_install(&built, &installed)];
+    assert!(staged[0] != staged[1]);
+    for p in staged.iter() {
+        fs::unlink(p);
+    }
+    install_file(&built, &installed);
+    assert_eq!(staged_files(), before);
+}
+
+/// Records the progress of each copy it's told about in `events`
+struct CopyRecorder {
+    events: RWArc<~[(~str, u64, u64)]>
+}
+
+impl CopyObserver for CopyRecorder {
+    fn copied(&mut self, dest: &str, copied: u64, total: u64) {
+        self.events.write(|e| e.push((dest.to_owned(), copied, total)));
     }
 }
 
 #[test]
-fn do_rebuild_dep_dates_change() {
+fn install_reports_copy_progress() {
+    use util::install_file_reporting;
+
+    let dir = TempDir::new("copy_progress").expect("copy_progress failed");
+    let dir = dir.path();
+    let built = dir.join("big-built");
+    let installed = dir.join("big");
+    // A few chunks' worth, and then some
+    let contents = vec::from_elem(200 * 1024 + 17, 'x' as u8);
+    File::create(&built).write(contents);
+
+    let events = RWArc::new(~[]);
+    let recorder = ~CopyRecorder { events: events.clone() };
+    install_file_reporting(&built, &installed,
+                           &Some(Callback::new(recorder as ~CopyObserver:Send)));
+    assert_eq!(File::open(&installed).read_to_end(), contents);
+
+    let events = events.read(|e| e.clone());
+    let total = contents.len() as u64;
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    assert!(events.iter().all(|&(ref name, _, t)| name.as_slice() == installed.as_str().unwrap()
+                                                 && t == total));
+    assert!(events.len() > 2);
+    assert_eq!(events[0].second(), 0);
+    assert_eq!(events[events.len() - 1].second(), total);
+    for i in range(1, events.len()) {
+        assert!(events[i - 1].second() <= events[i].second());
+    }
+}
+
+#[test]
+fn install_skips_unchanged_artifacts() {
+    use util::install_file_if_changed;
+
     let p_id = PkgId::new("foo");
-    let dep_id = PkgId::new("bar");
-    let workspace = create_local_package_with_dep(&p_id, &dep_id);
+    let workspace = create_local_package(&p_id);
     let workspace = workspace.path();
-    command_line_test([~"build", ~"foo"], workspace);
-    let bar_lib_name = lib_output_file_name(workspace, "bar");
-    touch_source_file(workspace, &dep_id);
+    command_line_test([~"install", ~"foo"], workspace);
+    let installed = target_executable_in_workspace(&p_id, workspace);
+    let mtime = installed.stat().modified;
 
-    // Now make `bar` read-only so that subsequent rebuilds of it will fail
-    assert!(chmod_read_only(&bar_lib_name));
+    command_line_test([~"install", ~"foo"], workspace);
+    assert_eq!(installed.stat().modified, mtime);
 
-    match command_line_test_partial([~"build", ~"foo"], workspace) {
-        Success(*) => fail!("do_rebuild_dep_dates_change failed: it didn't rebuild bar"),
-        Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
-        Fail(_) => fail!("do_rebuild_dep_dates_change failed for some other reason")
+    // Even when the workcache doesn't catch it, the copy itself is skipped
+    let built = built_executable_in_workspace(&p_id, workspace)
+        .expect("install_skips_unchanged_artifacts: no built executable");
+    assert!(!install_file_if_changed(&built, &installed, &None, Sha1Digest));
+    assert_eq!(installed.stat().modified, mtime);
+}
+
+#[test]
+fn test_install_root() {
+    use api::new_workcache_context;
+    use path_util::path_under_root;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let stage = TempDir::new("test_install_root").expect("test_install_root failed");
+    let stage = stage.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([~"install", ~"--root", stage.as_str().unwrap().to_owned(), ~"foo"],
+                      workspace);
+
+    let staged = path_under_root(stage, &target_executable_in_workspace(&p_id, workspace));
+    assert!(stage.is_ancestor_of(&staged));
+    assert!(staged.exists());
+    assert!(!executable_exists(workspace, "foo"));
+    assert!(installed_library_in_workspace(&p_id.path, workspace).is_none());
+
+    // The workcache records where the files will be once the stage is
+    // unpacked, with the digests of the staged files
+    let install_tag = format!("{} under {}", p_id.install_tag(), stage.display());
+    let outputs = new_workcache_context(workspace).db.read(|db| {
+        db.discovered_outputs(install_tag)
+    });
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    assert!(outputs.iter().all(|&(_, ref name, _)| {
+        !stage.is_ancestor_of(&Path::new(name.as_slice()))
+    }));
+    match outputs.iter().find(|&&(_, ref name, _)| name.as_slice() == exec.as_str().unwrap()) {
+        Some(&(_, _, ref digest)) => assert!(date_digest_matches(&staged, *digest)),
+        None => fail!("test_install_root: {} wasn't recorded", exec.display())
     }
 }
 
 #[test]
-fn do_rebuild_dep_only_contents_change() {
+fn test_deny_warnings() {
     let p_id = PkgId::new("foo");
-    let dep_id = PkgId::new("bar");
-    let workspace = create_local_package_with_dep(&p_id, &dep_id);
+    let workspace = create_local_package(&p_id);
     let workspace = workspace.path();
-    command_line_test([~"build", ~"foo"], workspace);
-    frob_source_file(workspace, &dep_id, "lib.rs");
-    let bar_lib_name = lib_output_file_name(workspace, "bar");
+    let main_file = workspace.join_many(["src", "foo-0.1", "main.rs"]);
+    // `x` is unused, so rustc warns about it
+    writeFile(&main_file, "fn main() { let x = 1; }");
 
-    // Now make `bar` read-only so that subsequent rebuilds of it will fail
-    assert!(chmod_read_only(&bar_lib_name));
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "foo");
 
-    // should adjust the datestamp
-    match command_line_test_partial([~"build", ~"foo"], workspace) {
-        Success(*) => fail!("do_rebuild_dep_only_contents_change failed: it didn't rebuild bar"),
-        Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
-        Fail(_) => fail!("do_rebuild_dep_only_contents_change failed for some other reason")
+    match command_line_test_partial([~"build", ~"--deny-warnings", ~"foo"], workspace) {
+        Success(*) => fail!("test_deny_warnings: expected the build to fail"),
+        Fail(ref r) => {
+            let output = str::from_utf8(r.output);
+            assert!(output.contains("Couldn't build"));
+            assert!(output.contains(main_file.display().to_str()));
+        }
     }
 }
 
 #[test]
-fn test_versions() {
-    let workspace = create_local_package(&PkgId::new("foo#0.1"));
-    let _other_workspace = create_local_package(&PkgId::new("foo#0.2"));
-    command_line_test([~"install", ~"foo#0.1"], workspace.path());
-    let output = command_line_test_output([~"list"]);
-    // make sure output includes versions
+fn test_build_out_dir() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let out_dir = TempDir::new("test_build_out_dir").expect("test_build_out_dir failed");
+    let out_dir = out_dir.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let args = [~"build", ~"--out-dir", out_dir.as_str().unwrap().to_owned(), ~"foo"];
+    command_line_test(args, workspace);
+
+    let exe = out_dir.join(format!("foo{}", os::EXE_SUFFIX));
+    assert!(exe.exists());
+    assert!(built_executable_in_workspace(&p_id, workspace).is_none());
+    assert!(built_library_in_workspace(&p_id, workspace).is_none());
+
+    // Building again into the same directory is a cache hit
+    let mtime = exe.stat().modified;
+    command_line_test(args, workspace);
+    assert_eq!(exe.stat().modified, mtime);
+}
+
+#[test]
+fn test_run_example() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let examples = workspace.join_many(["src", "foo-0.1", "examples"]);
+    fs::mkdir_recursive(&examples, io::UserRWX);
+    writeFile(&examples.join("echo.rs"),
+              "use std::os;
+               fn main() {
+                   for arg in os::args().slice_from(1).iter() {
+                       println!(\"arg: {}\", *arg);
+                   }
+               }");
+    let output = command_line_test([~"run-example", ~"foo", ~"echo", ~"hello", ~"world"],
+                                   workspace);
+    let output = str::from_utf8(output.output);
+    let lines: ~[&str] = output.lines().collect();
+    assert!(lines.contains(&"arg: hello"));
+    assert!(lines.contains(&"arg: world"));
+
+    // The example is built under its own name, apart from the package's
+    // executables, and isn't installed
+    let echo_id = PkgId { short_name: ~"echo", ..p_id.clone() };
+    assert!(built_example_in_profile(&p_id, "echo", workspace, None).is_some());
+    assert!(built_executable_in_workspace(&echo_id, workspace).is_none());
+    command_line_test([~"install", ~"foo"], workspace);
+    assert!(target_executable_in_workspace(&p_id, workspace).exists());
+    assert!(!target_executable_in_workspace(&echo_id, workspace).exists());
+
+    command_line_test_expect_fail([~"run-example", ~"foo", ~"nonesuch"], workspace, None,
+                                  NONEXISTENT_PACKAGE_CODE);
+}
+
+#[test]
+fn test_reinstall() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"foo"], workspace);
+
+    // Clobber the installed executable without changing its modification
+    // time, so that the workcache's record of it is stale but looks fresh
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let stat = exec.stat();
+    File::create(&exec).write(bytes!("stale"));
+    fs::change_file_times(&exec, stat.accessed, stat.modified);
+
+    command_line_test([~"install", ~"foo"], workspace);
+    assert_eq!(File::open(&exec).read_to_end(), "stale".as_bytes().to_owned());
+
+    let output = command_line_test([~"reinstall", ~"foo"], workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.contains(format!("Reinstalling package {}: cleaning it first",
+                                    p_id.to_str())));
+    assert!(output.contains(format!("Reinstalling package {}: installing it", p_id.to_str())));
+    assert!(File::open(&exec).read_to_end() != "stale".as_bytes().to_owned());
+    assert_executable_exists(workspace, "foo");
+    command_line_test([~"verify", ~"foo"], workspace);
+}
+
+#[test]
+fn test_corrupt_workcache() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env = Some(~[(~"RUST_PATH", workspace.as_str().unwrap().to_owned())]);
+    command_line_test_with_env([~"build", ~"foo"], workspace, env.clone());
+
+    // Clobber the built executable without changing its modification time,
+    // so that only a cache miss will replace it
+    let exec = built_executable_in_workspace(&p_id, workspace).expect("foo wasn't built");
+    let stat = exec.stat();
+    File::create(&exec).write(bytes!("stale"));
+    fs::change_file_times(&exec, stat.accessed, stat.modified);
+
+    let db = workspace.join("rustpkg_db.json");
+    assert!(db.exists());
+    File::create(&db).write(bytes!("{\"build(foo"));
+    command_line_test_expect_fail([~"build", ~"--strict-cache", ~"foo"], workspace, env.clone(),
+                                  CORRUPT_WORKCACHE_CODE);
+
+    let output = match command_line_test_with_env([~"build", ~"foo"], workspace, env) {
+        Success(output) => output,
+        Fail(error) => fail!("build with a corrupt workcache failed: {}", error.status)
+    };
+    assert!(str::from_utf8(output.output).contains("discarding it"));
+    assert!(File::open(&exec).read_to_end() != "stale".as_bytes().to_owned());
+    assert_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_emit_deps() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"--emit-deps", ~"foo"], workspace);
+
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    let build_dir = target_build_dir(workspace).join("foo");
+    let exec = built_executable_in_workspace(&p_id, workspace)
+        .expect("test_emit_deps: no executable was built");
+    let main_deps = str::from_utf8_owned(File::open(&build_dir.join("main.d")).read_to_end());
+    assert_eq!(main_deps, format!("{}: {}\n", exec.display(), pkg_dir.join("main.rs").display()));
+
+    let lib = built_library_in_workspace(&p_id, workspace)
+        .expect("test_emit_deps: no library was built");
+    let lib_deps = str::from_utf8_owned(File::open(&build_dir.join("lib.d")).read_to_end());
+    assert_eq!(lib_deps, format!("{}: {}\n", lib.display(), pkg_dir.join("lib.rs").display()));
+}
+
+#[test]
+fn test_concurrent_installs() {
+    use std::comm::SharedChan;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env = ~[(~"RUST_PATH", workspace.as_str().unwrap().to_owned())];
+
+    let (port, chan) = stream();
+    let chan = SharedChan::new(chan);
+    for _ in range(0, 2) {
+        let (chan, workspace, env) = (chan.clone(), workspace.clone(), env.clone());
+        do task::spawn {
+            chan.send(match command_line_test_with_env([~"install", ~"foo"],
+                                                       &workspace, Some(env)) {
+                Success(*) => true,
+                Fail(*) => false
+            });
+        }
+    }
+    assert!(port.recv());
+    assert!(port.recv());
+    assert_executable_exists(workspace, "foo");
+    assert!(lock::lock_file(workspace).exists());
+    // The workcache database is still readable, and up to date
+    match command_line_test_with_env([~"install", ~"foo"], workspace, Some(env)) {
+        Success(*) => (),
+        Fail(*) => fail!("test_concurrent_installs: installing a third time failed")
+    }
+}
+
+#[test]
+fn test_no_wait() {
+    use lock::WorkspaceLock;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env = ~[(~"RUST_PATH", workspace.as_str().unwrap().to_owned())];
+
+    {
+        let _lock = WorkspaceLock::acquire(workspace, false)
+            .expect("test_no_wait: the workspace is already locked");
+        command_line_test_expect_fail([~"install", ~"--no-wait", ~"foo"], workspace,
+                                      Some(env.clone()), WORKSPACE_LOCKED_CODE);
+        assert!(!executable_exists(workspace, "foo"));
+        // Read-only commands don't need the lock
+        match command_line_test_with_env([~"list"], workspace, Some(env.clone())) {
+            Success(*) => (),
+            Fail(*) => fail!("test_no_wait: list shouldn't wait for the lock")
+        }
+    }
+
+    // Once the lock is released, installing works
+    match command_line_test_with_env([~"install", ~"--no-wait", ~"foo"], workspace, Some(env)) {
+        Success(*) => (),
+        Fail(*) => fail!("test_no_wait: install failed after the lock was released")
+    }
+    assert_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn no_rebuilding_dep() {
+    let p_id = PkgId::new("foo");
+    let dep_id = PkgId::new("bar");
+    let workspace = create_local_package_with_dep(&p_id, &dep_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    let bar_lib = lib_output_file_name(workspace, "bar");
+    frob_source_file(workspace, &p_id, "main.rs");
+    // Now make `bar` read-only so that subsequent rebuilds of it will fail
+    assert!(chmod_read_only(&bar_lib));
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => (), // ok
+        Fail(ref r) if r.status.matches_exit_status(65) =>
+            fail!("no_rebuilding_dep failed: it tried to rebuild bar"),
+        Fail(_) => fail!("no_rebuilding_dep failed for some other reason")
+    }
+}
+
+#[test]
+fn do_rebuild_dep_dates_change() {
+    let p_id = PkgId::new("foo");
+    let dep_id = PkgId::new("bar");
+    let workspace = create_local_package_with_dep(&p_id, &dep_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    let bar_lib_name = lib_output_file_name(workspace, "bar");
+    touch_source_file(workspace, &dep_id);
+
+    // Now make `bar` read-only so that subsequent rebuilds of it will fail
+    assert!(chmod_read_only(&bar_lib_name));
+
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("do_rebuild_dep_dates_change failed: it didn't rebuild bar"),
+        Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
+        Fail(_) => fail!("do_rebuild_dep_dates_change failed for some other reason")
+    }
+}
+
+#[test]
+fn do_rebuild_dep_only_contents_change() {
+    let p_id = PkgId::new("foo");
+    let dep_id = PkgId::new("bar");
+    let workspace = create_local_package_with_dep(&p_id, &dep_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    frob_source_file(workspace, &dep_id, "lib.rs");
+    let bar_lib_name = lib_output_file_name(workspace, "bar");
+
+    // Now make `bar` read-only so that subsequent rebuilds of it will fail
+    assert!(chmod_read_only(&bar_lib_name));
+
+    // should adjust the datestamp
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("do_rebuild_dep_only_contents_change failed: it didn't rebuild bar"),
+        Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
+        Fail(_) => fail!("do_rebuild_dep_only_contents_change failed for some other reason")
+    }
+}
+
+#[test]
+fn test_default_workspace_creation_noted_once() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let home = TempDir::new("default_workspace_home").expect("couldn't create temp dir");
+    let default_ws = home.path().join(".rust");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env = Some(~[(~"RUST_PATH", default_ws.as_str().unwrap().to_owned()),
+                     (~"HOME", home.path().as_str().unwrap().to_owned())]);
+    let count_notes = |output: &ProcessOutput| {
+        str::from_utf8(output.output).split_str("Creating the default workspace").count() - 1
+    };
+
+    let output = match command_line_test_with_env([~"install", ~"foo"], workspace, env.clone()) {
+        Success(r) => r,
+        Fail(_) => fail!("test_default_workspace_creation_noted_once: install failed")
+    };
+    assert_eq!(count_notes(&output), 1);
+    assert!(default_ws.join("src").is_dir());
+
+    // The workspace exists now, so it shouldn't be mentioned again
+    let output = match command_line_test_with_env([~"install", ~"foo"], workspace, env) {
+        Success(r) => r,
+        Fail(_) => fail!("test_default_workspace_creation_noted_once: install failed")
+    };
+    assert_eq!(count_notes(&output), 0);
+}
+
+#[test]
+fn test_cfg_gated_crate() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    fs::unlink(&pkg_dir.join("main.rs"));
+    let gated_dir = pkg_dir.join("cfg-frobnicate");
+    fs::mkdir_recursive(&gated_dir, io::UserRWX);
+    writeFile(&gated_dir.join("main.rs"), "fn main() {}");
+
+    // Without the cfg, the executable isn't built
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_library_exists(workspace, "foo");
+    assert!(!built_executable_exists(workspace, "foo"));
+
+    // With it, it is
+    command_line_test([~"build", ~"--cfg", ~"frobnicate", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_host_cfg_gated_crate() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    fs::unlink(&pkg_dir.join("main.rs"));
+    // The tests don't run on Windows, so `unix` is always a host default
+    let unix_dir = pkg_dir.join("cfg-unix");
+    fs::mkdir_recursive(&unix_dir, io::UserRWX);
+    writeFile(&unix_dir.join("main.rs"), "fn main() {}");
+    let windows_dir = pkg_dir.join("cfg-windows");
+    fs::mkdir_recursive(&windows_dir, io::UserRWX);
+    writeFile(&windows_dir.join("main.rs"), "this isn't even valid Rust");
+
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_targets() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let output = command_line_test([~"targets", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    let lines: ~[&str] = output_str.lines().map(|l| l.trim()).collect();
+    for expected in ["lib    lib.rs", "main   main.rs", "test   test.rs", "bench  bench.rs"].iter() {
+        assert!(lines.contains(expected));
+    }
+    // Nothing gets built
+    assert!(!built_executable_exists(workspace, "foo"));
+    assert!(!built_library_exists(workspace, "foo"));
+}
+
+#[test]
+fn test_targets_json() {
+    use extra::json;
+
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]), "fn main() {}");
+    let output = command_line_test([~"targets", ~"--json", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    // Skip any warnings that get printed before the JSON
+    let json_start = output_str.find('{').expect("test_targets_json: no JSON in output");
+    let obj = match json::from_str(output_str.slice_from(json_start)) {
+        Ok(json::Object(obj)) => obj,
+        _ => fail!("test_targets_json: expected a JSON object, got {}", output_str)
+    };
+    assert_eq!(obj.find(&~"package"), Some(&json::String(~"foo")));
+    assert_eq!(obj.find(&~"inferred_only"), Some(&json::Boolean(true)));
+    let targets = match obj.find(&~"targets") {
+        Some(&json::List(ref ts)) => ts.clone(),
+        _ => fail!("test_targets_json: no list of targets")
+    };
+    assert_eq!(targets.len(), 4);
+    let mut lib = ~TreeMap::new();
+    lib.insert(~"kind", json::String(~"lib"));
+    lib.insert(~"path", json::String(~"lib.rs"));
+    assert!(targets.contains(&json::Object(lib)));
+}
+
+#[test]
+fn test_targets_print_crate_id() {
+    use extra::json;
+
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    writeFile(&pkg_dir.join("lib.rs"),
+              "#[link(name = \"quux\", vers = \"2.3\", uuid = \"1234-abcd\")];\n\
+               pub fn f() {}");
+    writeFile(&pkg_dir.join("test.rs"), "#[link(vers = \"1.0\")];\n#[test] fn f() {}");
+    let output = command_line_test([~"targets", ~"--print-crate-id", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains("lib    lib.rs name=quux vers=2.3 package_id=quux \
+                                 uuid=1234-abcd\n"));
+    // A link attribute without a name gets the one rustc would use
+    assert!(output_str.contains("test   test.rs name=test vers=1.0 package_id=test\n"));
+    // No link attribute, so rustpkg's is used
+    assert!(output_str.contains("main   main.rs name=foo "));
+    assert!(output_str.contains("package_id=foo (inferred)\n"));
+
+    let output = command_line_test([~"targets", ~"--print-crate-id", ~"--json", ~"foo"],
+                                   workspace);
+    let output_str = str::from_utf8(output.output);
+    let json_start = output_str.find('{').expect("test_targets_print_crate_id: no JSON");
+    let targets = match json::from_str(output_str.slice_from(json_start)) {
+        Ok(json::Object(obj)) => match obj.find(&~"targets") {
+            Some(&json::List(ref ts)) => ts.clone(),
+            _ => fail!("test_targets_print_crate_id: no list of targets")
+        },
+        _ => fail!("test_targets_print_crate_id: expected a JSON object, got {}", output_str)
+    };
+    let mut id = ~TreeMap::new();
+    id.insert(~"name", json::String(~"quux"));
+    id.insert(~"vers", json::String(~"2.3"));
+    id.insert(~"package_id", json::String(~"quux"));
+    id.insert(~"uuid", json::String(~"1234-abcd"));
+    id.insert(~"inferred", json::Boolean(false));
+    let mut lib = ~TreeMap::new();
+    lib.insert(~"kind", json::String(~"lib"));
+    lib.insert(~"path", json::String(~"lib.rs"));
+    lib.insert(~"crate_id", json::Object(id));
+    assert!(targets.contains(&json::Object(lib)));
+
+    command_line_test_expect_fail([~"build", ~"--print-crate-id", ~"foo"], workspace, None,
+                                  BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_deps() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    writeFile(&pkg_dir.join("main.rs"), "extern mod foo; extern mod extra; fn main() {}");
+    let output = command_line_test([~"deps", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    let lines: ~[&str] = output_str.lines().map(|l| l.trim()).collect();
+    let main = lines.iter().position(|l| *l == "main   main.rs")
+        .expect("test_deps: main.rs isn't listed");
+    assert_eq!(lines[main + 1], "-> lib.rs");
+    assert_eq!(lines[main + 2], "-> extra (external)");
+    assert!(!output_str.contains("cycle"));
+    // Nothing gets built
+    assert!(!built_executable_exists(workspace, "foo"));
+}
+
+#[test]
+fn test_build_libs_in_dependency_order() {
+    use api::{new_default_context, new_workcache_context};
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    fs::mkdir_recursive(&pkg_dir.join("a"), io::UserRWX);
+    fs::mkdir_recursive(&pkg_dir.join("b"), io::UserRWX);
+    writeFile(&pkg_dir.join_many(["a", "lib.rs"]),
+              "#[link(name = \"a\")]; extern mod b; pub fn f() -> int { b::g() + 1 }");
+    writeFile(&pkg_dir.join_many(["b", "lib.rs"]),
+              "#[link(name = \"b\")]; pub fn g() -> int { 41 }");
+
+    let mut ctxt = new_default_context(new_workcache_context(workspace), test_sysroot());
+    let decisions = record_cache_decisions(&mut ctxt);
+    let mut pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+    ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    let out_dir = target_build_dir(workspace).join("foo");
+    library_in("a", &NoVersion, &out_dir).expect("a wasn't built");
+    library_in("b", &NoVersion, &out_dir).expect("b wasn't built");
+
+    // a can only be compiled once b has been, whichever one was found first
+    let compiled: ~[~str] = decisions.read(|d| d.clone()).move_iter()
+        .filter(|&(_, fresh)| !fresh).map(|(key, _)| key).collect();
+    let a_tag = crate_tag(&pkg_dir.join_many(["a", "lib.rs"]));
+    let b_tag = crate_tag(&pkg_dir.join_many(["b", "lib.rs"]));
+    let a_pos = compiled.iter().position(|k| *k == a_tag).expect("a wasn't compiled");
+    let b_pos = compiled.iter().position(|k| *k == b_tag).expect("b wasn't compiled");
+    assert!(b_pos < a_pos);
+}
+
+#[test]
+fn test_build_libs_in_cycle_fails() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    for &(name, other) in [("a", "b"), ("b", "a")].iter() {
+        fs::mkdir_recursive(&pkg_dir.join(name), io::UserRWX);
+        writeFile(&pkg_dir.join_many([name, "lib.rs"]),
+                  format!("#[link(name = \"{}\")]; extern mod {};", name, other));
+    }
+
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_build_libs_in_cycle_fails: expected the build to fail"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(DEPENDENCY_CYCLE_CODE));
+            let output = str::from_utf8(r.output);
+            assert!(output.contains("depend on each other in a cycle"));
+        }
+    }
+}
+
+#[test]
+fn test_deps_cycle_json() {
+    use extra::json;
+
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    for &(name, other) in [("a", "b"), ("b", "a")].iter() {
+        fs::mkdir_recursive(&pkg_dir.join(name), io::UserRWX);
+        writeFile(&pkg_dir.join_many([name, "lib.rs"]), format!("extern mod {};", other));
+    }
+
+    // The cycle starts with whichever crate was found first
+    let output = command_line_test([~"deps", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    let a = Path::new("a").join("lib.rs").display().to_str();
+    let b = Path::new("b").join("lib.rs").display().to_str();
+    assert!(output_str.contains(format!("cycle: {} -> {} -> {}", a, b, a))
+            || output_str.contains(format!("cycle: {} -> {} -> {}", b, a, b)));
+
+    let output = command_line_test([~"deps", ~"--json", ~"foo"], workspace);
+    let output_str = str::from_utf8(output.output);
+    let json_start = output_str.find('{').expect("test_deps_cycle_json: no JSON in output");
+    let obj = match json::from_str(output_str.slice_from(json_start)) {
+        Ok(json::Object(obj)) => obj,
+        _ => fail!("test_deps_cycle_json: expected a JSON object, got {}", output_str)
+    };
+    let cycles = match obj.find(&~"cycles") {
+        Some(&json::List(ref cs)) => cs.clone(),
+        _ => fail!("test_deps_cycle_json: no list of cycles")
+    };
+    assert_eq!(cycles.len(), 1);
+    let a = json::String(a);
+    let b = json::String(b);
+    assert!(cycles[0] == json::List(~[a.clone(), b.clone(), a.clone()])
+            || cycles[0] == json::List(~[b.clone(), a, b]));
+}
+
+#[test]
+fn test_quiet() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    // foo has no package script, so asking for a variant of it warns
+    let output = command_line_test([~"install", ~"--variant", ~"tiny", ~"foo"], workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.contains("Installed package foo"));
+    assert!(output.contains("no package script for variant"));
+
+    let output = command_line_test([~"install", ~"--quiet", ~"--variant", ~"tiny", ~"foo"],
+                                   workspace);
+    let output = str::from_utf8(output.output);
+    assert!(!output.contains("note: "));
+    assert!(!output.contains("Installed package foo"));
+    assert!(output.contains("no package script for variant"));
+
+    let output = command_line_test([~"install", ~"--silent", ~"--variant", ~"tiny", ~"foo"],
+                                   workspace);
+    assert!(!str::from_utf8(output.output).contains("warning: "));
+}
+
+#[test]
+fn test_verify() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"foo"], workspace);
+    command_line_test([~"verify", ~"foo"], workspace);
+
+    // Tamper with the executable, and remove the library
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let stat = exec.stat();
+    fs::change_file_times(&exec, stat.accessed, stat.modified + 2000);
+    let lib = installed_library_in_workspace(&p_id.path, workspace).expect("no library");
+    fs::unlink(&lib);
+
+    match command_line_test_partial([~"verify", ~"foo"], workspace) {
+        Success(*) => fail!("test_verify: verify didn't notice the changes"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(VERIFY_FAILED_CODE));
+            let output = str::from_utf8(r.output);
+            assert!(output.contains(format!("{} has changed", exec.display())));
+            assert!(output.contains(format!("{} is missing", lib.display())));
+        }
+    }
+}
+
+/// Whether there's a strip tool to run, without which --strip does nothing
+fn has_strip_tool() -> bool {
+    os::getenv("PATH").map_default(false, |path| {
+        path.split(':').any(|dir| Path::new(dir).join("strip").exists())
+    })
+}
+
+#[test]
+fn test_install_strip() {
+    if !has_strip_tool() {
+        return;
+    }
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"-Z", ~"debug-info", ~"foo"], workspace);
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let unstripped = exec.stat().size;
+
+    command_line_test([~"install", ~"--strip", ~"-Z", ~"debug-info", ~"foo"], workspace);
+    assert!(exec.stat().size < unstripped);
+    // What was recorded is the stripped binary
+    command_line_test([~"verify", ~"foo"], workspace);
+}
+
+#[test]
+fn test_install_strip_twice() {
+    use util::install_binary_or_fail;
+
+    if !has_strip_tool() {
+        return;
+    }
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"--strip", ~"-Z", ~"debug-info", ~"foo"], workspace);
+    let installed = target_executable_in_workspace(&p_id, workspace);
+    let built = built_executable_in_workspace(&p_id, workspace)
+        .expect("test_install_strip_twice: no built executable");
+    let stripped = installed.stat();
+    assert!(stripped.size < built.stat().size);
+    // Stripping keeps the built executable's time, so it still looks unchanged
+    assert_eq!(stripped.modified, built.stat().modified);
+
+    command_line_test([~"install", ~"--strip", ~"-Z", ~"debug-info", ~"foo"], workspace);
+    assert_eq!(installed.stat().size, stripped.size);
+    command_line_test([~"verify", ~"foo"], workspace);
+
+    // Even when the workcache doesn't catch it, the stripped copy is left alone...
+    assert!(!install_binary_or_fail(&built, &installed, true, true, &None, Sha1Digest));
+    // ...unless it wasn't stripped last time, or shouldn't be now
+    assert!(install_binary_or_fail(&built, &installed, true, false, &None, Sha1Digest));
+    assert_eq!(installed.stat().size, stripped.size);
+    assert!(install_binary_or_fail(&built, &installed, false, true, &None, Sha1Digest));
+    assert_eq!(installed.stat().size, built.stat().size);
+}
+
+#[test]
+fn test_install_with_docs() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    // Stand in for documentation that a package script generated
+    let doc_dir = target_build_dir(workspace).join_many(["foo", "doc"]);
+    fs::mkdir_recursive(&doc_dir.join("foo"), io::UserRWX);
+    writeFile(&doc_dir.join("index.html"), "<h1>foo</h1>");
+    writeFile(&doc_dir.join_many(["foo", "fn.f.html"]), "<h1>f</h1>");
+
+    let installed_docs = workspace.join_many(["doc", "foo"]);
+    command_line_test([~"install", ~"foo"], workspace);
+    assert!(!installed_docs.exists());
+
+    command_line_test([~"install", ~"--with-docs", ~"foo"], workspace);
+    let index = installed_docs.join("index.html");
+    assert!(index.is_file());
+    assert!(installed_docs.join_many(["foo", "fn.f.html"]).is_file());
+    command_line_test([~"verify", ~"foo"], workspace);
+
+    writeFile(&index, "<h1>bar</h1>");
+    match command_line_test_partial([~"verify", ~"foo"], workspace) {
+        Success(*) => fail!("test_install_with_docs: verify didn't notice the change"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(VERIFY_FAILED_CODE));
+            assert!(str::from_utf8(r.output).contains(format!("{} has changed",
+                                                               index.display())));
+        }
+    }
+}
+
+#[test]
+fn test_uninstall_removes_only_installed_docs() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    let doc_dir = target_build_dir(workspace).join_many(["foo", "doc"]);
+    fs::mkdir_recursive(&doc_dir.join("foo"), io::UserRWX);
+    writeFile(&doc_dir.join_many(["foo", "fn.f.html"]), "<h1>f</h1>");
+    command_line_test([~"install", ~"--with-docs", ~"foo"], workspace);
+
+    // A file that something other than rustpkg put there
+    let installed_docs = workspace.join_many(["doc", "foo"]);
+    let notes = installed_docs.join("notes.txt");
+    writeFile(&notes, "mine");
+
+    command_line_test([~"uninstall", ~"foo"], workspace);
+    assert!(!installed_docs.join("foo").exists());
+    assert!(notes.is_file());
+}
+
+#[test]
+fn test_verify_checks_latest_install() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"foo"], workspace);
+    let built_index = target_build_dir(workspace).join_many(["foo", "doc", "index.html"]);
+    fs::mkdir_recursive(&built_index.dir_path(), io::UserRWX);
+    writeFile(&built_index, "<h1>old</h1>");
+    command_line_test([~"install", ~"--with-docs", ~"foo"], workspace);
+    writeFile(&built_index, "<h1>new</h1>");
+    command_line_test([~"install", ~"--with-docs", ~"foo"], workspace);
+
+    // What the first install put there doesn't count any more
+    let index = workspace.join_many(["doc", "foo", "index.html"]);
+    writeFile(&index, "<h1>old</h1>");
+    match command_line_test_partial([~"verify", ~"foo"], workspace) {
+        Success(*) => fail!("test_verify_checks_latest_install: verify accepted an old file"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(VERIFY_FAILED_CODE));
+            assert!(str::from_utf8(r.output).contains(format!("{} has changed",
+                                                               index.display())));
+        }
+    }
+}
+
+#[test]
+fn test_verify_not_installed() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    command_line_test_expect_fail([~"verify", ~"foo"], workspace.path(), None,
+                                  NONEXISTENT_PACKAGE_CODE);
+}
+
+#[test]
+fn test_clean_cache() {
+    use api::open_workcache_context;
+
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let bar_dir = create_local_package_in(&PkgId::new("bar"), workspace);
+    command_line_test([~"build", ~"foo"], workspace);
+    command_line_test([~"build", ~"bar"], workspace);
+    let foo_tag = crate_tag(&workspace.join_many([~"src", PkgId::new("foo").to_str(),
+                                                  ~"main.rs"]));
+    let bar_tag = crate_tag(&bar_dir.join("main.rs"));
+    // How many entries there are for building `tag`, without changing
+    // the database
+    let entries = |tag: &str| {
+        let cx = open_workcache_context(workspace, true);
+        cx.db.write(|db| db.prune(true, |fn_name, _| fn_name == tag).len())
+    };
+
+    // bar's sources are gone, so its entries can never be used again
+    fs::rmdir_recursive(&bar_dir);
+    let output = command_line_test([~"clean-cache", ~"--dry-run"], workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.contains(format!("Would remove {}", bar_tag)));
+    assert!(!output.contains(format!("Would remove {}", foo_tag)));
+    assert_eq!(entries(bar_tag), 1);
+
+    let output = command_line_test([~"clean-cache"], workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.contains(format!("Removed {}", bar_tag)));
+    assert!(output.lines().any(|l| l.contains("workcache entries, freeing")));
+    assert_eq!(entries(bar_tag), 0);
+    assert_eq!(entries(foo_tag), 1);
+}
+
+#[test]
+fn test_exclude() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let experimental = workspace.join_many(["src", "foo-0.1", "experimental"]);
+    fs::mkdir_recursive(&experimental, io::UserRWX);
+    writeFile(&experimental.join("main.rs"), "fn main() { this is not rust");
+    command_line_test_expect_fail([~"build", ~"foo"], workspace, None, COPY_FAILED_CODE);
+    let output = command_line_test([~"build", ~"--exclude", ~"*/experimental/*", ~"foo"],
+                                   workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.lines().any(|l| l.contains("Excluding") && l.contains("experimental")));
+    assert_built_library_exists(workspace, "foo");
+    assert_built_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_crate_named_after_package() {
+    let workspace = TempDir::new("test_crate_named_after_package")
+        .expect("test_crate_named_after_package failed");
+    let workspace = workspace.path();
+    let foo_dir = mk_workspace(workspace, &Path::new("foo"), &NoVersion);
+    writeFile(&foo_dir.join("foo.rs"), "#[crate_type = \"lib\"];\npub fn f() {}");
+    let bar_dir = mk_workspace(workspace, &Path::new("bar"), &NoVersion);
+    writeFile(&bar_dir.join("bar.rs"), "fn main() {}");
+
+    // foo.rs says it's a library, and bar.rs doesn't say, so it's an executable
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_library_exists(workspace, "foo");
+    assert!(!built_executable_exists(workspace, "foo"));
+    command_line_test([~"build", ~"bar"], workspace);
+    assert_built_executable_exists(workspace, "bar");
+    assert!(built_library_in_workspace(&PkgId::new("bar"), workspace).is_none());
+}
+
+/// Creates package foo, each of whose crates has an unused variable
+/// named after the crate, to get a warning from each of them
+fn create_warning_package() -> TempDir {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let package_dir = workspace.path().join_many(["src", "foo-0.1"]);
+    writeFile(&package_dir.join("lib.rs"), "pub fn f() { let unused_lib = 1; }");
+    writeFile(&package_dir.join("main.rs"), "fn main() { let unused_main = 1; }");
+    writeFile(&package_dir.join("test.rs"), "#[test] fn t() { let unused_test = 1; }");
+    writeFile(&package_dir.join("bench.rs"), "pub fn b() { let unused_bench = 1; }");
+    workspace
+}
+
+/// The names of the unused variables warned about in `output`, in order
+fn unused_variable_warnings(output: &[u8]) -> ~[~str] {
+    let output = str::from_utf8(output);
+    output.lines().filter_map(|l| {
+        l.find_str("unused variable: `").map(|i| {
+            let name = l.slice_from(i + "unused variable: `".len());
+            name.slice_to(name.find('`').unwrap()).to_owned()
+        })
+    }).collect()
+}
+
+#[test]
+fn test_no_default_workspace() {
+    let tmp = TempDir::new("test_no_default_workspace").expect("couldn't create temp dir");
+    let dir = tmp.path().join("foo");
+    fs::mkdir_recursive(&dir, io::UserRWX);
+    writeFile(&dir.join("main.rs"), "fn main() {}");
+    match command_line_test_partial([~"install", ~"--no-default-workspace"], &dir) {
+        Success(*) => fail!("test_no_default_workspace: install should have failed"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(WORKSPACE_PROBLEMS_CODE));
+            let output = str::from_utf8(r.output);
+            assert!(output.contains("set RUST_PATH or run rustpkg inside a workspace"));
+        }
+    }
+    assert!(!executable_exists(&dir.join(".rust"), "foo"));
+
+    // Without the flag, foo goes in the default workspace
+    command_line_test([~"install"], &dir);
+    assert_executable_exists(&dir.join(".rust"), "foo");
+}
+
+#[test]
+fn test_serial_jobs_are_ordered() {
+    let workspace = create_warning_package();
+    let workspace = workspace.path();
+    let expected = ~[~"unused_lib", ~"unused_main", ~"unused_test", ~"unused_bench"];
+    for _ in range(0, 2) {
+        let output = command_line_test([~"build", ~"--jobs", ~"1", ~"foo"], workspace);
+        assert_eq!(unused_variable_warnings(output.error), expected);
+        command_line_test([~"clean", ~"foo"], workspace);
+    }
+}
+
+#[test]
+fn test_parallel_jobs_prefix_output() {
+    let workspace = create_warning_package();
+    let workspace = workspace.path();
+    let output = command_line_test([~"build", ~"--jobs", ~"4", ~"foo"], workspace);
+    // The warnings can come in any order, but each crate gets one
+    let warnings = unused_variable_warnings(output.error);
+    assert_eq!(warnings.len(), 4);
+    for name in ["unused_lib", "unused_main", "unused_test", "unused_bench"].iter() {
+        assert!(warnings.iter().any(|w| w.as_slice() == *name));
+    }
+    let errors = str::from_utf8(output.error);
+    for file in ["lib.rs", "main.rs", "test.rs", "bench.rs"].iter() {
+        assert!(errors.contains(format!("[foo/{}] unused variable", *file)));
+    }
+    assert_built_executable_exists(workspace, "foo");
+    command_line_test_expect_fail([~"build", ~"--jobs", ~"0", ~"foo"], workspace, None,
+                                  BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_digest_algorithms() {
+    use api::new_workcache_context;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let mut installed: ~[~[~str]] = ~[];
+    for alg in [~"sha1", ~"sha256"].iter() {
+        command_line_test([~"install", ~"--digest", alg.clone(), ~"foo"], workspace);
+        command_line_test([~"verify", ~"foo"], workspace);
+        // Switching algorithms should have made the install run again,
+        // recording its outputs with digests made by the new algorithm
+        let outputs = new_workcache_context(workspace).db.read(|db| {
+            db.discovered_outputs(p_id.install_tag())
+        });
+        let prefix = *alg + ":";
+        let names: ~[~str] = outputs.iter()
+            .filter(|&&(_, _, ref digest)| digest.starts_with(prefix))
+            .map(|&(_, ref name, _)| name.clone())
+            .collect();
+        debug!("{} outputs: {:?}", *alg, names);
+        assert!(!names.is_empty());
+        installed.push(names);
+    }
+    // Both algorithms installed the same files
+    assert_eq!(installed[0].len(), installed[1].len());
+    assert!(installed[0].iter().all(|n| installed[1].contains(n)));
+    assert_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_unprefixed_digests_are_sha1() {
+    let dir = mk_emptier_workspace("test_unprefixed_digests_are_sha1");
+    let file = dir.path().join("f.rs");
+    writeFile(&file, "fn main() {}");
+    // What digest_file_with_date made before digests were prefixed
+    let old = digest_file_with_date(&file);
+    let old = old.slice_from("sha1:".len());
+    assert!(file_digest_matches(&file, old));
+    assert!(date_digest_matches(&file, digest_only_date(&file).slice_from("sha1:".len())));
+    writeFile(&file, "fn main() { () }");
+    assert!(!file_digest_matches(&file, old));
+}
+
+#[test]
+fn test_help_before_command() {
+    let output = command_line_test_output([~"--help", ~"install"]);
+    assert!(output.iter().any(|l| l.starts_with("rustpkg install")));
+}
+
+#[test]
+fn test_help_after_command() {
+    let output = command_line_test_output([~"install", ~"--help"]);
+    assert!(output.iter().any(|l| l.starts_with("rustpkg install")));
+}
+
+#[test]
+fn test_help_is_general_usage() {
+    let output = command_line_test_output([~"-h"]);
+    assert!(output.iter().any(|l| l.starts_with("Usage: rustpkg")));
+    assert!(!output.iter().any(|l| l.starts_with("rustpkg install")));
+}
+
+#[test]
+fn test_clean_all() {
+    let foo_ws = create_local_package(&PkgId::new("foo"));
+    let foo_ws = foo_ws.path();
+    let bar_ws = create_local_package(&PkgId::new("bar"));
+    let bar_ws = bar_ws.path();
+    command_line_test([~"install", ~"foo"], foo_ws);
+    command_line_test([~"install", ~"bar"], bar_ws);
+    assert!(built_executable_exists(foo_ws, "foo"));
+    assert!(built_executable_exists(bar_ws, "bar"));
+
+    // Don't let rustpkg find (and clean) a real ~/.rust
+    let home = TempDir::new("clean_all_home").expect("test_clean_all failed");
+    let nonexistent = foo_ws.join("nonexistent");
+    let rust_path = rust_path_value([foo_ws.clone(), bar_ws.clone(), nonexistent.clone()]);
+    let env = ~[(~"RUST_PATH", rust_path),
+                (~"HOME", home.path().as_str().unwrap().to_owned())];
+
+    let output = match command_line_test_with_env([~"clean", ~"--all"], foo_ws,
+                                                  Some(env.clone())) {
+        Success(r) => str::from_utf8(r.output),
+        Fail(r) => fail!("test_clean_all: clean --all failed: {}", str::from_utf8(r.output))
+    };
+    assert!(output.contains(format!("Skipping {}", nonexistent.display())));
+    assert!(output.contains("Freed"));
+    // The build output is gone, but what was installed is still there
+    assert!(!built_executable_exists(foo_ws, "foo"));
+    assert!(!built_executable_exists(bar_ws, "bar"));
+    assert_executable_exists(foo_ws, "foo");
+    assert_executable_exists(bar_ws, "bar");
+
+    match command_line_test_with_env([~"clean", ~"--all", ~"--deep"], foo_ws, Some(env)) {
+        Success(*) => (),
+        Fail(r) => fail!("test_clean_all: clean --all --deep failed: {}",
+                         str::from_utf8(r.output))
+    }
+    assert!(!executable_exists(foo_ws, "foo"));
+    assert!(!executable_exists(bar_ws, "bar"));
+    assert!(installed_library_in_workspace(&Path::new("foo"), foo_ws).is_none());
+}
+
+#[test]
+fn test_deep_clean_needs_all() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    command_line_test_expect_fail([~"clean", ~"--deep", ~"foo"], workspace.path(), None,
+                                  BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_doctor_healthy_workspace() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"foo"], workspace);
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let env = Some(~[(~"RUST_PATH", workspace.as_str().unwrap().to_owned())]);
+    match command_line_test_with_env([~"doctor"], workspace, env) {
+        Success(*) => (),
+        Fail(r) => fail!("test_doctor_healthy_workspace: doctor found problems: {}",
+                         str::from_utf8(r.output))
+    }
+}
+
+#[test]
+fn test_doctor_reports_problems() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    // A package with no crate file, and a library with no source
+    fs::mkdir_recursive(&workspace.join_many(["src", "bar"]), io::UserRWX);
+    let lib_dir = target_library_in_workspace(&PkgId::new("baz"), workspace).dir_path();
+    writeFile(&lib_dir.join(os::dll_filename("baz-0.1")), "");
+    // ...and a file that only looks like a library, which isn't a problem
+    writeFile(&lib_dir.join("x.rlib"), "");
+    let nonexistent = workspace.join("nonexistent");
+    let env = Some(~[(~"RUST_PATH", rust_path_value([workspace.clone(), nonexistent.clone()]))]);
+    match command_line_test_with_env([~"doctor"], workspace, env) {
+        Success(*) => fail!("test_doctor_reports_problems: doctor didn't find any problems"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(WORKSPACE_PROBLEMS_CODE));
+            let output = str::from_utf8(r.output);
+            assert!(output.contains(format!("{} doesn't exist", nonexistent.display())));
+            assert!(output.contains(format!("{} is missing",
+                                            workspace.join("bin").display())));
+            assert!(output.contains(format!("{} has no crate file",
+                                            workspace.join_many(["src", "bar"]).display())));
+            assert!(output.contains("baz"));
+            assert!(!output.contains("x.rlib"));
+        }
+    }
+}
+
+#[test]
+fn test_versions() {
+    let workspace = create_local_package(&PkgId::new("foo#0.1"));
+    let _other_workspace = create_local_package(&PkgId::new("foo#0.2"));
+    command_line_test([~"install", ~"foo#0.1"], workspace.path());
+    let output = command_line_test_output([~"list"]);
+    // make sure output includes versions
     assert!(!output.iter().any(|x| x == &~"foo#0.2"));
 }
 
@@ -1367,6 +3024,180 @@ fn test_macro_pkg_script() {
         os::EXE_SUFFIX)).exists());
 }
 
+#[test]
+fn test_pkg_script_declared_inputs() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    writeFile(&pkg_dir.join("template.txt"), "version 1");
+    writeFile(&pkg_dir.join("pkg.rs"),
+              "fn main() { if std::os::args()[2] == ~\"inputs\" { \
+                   println(\"Reading the template\"); println(\"input:template.txt\"); } }");
+    command_line_test([~"build", ~"foo"], workspace);
+    let pkg_exe = target_build_dir(workspace).join("foo").join(format!("pkg{}",
+                                                                     os::EXE_SUFFIX));
+    assert!(pkg_exe.exists());
+
+    // Nothing changed, so the package script shouldn't get rebuilt
+    // (it still needs to be executable, since rustpkg always runs it)
+    fs::chmod(&pkg_exe, io::UserRead | io::UserExecute);
+    command_line_test([~"build", ~"foo"], workspace);
+
+    // Changing the declared input should force a rebuild, which fails
+    // because the package script binary is read-only
+    writeFile(&pkg_dir.join("template.txt"), "version 2");
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_declared_inputs failed: it didn't rebuild"),
+        Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
+        Fail(_) => fail!("test_pkg_script_declared_inputs failed for some other reason")
+    }
+}
+
+#[test]
+fn test_pkg_script_missing_declared_input() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]),
+              "fn main() { if std::os::args()[2] == ~\"inputs\" { \
+                   println(\"input:nowhere.txt\"); } }");
+    command_line_test_expect_fail([~"build", ~"foo"], workspace, None, COPY_FAILED_CODE);
+}
+
+#[test]
+fn test_pkg_script_parse_error() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let script = workspace.join_many(["src", "foo-0.1", "pkg.rs"]);
+    writeFile(&script, "fn main() { this is not rust");
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_parse_error: build should have failed"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(BAD_PKG_SCRIPT_CODE));
+            let out = str::from_utf8(r.output);
+            assert!(out.contains(format!("package script {} failed to parse",
+                                         script.display())));
+        }
+    }
+    assert!(!built_executable_exists(workspace, "foo"));
+
+    // The failed build of the script wasn't cached
+    command_line_test_expect_fail([~"build", ~"foo"], workspace, None, BAD_PKG_SCRIPT_CODE);
+    writeFile(&script, "fn main() {}");
+    command_line_test([~"build", ~"foo"], workspace);
+}
+
+#[test]
+fn test_pkg_script_static_configs() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let pkg_dir = workspace.join_many(["src", "foo-0.1"]);
+    // If rustpkg ran the package script, the build would fail
+    writeFile(&pkg_dir.join("pkg.rs"), "fn main() { fail!(\"the package script ran\"); }");
+    writeFile(&pkg_dir.join("pkg.cfgs"), "# the cfgs foo is built with\nquux\n");
+    // ...and without the cfg, main.rs would have no main function
+    writeFile(&pkg_dir.join("main.rs"), "#[cfg(quux)] fn main() {}");
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "foo");
+    let pkg_exe = target_build_dir(workspace).join("foo").join(format!("pkg{}",
+                                                                     os::EXE_SUFFIX));
+    assert!(!pkg_exe.exists());
+
+    // The file can name the executables, like the binaries hook
+    writeFile(&pkg_dir.join("pkg.cfgs"), "quux binary:main.rs=frobnicate\n");
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "frobnicate");
+
+    // Link arguments from the file are passed to the linker, and changing
+    // the file rebuilds the package
+    writeFile(&pkg_dir.join("pkg.cfgs"), "quux link-arg:-lrustpkg_no_such_library\n");
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_static_configs: the link argument wasn't used"),
+        Fail(*) => ()
+    }
+
+    // A crate target can only come from running the script
+    writeFile(&pkg_dir.join("pkg.cfgs"), "quux target:src/gpu/lib.rs\n");
+    command_line_test_expect_fail([~"build", ~"foo"], workspace, None, BAD_PKG_SCRIPT_CODE);
+}
+
+#[test]
+fn test_pkg_script_captured_output() {
+    use api::{new_default_context, new_workcache_context};
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]),
+              "use std::os;
+               fn main() {
+                   if os::args()[2] == ~\"install\" {
+                       println(\"hello from pkg.rs\");
+                       std::io::stderr().write(bytes!(\"warning from pkg.rs\"));
+                   }
+               }");
+    let mut ctxt = new_default_context(new_workcache_context(workspace), test_sysroot());
+
+    // By default, the output isn't captured
+    let mut pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+    ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    assert!(pkg_src.script_output.is_none());
+
+    ctxt.context.capture_script_output = true;
+    let mut pkg_src = PkgSrc::new(workspace.clone(), workspace.clone(), false, p_id.clone());
+    ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything));
+    let (out, err) = pkg_src.script_output.expect("the build hook's output wasn't captured");
+    assert!(str::from_utf8(out).contains("hello from pkg.rs"));
+    assert!(str::from_utf8(err).contains("warning from pkg.rs"));
+}
+
+#[test]
+fn test_pkg_script_binary_names() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]),
+              "extern mod rustpkg;
+               use std::os;
+               use rustpkg::api;
+               use rustpkg::version::NoVersion;
+               fn main() {
+                   let args = os::args();
+                   if args[2] == ~\"binaries\" { println(\"main.rs frobnicate\"); return; }
+                   if args[2] != ~\"install\" { return; }
+                   let context = api::default_context(Path::new(args[1].clone()),
+                                                      api::default_workspace());
+                   api::install_pkg(&context, os::getcwd(), ~\"foo\", NoVersion, ~[]);
+               }");
+    command_line_test([~"install", ~"foo"], workspace);
+    assert_executable_exists(workspace, "frobnicate");
+    assert!(!executable_exists(workspace, "foo"));
+    // The library keeps its default name
+    assert_lib_exists(workspace, &Path::new("foo"), NoVersion);
+}
+
+#[test]
+fn test_pkg_script_binary_names_for_targets() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    // The script builds nothing itself, so main.rs is only built because
+    // the configs hook asks for it, and should still get its binary name
+    writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]),
+              "use std::os;
+               fn main() {
+                   let args = os::args();
+                   if args[2] == ~\"configs\" { println(\"target:main.rs\"); }
+                   if args[2] == ~\"binaries\" { println(\"main.rs frobnicate\"); }
+               }");
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_executable_exists(workspace, "frobnicate");
+    assert!(!built_executable_exists(workspace, "foo"));
+}
+
 #[test]
 fn multiple_workspaces() {
 // Make a package foo; build/install in directory A
@@ -1380,9 +3211,7 @@ fn multiple_workspaces() {
     command_line_test([~"install", ~"foo"], a_loc);
     debug!("Trying to install foo in {}", b_loc.display());
     command_line_test([~"install", ~"foo"], b_loc);
-    // FIXME (#9639): This needs to handle non-utf8 paths
-    let env = Some(~[(~"RUST_PATH", format!("{}:{}", a_loc.as_str().unwrap(),
-                                            b_loc.as_str().unwrap()))]);
+    let env = Some(~[(~"RUST_PATH", rust_path_value([a_loc.clone(), b_loc.clone()]))]);
     let c_loc = create_local_package_with_dep(&PkgId::new("bar"), &PkgId::new("foo"));
     command_line_test_with_env([~"install", ~"bar"], c_loc.path(), env);
 }
@@ -1403,9 +3232,7 @@ fn rust_path_hack_test(hack_flag: bool) {
    let dest_workspace = dest_workspace.path();
    let foo_path = workspace.join_many(["src", "foo-0.1"]);
    let rust_path = Some(~[(~"RUST_PATH",
-       format!("{}:{}",
-               dest_workspace.as_str().unwrap(),
-               foo_path.as_str().unwrap()))]);
+       rust_path_value([dest_workspace.clone(), foo_path.clone()]))]);
    command_line_test_with_env(~[~"install"] + if hack_flag { ~[~"--rust-path-hack"] } else { ~[] } +
                                ~[~"foo"], dest_workspace, rust_path);
    assert_lib_exists(dest_workspace, &Path::new("foo"), NoVersion);
@@ -1535,14 +3362,76 @@ fn rust_path_hack_build_with_dependency() {
     writeFile(&source_dir.join("lib.rs"), "extern mod dep; pub fn f() { }");
     let dep_dir = dep_workspace.join_many(["src", "dep-0.1"]);
     let rust_path = Some(~[(~"RUST_PATH",
-                          format!("{}:{}",
-                                  dest_workspace.display(),
-                                  dep_dir.display()))]);
+                          rust_path_value([dest_workspace.clone(), dep_dir.clone()]))]);
     command_line_test_with_env([~"build", ~"--rust-path-hack", ~"foo"], work_dir, rust_path);
     assert_built_library_exists(dest_workspace, "dep");
     assert!(!built_library_exists(dep_workspace, "dep"));
 }
 
+/// Runs `rustpkg -r <cmd> foo` in `cwd` with `rust_path` as the RUST_PATH,
+/// returning its output
+fn rust_path_hack_command(cmd: &str, cwd: &Path, rust_path: &str) -> ~str {
+    let output = command_line_test_with_env([cmd.to_owned(), ~"-r", ~"foo"], cwd,
+                                            Some(~[(~"RUST_PATH", rust_path.to_owned())]));
+    match output {
+        Success(r) => str::from_utf8(r.output),
+        Fail(r) => fail!("rustpkg -r {} foo failed: {}", cmd,
+                         str::from_utf8(r.output) + str::from_utf8(r.error))
+    }
+}
+
+#[test]
+fn rust_path_hack_build_test_install_package_dir() {
+    // With foo's package directory in the RUST_PATH, everything built from
+    // it goes in the destination workspace, which is where test looks
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let foo_dir = workspace.path().join_many(["src", "foo-0.1"]);
+    let dest_workspace = mk_emptier_workspace("dest_workspace");
+    let dest_workspace = dest_workspace.path();
+    let rust_path = rust_path_value([dest_workspace.clone(), foo_dir.clone()]);
+
+    rust_path_hack_command("build", dest_workspace, rust_path);
+    assert_built_library_exists(dest_workspace, "foo");
+    assert_built_executable_exists(dest_workspace, "foo");
+    assert!(!built_library_exists(workspace.path(), "foo"));
+
+    let output = rust_path_hack_command("test", dest_workspace, rust_path);
+    assert!(output.contains("1 passed; 0 failed"));
+    assert!(test_executable_exists(dest_workspace, "foo"));
+    assert!(!test_executable_exists(workspace.path(), "foo"));
+
+    rust_path_hack_command("install", dest_workspace, rust_path);
+    assert_lib_exists(dest_workspace, &Path::new("foo"), NoVersion);
+    assert_executable_exists(dest_workspace, "foo");
+    assert!(!lib_exists(workspace.path(), &Path::new("foo"), NoVersion));
+}
+
+#[test]
+fn rust_path_hack_build_test_install_workspace() {
+    // With foo in a workspace in the RUST_PATH, it's built in that workspace
+    // and installed to the first one, and test finds it where it was built
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let dest_workspace = mk_emptier_workspace("dest_workspace");
+    let dest_workspace = dest_workspace.path();
+    let rust_path = rust_path_value([dest_workspace.clone(), workspace.clone()]);
+
+    rust_path_hack_command("build", dest_workspace, rust_path);
+    assert_built_library_exists(workspace, "foo");
+    assert!(!built_library_exists(dest_workspace, "foo"));
+
+    let output = rust_path_hack_command("test", dest_workspace, rust_path);
+    assert!(output.contains("1 passed; 0 failed"));
+    assert!(test_executable_exists(workspace, "foo"));
+
+    rust_path_hack_command("install", dest_workspace, rust_path);
+    assert_lib_exists(dest_workspace, &Path::new("foo"), NoVersion);
+    assert_executable_exists(dest_workspace, "foo");
+    assert!(!lib_exists(workspace, &Path::new("foo"), NoVersion));
+}
+
 #[test]
 fn rust_path_install_target() {
     let dir_for_path = TempDir::new(
@@ -1555,10 +3444,8 @@ fn rust_path_install_target() {
     let dir_to_install_to = dir_to_install_to.path();
     dir.pop(); dir.pop();
 
-    // FIXME (#9639): This needs to handle non-utf8 paths
-    let rust_path = Some(~[(~"RUST_PATH", format!("{}:{}",
-                                                  dir_to_install_to.as_str().unwrap(),
-                                                  dir.as_str().unwrap()))]);
+    let rust_path = Some(~[(~"RUST_PATH",
+                            rust_path_value([dir_to_install_to.clone(), dir.clone()]))]);
     let cwd = os::getcwd();
     command_line_test_with_env([~"install", ~"foo"],
                                &cwd,
@@ -1584,6 +3471,46 @@ fn sysroot_flag() {
     assert_built_executable_exists(workspace, "foo");
 }
 
+#[test]
+fn test_print_sysroot() {
+    let dir = TempDir::new("test_print_sysroot").expect("test_print_sysroot failed");
+    let test_sys = test_sysroot();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let output = command_line_test([~"--sysroot", test_sys.as_str().unwrap().to_owned(),
+                                    ~"--print-sysroot"], dir.path());
+    assert_eq!(str::from_utf8(output.output).trim(),
+               resolve_sysroot(&test_sys).display().to_str());
+
+    // Without --sysroot, it's the one rustpkg found for itself
+    let output = command_line_test([~"--print-sysroot"], dir.path());
+    assert!(Path::new(str::from_utf8(output.output).trim()).is_dir());
+}
+
+#[test]
+fn test_bad_sysroot() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+
+    // Neither a nonexistent directory nor one that isn't laid out like a
+    // sysroot gets as far as building anything
+    let nonexistent = workspace.join("nonexistent");
+    let not_sysroot = workspace.join("src");
+    for sysroot in [nonexistent, not_sysroot].iter() {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let args = [~"--sysroot", sysroot.as_str().unwrap().to_owned(), ~"build", ~"foo"];
+        match command_line_test_partial(args, workspace) {
+            Success(*) => fail!("test_bad_sysroot: {} was accepted", sysroot.display()),
+            Fail(r) => {
+                assert!(r.status.matches_exit_status(BAD_SYSROOT_CODE));
+                let output = str::from_utf8(r.output);
+                assert!(output.contains(format!("sysroot {}", sysroot.display())));
+            }
+        }
+        assert!(!built_executable_exists(workspace, "foo"));
+    }
+}
+
 #[test]
 fn compile_flag_build() {
     let p_id = PkgId::new("foo");
@@ -1837,6 +3764,118 @@ fn test_linker_build() {
     assert_executable_exists(workspace, "foo");
 }
 
+#[test]
+fn test_target_features() {
+    use context::Rlib;
+    use rustc::driver::session;
+
+    let mut rustc_flags = RustcFlags::default();
+    rustc_flags.target_cpu = Some(~"core2");
+    rustc_flags.target_features = ~[~"+sse4.2", ~"-avx"];
+    // This is how compile_crate_from_input gets its session options
+    let matches = getopts(rustc_flags.effective_flag_strs(), optgroups()).unwrap();
+    let options = build_session_options(@"rustpkg",
+                                        &matches,
+                                        @diagnostic::DefaultEmitter as
+                                            @diagnostic::Emitter);
+    assert_eq!(options.target_cpu, ~"core2");
+    assert_eq!(options.target_feature, ~"+sse4.2,-avx");
+
+    // With --lib-type rlib, what's printed asks for a static library
+    rustc_flags.lib_type = Rlib;
+    let matches = getopts(rustc_flags.effective_flag_strs(), optgroups()).unwrap();
+    let options = build_session_options(@"rustpkg",
+                                        &matches,
+                                        @diagnostic::DefaultEmitter as
+                                            @diagnostic::Emitter);
+    assert!(options.debugging_opts & session::statik != 0);
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"build", ~"--target-cpu", ~"generic", ~"--target-feature", ~"-avx",
+                       ~"foo"],
+                      workspace);
+    assert!(built_executable_exists(workspace, "foo"));
+}
+
+#[test]
+fn test_lib_type() {
+    let p_id = PkgId::new("foo");
+    let test_sys = test_sysroot();
+    for &(lib_type, extension) in [("rlib", "rlib"),
+                                   ("dylib", os::consts::DLL_EXTENSION)].iter() {
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        command_line_test([test_sys.as_str().unwrap().to_owned(),
+                           ~"install",
+                           ~"--lib-type",
+                           lib_type.to_owned(),
+                           ~"foo"],
+                          workspace);
+        let lib = installed_library_in_workspace(&p_id.path, workspace);
+        debug!("{} lib = {:?}", lib_type, lib);
+        assert_eq!(lib.expect("library wasn't installed").extension_str(), Some(extension));
+        assert_executable_exists(workspace, "foo");
+    }
+}
+
+#[test]
+fn test_bad_lib_type() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    for bad in [~"staticlib", ~"shared"].iter() {
+        command_line_test_expect_fail([~"install", ~"--lib-type", bad.clone(), ~"foo"],
+                                      workspace, None, BAD_FLAG_CODE);
+    }
+}
+
+#[test]
+fn test_target_linker() {
+    let workspace = TempDir::new("test").expect("couldn't create temp dir");
+    let mut ctxt = fake_ctxt(test_sysroot(), workspace.path());
+    ctxt.context.rustc_flags.linker = Some(~"global-cc");
+    ctxt.context.rustc_flags.target_linkers = ~[(~"arm-linux-androideabi", ~"arm-cc"),
+                                                (~"i686-unknown-linux-gnu", ~"x86-cc")];
+    let session_linker = |target: Option<~str>| {
+        ctxt.context.rustc_flags.target = target;
+        let matches = getopts(ctxt.flag_strs(), optgroups());
+        let options = build_session_options(@"rustpkg",
+                                            matches.get_ref(),
+                                            @diagnostic::DefaultEmitter as
+                                                @diagnostic::Emitter);
+        options.linker.clone()
+    };
+    assert_eq!(session_linker(Some(~"arm-linux-androideabi")), Some(~"arm-cc"));
+    assert_eq!(session_linker(Some(~"i686-unknown-linux-gnu")), Some(~"x86-cc"));
+    // Targets with no linker of their own get the --linker one
+    assert_eq!(session_linker(Some(~"mips-unknown-linux-gnu")), Some(~"global-cc"));
+    if host_triple() != ~"arm-linux-androideabi" && host_triple() != ~"i686-unknown-linux-gnu" {
+        assert_eq!(session_linker(None), Some(~"global-cc"));
+    }
+}
+
+#[test]
+fn test_link_arg_with_space() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let lib_dir = workspace.join("lib dir");
+    fs::mkdir_recursive(&lib_dir, io::UserRWX);
+    // If this got split on the space, the linker would look for an input file named `dir`
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let link_arg = format!("-L{}", lib_dir.as_str().unwrap());
+    command_line_test([test_sysroot().as_str().unwrap().to_owned(),
+                       ~"install",
+                       ~"--link-arg",
+                       link_arg,
+                       ~"foo"],
+                      workspace);
+    assert_executable_exists(workspace, "foo");
+}
+
 #[test]
 fn test_build_install_flags_fail() {
     // The following flags can only be used with build or install:
@@ -1847,7 +3886,31 @@ fn test_build_install_flags_fail() {
                      ~[~"--save-temps"],
                      ~[~"--target", host_triple()],
                      ~[~"--target-cpu", ~"generic"],
-                     ~[~"-Z", ~"--time-passes"]];
+                     ~[~"--target-feature", ~"+sse4.2"],
+                     ~[~"-Z", ~"--time-passes"],
+                     ~[~"--lib-type", ~"rlib"],
+                     ~[~"--target-linker", host_triple() + "=cc"],
+                     ~[~"--link-arg", ~"quux"],
+                     ~[~"--profile", ~"release"],
+                     ~[~"--digest", ~"sha256"],
+                     ~[~"--exclude", ~"*"],
+                     ~[~"--jobs", ~"2"],
+                     ~[~"--root", ~"stage"],
+                     ~[~"--no-wait"],
+                     ~[~"--emit-deps"],
+                     ~[~"--strip"],
+                     ~[~"--allow-dirty"],
+                     ~[~"--config-arg", ~"with_cuda"],
+                     ~[~"--out-dir", ~"out"],
+                     ~[~"--tmp-dir", ~"scratch"],
+                     ~[~"--isolated"],
+                     ~[~"--timings"],
+                     ~[~"--report", ~"report.json"],
+                     ~[~"--keep-going"],
+                     ~[~"--watch"],
+                     ~[~"--ambiguous", ~"first"],
+                     ~[~"--variant", ~"minimal"],
+                     ~[~"--all"]];
     let cwd = os::getcwd();
     for flag in forbidden.iter() {
         let test_sys = test_sysroot();
@@ -1857,19 +3920,214 @@ fn test_build_install_flags_fail() {
     }
 }
 
-#[test]
-fn test_optimized_build() {
-    let p_id = PkgId::new("foo");
-    let workspace = create_local_package(&p_id);
-    let workspace = workspace.path();
-    let test_sys = test_sysroot();
-    // FIXME (#9639): This needs to handle non-utf8 paths
+#[test]
+fn test_timings() {
+    use extra::json;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let output = command_line_test([~"build", ~"--timings", ~"foo"], workspace);
+    let output = str::from_utf8(output.output);
+    assert!(output.contains("Timings (slowest first):"));
+    let crate_lines: ~[&str] = output.lines()
+        .skip_while(|l| !l.starts_with("Timings")).skip(1).collect();
+    for crate in ["lib.rs", "main.rs", "test.rs", "bench.rs"].iter() {
+        assert!(crate_lines.iter().any(|l| l.ends_with(*crate)));
+    }
+
+    command_line_test([~"clean", ~"foo"], workspace);
+    let output = command_line_test([~"build", ~"--timings", ~"--json", ~"foo"], workspace);
+    let output = str::from_utf8(output.output);
+    // Skip the warning that rustpkg prints first, and any notes
+    let report = output.slice_from(output.find_str("{\n").expect("no JSON timing report"));
+    let timings = match json::from_str(report) {
+        Ok(json::Object(obj)) => match obj.find(&~"timings") {
+            Some(&json::List(ref ts)) => ts.clone(),
+            _ => fail!("test_timings: no list of timings in {}", report)
+        },
+        _ => fail!("test_timings: expected a JSON object, got {}", report)
+    };
+    assert_eq!(timings.len(), 4);
+    for t in timings.iter() {
+        match *t {
+            json::Object(ref obj) => {
+                assert!(obj.contains_key(&~"what"));
+                assert!(obj.contains_key(&~"seconds"));
+            }
+            _ => fail!("test_timings: expected a JSON object, got {}", t.to_str())
+        }
+    }
+}
+
+#[test]
+fn test_report() {
+    use extra::json;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let report = workspace.join("report.json");
+    command_line_test([~"install", ~"--report", report.as_str().unwrap().to_owned(),
+                       ~"foo"], workspace);
+    let obj = read_json_object(&report);
+    assert_eq!(obj.find(&~"exit_code"), Some(&json::Number(0.0)));
+    assert_eq!(obj.find(&~"packages"), Some(&json::List(~[json::String(p_id.to_str())])));
+    let installed = path_digest_list(obj, "installed").map(|&(ref p, ref d)| {
+        assert!(d.starts_with("sha1:"));
+        p.clone()
+    });
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let lib = installed_library_in_workspace(&p_id.path, workspace).expect("no library");
+    assert!(installed.contains(&exec.display().to_str()));
+    assert!(installed.contains(&lib.display().to_str()));
+
+    // The report is still written when the command fails
+    writeFile(&workspace.join_many(["src", "foo-0.1", "main.rs"]), "fn main() { x }");
+    command_line_test_partial([~"build", ~"--report", report.as_str().unwrap().to_owned(),
+                               ~"foo"], workspace);
+    let obj = read_json_object(&report);
+    assert!(obj.find(&~"exit_code") != Some(&json::Number(0.0)));
+    assert!(obj.contains_key(&~"seconds"));
+}
+
+#[test]
+fn test_install_record() {
+    use extra::json;
+
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let record = workspace.join("installed.json");
+    command_line_test([~"install", ~"--record", record.as_str().unwrap().to_owned(),
+                       ~"foo"], workspace);
+    let obj = read_json_object(&record);
+    assert_eq!(obj.find(&~"version"), Some(&json::Number(1.0)));
+    let files = path_digest_list(obj, "files");
+    let exec = target_executable_in_workspace(&p_id, workspace);
+    let lib = installed_library_in_workspace(&p_id.path, workspace).expect("no library");
+    // Exactly the installed files, sorted by path, each with a digest of
+    // just its contents
+    let exec = (exec.display().to_str(), digest_file_contents_using(&exec, Sha1Digest));
+    let lib = (lib.display().to_str(), digest_file_contents_using(&lib, Sha1Digest));
+    let expected = if exec < lib { ~[exec, lib] } else { ~[lib, exec] };
+    assert_eq!(files, expected);
+
+    // --record only makes sense for commands that install
+    command_line_test_expect_fail([~"build", ~"--record",
+                                   record.as_str().unwrap().to_owned(), ~"foo"],
+                                  workspace, None, BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_contradictory_phase_flags() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    match command_line_test_partial([~"build", ~"--parse-only", ~"--emit-llvm", ~"foo"],
+                                    workspace) {
+        Success(*) => fail!("Expected contradictory flags to be rejected"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(BAD_FLAG_CODE));
+            assert!(str::from_utf8(r.output)
+                    .contains("flags `--parse-only` and `--emit-llvm` can't be used together"));
+        }
+    }
+    assert!(built_executable_in_workspace(&p_id, workspace).is_none());
+    // -S and --emit-llvm together are fine: they ask for LLVM assembly
+    command_line_test([~"build", ~"-S", ~"--emit-llvm", ~"foo"], workspace);
+}
+
+#[test]
+fn test_forbidden_flag_is_reported() {
+    // Each of these commands should be rejected, naming the offending flag
+    let cases = [(~[~"clean", ~"--emit-llvm"], "--emit-llvm", "clean"),
+                 (~[~"list", ~"--linker", ~"ld"], "--linker", "list"),
+                 (~[~"install", ~"--no-link", ~"foo"], "--no-link", "install"),
+                 (~[~"test", ~"--target-cpu", ~"generic"], "--target-cpu", "test"),
+                 (~[~"uninstall", ~"--cfg", ~"quux", ~"foo"], "--cfg", "uninstall")];
+    let cwd = os::getcwd();
+    let test_sys = test_sysroot();
+    for &(ref args, flag, cmd) in cases.iter() {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        match command_line_test_partial([test_sys.as_str().unwrap().to_owned()] + *args,
+                                        &cwd) {
+            Success(*) => fail!("rustpkg {} should have been rejected", args.connect(" ")),
+            Fail(ref r) => {
+                assert!(r.status.matches_exit_status(BAD_FLAG_CODE));
+                let expected = format!("flag `{}` is not valid for command `{}`", flag, cmd);
+                assert!(str::from_utf8(r.output).contains(expected));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_optimized_build() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let test_sys = test_sysroot();
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([test_sys.as_str().unwrap().to_owned(),
+                       ~"build",
+                       ~"-O",
+                       ~"foo"],
+                      workspace);
+    assert!(built_executable_exists(workspace, "foo"));
+}
+
+#[test]
+fn test_profiles() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let test_sys = test_sysroot();
+    for profile in [~"debug", ~"release"].iter() {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        command_line_test([test_sys.as_str().unwrap().to_owned(),
+                           ~"build",
+                           ~"--profile",
+                           profile.clone(),
+                           ~"foo"],
+                          workspace);
+    }
+    // Both profiles' artifacts exist side by side
+    for &profile in [Debug, Release].iter() {
+        let exe = built_executable_in_profile(&p_id, workspace, Some(profile));
+        let lib = built_library_in_profile(&p_id, workspace, Some(profile));
+        debug!("{} exe = {:?} lib = {:?}", profile.name(), exe, lib);
+        assert!(exe.expect("executable wasn't built").dir_path().filename_str()
+                == Some(profile.name()));
+        assert!(lib.is_some());
+    }
+    // and neither went in the profile-less build directory
+    assert!(built_executable_in_workspace(&p_id, workspace).is_none());
+
     command_line_test([test_sys.as_str().unwrap().to_owned(),
-                       ~"build",
-                       ~"-O",
+                       ~"install",
+                       ~"--profile",
+                       ~"release",
                        ~"foo"],
                       workspace);
-    assert!(built_executable_exists(workspace, "foo"));
+    assert_executable_exists(workspace, "foo");
+    assert_lib_exists(workspace, &p_id.path, NoVersion);
+
+    command_line_test([test_sys.as_str().unwrap().to_owned(),
+                       ~"clean",
+                       ~"--profile",
+                       ~"debug",
+                       ~"foo"],
+                      workspace);
+    assert!(built_executable_in_profile(&p_id, workspace, Some(Debug)).is_none());
+    assert!(built_executable_in_profile(&p_id, workspace, Some(Release)).is_some());
+}
+
+#[test]
+fn test_bad_profile() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    command_line_test_expect_fail([~"build", ~"--profile", ~"fast", ~"foo"],
+                                  workspace.path(), None, BAD_FLAG_CODE);
 }
 
 #[test]
@@ -1938,10 +4196,9 @@ fn test_install_to_rust_path() {
     let second_workspace = second_workspace.path();
     let first_workspace = mk_empty_workspace(&Path::new("p"), &NoVersion, "dest");
     let first_workspace = first_workspace.path();
-    // FIXME (#9639): This needs to handle non-utf8 paths
     let rust_path = Some(~[(~"RUST_PATH",
-                            format!("{}:{}", first_workspace.as_str().unwrap(),
-                                    second_workspace.as_str().unwrap()))]);
+                            rust_path_value([first_workspace.clone(),
+                                             second_workspace.clone()]))]);
     debug!("RUST_PATH={}:{}", first_workspace.display(), second_workspace.display());
     let test_sys = test_sysroot();
     // FIXME (#9639): This needs to handle non-utf8 paths
@@ -2066,9 +4323,8 @@ fn correct_package_name_with_rust_path_hack() {
               "extern mod blat; fn main() { let _x = (); }");
 
     let foo_path = foo_workspace.join_many(["src", "foo-0.1"]);
-    // FIXME (#9639): This needs to handle non-utf8 paths
-    let rust_path = Some(~[(~"RUST_PATH", format!("{}:{}", dest_workspace.as_str().unwrap(),
-                                                  foo_path.as_str().unwrap()))]);
+    let rust_path = Some(~[(~"RUST_PATH",
+                            rust_path_value([dest_workspace.clone(), foo_path.clone()]))]);
     // bar doesn't exist, but we want to make sure rustpkg doesn't think foo is bar
     command_line_test_expect_fail([~"install", ~"--rust-path-hack", ~"bar"],
                                   // FIXME #3408: Should be NONEXISTENT_PACKAGE_CODE
@@ -2106,6 +4362,30 @@ fn test_rustpkg_test_output() {
     assert!(output_str.contains("1 passed; 0 failed; 0 ignored; 0 measured"));
 }
 
+#[test]
+fn test_rustpkg_test_no_test_crates() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    fs::unlink(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]));
+    let output = command_line_test([~"test", ~"foo"], foo_workspace);
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains(format!("Package {} has no test crates", foo_id.to_str())));
+    assert!(!output_str.contains("Internal error"));
+}
+
+#[test]
+fn test_rustpkg_test_excluded_test_crates() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    let output = command_line_test([~"test", ~"--exclude", ~"test.rs", ~"foo"], foo_workspace);
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains("test crates were excluded with --exclude"));
+    assert!(!output_str.contains("Internal error"));
+    assert!(!test_executable_exists(foo_workspace, "foo"));
+}
+
 #[test]
 fn test_rustpkg_test_failure_exit_status() {
     let foo_id = PkgId::new("foo");
@@ -2120,6 +4400,166 @@ fn test_rustpkg_test_failure_exit_status() {
     }
 }
 
+#[test]
+fn test_rustpkg_test_several_test_crates() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+              "#[test] fn passes() { assert!('a' == 'a'); }");
+    let more_dir = foo_workspace.join_many(["src", "foo-0.1", "more"]);
+    fs::mkdir_recursive(&more_dir, io::UserRWX);
+    writeFile(&more_dir.join("test.rs"),
+              "#[test] fn fails() { assert!('a' != 'a'); }");
+    match command_line_test_partial([~"test", ~"foo"], foo_workspace) {
+        Success(*) => fail!("Expected test failure but got success"),
+        Fail(ref r) => {
+            let output = str::from_utf8(r.output);
+            assert!(output.contains("1 of 2 test crates for package foo passed"));
+            assert!(output.contains(format!("Tests failed in foo-moretest{}", os::EXE_SUFFIX)));
+            assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+        }
+    }
+    assert!(built_test_in_workspace(&foo_id, foo_workspace).is_some());
+    let more_id = PkgId { short_name: ~"foo-more", ..foo_id.clone() };
+    let more_test = built_test_in_workspace(&more_id, foo_workspace)
+        .expect("test_rustpkg_test_several_test_crates: foo-moretest wasn't built");
+
+    // Once more/test.rs is gone, its old executable isn't run, even though
+    // it's still in the build directory
+    fs::unlink(&more_dir.join("test.rs"));
+    command_line_test([~"test", ~"foo"], foo_workspace);
+    assert!(more_test.exists());
+}
+
+#[test]
+fn test_rustpkg_test_crates_in_similar_dirs() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    let src_dir = foo_workspace.join_many(["src", "foo-0.1"]);
+    // a-b/test.rs and a/b/test.rs each get an executable of their own
+    fs::mkdir_recursive(&src_dir.join("a-b"), io::UserRWX);
+    writeFile(&src_dir.join_many(["a-b", "test.rs"]),
+              "#[test] fn passes() { assert!('a' == 'a'); }");
+    fs::mkdir_recursive(&src_dir.join_many(["a", "b"]), io::UserRWX);
+    writeFile(&src_dir.join_many(["a", "b", "test.rs"]),
+              "#[test] fn fails() { assert!('a' != 'a'); }");
+    match command_line_test_partial([~"test", ~"foo"], foo_workspace) {
+        Success(*) => fail!("Expected test failure but got success"),
+        Fail(ref r) => {
+            let output = str::from_utf8(r.output);
+            // The package's own test.rs, and the two in subdirectories
+            assert!(output.contains("2 of 3 test crates for package foo passed"));
+            assert!(output.contains(format!("Tests failed in foo-a-btest{}", os::EXE_SUFFIX)));
+        }
+    }
+    for name in ["foo-a_-b", "foo-a-b"].iter() {
+        let test_id = PkgId { short_name: name.to_owned(), ..foo_id.clone() };
+        assert!(built_test_in_workspace(&test_id, foo_workspace).is_some());
+    }
+}
+
+#[test]
+fn test_rustpkg_test_timeout() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+              "#[test] fn f() { std::io::timer::sleep(600000); }");
+    match command_line_test_partial([~"test", ~"--test-timeout", ~"1", ~"foo"],
+                                    foo_workspace) {
+        Success(*) => fail!("Expected the test to time out, but it succeeded"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+            assert!(str::from_utf8(r.output).contains("timed out after 1 seconds"));
+        }
+    }
+}
+
+#[test]
+fn test_rustpkg_test_timeout_not_reached() {
+    let workspace = create_local_package_with_test(&PkgId::new("foo"));
+    let output = command_line_test([~"test", ~"--test-timeout", ~"600", ~"foo"],
+                                   workspace.path());
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains("1 passed; 0 failed; 0 ignored; 0 measured"));
+}
+
+#[test]
+fn test_rustpkg_test_threads() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+              "#[test] fn f() {
+                   let tasks = std::os::getenv(\"RUST_TEST_TASKS\").unwrap();
+                   println!(\"RUST_TEST_TASKS={}\", tasks);
+               }");
+    let output = command_line_test([~"test", ~"--test-threads", ~"3", ~"foo"], foo_workspace);
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains("RUST_TEST_TASKS=3"));
+
+    command_line_test_expect_fail([~"test", ~"--test-threads", ~"0", ~"foo"],
+                                  foo_workspace, None, BAD_FLAG_CODE);
+    command_line_test_expect_fail([~"build", ~"--test-threads", ~"3", ~"foo"],
+                                  foo_workspace, None, BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_rustpkg_test_isolated() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+              "#[test] fn f() {
+                   let home = std::os::getenv(\"HOME\").unwrap();
+                   assert!(home.contains(\"rustpkg-test-home\"));
+                   assert_eq!(std::os::getenv(\"RUST_PATH\"), Some(home));
+               }");
+    let output = command_line_test([~"test", ~"--isolated", ~"foo"], foo_workspace);
+    let output_str = str::from_utf8(output.output);
+    assert!(output_str.contains("1 passed; 0 failed; 0 ignored; 0 measured"));
+
+    // Without --isolated, the tests see rustpkg's own HOME
+    match command_line_test_partial([~"test", ~"foo"], foo_workspace) {
+        Success(*) => fail!("Expected the test to see the real HOME, but it passed"),
+        Fail(ref r) => assert!(str::from_utf8(r.output).contains("1 failed"))
+    }
+}
+
+#[test]
+fn test_rustpkg_test_harness_args() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+              "#[test] fn build_passes() {}
+               #[test] #[ignore] fn ignored_passes() {}
+               #[test] fn other_fails() { fail!() }");
+
+    // `build` is a rustpkg command, but after `--` it's a test filter
+    let output = command_line_test([~"test", ~"foo", ~"--", ~"build"], foo_workspace);
+    assert!(str::from_utf8(output.output).contains("1 passed; 0 failed; 0 ignored"));
+
+    let output = command_line_test([~"test", ~"foo", ~"--", ~"--ignored", ~"ignored"],
+                                   foo_workspace);
+    assert!(str::from_utf8(output.output).contains("1 passed; 0 failed; 0 ignored"));
+
+    // rustpkg's own flags after `--` go to the harness, which rejects them
+    match command_line_test_partial([~"test", ~"foo", ~"--", ~"--no-wait"], foo_workspace) {
+        Success(*) => fail!("Expected the test harness to reject --no-wait"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+            assert!(str::from_utf8(r.error).contains("Unrecognized option: 'no-wait'"));
+        }
+    }
+
+    // Only `test` and `run-example` take arguments after `--`
+    command_line_test_expect_fail([~"build", ~"foo", ~"--", ~"build"], foo_workspace, None,
+                                  BAD_FLAG_CODE);
+}
+
 #[test]
 fn test_rustpkg_test_cfg() {
     let foo_id = PkgId::new("foo");
@@ -2151,69 +4591,417 @@ fn test_rebuild_when_needed() {
         Fail(ref r) if r.status.matches_exit_status(65) => (), // ok
         Fail(_) => fail!("test_rebuild_when_needed failed for some other reason")
     }
-}
+}
+
+#[test]
+#[ignore] // FIXME (#10257): This doesn't work as is since a read only file can't execute
+fn test_no_rebuilding() {
+    let foo_id = PkgId::new("foo");
+    let foo_workspace = create_local_package(&foo_id);
+    let foo_workspace = foo_workspace.path();
+    let test_crate = foo_workspace.join_many(["src", "foo-0.1", "test.rs"]);
+    writeFile(&test_crate, "#[test] fn f() { assert!('a' == 'a'); }");
+    command_line_test([~"test", ~"foo"], foo_workspace);
+    assert!(test_executable_exists(foo_workspace, "foo"));
+    let test_executable = built_test_in_workspace(&foo_id,
+                            foo_workspace).expect("test_no_rebuilding failed");
+    chmod_read_only(&test_executable);
+    match command_line_test_partial([~"test", ~"foo"], foo_workspace) {
+        Success(*) => (), // ok
+        Fail(ref r) if r.status.matches_exit_status(65) =>
+            fail!("test_no_rebuilding failed: it rebuilt the tests"),
+        Fail(_) => fail!("test_no_rebuilding failed for some other reason")
+    }
+}
+
+#[test]
+fn test_installed_read_only() {
+    // Install sources from a "remote" (actually a local github repo)
+    // Check that afterward, sources are read-only and installed under build/
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    debug!("repo = {}", repo.display());
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    debug!("repo_subdir = {}", repo_subdir.display());
+
+    writeFile(&repo_subdir.join("main.rs"),
+              "fn main() { let _x = (); }");
+    writeFile(&repo_subdir.join("lib.rs"),
+              "pub fn f() { let _x = (); }");
+    add_git_tag(&repo_subdir, ~"0.1"); // this has the effect of committing the files
+
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([~"install", temp_pkg_id.path.as_str().unwrap().to_owned()], repo);
+
+    let ws = repo.join(".rust");
+    // Check that all files exist
+    debug!("Checking for files in {}", ws.display());
+    let exec = target_executable_in_workspace(&temp_pkg_id, &ws);
+    debug!("exec = {}", exec.display());
+    assert!(exec.exists());
+    assert!(is_rwx(&exec));
+    let built_lib =
+        built_library_in_workspace(&temp_pkg_id,
+                                   &ws).expect("test_install_git: built lib should exist");
+    assert!(built_lib.exists());
+    assert!(is_rwx(&built_lib));
+
+    // Make sure sources are (a) under "build" and (b) read-only
+    let src1 = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str(), ~"main.rs"]);
+    let src2 = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str(), ~"lib.rs"]);
+    assert!(src1.exists());
+    assert!(src2.exists());
+    assert!(is_read_only(&src1));
+    assert!(is_read_only(&src2));
+}
+
+#[test]
+fn test_update() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("main.rs"), "fn main() { println(\"version one\"); }");
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn f() {}");
+    add_all_and_commit(&repo_subdir);
+
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let pkg_path = temp_pkg_id.path.as_str().unwrap().to_owned();
+    command_line_test([~"install", pkg_path.clone()], repo);
+    let ws = repo.join(".rust");
+    let exec = target_executable_in_workspace(&temp_pkg_id, &ws);
+    let run_exec = || str::from_utf8_owned(run::process_output(exec.as_str().unwrap(),
+                                                               []).output);
+    assert!(run_exec().contains("version one"));
+
+    // Advance the "remote" repository, and pick up the new commit
+    writeFile(&repo_subdir.join("main.rs"), "fn main() { println(\"version two\"); }");
+    add_all_and_commit(&repo_subdir);
+    let output = command_line_test([~"update", pkg_path], repo);
+    assert!(str::from_utf8(output.output).contains("Updated the sources"));
+    let src = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str(), ~"main.rs"]);
+    assert!(str::from_utf8_owned(File::open(&src).read_to_end()).contains("version two"));
+    assert!(is_read_only(&src));
+    assert!(run_exec().contains("version two"));
+
+    // Sources that aren't a git clone can't be updated
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let output = command_line_test([~"update", ~"foo"], workspace.path());
+    assert!(str::from_utf8(output.output).contains("aren't a git clone"));
+}
+
+#[test]
+fn test_install_git_shallow_clone() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("main.rs"),
+              "fn main() { let _x = (); }");
+    writeFile(&repo_subdir.join("lib.rs"),
+              "pub fn f() { let _x = (); }");
+    add_all_and_commit(&repo_subdir);
+    add_git_tag(&repo_subdir, ~"0.1"); // this has the effect of committing the files
+
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([~"install", ~"--depth", ~"1",
+                       temp_pkg_id.path.as_str().unwrap().to_owned()], repo);
+
+    let ws = repo.join(".rust");
+    assert!(target_executable_in_workspace(&temp_pkg_id, &ws).exists());
+    assert_lib_exists(&ws, &temp_pkg_id.path, temp_pkg_id.version.clone());
+
+    // The checkout should be shallow, and its sources read-only
+    let checkout = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str()]);
+    assert!(checkout.join_many([".git", "shallow"]).exists());
+    assert!(is_read_only(&checkout.join("main.rs")));
+    assert!(is_read_only(&checkout.join("lib.rs")));
+}
+
+#[test]
+fn test_install_git_tmp_dir() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("main.rs"),
+              "fn main() { let _x = (); }");
+    writeFile(&repo_subdir.join("lib.rs"),
+              "pub fn f() { let _x = (); }");
+    add_all_and_commit(&repo_subdir);
+    add_git_tag(&repo_subdir, ~"0.1");
+
+    let scratch = TempDir::new("test_install_git_tmp_dir").expect("couldn't create temp dir");
+    let scratch = scratch.path().join("scratch");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([~"install", ~"--tmp-dir", scratch.as_str().unwrap().to_owned(),
+                       temp_pkg_id.path.as_str().unwrap().to_owned()], repo);
+
+    let ws = repo.join(".rust");
+    assert!(target_executable_in_workspace(&temp_pkg_id, &ws).exists());
+    assert_lib_exists(&ws, &temp_pkg_id.path, temp_pkg_id.version.clone());
+
+    // The clone was made under the scratch directory, and then moved
+    // into the workspace, leaving nothing behind
+    let checkout = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str()]);
+    assert!(checkout.join(".git").is_dir());
+    assert!(checkout.join("main.rs").exists());
+    assert!(checkout.join("lib.rs").exists());
+    assert!(scratch.is_dir());
+    assert!(fs::walk_dir(&scratch).all(|p| p.is_dir()));
+}
+
+#[test]
+fn test_depth_only_for_build_and_install() {
+    let foo_id = PkgId::new("foo");
+    let workspace = create_local_package(&foo_id);
+    let workspace = workspace.path();
+    command_line_test_expect_fail([~"test", ~"--depth", ~"1", ~"foo"],
+                                  workspace, None, BAD_FLAG_CODE);
+    command_line_test_expect_fail([~"install", ~"--depth", ~"0", ~"foo"],
+                                  workspace, None, BAD_FLAG_CODE);
+}
+
+#[test]
+fn test_clone_is_current() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn f() { let _x = (); }");
+    add_git_tag(&repo_subdir, ~"0.1");
+
+    let target_dir = repo.join_many([".rust", "src", "mockgithub.com", "catamorphism",
+                                     "test-pkg"]);
+    let tagged = Tagged(~"0.1");
+    // Nothing has been cloned yet
+    assert!(!clone_is_current(&repo_subdir, &target_dir, &tagged));
+    match safe_git_clone(&repo_subdir, &tagged, &target_dir, None, &None) {
+        CheckedOutSources => (),
+        _ => fail!("test_clone_is_current: couldn't clone")
+    };
+    // So a second build of the same version wouldn't clone again...
+    assert!(clone_is_current(&repo_subdir, &target_dir, &tagged));
+    assert!(clone_is_current(&repo_subdir, &target_dir, &NoVersion));
+
+    // ...but after a new commit, a build of the latest version would
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn g() { let _x = (); }");
+    add_all_and_commit(&repo_subdir);
+    assert!(clone_is_current(&repo_subdir, &target_dir, &tagged));
+    assert!(!clone_is_current(&repo_subdir, &target_dir, &NoVersion));
+    assert!(!clone_is_current(&repo_subdir, &target_dir, &Tagged(~"0.2")));
+}
+
+#[test]
+fn test_clone_nonexistent_tag() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn f() { let _x = (); }");
+    add_git_tag(&repo_subdir, ~"0.1");
+
+    let target_dir = repo.join_many([".rust", "src", "mockgithub.com", "catamorphism",
+                                     "test-pkg"]);
+    match safe_git_clone(&repo_subdir, &Tagged(~"0.2"), &target_dir, None, &None) {
+        CloneFailed(_, status, error) => {
+            assert!(!status.success());
+            debug!("git_clone failed with {}", error);
+            assert!(error.contains("requested version 0.2 not found, got "));
+        }
+        _ => fail!("test_clone_nonexistent_tag: cloning a nonexistent tag should have failed")
+    }
+    // The clone at the wrong version isn't left behind to be built
+    assert!(!target_dir.exists());
+
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    match command_line_test_partial([~"install", format!("{}\\#0.2",
+                                     temp_pkg_id.path.as_str().unwrap())], repo) {
+        Success(*) => fail!("test_clone_nonexistent_tag: install should have failed"),
+        Fail(r) => assert!(str::from_utf8(r.output).contains("requested version 0.2 not found"))
+    }
+    assert!(!executable_exists(repo, "test-pkg"));
+}
+
+#[test]
+fn test_clone_version_names_a_file() {
+    let temp_pkg_id = git_repo_pkg();
+    let repo = init_git_repo(&temp_pkg_id.path);
+    let repo = repo.path();
+    let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn f() { let _x = (); }");
+    // So `git checkout 0.2` succeeds, by checking out this file rather than
+    // a commit
+    writeFile(&repo_subdir.join("0.2"), "");
+    add_git_tag(&repo_subdir, ~"0.1");
+
+    let target_dir = repo.join_many([".rust", "src", "mockgithub.com", "catamorphism",
+                                     "test-pkg"]);
+    match safe_git_clone(&repo_subdir, &Tagged(~"0.2"), &target_dir, None, &None) {
+        WrongVersion(_, error) => {
+            debug!("git_clone failed with {}", error);
+            assert!(error.contains("requested version 0.2 not found, got "));
+        }
+        _ => fail!("test_clone_version_names_a_file: cloning a version that isn't a commit \
+                    should have failed")
+    }
+    assert!(!target_dir.exists());
 
-#[test]
-#[ignore] // FIXME (#10257): This doesn't work as is since a read only file can't execute
-fn test_no_rebuilding() {
-    let foo_id = PkgId::new("foo");
-    let foo_workspace = create_local_package(&foo_id);
-    let foo_workspace = foo_workspace.path();
-    let test_crate = foo_workspace.join_many(["src", "foo-0.1", "test.rs"]);
-    writeFile(&test_crate, "#[test] fn f() { assert!('a' == 'a'); }");
-    command_line_test([~"test", ~"foo"], foo_workspace);
-    assert!(test_executable_exists(foo_workspace, "foo"));
-    let test_executable = built_test_in_workspace(&foo_id,
-                            foo_workspace).expect("test_no_rebuilding failed");
-    chmod_read_only(&test_executable);
-    match command_line_test_partial([~"test", ~"foo"], foo_workspace) {
-        Success(*) => (), // ok
-        Fail(ref r) if r.status.matches_exit_status(65) =>
-            fail!("test_no_rebuilding failed: it rebuilt the tests"),
-        Fail(_) => fail!("test_no_rebuilding failed for some other reason")
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    match command_line_test_partial([~"install", format!("{}\\#0.2",
+                                     temp_pkg_id.path.as_str().unwrap())], repo) {
+        Success(*) => fail!("test_clone_version_names_a_file: install should have failed"),
+        Fail(r) => {
+            let output = str::from_utf8(r.output);
+            assert!(output.contains("requested version 0.2 not found"));
+            // It isn't reported as git failing, since git didn't
+            assert!(!output.contains("git failed with"));
+        }
     }
+    assert!(!executable_exists(repo, "test-pkg"));
 }
 
 #[test]
-fn test_installed_read_only() {
-    // Install sources from a "remote" (actually a local github repo)
-    // Check that afterward, sources are read-only and installed under build/
+fn test_clone_from_file_url() {
     let temp_pkg_id = git_repo_pkg();
     let repo = init_git_repo(&temp_pkg_id.path);
     let repo = repo.path();
-    debug!("repo = {}", repo.display());
     let repo_subdir = repo.join_many(["mockgithub.com", "catamorphism", "test-pkg"]);
-    debug!("repo_subdir = {}", repo_subdir.display());
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn f() { let _x = (); }");
+    add_git_tag(&repo_subdir, ~"0.1");
+    writeFile(&repo_subdir.join("lib.rs"), "pub fn g() { let _x = (); }");
+    add_all_and_commit(&repo_subdir);
 
-    writeFile(&repo_subdir.join("main.rs"),
-              "fn main() { let _x = (); }");
-    writeFile(&repo_subdir.join("lib.rs"),
-              "pub fn f() { let _x = (); }");
-    add_git_tag(&repo_subdir, ~"0.1"); // this has the effect of committing the files
+    let target = repo.join("clone");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let url = format!("file://{}", os::make_absolute(&repo_subdir).as_str().unwrap());
+    git_clone_url(url, &target, &Tagged(~"0.1"), None);
+    assert!(is_git_dir(&target));
+    // The tagged version is checked out, not the latest one
+    assert_eq!(File::open(&target.join("lib.rs")).read_to_end(),
+               "pub fn f() { let _x = (); }".as_bytes().to_owned());
+}
+
+#[test]
+fn test_clone_from_plain_dir() {
+    let tmp = TempDir::new("test_clone_from_plain_dir").expect("couldn't create temp dir");
+    let source = tmp.path().join("source");
+    fs::mkdir_recursive(&source.join("sub"), io::UserRWX);
+    writeFile(&source.join("lib.rs"), "pub fn f() {}");
+    writeFile(&source.join_many(["sub", "main.rs"]), "fn main() {}");
+
+    let check_copy = |target: &Path| {
+        assert!(!is_git_dir(target));
+        assert_eq!(File::open(&target.join("lib.rs")).read_to_end(),
+                   "pub fn f() {}".as_bytes().to_owned());
+        assert_eq!(File::open(&target.join_many(["sub", "main.rs"])).read_to_end(),
+                   "fn main() {}".as_bytes().to_owned());
+    };
 
     // FIXME (#9639): This needs to handle non-utf8 paths
-    command_line_test([~"install", temp_pkg_id.path.as_str().unwrap().to_owned()], repo);
+    let url = format!("file://{}", source.as_str().unwrap());
+    let url_target = tmp.path().join("from-url");
+    git_clone_url(url, &url_target, &NoVersion, None);
+    check_copy(&url_target);
+
+    let target = tmp.path().join("from-path");
+    match safe_git_clone(&source, &NoVersion, &target, None, &None) {
+        CheckedOutSources => make_read_only(&target),
+        _ => fail!("test_clone_from_plain_dir: couldn't copy {}", source.display())
+    }
+    check_copy(&target);
+    assert!(is_read_only(&target.join("lib.rs")));
+
+    // Copying again replaces the old, read-only copy
+    writeFile(&source.join("lib.rs"), "pub fn g() {}");
+    match safe_git_clone(&source, &NoVersion, &target, None, &None) {
+        CheckedOutSources => (),
+        _ => fail!("test_clone_from_plain_dir: couldn't copy {} again", source.display())
+    }
+    assert_eq!(File::open(&target.join("lib.rs")).read_to_end(),
+               "pub fn g() {}".as_bytes().to_owned());
+}
 
-    let ws = repo.join(".rust");
-    // Check that all files exist
-    debug!("Checking for files in {}", ws.display());
-    let exec = target_executable_in_workspace(&temp_pkg_id, &ws);
-    debug!("exec = {}", exec.display());
-    assert!(exec.exists());
-    assert!(is_rwx(&exec));
-    let built_lib =
-        built_library_in_workspace(&temp_pkg_id,
-                                   &ws).expect("test_install_git: built lib should exist");
-    assert!(built_lib.exists());
-    assert!(is_rwx(&built_lib));
+#[test]
+fn test_git_checkout_failed_has_git_error() {
+    use conditions::git_checkout_failed::cond;
 
-    // Make sure sources are (a) under "build" and (b) read-only
-    let src1 = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str(), ~"main.rs"]);
-    let src2 = target_build_dir(&ws).join_many([~"src", temp_pkg_id.to_str(), ~"lib.rs"]);
-    assert!(src1.exists());
-    assert!(src2.exists());
-    assert!(is_read_only(&src1));
-    assert!(is_read_only(&src2));
+    let tmp = TempDir::new("test_git_checkout_failed").expect("couldn't create temp dir");
+    let source = tmp.path().join("no-such-repo");
+    let target = tmp.path().join("clone");
+    let mut failure = None;
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    cond.trap(|(_, _, status, git_error)| {
+        failure = Some((status, git_error));
+    }).inside(|| git_clone_url(source.as_str().unwrap(), &target, &NoVersion, None));
+    let (status, git_error) = failure.expect("cloning a nonexistent repo should have failed");
+    assert!(!status.success());
+    debug!("git said: {}", git_error);
+    assert!(git_error.contains("no-such-repo"));
+}
+
+/// A source fetcher that writes a one-line library instead of fetching
+struct TestFetcher;
+
+impl SourceFetcher for TestFetcher {
+    fn name(&self) -> &'static str { "test" }
+
+    fn fetch(&self, url: &str, _v: &Version, target: &Path) -> CloneResult {
+        fs::mkdir_recursive(target, io::UserRWX);
+        writeFile(&target.join("lib.rs"), format!("// fetched from {}", url));
+        CheckedOutSources
+    }
+}
+
+#[test]
+fn test_fetcher_dispatch_by_scheme() {
+    let mut fetchers = FetcherRegistry::with_defaults(None);
+    fetchers.register("test", ~TestFetcher as ~SourceFetcher:Send);
+    assert_eq!(fetchers.fetcher_name("test://mockgithub.com/foo"), Some("test"));
+    assert_eq!(fetchers.fetcher_name("https://mockgithub.com/foo"), Some("git"));
+    assert_eq!(fetchers.fetcher_name("file:///tmp/foo"), Some("git"));
+    assert_eq!(fetchers.fetcher_name("hg://mockgithub.com/foo"), None);
+    assert_eq!(fetchers.fetcher_name("mockgithub.com/foo"), None);
+
+    let tmp = TempDir::new("test_fetcher_dispatch").expect("couldn't create temp dir");
+    let target = tmp.path().join("clone");
+    match fetchers.fetch("test://mockgithub.com/foo", &NoVersion, &target) {
+        Some(CheckedOutSources) => (),
+        _ => fail!("test_fetcher_dispatch_by_scheme: the test fetcher wasn't used")
+    }
+    assert_eq!(File::open(&target.join("lib.rs")).read_to_end(),
+               "// fetched from test://mockgithub.com/foo".as_bytes().to_owned());
+
+    // Registering a fetcher for a scheme replaces the old one
+    fetchers.register("https", ~TestFetcher as ~SourceFetcher:Send);
+    assert_eq!(fetchers.fetcher_name("https://mockgithub.com/foo"), Some("test"));
+    assert!(fetchers.fetch("hg://mockgithub.com/foo", &NoVersion, &target).is_none());
+}
+
+#[test]
+fn test_build_fetches_by_package_id_scheme() {
+    use api::{new_default_context, new_workcache_context};
+
+    let workspace = mk_emptier_workspace("test_build_fetches_by_package_id_scheme");
+    let workspace = workspace.path();
+    let ctxt = new_default_context(new_workcache_context(workspace), test_sysroot());
+    unsafe { ctxt.fetchers.with(|f| f.register("test", ~TestFetcher as ~SourceFetcher:Send)); }
+    // Built by hand, so that nothing tries to look up its version remotely
+    let p_id = PkgId {
+        path: Path::new("test://mockgithub.com/foo"),
+        short_name: ~"foo",
+        version: NoVersion
+    };
+    assert_eq!(p_id.remote_url(), ~"test://mockgithub.com/foo");
+    assert_eq!(git_repo_pkg().remote_url(), ~"https://mockgithub.com/catamorphism/test-pkg");
+
+    let mut pkg_src = PkgSrc::new_with_fetchers(workspace.clone(), workspace.clone(), false,
+                                                p_id.clone(), None, &ctxt.fetchers, &None);
+    let fetched = File::open(&pkg_src.start_dir.join("lib.rs")).read_to_end();
+    assert!(str::from_utf8(fetched).contains("fetched from test://mockgithub.com/foo"));
+    assert!(ctxt.build(&mut pkg_src, &WhatToBuild::new(MaybeCustom, Everything)) == Built(()));
+    assert!(built_library_in_workspace(&p_id, workspace).is_some());
 }
 
 #[test]
@@ -2246,11 +5034,11 @@ fn test_installed_local_changes() {
                                                   "test-pkg-0.1"]);
     debug!("---- git clone {} {}", repo_subdir.display(), target_dir.display());
 
-    let c_res = safe_git_clone(&repo_subdir, &NoVersion, &target_dir);
+    let c_res = safe_git_clone(&repo_subdir, &NoVersion, &target_dir, None, &None);
 
     match c_res {
-        DirToUse(_) => fail!("test_installed_local_changes failed"),
-        CheckedOutSources => ()
+        CheckedOutSources => (),
+        _ => fail!("test_installed_local_changes failed")
     };
 
     // Make a local change to it
@@ -2264,13 +5052,55 @@ fn test_installed_local_changes() {
               "extern mod test = \"mockgithub.com/catamorphism/test-pkg\"; \
               use test::g;
               fn main() { g(); }");
-    // And make sure we can build it
+    // And make sure we can build it, once we say the local change is wanted
 
     // FIXME (#9639): This needs to handle non-utf8 paths
-    command_line_test([~"build", importer_pkg_id.path.as_str().unwrap().to_owned()],
+    command_line_test([~"build", ~"--allow-dirty",
+                       importer_pkg_id.path.as_str().unwrap().to_owned()],
                       hacking_workspace);
 }
 
+#[test]
+fn test_allow_dirty() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let package_dir = workspace.join_many(["src", "foo-0.1"]);
+    run_git([~"init"], None, &package_dir,
+            format!("Couldn't initialize git repository in {}", package_dir.display()));
+    add_all_and_commit(&package_dir);
+
+    // Files git doesn't know about don't make the sources dirty
+    writeFile(&package_dir.join("notes.txt"), "not a source file");
+    command_line_test([~"build", ~"foo"], workspace);
+
+    // Changes that haven't been committed do
+    writeFile(&package_dir.join("lib.rs"), "pub fn f() { let _x = 1; }");
+    match command_line_test_partial([~"install", ~"foo"], workspace) {
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(DIRTY_SOURCES_CODE));
+            let out = str::from_utf8(r.output);
+            assert!(out.contains("uncommitted changes to: lib.rs"));
+            assert!(out.contains("--allow-dirty"));
+        }
+        Success(..) => fail!("test_allow_dirty: installing dirty sources should fail")
+    }
+    assert!(!lib_exists(workspace, &p_id.path, p_id.version.clone()));
+
+    command_line_test([~"install", ~"--allow-dirty", ~"foo"], workspace);
+    assert_lib_exists(workspace, &p_id.path, p_id.version.clone());
+    // Testing builds the package too, so it needs the flag as well
+    match command_line_test_partial([~"test", ~"foo"], workspace) {
+        Fail(r) => assert!(r.status.matches_exit_status(DIRTY_SOURCES_CODE)),
+        Success(..) => fail!("test_allow_dirty: testing dirty sources should fail")
+    }
+    command_line_test([~"test", ~"--allow-dirty", ~"foo"], workspace);
+
+    // Committing the change makes the sources clean again
+    add_all_and_commit(&package_dir);
+    command_line_test([~"build", ~"foo"], workspace);
+}
+
 #[test]
 fn test_7402() {
     let dir = create_local_package(&PkgId::new("foo"));
@@ -2440,7 +5270,621 @@ fn correct_error_dependency() {
     }
 }
 
+#[test]
+fn install_from_file() {
+    let foo_id = PkgId::new("foo");
+    let bar_id = PkgId::new("bar");
+    let workspace = create_local_package(&foo_id);
+    let workspace = workspace.path();
+    create_local_package_in(&bar_id, workspace);
+    let list = workspace.join("packages.txt");
+    writeFile(&list, "# Packages for this machine\n\nfoo\n  bar   # the other one\n");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test([~"install", ~"--from-file", list.as_str().unwrap().to_owned()],
+                      workspace);
+    assert_executable_exists(workspace, "foo");
+    assert_executable_exists(workspace, "bar");
+    assert_lib_exists(workspace, &foo_id.path, NoVersion);
+    assert_lib_exists(workspace, &bar_id.path, NoVersion);
+}
+
+#[test]
+fn install_from_file_keeps_going() {
+    let foo_id = PkgId::new("foo");
+    let workspace = create_local_package(&foo_id);
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "lib.rs"]),
+              "pub fn f() { this_is_a_type_error + 1 }");
+    let bar_id = PkgId::new("bar");
+    create_local_package_in(&bar_id, workspace);
+    let list = workspace.join("packages.txt");
+    writeFile(&list, "foo\nbar");
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    match command_line_test_partial([~"install",
+                                      ~"--from-file",
+                                      list.as_str().unwrap().to_owned()], workspace) {
+        Success(*) => fail!("install --from-file should have failed"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+            let out = str::from_utf8(r.output);
+            assert!(out.contains("install: package bar succeeded"));
+            assert!(out.contains("install: 1 of 2 packages failed: foo"));
+        }
+    }
+    // bar still gets installed, even though foo came first and failed
+    assert_executable_exists(workspace, "bar");
+    assert!(!executable_exists(workspace, "foo"));
+}
+
+#[test]
+fn install_from_file_bad_flag() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    let list = workspace.join("packages.txt");
+    writeFile(&list, "foo");
+    // --from-file is only valid for install
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    command_line_test_expect_fail([~"build", ~"--from-file", list.as_str().unwrap().to_owned()],
+                                  workspace, None, BAD_FLAG_CODE);
+    // and the list file has to exist
+    command_line_test_expect_fail([~"install", ~"--from-file", ~"no-such-file.txt"],
+                                  workspace, None, BAD_FLAG_CODE);
+    // and be UTF-8
+    File::create(&list).write([0xff, 0xfe, 'f' as u8, 'o' as u8, 'o' as u8]);
+    match command_line_test_partial([~"install", ~"--from-file",
+                                     list.as_str().unwrap().to_owned()], workspace) {
+        Success(*) => fail!("install_from_file_bad_flag: a non-UTF-8 list was accepted"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(BAD_FLAG_CODE));
+            assert!(str::from_utf8(r.output).contains(
+                format!("Couldn't read the package list {}: it isn't UTF-8", list.display())));
+        }
+    }
+}
+
 /// Returns true if p exists and is executable
+#[cfg(not(windows))]
 fn is_executable(p: &Path) -> bool {
     p.exists() && p.stat().perm & io::UserExecute == io::UserExecute
 }
+
+/// Returns true if p exists (on Windows, whether a file is executable
+/// depends only on its name)
+#[cfg(windows)]
+fn is_executable(p: &Path) -> bool {
+    p.exists()
+}
+
+#[test]
+fn test_uninstall_then_reinstall() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    command_line_test([~"install", ~"foo"], workspace);
+    assert_executable_exists(workspace, "foo");
+    command_line_test([~"uninstall", ~"foo"], workspace);
+    assert!(!executable_exists(workspace, "foo"));
+    let outputs = new_workcache_context(workspace).db.read(|db| {
+        db.discovered_outputs(p_id.install_tag())
+    });
+    assert!(outputs.is_empty());
+
+    // Nothing changed in the sources, but the installed files are gone, so
+    // installing again has to copy them again
+    command_line_test([~"install", ~"foo"], workspace);
+    assert_executable_exists(workspace, "foo");
+}
+
+#[test]
+fn test_build_several_packages() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    create_local_package_in(&PkgId::new("bar"), workspace);
+    create_local_package_in(&PkgId::new("baz"), workspace);
+    command_line_test([~"build", ~"foo", ~"bar", ~"baz"], workspace);
+    assert_built_executable_exists(workspace, "foo");
+    assert_built_executable_exists(workspace, "bar");
+    assert_built_executable_exists(workspace, "baz");
+}
+
+#[test]
+fn test_build_several_packages_keep_going() {
+    let workspace = create_local_package(&PkgId::new("foo"));
+    let workspace = workspace.path();
+    writeFile(&workspace.join_many(["src", "foo-0.1", "lib.rs"]),
+              "pub fn f() { this_is_a_type_error + 1 }");
+    create_local_package_in(&PkgId::new("bar"), workspace);
+
+    // Without --keep-going, bar isn't built once foo has failed
+    match command_line_test_partial([~"build", ~"foo", ~"bar"], workspace) {
+        Success(*) => fail!("build should have failed"),
+        Fail(ref r) => assert!(str::from_utf8(r.output).contains("build: package foo failed"))
+    }
+    assert!(!built_executable_exists(workspace, "bar"));
+
+    match command_line_test_partial([~"build", ~"--keep-going", ~"foo", ~"bar"], workspace) {
+        Success(*) => fail!("build --keep-going should have failed"),
+        Fail(ref r) => {
+            assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+            assert!(str::from_utf8(r.output).contains("1 of 2 packages failed: foo"));
+        }
+    }
+    assert_built_executable_exists(workspace, "bar");
+}
+
+#[test]
+fn test_pkg_script_conditional_target() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let package_dir = workspace.join_many(["src", "foo-0.1"]);
+    fs::mkdir_recursive(&package_dir.join_many(["src", "gpu"]), io::UserRWX);
+    writeFile(&package_dir.join_many(["src", "gpu", "lib.rs"]),
+              "pub fn render() {}");
+    fs::mkdir_recursive(&package_dir.join_many(["src", "opencl"]), io::UserRWX);
+    writeFile(&package_dir.join_many(["src", "opencl", "main.rs"]),
+              "fn main() {}");
+    // The script builds nothing itself, and only asks for the gpu library
+    // when it emits the gpu cfg, which it always does here
+    writeFile(&package_dir.join("pkg.rs"),
+              "use std::os;
+               fn main() {
+                   let args = os::args();
+                   if args[2] == ~\"configs\" {
+                       println(\"gpu target(gpu):src/gpu/lib.rs \\
+                                target(opencl):src/opencl/main.rs\");
+                   }
+               }");
+    command_line_test([~"build", ~"foo"], workspace);
+    assert_built_library_exists(workspace, "foo");
+    assert!(!built_executable_exists(workspace, "foo"));
+}
+
+#[test]
+fn test_pkg_script_truncated_configs() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let package_dir = workspace.join_many(["src", "foo-0.1"]);
+    // 17MB of configs, more than rustpkg keeps of what a hook prints
+    writeFile(&package_dir.join("pkg.rs"),
+              "use std::os;
+               fn main() {
+                   let args = os::args();
+                   if args[2] == ~\"configs\" {
+                       let line = \"x\".repeat(1023);
+                       for _ in range(0, 17 * 1024) { println(line.as_slice()); }
+                   }
+               }");
+
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_truncated_configs: expected the build to fail"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(BAD_PKG_SCRIPT_CODE));
+            assert!(str::from_utf8(r.output).contains("for its configs hook"));
+        }
+    }
+}
+
+#[test]
+fn test_pkg_script_config_args() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let package_dir = workspace.join_many(["src", "foo-0.1"]);
+    // The script turns its extra arguments into cfgs, which decide whether
+    // the library compiles
+    writeFile(&package_dir.join("pkg.rs"),
+              "use std::os;
+               fn main() {
+                   let args = os::args();
+                   if args[2] == ~\"configs\" {
+                       println(args.slice_from(3).connect(\" \") + \" target:lib.rs\");
+                   }
+               }");
+    writeFile(&package_dir.join("lib.rs"),
+              "#[cfg(not(with_cuda))] pub fn f() { let _x: int = ~\"not an int\"; }");
+
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_config_args: expected the build to fail"),
+        Fail(*) => ()
+    }
+
+    command_line_test([~"build", ~"--config-arg", ~"with_cuda", ~"foo"], workspace);
+    assert_built_library_exists(workspace, "foo");
+
+    // The build with the config arg isn't used for one without it
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_config_args: a cached build was used"),
+        Fail(*) => ()
+    }
+}
+
+#[test]
+fn test_pkg_script_variant() {
+    let p_id = PkgId::new("foo");
+    let workspace = create_local_package(&p_id);
+    let workspace = workspace.path();
+    let package_dir = workspace.join_many(["src", "foo-0.1"]);
+    // Each script asks for a different cfg, which decides whether the
+    // library compiles
+    let script = |cfg: &str| format!("use std::os;
+               fn main() \\{
+                   let args = os::args();
+                   if args[2] == ~\"configs\" \\{
+                       println(\"{}\");
+                   \\}
+               \\}", cfg);
+    writeFile(&package_dir.join("pkg.rs"), script("full"));
+    writeFile(&package_dir.join("pkg-minimal.rs"), script("minimal"));
+    writeFile(&package_dir.join("lib.rs"),
+              "#[cfg(not(minimal))] pub fn f() { let _x: int = ~\"not an int\"; }");
+
+    // Without --variant, pkg.rs is used, so the library doesn't compile
+    match command_line_test_partial([~"build", ~"foo"], workspace) {
+        Success(*) => fail!("test_pkg_script_variant: expected pkg.rs's cfgs to be used"),
+        Fail(*) => ()
+    }
+
+    command_line_test([~"--variant", ~"minimal", ~"build", ~"foo"], workspace);
+    assert_built_library_exists(workspace, "foo");
+
+    // A variant the package has no script for falls back on pkg.rs
+    let output = match command_line_test_partial([~"--variant", ~"tiny", ~"build", ~"foo"],
+                                                 workspace) {
+        Success(r) | Fail(r) => str::from_utf8(r.output)
+    };
+    assert!(output.contains("has no package script for variant `tiny`"));
+}
+
+#[test]
+fn test_ambiguous_package_id() {
+    let p_id = PkgId::new("foo");
+    let a = create_local_package(&p_id);
+    let b = create_local_package(&p_id);
+    let (a, b) = (a.path(), b.path());
+    let cwd = TempDir::new("ambiguous").expect("couldn't create temp dir");
+    let cwd = cwd.path();
+    let env = Some(~[(~"RUST_PATH", rust_path_value([a.clone(), b.clone()]))]);
+
+    // By default, rustpkg won't guess which foo is meant
+    match command_line_test_with_env([~"build", ~"foo"], cwd, env.clone()) {
+        Success(*) => fail!("test_ambiguous_package_id: build should have failed"),
+        Fail(r) => {
+            assert!(r.status.matches_exit_status(WORKSPACE_PROBLEMS_CODE));
+            let output = str::from_utf8(r.output);
+            assert!(output.contains(format!("Package {} is in more than one workspace: {}, {}",
+                                            p_id.to_str(), a.display(), b.display())));
+        }
+    }
+    assert!(!built_executable_exists(a, "foo"));
+    assert!(!built_executable_exists(b, "foo"));
+
+    command_line_test_with_env([~"build", ~"--ambiguous", ~"first", ~"foo"], cwd, env.clone());
+    assert_built_executable_exists(a, "foo");
+    assert!(!built_executable_exists(b, "foo"));
+
+    command_line_test_with_env([~"build", ~"--ambiguous", ~"all", ~"foo"], cwd, env.clone());
+    assert_built_executable_exists(b, "foo");
+
+    command_line_test_expect_fail([~"build", ~"--ambiguous", ~"last", ~"foo"], cwd, env,
+                                  BAD_FLAG_CODE);
+}
+
+/// Tests that depend on how Unix does things: signals, and directory
+/// permissions (on Windows, files can still be created in a read-only
+/// directory), and RUST_PATH's format there
+#[cfg(unix)]
+mod unix {
+    use std::{libc, os, run, str};
+    use std::io;
+    use std::io::fs;
+    use std::io::timer;
+    use rustc::metadata::filesearch::rust_path;
+    use package_id::PkgId;
+    use path_util::{built_executable_in_workspace, chmod_read_only};
+    use source_control::make_read_only;
+    use util::read_only_ancestor;
+    use exit_codes::COPY_FAILED_CODE;
+    use super::{Success, Fail, create_local_package, writeFile, rustpkg_exec};
+    use super::{command_line_test, command_line_test_partial, command_line_test_with_env};
+    use super::{assert_built_library_exists, assert_built_executable_exists};
+
+    #[test]
+    fn rust_path_parse() {
+        os::setenv("RUST_PATH", "/a/b/c:/d/e/f:/g/h/i");
+        let paths = rust_path();
+        assert!(paths.contains(&Path::new("/g/h/i")));
+        assert!(paths.contains(&Path::new("/d/e/f")));
+        assert!(paths.contains(&Path::new("/a/b/c")));
+        os::unsetenv("RUST_PATH");
+    }
+
+    #[test]
+    fn test_lock_not_inherited() {
+        use lock::WorkspaceLock;
+
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        // A package script that leaves a process running after rustpkg exits
+        writeFile(&workspace.join_many(["src", "foo-0.1", "pkg.rs"]),
+                  "fn main() { std::run::process_status(\"sh\", \
+                       [~\"-c\", ~\"sleep 10 >/dev/null 2>&1 &\"]); }");
+        command_line_test([~"build", ~"foo"], workspace);
+        assert!(WorkspaceLock::acquire(workspace, false).is_some());
+    }
+
+    #[test]
+    fn test_run_example_unlocked() {
+        use lock::lock_file;
+
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        let examples = workspace.join_many(["src", "foo-0.1", "examples"]);
+        fs::mkdir_recursive(&examples, io::UserRWX);
+        // The example checks whether it can take the workspace lock
+        writeFile(&examples.join("lock.rs"),
+                  format!("use std::libc;
+                           extern \{ fn flock(fd: libc::c_int, op: libc::c_int) -> libc::c_int; \}
+                           fn main() \{
+                               let fd = \"{}\".with_c_str(|p| unsafe \{
+                                   libc::open(p, libc::O_RDWR, 0)
+                               \});
+                               let locked = unsafe \{ flock(fd, 2 | 4) \} != 0;
+                               println!(\"locked: \{\}\", locked);
+                           \}", lock_file(workspace).display()));
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let env = ~[(~"RUST_PATH", workspace.as_str().unwrap().to_owned())];
+        match command_line_test_with_env([~"run-example", ~"foo", ~"lock"], workspace,
+                                         Some(env)) {
+            Success(r) => assert!(str::from_utf8(r.output).contains("locked: false")),
+            Fail(*) => fail!("test_run_example_unlocked: run-example failed")
+        }
+    }
+
+    #[test]
+    fn test_build_watch() {
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        let main_file = workspace.join_many(["src", "foo-0.1", "main.rs"]);
+        writeFile(&main_file, "fn main() { println(\"first\"); }");
+
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let exec_path = rustpkg_exec();
+        let mut prog = run::Process::new(exec_path.as_str().unwrap(),
+                                         [~"build", ~"--watch", ~"foo"],
+                                         run::ProcessOptions {
+                                             env: None,
+                                             dir: Some(workspace),
+                                             in_fd: None,
+                                             out_fd: None,
+                                             err_fd: None
+                                         });
+        // Reads rustpkg's output until `line` appears in it
+        let wait_for = |prog: &mut run::Process, line: &str| {
+            let mut output = ~"";
+            while !output.contains(line) {
+                match prog.output().read_byte() {
+                    Some(b) => output.push_char(b as char),
+                    None => fail!("test_build_watch: rustpkg exited before printing `{}`; \
+                                   its output was: {}", line, output)
+                }
+            }
+        };
+        let run_foo = || {
+            let exec = built_executable_in_workspace(&p_id, workspace).expect("foo wasn't built");
+            str::from_utf8(run::process_output(exec.as_str().unwrap(), []).output)
+        };
+
+        wait_for(&mut prog, "watch: build 1 succeeded");
+        wait_for(&mut prog, "waiting for changes");
+        assert_eq!(run_foo(), ~"first\n");
+
+        // Make sure the modification time changes, even with a coarse clock
+        timer::sleep(1100);
+        writeFile(&main_file, "fn main() { println(\"second\"); }");
+        wait_for(&mut prog, "watch: build 2 succeeded");
+        wait_for(&mut prog, "waiting for changes");
+        assert_eq!(run_foo(), ~"second\n");
+
+        // Ctrl-C while it's waiting stops it cleanly
+        unsafe { libc::funcs::posix88::signal::kill(prog.get_id(), libc::SIGINT); }
+        assert!(prog.finish().success());
+    }
+
+    #[test]
+    fn test_build_read_only_sources() {
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        let package_dir = workspace.join_many(["src", "foo-0.1"]);
+        let sources: ~[Path] = fs::readdir(&package_dir);
+        make_read_only(&package_dir);
+        fs::chmod(&package_dir, io::UserRead | io::UserExecute);
+
+        command_line_test([~"build", ~"foo"], workspace);
+        assert_built_library_exists(workspace, "foo");
+        assert_built_executable_exists(workspace, "foo");
+        // Nothing was generated among the sources
+        assert_eq!(fs::readdir(&package_dir).len(), sources.len());
+
+        // Outputs that would land next to the sources are refused
+        fs::chmod(&package_dir, io::UserRWX);
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        match command_line_test_partial([~"build", ~"--out-dir",
+                                         package_dir.as_str().unwrap().to_owned(), ~"foo"],
+                                        workspace) {
+            Success(*) => fail!("test_build_read_only_sources: expected the build to fail"),
+            Fail(ref r) => {
+                assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+                assert!(str::from_utf8(r.output).contains("would be next to its source"));
+            }
+        }
+        assert_eq!(fs::readdir(&package_dir).len(), sources.len());
+    }
+
+    #[test]
+    fn test_build_into_unwritable_out_dir() {
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        let out_dir = workspace.join("out");
+        fs::mkdir_recursive(&out_dir, io::UserRWX);
+        fs::chmod(&out_dir, io::UserRead | io::UserExecute);
+        let root = unsafe { libc::funcs::posix88::unistd::geteuid() == 0 };
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let args = [~"build", ~"--out-dir", out_dir.as_str().unwrap().to_owned(), ~"foo"];
+        match command_line_test_partial(args, workspace) {
+            // Root can write to it anyway
+            Success(*) if root => {}
+            Success(*) => fail!("test_build_into_unwritable_out_dir: expected a failure"),
+            Fail(ref r) => {
+                assert!(!root);
+                assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+                assert!(str::from_utf8(r.output)
+                        .contains(format!("{} is read-only", out_dir.display())));
+            }
+        }
+        fs::chmod(&out_dir, io::UserRWX);
+        if root {
+            return;
+        }
+
+        // A directory owned by another user is just as unwritable, even
+        // though its owner can write to it
+        let args = [~"build", ~"--out-dir", ~"/", ~"foo"];
+        match command_line_test_partial(args, workspace) {
+            Success(*) => fail!("test_build_into_unwritable_out_dir: built into /"),
+            Fail(ref r) => {
+                assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+                assert!(str::from_utf8(r.output).contains("/ is read-only"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_install_into_read_only_dir() {
+        let workspace = create_local_package(&PkgId::new("foo"));
+        let workspace = workspace.path();
+        let bin = workspace.join("bin");
+        fs::mkdir_recursive(&bin, io::UserRWX);
+        assert!(chmod_read_only(&bin));
+        match command_line_test_partial([~"install", ~"foo"], workspace) {
+            Success(*) => fail!("install into a read-only bin directory should have failed"),
+            Fail(ref r) => {
+                assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+                let out = str::from_utf8(r.output);
+                assert!(out.contains("Couldn't copy"));
+                assert!(out.contains(format!("permission denied; check that you can write to {}",
+                                             bin.display())));
+            }
+        }
+        fs::chmod(&bin, io::UserRWX);
+    }
+
+    #[test]
+    fn test_install_into_read_only_source_workspace() {
+        let p_id = PkgId::new("foo");
+        let workspace = create_local_package(&p_id);
+        let workspace = workspace.path();
+        command_line_test([~"build", ~"foo"], workspace);
+        // The sources' workspace is also where foo would be installed
+        fs::chmod(workspace, io::UserRead | io::UserExecute);
+        let root = unsafe { libc::funcs::posix88::unistd::geteuid() == 0 };
+        match command_line_test_partial([~"install", ~"foo"], workspace) {
+            // Root can write to it anyway
+            Success(*) if root => {}
+            Success(*) => fail!("install into a read-only source workspace should have failed"),
+            Fail(ref r) => {
+                assert!(r.status.matches_exit_status(COPY_FAILED_CODE));
+                let out = str::from_utf8(r.output);
+                assert!(out.contains(format!("Can't install {} into {}, because {} is read-only",
+                                             p_id.to_str(), workspace.display(),
+                                             workspace.display())));
+                assert!(out.contains("install it into a different workspace than its \
+                                      read-only sources"));
+                assert!(!out.contains("Couldn't copy"));
+            }
+        }
+        fs::chmod(workspace, io::UserRWX);
+        assert!(root || !workspace.join("bin").exists());
+
+        // A directory that another user owns is read-only too, even though
+        // its owner can write to it
+        let missing = Path::new("/rustpkg-test-no-such-dir/workspace");
+        let expected = if root { None } else { Some(Path::new("/")) };
+        assert_eq!(read_only_ancestor(&missing), expected);
+    }
+
+    #[test]
+    fn test_rustpkg_test_timeout_kills_children() {
+        let foo_id = PkgId::new("foo");
+        let foo_workspace = create_local_package(&foo_id);
+        let foo_workspace = foo_workspace.path();
+        let marker = foo_workspace.join("marker");
+        writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+                  format!("\\#[test] fn f() \\{ std::run::process_status(\"/bin/sh\", \
+                           [~\"-c\", ~\"sleep 5; touch {}\"]); \\}",
+                          marker.display()));
+        match command_line_test_partial([~"test", ~"--test-timeout", ~"1", ~"foo"],
+                                        foo_workspace) {
+            Success(*) => fail!("Expected the test to time out, but it succeeded"),
+            Fail(ref r) => assert!(r.status.matches_exit_status(COPY_FAILED_CODE))
+        }
+        // The shell the test started should have been killed along with it
+        timer::sleep(6000);
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_interrupted_test_timeout_kills_children() {
+        let foo_id = PkgId::new("foo");
+        let foo_workspace = create_local_package(&foo_id);
+        let foo_workspace = foo_workspace.path();
+        let started = foo_workspace.join("started");
+        let marker = foo_workspace.join("marker");
+        writeFile(&foo_workspace.join_many(["src", "foo-0.1", "test.rs"]),
+                  format!("\\#[test] fn f() \\{ std::run::process_status(\"/bin/sh\", \
+                           [~\"-c\", ~\"touch {}; sleep 5; touch {}\"]); \\}",
+                          started.display(), marker.display()));
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        let exec_path = rustpkg_exec();
+        let mut prog = run::Process::new(exec_path.as_str().unwrap(),
+                                         [~"test", ~"--test-timeout", ~"60", ~"foo"],
+                                         run::ProcessOptions {
+                                             env: None,
+                                             dir: Some(foo_workspace),
+                                             in_fd: None,
+                                             out_fd: None,
+                                             err_fd: None
+                                         });
+        let mut waited = 0;
+        while !started.exists() {
+            assert!(waited < 120, "test_interrupted_test_timeout_kills_children: \
+                                   the test never started");
+            timer::sleep(1000);
+            waited += 1;
+        }
+
+        // The test has a session of its own, so Ctrl-C only reaches rustpkg,
+        // which should kill the test before stopping
+        unsafe { libc::funcs::posix88::signal::kill(prog.get_id(), libc::SIGINT); }
+        assert!(!prog.finish().success());
+        timer::sleep(6000);
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_process_status_missing_program() {
+        use std::io::process::ExitStatus;
+        use util::process_status_in_env;
+
+        assert_eq!(process_status_in_env("/rustpkg-test-no-such-program", [], None, None),
+                   Some(ExitStatus(127)));
+        assert_eq!(process_status_in_env("/rustpkg-test-no-such-program", [], None, Some(1)),
+                   Some(ExitStatus(127)));
+    }
+}
@@ -35,6 +35,11 @@ pub fn main() {
         fail!("Package script requires a sysroot that exists; {} doesn't", sysroot.display());
     }
 
+    // It also installs everything under the default names
+    if args[2] == ~"binaries" {
+        return;
+    }
+
     if args[2] != ~"install" {
         println(format!("Warning: I don't know how to {}", args[2]));
         return;
@@ -12,24 +12,77 @@ pub fn general() {
     println("Usage: rustpkg [options] <cmd> [args..]
 
 Where <cmd> is one of:
-    build, clean, do, info, install, list, prefer, test, uninstall, unprefer
+    build, clean, clean-cache, deps, do, doctor, env, info, install, list, prefer,
+    reinstall, run-example, targets, test, uninstall, unprefer, update, verify, which
 
 Options:
 
     -h, --help                  Display this message
-    --sysroot PATH              Override the system root
+    -h <cmd>, --help <cmd>      Display help for <cmd>
+    --sysroot PATH              Override the system root, which must have
+                                the host's libraries in it
+    --print-sysroot             Print the system root rustpkg uses, and exit
+    --no-default-workspace      Fail, rather than use the default workspace,
+                                when RUST_PATH isn't set and the current
+                                directory isn't a workspace
+    --no-wait                   Fail, rather than wait, if another rustpkg is
+                                changing the workspace
+    --strict-cache              Fail, rather than start over, if the workcache
+                                database is corrupt
+    --quiet                     Only print warnings and errors, not notes
+    --silent                    Only print errors
     <cmd> -h, <cmd> --help      Display help for <cmd>");
 }
 
+/// Prints the usage for `cmd`, or the general usage if `cmd` isn't a command
+pub fn command(cmd: &str) {
+    match cmd {
+        "build" => build(),
+        "clean" => clean(),
+        "clean-cache" => clean_cache(),
+        "deps" => deps(),
+        "do" => do_cmd(),
+        "doctor" => doctor(),
+        "env" => env(),
+        "info" => info(),
+        "install" => install(),
+        "list" => list(),
+        "prefer" => prefer(),
+        "reinstall" => reinstall(),
+        "run-example" => run_example(),
+        "targets" => targets(),
+        "test" => test(),
+        "init" => init(),
+        "uninstall" => uninstall(),
+        "unprefer" => unprefer(),
+        "update" => update(),
+        "verify" => verify(),
+        "which" => which(),
+        _ => general()
+    }
+}
+
 pub fn build() {
-    println("rustpkg build [options..] [package-ID]
+    println("rustpkg build [options..] [package-ID..]
 
-Build the given package ID if specified. With no package ID argument,
-build the package in the current directory. In that case, the current
-directory must be a direct child of an `src` directory in a workspace.
+Build the given package IDs, in turn, if specified. With no package ID
+argument, build the package in the current directory. In that case, the
+current directory must be a direct child of an `src` directory in a workspace.
 
 Options:
     -c, --cfg      Pass a cfg flag to the package script
+    --config-arg ARG Pass ARG to the package script, after the command
+    --keep-going   With several package IDs, carry on building the rest
+                   after one of them fails
+    --watch        After building, wait for the crates' source files to
+                   change and build again, until Ctrl-C is pressed
+    --allow-dirty  Build packages whose sources are in a git working tree
+                   even if they have uncommitted changes
+    --ambiguous POLICY What to do if the package ID is in more than one
+                   workspace: `error` (the default), use the `first` one in
+                   the RUST_PATH, or use `all` of them
+    --variant NAME Use the package script `pkg-NAME.rs` instead of `pkg.rs`,
+                   in packages that have one
     --no-link      Compile and assemble, but don't link (like -c in rustc)
     --no-trans     Parse and translate, but don't generate any code
     --pretty       Pretty-print the code, but don't generate output
@@ -38,20 +91,65 @@ Options:
     -S --emit-llvm Generate LLVM assembly code
     --emit-llvm    Generate LLVM bitcode
     --linker PATH  Use a linker other than the system linker
-    --link-args [ARG..] Extra arguments to pass to the linker
+    --target-linker TRIPLE=PATH Use PATH as the linker when building for
+                   TRIPLE, instead of the --linker one (may be repeated)
+    --link-args [ARG..] Extra arguments to pass to the linker, separated by spaces
+    --link-arg ARG An extra argument to pass to the linker (may be repeated)
+    --lib-type TYPE Build libraries as `dylib` (the default) or `rlib`
     --opt-level=n  Set the optimization level (0 <= n <= 3)
     -O             Equivalent to --opt-level=2
+    --profile NAME Build with the `debug` or `release` profile, whose
+                   artifacts go in build/<triple>/<package-ID>/NAME/.
+                   `release` implies -O unless --opt-level is given
+    --digest ALG   Digest inputs and outputs with `sha1` (the default) or
+                   `sha256` when deciding what needs rebuilding
+    --exclude GLOB Don't build crates whose paths match GLOB (repeatable)
+    --jobs N       Compile up to N crates at once (the default is the number
+                   of CPUs); --jobs 1 keeps compiler output in a fixed order
+    --timings      Report how long compiling each crate, and building and
+                   running the package script, took (as JSON with --json)
+    --report FILE  When done, write a JSON summary of what was built and
+                   installed to FILE, even if the command failed
     --save-temps   Don't delete temporary files
+    --deny-warnings Treat warnings as errors, so that a crate (or package
+                   script) with warnings fails to build
     --target TRIPLE Set the target triple
     --target-cpu CPU Set the target CPU
+    --target-feature FEAT Enable (`+FEAT`) or disable (`-FEAT`) a feature of
+                   the target CPU, like `+sse4.2` (may be repeated)
+    --depth N      Only fetch the last N commits of git sources
+    --tmp-dir DIR  Clone git sources under DIR (the default is $TMPDIR)
+                   before moving them into the workspace
+    --out-dir DIR  Put the package's compiled artifacts in DIR, instead of
+                   its build directory (dependencies are built as usual)
     -Z FLAG        Enable an experimental rustc feature (see `rustc --help`)");
 }
 
 pub fn clean() {
-    println("rustpkg clean
+    println("rustpkg clean [options..] [package-ID]
 
 Remove all build files in the work cache for the package in the current
-directory.");
+directory.
+
+Options:
+    --profile NAME Only remove the build files for the `debug` or
+                   `release` profile
+    --all          Remove the build files for every package in every
+                   workspace in the RUST_PATH, reporting the space freed
+    --deep         With --all, also remove everything installed in those
+                   workspaces' `bin` and `lib` directories");
+}
+
+pub fn clean_cache() {
+    println("rustpkg clean-cache [options..]
+
+Remove the entries in the workcache database for builds and installs that can
+never be reused, because a source file or binary they read no longer exists,
+and report how many were removed and how many bytes they took up.
+
+Options:
+    --dry-run      List the entries that would be removed, without removing
+                   them");
 }
 
 pub fn do_cmd() {
@@ -61,6 +159,30 @@ Runs a command in the package script. You can listen to a command
 by tagging a function with the attribute `#[pkg_do(cmd)]`.");
 }
 
+pub fn doctor() {
+    println("rustpkg doctor [workspace]
+
+Check the given workspace for problems, or with no argument, the workspace
+containing the current directory. Reports RUST_PATH entries that don't exist,
+missing `src`, `lib`, `bin` or `build` directories, package directories with
+no crate file, and build outputs or libraries with no corresponding source.
+Exits with a nonzero status if it finds any problems.");
+}
+
+pub fn env() {
+    println("rustpkg env [options..]
+
+Print the configuration rustpkg resolved from the environment and the given
+flags, one `KEY=value` line each: SYSROOT, RUST_PATH, DEFAULT_WORKSPACE,
+WORKCACHE_DB, CFGS, RUSTC_FLAGS, PROFILE, DIGEST, JOBS, TMP_DIR, INSTALL_ROOT,
+AMBIGUOUS, STRICT_CACHE and VARIANT. Accepts --cfg and the rustc flags that
+`install` does, as well as --profile, --digest, --jobs, --tmp-dir, --root,
+--ambiguous, --strict-cache and --variant, and shows what they amount to.
+
+Options:
+    --json         Print the configuration as JSON");
+}
+
 pub fn info() {
     println("rustpkg [options..] info
 
@@ -71,15 +193,23 @@ Options:
 }
 
 pub fn list() {
-    println("rustpkg list
+    println("rustpkg list [options..]
+
+List all installed packages. Each one is followed by its status: `ok`, or
+`missing-bin` or `missing-lib` if the package has an executable or library
+in its workspace's build directory that isn't installed.
 
-List all installed packages.");
+Options:
+    --json         Print the list as JSON
+    --cache-index  Keep each workspace's list of installed packages in its
+                   rustpkg_index.json, and reuse it until the workspace's
+                   src, bin or lib directory changes");
 }
 
 pub fn install() {
-    println("rustpkg install [options..] [package-ID]
+    println("rustpkg install [options..] [package-ID..]
 
-Install the given package ID if specified. With no package ID
+Install the given package IDs, in turn, if specified. With no package ID
 argument, install the package in the current directory.
 In that case, the current directory must be a direct child of a
 `src` directory in a workspace.
@@ -88,25 +218,88 @@ Examples:
     rustpkg install
     rustpkg install github.com/mozilla/servo
     rustpkg install github.com/mozilla/servo#0.1.2
+    rustpkg install github.com/mozilla/servo github.com/mozilla/rust-azure
+    rustpkg install --from-file packages.txt
+    rustpkg install --root ./stage github.com/mozilla/servo
 
 Options:
     -c, --cfg      Pass a cfg flag to the package script
+    --config-arg ARG Pass ARG to the package script, after the command
+    --from-file FILE Install every package ID listed in FILE, one per line,
+                   continuing past failures. Blank lines and lines
+                   starting with `#` are ignored
+    --keep-going   With several package IDs, carry on installing the rest
+                   after one of them fails
+    --allow-dirty  Install packages whose sources are in a git working tree
+                   even if they have uncommitted changes
+    --ambiguous POLICY What to do if the package ID is in more than one
+                   workspace: `error` (the default), use the `first` one in
+                   the RUST_PATH, or use `all` of them
+    --variant NAME Use the package script `pkg-NAME.rs` instead of `pkg.rs`,
+                   in packages that have one
     --emit-llvm    Generate LLVM bitcode
     --linker PATH  Use a linker other than the system linker
-    --link-args [ARG..] Extra arguments to pass to the linker
+    --target-linker TRIPLE=PATH Use PATH as the linker when building for
+                   TRIPLE, instead of the --linker one (may be repeated)
+    --link-args [ARG..] Extra arguments to pass to the linker, separated by spaces
+    --link-arg ARG An extra argument to pass to the linker (may be repeated)
+    --lib-type TYPE Build libraries as `dylib` (the default) or `rlib`
     --opt-level=n  Set the optimization level (0 <= n <= 3)
     -O             Equivalent to --opt-level=2
+    --profile NAME Build with the `debug` or `release` profile, whose
+                   artifacts go in build/<triple>/<package-ID>/NAME/.
+                   `release` implies -O unless --opt-level is given
+    --digest ALG   Digest inputs and outputs with `sha1` (the default) or
+                   `sha256` when deciding what needs rebuilding
+    --exclude GLOB Don't build crates whose paths match GLOB (repeatable)
+    --jobs N       Compile up to N crates at once (the default is the number
+                   of CPUs); --jobs 1 keeps compiler output in a fixed order
+    --timings      Report how long compiling each crate, and building and
+                   running the package script, took (as JSON with --json)
+    --report FILE  When done, write a JSON summary of what was built and
+                   installed to FILE, even if the command failed
+    --record FILE  Write a manifest of every installed file, with its digest,
+                   to FILE as JSON
     --save-temps   Don't delete temporary files
+    --deny-warnings Treat warnings as errors, so that a crate (or package
+                   script) with warnings fails to build
     --target TRIPLE Set the target triple
     --target-cpu CPU Set the target CPU
+    --target-feature FEAT Enable (`+FEAT`) or disable (`-FEAT`) a feature of
+                   the target CPU, like `+sse4.2` (may be repeated)
+    --depth N      Only fetch the last N commits of git sources
+    --tmp-dir DIR  Clone git sources under DIR (the default is $TMPDIR)
+                   before moving them into the workspace
+    --root DIR     Install files under DIR instead, at the same paths relative
+                   to DIR that they'd otherwise have, for staging a package
+    --emit-deps    Write a Makefile-style dependency file for each crate, as
+                   build/<triple>/<package-ID>/<crate>.d
+    --with-docs    Also install the package's generated documentation, from
+                   build/<triple>/<package-ID>/doc/ to doc/<package-ID>/
+    --strip        Strip debugging symbols from the installed executables and
+                   dynamic libraries (with `strip -S`)
     -Z FLAG        Enable an experimental rustc feature (see `rustc --help`)");
 }
 
+pub fn reinstall() {
+    println("rustpkg reinstall [options..] [package-ID]
+
+Clean the given package ID (or the package in the current directory), make
+the workcache forget everything it knows about building and installing it,
+and install it again, so that all of its artifacts are rebuilt from scratch.
+
+Takes the same options as `rustpkg install`, except for --from-file.");
+}
+
 pub fn uninstall() {
     println("rustpkg uninstall <id|name>[@version]
 
 Remove a package by id or name and optionally version. If the package(s)
-is/are depended on by another package then they cannot be removed.");
+is/are depended on by another package then they cannot be removed.
+
+Options:
+    --cache-index  Find the package using the saved index of installed
+                   packages, as in `rustpkg list --cache-index`");
 }
 
 pub fn prefer() {
@@ -136,15 +329,103 @@ of the package will be unpreferred. See `rustpkg prefer -h` for more
 information.");
 }
 
+pub fn update() {
+    println("rustpkg [options..] update <package-ID>
+
+Fetch what's new in the git repository that the given package's sources were
+cloned from, into the existing clone, and check out the newest commit of the
+package ID's version (or of the repository's default branch, if it has no
+version). Then reinstall the package from the updated sources.");
+}
+
+pub fn targets() {
+    println("rustpkg targets [options..] [package-ID]
+
+List the crates that rustpkg would build for the given package ID,
+without building them. With no package ID argument, list the crates
+for the package in the current directory. Each crate is listed as
+`lib`, `main`, `test` or `bench`, followed by its path relative to
+the package directory.
+
+Options:
+    -c, --cfg      Pass a cfg flag, as when building
+    --json         Print the list as JSON
+    --print-crate-id Also print the name, version, package ID and uuid in
+                   each crate's link attribute, or that rustpkg gives it");
+}
+
+pub fn deps() {
+    println("rustpkg deps [options..] [package-ID]
+
+Print the libraries that each of the given package's crates declares with
+`extern mod`, without building anything. With no package ID argument,
+use the package in the current directory. Libraries that belong to the
+package itself are shown as crate paths; others are marked `(external)`.
+Warns about crates in the package that depend on each other in a cycle.
+
+Options:
+    -c, --cfg      Pass a cfg flag, as when building
+    --json         Print the dependencies as JSON");
+}
+
 pub fn test() {
-    println("rustpkg [options..] test
+    println("rustpkg [options..] test [package-ID] [-- harness-args..]
 
 Build all test crates in the current directory with the test flag.
-Then, run all the resulting test executables, redirecting the output
-and exit code.
+Then, run all the resulting test executables, one per test crate, and
+report how many of them passed; the command fails if any of them did.
+Everything after `--` is passed to the test executables, even arguments
+that look like rustpkg's own flags.
+
+Options:
+    -c, --cfg      Pass a cfg flag to the package script
+    --config-arg ARG Pass ARG to the package script, after the command
+    --ambiguous POLICY What to do if the package ID is in more than one
+                   workspace: `error` (the default), use the `first` one in
+                   the RUST_PATH, or use `all` of them
+    --variant NAME Use the package script `pkg-NAME.rs` instead of `pkg.rs`,
+                   in packages that have one
+    --allow-dirty  Test packages whose sources are in a git working tree
+                   even if they have uncommitted changes
+    --test-timeout SECS Kill the test executable, and anything it started,
+                   if it hasn't finished after SECS seconds, and report
+                   the tests as failed
+    --test-threads N Have each test executable run up to N tests at once
+                   (by setting RUST_TEST_TASKS), instead of its default
+    --isolated     Run the tests with HOME and RUST_PATH set to a temporary
+                   directory, which is removed afterward
+    --profile NAME Build and run the tests with the `debug` or `release`
+                   profile
+    --digest ALG   Digest inputs and outputs with `sha1` (the default) or
+                   `sha256` when deciding what needs rebuilding
+    --exclude GLOB Don't build crates whose paths match GLOB (repeatable)
+    --deny-warnings Treat warnings as errors, so that a crate (or package
+                   script) with warnings fails to build
+    --jobs N       Compile, and then run the test executables of, up to N
+                   crates at once (the default is the number of CPUs);
+                   --jobs 1 keeps the output in a fixed order
+    --timings      Report how long compiling each crate, and building and
+                   running the package script, took (as JSON with --json)");
+}
+
+pub fn run_example() {
+    println("rustpkg [options..] run-example <package-ID> <name> [args..]
+
+Build the given package, then build and run its example <name>, the crate
+examples/<name>.rs in the package's source directory, passing it any
+remaining arguments. Put `--` before arguments that start with `-`.
+Examples are built in the `examples` directory of the package's build
+directory, and are never installed.
 
 Options:
-    -c, --cfg      Pass a cfg flag to the package script");
+    -c, --cfg      Pass a cfg flag to the package script
+    --config-arg ARG Pass ARG to the package script, after the command
+    --allow-dirty  Run the example even if the package's sources are in a
+                   git working tree and have uncommitted changes
+    --variant NAME Use the package script `pkg-NAME.rs` instead of `pkg.rs`,
+                   in packages that have one
+    --profile NAME Build and run the example with the `debug` or `release`
+                   profile");
 }
 
 pub fn init() {
@@ -154,3 +435,27 @@ This will turn the current working directory into a workspace. The first
 command you run when starting off a new project.
 ");
 }
+
+pub fn verify() {
+    println("rustpkg verify <id|name>[@version]
+
+Check that the files installed for a package haven't changed since rustpkg
+installed them, by comparing them against the digests recorded at install
+time. Reports missing and modified files, and exits with a nonzero status
+if there are any.");
+}
+
+pub fn which() {
+    println("rustpkg [options..] which <package-ID>[#version]
+
+Print the absolute path of the executable installed for the given version of
+the package, or without a version, for the latest version installed (the one
+`rustpkg prefer` prefers). Exits with a nonzero status, printing nothing, if
+that version has no executable installed in any workspace.
+
+Options:
+    --all          Print the executable of every installed version of the
+                   package, oldest first
+    --cache-index  Find the executable using the saved index of installed
+                   packages, as in `rustpkg list --cache-index`");
+}
@@ -12,43 +12,66 @@ use std::libc;
 use std::os;
 use std::io;
 use std::io::fs;
+use std::io::File;
+use std::io::process;
+use std::io::process::{ProcessConfig, ProcessExit, Ignored, InheritFd};
+use std::io::timer::Timer;
+use std::io::signal::{Listener, Interrupt};
+use std::comm::oneshot;
+use std::{run, str, task, vec};
+use std::unstable::finally::Finally;
+use std::unstable::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use extra::json;
 use extra::workcache;
 use rustc::driver::{driver, session};
+use rustc::middle::lint;
 use extra::getopts::groups::getopts;
 use syntax::ast_util::*;
 use syntax::codemap::{dummy_sp, Spanned};
 use syntax::ext::base::ExtCtxt;
-use syntax::{ast, attr, codemap, diagnostic, fold, visit};
+use syntax::{abi, ast, attr, codemap, diagnostic, fold, visit};
 use syntax::attr::AttrMetaMethods;
 use syntax::fold::ast_fold;
 use syntax::visit::Visitor;
 use syntax::util::small_vector::SmallVector;
+use syntax::parse;
+use syntax::parse::attr::parser_attr;
 use rustc::back::link::output_type_exe;
 use rustc::back::link;
+use rustc::metadata::common::LinkMeta;
 use rustc::driver::session::{lib_crate, bin_crate};
-use context::{in_target, StopBefore, Link, Assemble, BuildContext};
+use context::{in_target, StopBefore, Link, Assemble, Nothing, BuildContext, Rlib};
+use context::{Built, Canceled};
+use context::{Callback, CopyObserver};
 use package_id::PkgId;
 use package_source::PkgSrc;
 use workspace::pkg_parent_workspaces;
-use path_util::{system_library, target_build_dir};
-use path_util::{default_workspace, built_library_in_workspace};
+use path_util::system_library;
+use path_util::{default_workspace, library_in, profile_build_dir};
+use version::NoVersion;
 pub use target::{OutputType, Main, Lib, Bench, Test, JustOne, lib_name_of, lib_crate_filename};
 pub use target::{Target, Build, Install};
 use extra::treemap::TreeMap;
 pub use target::{lib_name_of, lib_crate_filename, WhatToBuild, MaybeCustom, Inferred};
-use workcache_support::{digest_file_with_date, digest_only_date};
-use messages::error;
+use workcache_support::{DigestAlgorithm, digest_file_with_date_using, digest_only_date_using};
+use messages::{error, warn, note};
+use exit_codes::COPY_FAILED_CODE;
 
 // It would be nice to have the list of commands in just one place -- for example,
 // you could update the match in rustpkg.rc but forget to update this list. I think
 // that should be fixed.
 static COMMANDS: &'static [&'static str] =
-    &["build", "clean", "do", "info", "init", "install", "list", "prefer", "test", "uninstall",
-      "unprefer"];
+    &["build", "clean", "clean-cache", "deps", "do", "doctor", "env", "info", "init", "install",
+      "list", "prefer", "reinstall", "run-example", "targets", "test", "uninstall", "unprefer",
+      "update", "verify", "which"];
 
 
 pub type ExitCode = int; // For now
 
+/// How often (in milliseconds) `process_status_with_timeout` checks
+/// whether the process it's waiting on has exited
+static TIMEOUT_POLL_MS: u64 = 50;
+
 pub struct Pkg {
     id: PkgId,
     bins: ~[~str],
@@ -65,6 +88,13 @@ pub fn is_cmd(cmd: &str) -> bool {
     COMMANDS.iter().any(|&c| c == cmd)
 }
 
+/// True if `cmd` can change a workspace, so that rustpkg locks the workspace
+/// while it runs
+pub fn changes_workspace(cmd: &str) -> bool {
+    ["build", "clean", "clean-cache", "do", "init", "install", "prefer", "reinstall",
+     "run-example", "test", "uninstall", "unprefer", "update"].iter().any(|&c| c == cmd)
+}
+
 struct ListenerFn {
     cmds: ~[~str],
     span: codemap::Span,
@@ -166,6 +196,21 @@ pub fn ready_crate(sess: session::Session,
     fold.fold_crate(crate)
 }
 
+/// Passes diagnostics on to the default emitter with `prefix` in front, so
+/// that the messages of crates that are compiled at once can be told apart
+struct PrefixEmitter {
+    prefix: ~str
+}
+
+impl diagnostic::Emitter for PrefixEmitter {
+    fn emit(&self,
+            cmsp: Option<(@codemap::CodeMap, codemap::Span)>,
+            msg: &str,
+            lvl: diagnostic::level) {
+        diagnostic::DefaultEmitter.emit(cmsp, format!("[{}] {}", self.prefix, msg), lvl);
+    }
+}
+
 pub fn compile_input(context: &BuildContext,
                      exec: &mut workcache::Exec,
                      pkg_id: &PkgId,
@@ -175,15 +220,21 @@ pub fn compile_input(context: &BuildContext,
                      flags: &[~str],
                      cfgs: &[~str],
                      opt: session::OptLevel,
-                     what: OutputType) -> Option<Path> {
+                     what: OutputType,
+                     example: bool) -> Compiled {
     assert!(in_file.components().nth(1).is_some());
     let input = driver::file_input(in_file.clone());
     debug!("compile_input: {} / {:?}", in_file.display(), what);
     // tjc: by default, use the package ID name as the link name
     // not sure if we should support anything else
 
-    let mut out_dir = target_build_dir(workspace);
-    out_dir.push(&pkg_id.path);
+    // Where the package's libraries are built
+    let lib_dir = match context.context.out_dir {
+        Some(ref dir) => dir.clone(),
+        None => profile_build_dir(pkg_id, workspace, context.context.profile)
+    };
+    // Examples are built apart from the package's own executables
+    let out_dir = if example { lib_dir.join("examples") } else { lib_dir.clone() };
     // Make the output directory if it doesn't exist already
     fs::mkdir_recursive(&out_dir, io::UserRWX);
 
@@ -238,6 +289,19 @@ pub fn compile_input(context: &BuildContext,
 
     debug!("Output type = {:?}", output_type);
 
+    // Other crates may be compiling at the same time, so say which crate
+    // each message is about, unless an embedder is collecting them
+    let emitter = match context.emitter {
+        Some(ref maker) => unsafe { maker.with(|m| m.make_emitter()) },
+        None if context.context.jobs > 1 => {
+            @PrefixEmitter {
+                prefix: format!("{}/{}", pkg_id.short_name, in_file.filename_display())
+            } as @diagnostic::Emitter
+        }
+        None => @diagnostic::DefaultEmitter as @diagnostic::Emitter
+    };
+
+    let session_options = driver::build_session_options(binary, &matches, emitter);
     let options = @session::options {
         crate_type: crate_type,
         optimize: opt,
@@ -245,10 +309,16 @@ pub fn compile_input(context: &BuildContext,
         maybe_sysroot: Some(sysroot_to_use),
         addl_lib_search_paths: @mut context.additional_library_paths(),
         output_type: output_type,
-        .. (*driver::build_session_options(binary,
-                                           &matches,
-                                           @diagnostic::DefaultEmitter as
-                                            @diagnostic::Emitter)).clone()
+        // The linker and link arguments that rustpkg was given are only
+        // used when the crate is linked, by `link_crate`, so that changing
+        // them doesn't mean compiling the crate again. The crate's own
+        // flags are part of compiling it.
+        linker: None,
+        linker_args: session_options.linker_args.clone(),
+        // Only libraries are built statically; rustc can't link
+        // an executable against a static libstd
+        is_static: what == Lib && context.context.rustc_flags.lib_type == Rlib,
+        .. (*session_options).clone()
     };
 
     debug!("Created options...");
@@ -267,9 +337,7 @@ pub fn compile_input(context: &BuildContext,
 
     debug!("About to build session...");
 
-    let sess = driver::build_session(options,
-                                     @diagnostic::DefaultEmitter as
-                                        @diagnostic::Emitter);
+    let sess = driver::build_session(options, emitter);
 
     debug!("About to build config...");
 
@@ -282,7 +350,7 @@ pub fn compile_input(context: &BuildContext,
     debug!("About to call find_and_install_dependencies...");
 
     find_and_install_dependencies(context, pkg_id, in_file, sess, exec, &crate, deps,
-                                  |p| {
+                                  &lib_dir, |p| {
                                       debug!("a dependency: {}", p.display());
                                       // Pass the directory containing a dependency
                                       // as an additional lib search path
@@ -291,11 +359,7 @@ pub fn compile_input(context: &BuildContext,
 
     // Inject the link attributes so we get the right package name and version
     if attr::find_linkage_metas(crate.attrs).is_empty() {
-        let name_to_use = match what {
-            Test  => format!("{}test", pkg_id.short_name).to_managed(),
-            Bench => format!("{}bench", pkg_id.short_name).to_managed(),
-            _     => pkg_id.short_name.to_managed()
-        };
+        let name_to_use = injected_link_name(pkg_id, what).to_managed();
         debug!("Injecting link name: {}", name_to_use);
         // FIXME (#9639): This needs to handle non-utf8 paths
         let link_options =
@@ -315,25 +379,149 @@ pub fn compile_input(context: &BuildContext,
                                           context.compile_upto(),
                                           &out_dir,
                                           sess,
-                                          crate);
-    // Discover the output
-    let discovered_output = if what == Lib  {
-        built_library_in_workspace(pkg_id, workspace) // Huh???
-    }
-    else {
-        result
+                                          crate,
+                                          context.context.digest,
+                                          true);
+    // Discover the output. If it's still to be linked, that's the object
+    // file, so that the crate is compiled again if it's removed (as by
+    // `clean`); linking it discovers the executable or library.
+    let discovered_output = match result {
+        NeedsLink(ref cmd) => Some(cmd.object.clone()),
+        Output(_) if what == Lib => {
+            library_in(pkg_id.short_name, &pkg_id.version, &out_dir) // Huh???
+        }
+        Output(ref p) => Some(p.clone()),
+        NoOutput => None
     };
     for p in discovered_output.iter() {
         debug!("About to discover output {}", p.display());
         if p.exists() {
             debug!("4. discovering output {}", p.display());
             // FIXME (#9639): This needs to handle non-utf8 paths
-            exec.discover_output("binary", p.as_str().unwrap(), digest_only_date(p));
+            exec.discover_output("binary", p.as_str().unwrap(),
+                                 digest_only_date_using(p, context.context.digest));
         }
         // Nothing to do if it doesn't exist -- that could happen if we had the
         // -S or -emit-llvm flags, etc.
     }
-    discovered_output
+    result
+}
+
+/// What compiling a crate produced
+pub enum Compiled {
+    /// Nothing, because of a flag that stops compilation early
+    NoOutput,
+    /// This file, which is finished
+    Output(Path),
+    /// An object file, which this command links (see `link_crate`)
+    NeedsLink(LinkCommand)
+}
+
+/// How to link a crate's object file into its executable or dynamic
+/// library. The linker and link arguments that rustpkg was given aren't
+/// included: `link_crate` adds them, so that linking is cached separately
+/// from compiling.
+#[deriving(Clone, Eq)]
+pub struct LinkCommand {
+    // The linker rustc would use
+    linker: ~str,
+    // The object file to link
+    object: Path,
+    // The linker's arguments, which name the object file and the output
+    args: ~[~str],
+    // The executable or dynamic library the linker makes
+    output: Path,
+    // True if the output's debugging symbols need to be extracted with
+    // dsymutil afterward, as on OS X
+    run_dsymutil: bool
+}
+
+impl LinkCommand {
+    fn new(sess: session::Session, obj: &Path, out: &Path, lm: LinkMeta) -> LinkCommand {
+        let output = if *sess.building_library {
+            out.with_filename(link::output_dll_filename(sess.targ_cfg.os, lm))
+        } else {
+            out.clone()
+        };
+        LinkCommand {
+            linker: link::get_cc_prog(sess),
+            object: obj.clone(),
+            args: link::link_args(sess, obj, out, lm),
+            output: output,
+            run_dsymutil: sess.targ_cfg.os == abi::OsMacos && sess.opts.debuginfo
+        }
+    }
+
+    /// Encodes the command as a string, for the workcache to store
+    pub fn to_json_str(&self) -> ~str {
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"linker", json::String(self.linker.clone()));
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        obj.insert(~"object", json::String(self.object.as_str().unwrap().to_owned()));
+        obj.insert(~"args", json::List(self.args.map(|a| json::String(a.clone()))));
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        obj.insert(~"output", json::String(self.output.as_str().unwrap().to_owned()));
+        obj.insert(~"run_dsymutil", json::Boolean(self.run_dsymutil));
+        json::Object(obj).to_str()
+    }
+
+    /// The reverse of `to_json_str`. Returns None for anything else, such as
+    /// what the workcache stored for crates built before linking was
+    /// separate, or for crates that don't need linking.
+    pub fn from_json_str(s: &str) -> Option<LinkCommand> {
+        let obj = match json::from_str(s) {
+            Ok(json::Object(obj)) => obj,
+            _ => return None
+        };
+        let args = match obj.find(&~"args") {
+            Some(&json::List(ref l)) => l.iter().filter_map(|a| match *a {
+                json::String(ref s) => Some(s.clone()),
+                _ => None
+            }).collect(),
+            _ => return None
+        };
+        match (obj.find(&~"linker"), obj.find(&~"object"), obj.find(&~"output"),
+               obj.find(&~"run_dsymutil")) {
+            (Some(&json::String(ref linker)), Some(&json::String(ref object)),
+             Some(&json::String(ref output)), Some(&json::Boolean(run_dsymutil))) => {
+                Some(LinkCommand {
+                    linker: linker.clone(),
+                    object: Path::new(object.as_slice()),
+                    args: args,
+                    output: Path::new(output.as_slice()),
+                    run_dsymutil: run_dsymutil
+                })
+            }
+            _ => None
+        }
+    }
+}
+
+/// Runs `cmd`, with the linker and link arguments in `context` if it has
+/// any, and discovers the output
+pub fn link_crate(context: &BuildContext, exec: &mut workcache::Exec, cmd: &LinkCommand) {
+    use command_failed = conditions::command_failed::cond;
+
+    let flags = &context.context.rustc_flags;
+    let linker = flags.linker_for_target().unwrap_or(cmd.linker.clone());
+    // After the crate's own link arguments, as when rustc links
+    let args = cmd.args + flags.link_args;
+    debug!("{} link args: {}", linker, args.connect(" "));
+    let outp = run::process_output(linker.as_slice(), args);
+    if !outp.status.success() {
+        error(format!("linking with `{}` failed: {}", linker, outp.status));
+        note(format!("{} arguments: {}", linker, args.connect(" ")));
+        note(str::from_utf8(outp.error + outp.output));
+        command_failed.raise((linker.clone(), args.clone(), outp.status));
+        return;
+    }
+    if cmd.run_dsymutil {
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        run::process_status("dsymutil", [cmd.output.as_str().unwrap().to_owned()]);
+    }
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    exec.discover_output("binary", cmd.output.as_str().unwrap(),
+                         digest_only_date_using(&cmd.output, context.context.digest));
 }
 
 // Should use workcache to avoid recompiling when not necessary
@@ -348,9 +536,13 @@ pub fn compile_crate_from_input(input: &Path,
  // should be of the form <workspace>/build/<pkg id's path>
                                 out_dir: &Path,
                                 sess: session::Session,
-// Returns None if one of the flags that suppresses compilation output was
+// Returns NoOutput if one of the flags that suppresses compilation output was
 // given
-                                crate: ast::Crate) -> Option<Path> {
+                                crate: ast::Crate,
+                                digest: DigestAlgorithm,
+// If true, an executable or dynamic library isn't linked, and the result
+// says how to link it instead
+                                defer_link: bool) -> Compiled {
     debug!("Calling build_output_filenames with {}, building library? {:?}",
            out_dir.display(), sess.building_library);
 
@@ -364,29 +556,86 @@ pub fn compile_crate_from_input(input: &Path,
            outputs.out_filename.display(),
            outputs.obj_filename.display(),
            sess.opts.output_type);
+    for output in [&outputs.out_filename, &outputs.obj_filename].iter() {
+        check_output_path(input, *output);
+    }
     debug!("additional libraries:");
     for lib in sess.opts.addl_lib_search_paths.iter() {
         debug!("an additional library: {}", lib.display());
     }
-    let analysis = driver::phase_3_run_analysis_passes(sess, &crate);
-    if driver::stop_after_phase_3(sess) { return None; }
+    // Lints run during analysis, so with --deny-warnings, this is where a
+    // crate with warnings fails; say which one it was
+    let deny_warnings = sess.opts.lint_opts.iter().any(|&(l, level)| {
+        l == lint::warnings && level == lint::deny
+    });
+    let analysis = (|| driver::phase_3_run_analysis_passes(sess, &crate)).finally(|| {
+        if deny_warnings && task::failing() {
+            error(format!("Couldn't build {} (with --deny-warnings, its warnings are errors)",
+                          input.display()));
+        }
+    });
+    if driver::stop_after_phase_3(sess) { return NoOutput; }
     let translation = driver::phase_4_translate_to_llvm(sess, crate,
                                                         &analysis,
                                                         outputs);
     driver::phase_5_run_llvm_passes(sess, &translation, outputs);
+    // A static library is just the object file, named so that rustc
+    // can find it when it's looking for an rlib
+    if sess.opts.is_static && *sess.building_library && stop_before == Nothing {
+        let rlib = out_dir.join(rlib_filename(&translation.link));
+        fs::rename(&outputs.obj_filename, &rlib);
+        // FIXME (#9639): This needs to handle non-utf8 paths
+        exec.discover_input("file", input.as_str().unwrap(),
+                            digest_file_with_date_using(input, digest));
+        return Output(rlib);
+    }
     // The second check shouldn't be necessary, but rustc seems to ignore
     // -c
     if driver::stop_after_phase_5(sess)
-        || stop_before == Link || stop_before == Assemble { return Some(outputs.out_filename); }
-    driver::phase_6_link_output(sess, &translation, outputs);
+        || stop_before == Link || stop_before == Assemble { return Output(outputs.out_filename); }
 
     // Register dependency on the source file
     // FIXME (#9639): This needs to handle non-utf8 paths
-    exec.discover_input("file", input.as_str().unwrap(), digest_file_with_date(input));
+    exec.discover_input("file", input.as_str().unwrap(),
+                        digest_file_with_date_using(input, digest));
+
+    if defer_link {
+        // The object file is kept, so that the crate can be linked again
+        // without compiling it
+        return NeedsLink(LinkCommand::new(sess, &outputs.obj_filename, &outputs.out_filename,
+                                          translation.link));
+    }
+    driver::phase_6_link_output(sess, &translation, outputs);
 
     debug!("Built {}, date = {:?}", outputs.out_filename.display(),
            datestamp(&outputs.out_filename));
-    Some(outputs.out_filename)
+    Output(outputs.out_filename)
+}
+
+/// Fails with COPY_FAILED_CODE, saying why, if the file `output` generated
+/// from the crate `input` would be written next to `input`, or somewhere
+/// read-only. Sources fetched with git are made read-only (see
+/// `make_read_only`), and nothing rustpkg generates belongs among them.
+fn check_output_path(input: &Path, output: &Path) {
+    let dir = output.dir_path();
+    let problem = if dir == input.dir_path() {
+        Some(format!("it would be next to its source, {}, rather than in the workspace's \
+                      build directory", input.display()))
+    } else if output.exists() && is_read_only_file(output) {
+        Some(~"it's read-only")
+    } else {
+        read_only_ancestor(&dir).map(|ro| format!("{} is read-only", ro.display()))
+    };
+    for why in problem.iter() {
+        error(format!("Can't write {}: {}", output.display(), *why));
+        fail!(COPY_FAILED_CODE);
+    }
+}
+
+/// The name of the rlib for the crate with link metadata `lm`. Like a
+/// dynamic library's name, but always with the `lib` prefix
+fn rlib_filename(lm: &LinkMeta) -> ~str {
+    format!("lib{}-{}-{}.rlib", lm.name, lm.extras_hash, lm.vers)
 }
 
 #[cfg(windows)]
@@ -398,6 +647,132 @@ pub fn exe_suffix() -> ~str { ~".exe" }
 #[cfg(target_os = "macos")]
 pub fn exe_suffix() -> ~str { ~"" }
 
+/// The value of `crate_file`'s `#[crate_type]` attribute, if it has one.
+/// Only the attributes at the start of the crate are parsed, so a crate with
+/// syntax errors further on is still classified, and the errors are reported
+/// when it's compiled. Returns None if the attributes can't be parsed either.
+pub fn crate_type_attr(crate_file: &Path) -> Option<~str> {
+    let crate_file = crate_file.clone();
+    let result = do task::try {
+        let sess = parse::new_parse_sess(None);
+        let parser = parse::new_parser_from_file(sess, ~[], &crate_file);
+        let (attrs, _) = parser.parse_inner_attrs_and_next();
+        attr::first_attr_value_str_by_name(attrs, "crate_type").map(|t| t.to_owned())
+    };
+    match result {
+        Ok(crate_type) => crate_type,
+        Err(_) => None
+    }
+}
+
+/// The name in the link attribute that `compile_input` gives a crate that
+/// doesn't have one, when it's built as a `what` for `pkg_id`
+fn injected_link_name(pkg_id: &PkgId, what: OutputType) -> ~str {
+    match what {
+        Test  => format!("{}test", pkg_id.short_name),
+        Bench => format!("{}bench", pkg_id.short_name),
+        _     => pkg_id.short_name.clone()
+    }
+}
+
+/// A crate's link metadata: what it says in its `#[link(...)]` attribute, or
+/// what it gets in place of anything the attribute leaves out
+#[deriving(Clone, Eq)]
+pub struct CrateId {
+    name: ~str,
+    vers: ~str,
+    package_id: ~str,
+    // Only set if the link attribute gives one
+    uuid: Option<~str>,
+    // True if the crate has no link attribute, so that `compile_input`
+    // injects one
+    inferred: bool
+}
+
+impl CrateId {
+    /// Returns the ID that `crate_file` gets when it's built as a `what` for
+    /// `pkg_id`. The crate is only parsed, not configured or compiled. If it
+    /// has no link attribute, its ID is the one `compile_input` injects.
+    /// Otherwise, anything the attribute leaves out gets rustc's default:
+    /// the crate file's stem for the name, "0.0" for the version, and the
+    /// name for the package ID. Returns None if the crate doesn't parse.
+    pub fn of_crate(crate_file: &Path, pkg_id: &PkgId, what: OutputType) -> Option<CrateId> {
+        let crate_path = crate_file.clone();
+        let result = do task::try {
+            let input = driver::file_input(crate_path.clone());
+            let sess = driver::build_session(session::basic_options(),
+                                             @diagnostic::DefaultEmitter as
+                                                @diagnostic::Emitter);
+            let cfg = driver::build_configuration(sess);
+            let crate = driver::phase_1_parse_input(sess, cfg, &input);
+            let metas = attr::find_linkage_metas(crate.attrs);
+            if metas.is_empty() {
+                None
+            } else {
+                let pairs: ~[(~str, ~str)] = metas.iter()
+                    .filter_map(|m| m.name_str_pair())
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect();
+                Some(pairs)
+            }
+        };
+        let metas = match result {
+            Ok(metas) => metas,
+            Err(_) => return None
+        };
+        Some(match metas {
+            None => CrateId {
+                name: injected_link_name(pkg_id, what),
+                vers: pkg_id.version.to_str(),
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                package_id: pkg_id.path.as_str().unwrap().to_owned(),
+                uuid: None,
+                inferred: true
+            },
+            Some(metas) => {
+                let find = |key: &str| {
+                    metas.iter().find(|&&(ref k, ref v)| k.as_slice() == key && !v.is_empty())
+                                .map(|&(_, ref v)| v.clone())
+                };
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                let name = find("name").unwrap_or(crate_file.filestem_str().unwrap().to_owned());
+                CrateId {
+                    vers: find("vers").unwrap_or(~"0.0"),
+                    package_id: find("package_id").unwrap_or(name.clone()),
+                    uuid: find("uuid"),
+                    name: name,
+                    inferred: false
+                }
+            }
+        })
+    }
+
+    /// Returns self as a JSON object, with `uuid` null if there isn't one
+    pub fn to_json(&self) -> json::Json {
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"name", json::String(self.name.clone()));
+        obj.insert(~"vers", json::String(self.vers.clone()));
+        obj.insert(~"package_id", json::String(self.package_id.clone()));
+        obj.insert(~"uuid", match self.uuid {
+            Some(ref u) => json::String(u.clone()),
+            None => json::Null
+        });
+        obj.insert(~"inferred", json::Boolean(self.inferred));
+        json::Object(obj)
+    }
+}
+
+impl ToStr for CrateId {
+    fn to_str(&self) -> ~str {
+        let uuid = match self.uuid {
+            Some(ref u) => format!(" uuid={}", *u),
+            None => ~""
+        };
+        format!("name={} vers={} package_id={}{}{}", self.name, self.vers, self.package_id,
+                uuid, if self.inferred { " (inferred)" } else { "" })
+    }
+}
+
 // Called by build_crates
 pub fn compile_crate(ctxt: &BuildContext,
                      exec: &mut workcache::Exec,
@@ -408,13 +783,14 @@ pub fn compile_crate(ctxt: &BuildContext,
                      flags: &[~str],
                      cfgs: &[~str],
                      opt: session::OptLevel,
-                     what: OutputType) -> Option<Path> {
+                     what: OutputType,
+                     example: bool) -> Compiled {
     debug!("compile_crate: crate={}, workspace={}", crate.display(), workspace.display());
     debug!("compile_crate: short_name = {}, flags =...", pkg_id.to_str());
     for fl in flags.iter() {
         debug!("+++ {}", *fl);
     }
-    compile_input(ctxt, exec, pkg_id, crate, workspace, deps, flags, cfgs, opt, what)
+    compile_input(ctxt, exec, pkg_id, crate, workspace, deps, flags, cfgs, opt, what, example)
 }
 
 struct ViewItemVisitor<'self> {
@@ -425,7 +801,37 @@ struct ViewItemVisitor<'self> {
     exec: &'self mut workcache::Exec,
     c: &'self ast::Crate,
     save: 'self |Path|,
-    deps: &'self mut DepMap
+    deps: &'self mut DepMap,
+    // Where the parent crate's output goes, along with any of its
+    // package's other libraries
+    out_dir: &'self Path
+}
+
+impl<'self> ViewItemVisitor<'self> {
+    /// If `lib_name` is one of the parent package's other libraries, which
+    /// `PkgSrc::build` has already built into `out_dir`, records it as a
+    /// dependency and returns true
+    fn found_local_lib(&mut self, lib_name: &str) -> bool {
+        if lib_name == self.parent.short_name.as_slice() {
+            return false;
+        }
+        match library_in(lib_name, &NoVersion, self.out_dir) {
+            Some(ref built) => {
+                debug!("{} is one of the package's own libraries: {}",
+                       lib_name, built.display());
+                // FIXME (#9639): This needs to handle non-utf8 paths
+                add_dep(self.deps, self.parent_crate.as_str().unwrap().to_owned(),
+                        (~"binary", built.as_str().unwrap().to_owned()));
+                self.exec.discover_input("binary",
+                                         built.as_str().unwrap(),
+                                         digest_only_date_using(built,
+                                                                self.context.context.digest));
+                (self.save)(self.out_dir.clone());
+                true
+            }
+            None => false
+        }
+    }
 }
 
 impl<'self> Visitor<()> for ViewItemVisitor<'self> {
@@ -439,6 +845,9 @@ impl<'self> Visitor<()> for ViewItemVisitor<'self> {
                     Some((p, _)) => p,
                     None => self.sess.str_of(lib_ident)
                 };
+                if self.found_local_lib(lib_name.as_slice()) {
+                    return visit::walk_view_item(self, vi, env);
+                }
                 debug!("Finding and installing... {}", lib_name);
                 // Check standard Rust library path first
                 let whatever = system_library(&self.context.sysroot(), lib_name);
@@ -457,7 +866,9 @@ impl<'self> Visitor<()> for ViewItemVisitor<'self> {
                                 (~"binary", installed_path.as_str().unwrap().to_owned()));
                         self.exec.discover_input("binary",
                                                  installed_path.as_str().unwrap(),
-                                                 digest_only_date(installed_path));
+                                                 digest_only_date_using(
+                                                     installed_path,
+                                                     self.context.context.digest));
                     }
                     None => {
                         // FIXME #8711: need to parse version out of path_opt
@@ -500,18 +911,31 @@ impl<'self> Visitor<()> for ViewItemVisitor<'self> {
                                                pkg_id.path.display()));
                                  fail!()
                         }).inside(|| {
-                            PkgSrc::new(source_workspace.clone(),
+                            PkgSrc::new_with_fetchers(source_workspace.clone(),
                                         dest_workspace.clone(),
                                         // Use the rust_path_hack to search for dependencies iff
                                         // we were already using it
                                         self.context.context.use_rust_path_hack,
-                                        pkg_id.clone())
+                                        pkg_id.clone(),
+                                        self.context.context.git_clone_depth,
+                                        &self.context.fetchers,
+                                        &self.context.context.tmp_dir)
                         });
+                        // --out-dir is only for the package being built, so
+                        // dependencies are built where install expects them.
+                        // Installing them is part of compiling this crate,
+                        // which a cancel doesn't interrupt.
+                        let mut dep_context = self.context.clone();
+                        dep_context.context.out_dir = None;
+                        dep_context.cancel = None;
                         let (outputs_disc, inputs_disc) =
-                            self.context.install(
+                            match dep_context.install(
                                 pkg_src,
                                 &WhatToBuild::new(Inferred,
-                                                  JustOne(Path::new(lib_crate_filename))));
+                                                  JustOne(Path::new(lib_crate_filename)))) {
+                                Built(result) => result,
+                                Canceled => fail!("installing {} was canceled", lib_name)
+                            };
                         debug!("Installed {}, returned {:?} dependencies and \
                                {:?} transitive dependencies",
                                lib_name, outputs_disc.len(), inputs_disc.len());
@@ -524,7 +948,8 @@ impl<'self> Visitor<()> for ViewItemVisitor<'self> {
                             // FIXME (#9639): This needs to handle non-utf8 paths
                             self.exec.discover_input("binary",
                                                      dep.as_str().unwrap(),
-                                                     digest_only_date(dep));
+                                                     digest_only_date_using(
+                                                         dep, self.context.context.digest));
                             add_dep(self.deps,
                                     self.parent_crate.as_str().unwrap().to_owned(),
                                     (~"binary", dep.as_str().unwrap().to_owned()));
@@ -548,16 +973,18 @@ impl<'self> Visitor<()> for ViewItemVisitor<'self> {
                                         (~"file", dep.clone()));
                                 self.exec.discover_input(*what,
                                                          *dep,
-                                                         digest_file_with_date(
-                                                             &Path::new(dep.as_slice())));
+                                                         digest_file_with_date_using(
+                                                             &Path::new(dep.as_slice()),
+                                                             self.context.context.digest));
                             } else if *what == ~"binary" {
                                 add_dep(self.deps,
                                         self.parent_crate.as_str().unwrap().to_owned(),
                                         (~"binary", dep.clone()));
                                 self.exec.discover_input(*what,
                                                          *dep,
-                                                         digest_only_date(
-                                                             &Path::new(dep.as_slice())));
+                                                         digest_only_date_using(
+                                                             &Path::new(dep.as_slice()),
+                                                             self.context.context.digest));
                             } else {
                                 fail!("Bad kind: {}", *what);
                             }
@@ -586,6 +1013,7 @@ pub fn find_and_install_dependencies(context: &BuildContext,
                                      exec: &mut workcache::Exec,
                                      c: &ast::Crate,
                                      deps: &mut DepMap,
+                                     out_dir: &Path,
                                      save: |Path|) {
     debug!("In find_and_install_dependencies...");
     let mut visitor = ViewItemVisitor {
@@ -596,7 +1024,8 @@ pub fn find_and_install_dependencies(context: &BuildContext,
         exec: exec,
         c: c,
         save: save,
-        deps: deps
+        deps: deps,
+        out_dir: out_dir
     };
     visit::walk_crate(&mut visitor, c, ())
 }
@@ -608,23 +1037,658 @@ pub fn mk_string_lit(s: @str) -> ast::lit {
     }
 }
 
+/// The environment that `test --isolated` runs tests in: rustpkg's own,
+/// except that HOME and RUST_PATH are both `home`
+pub fn isolated_env(home: &Path) -> ~[(~str, ~str)] {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let home = home.as_str().unwrap().to_owned();
+    let mut env: ~[(~str, ~str)] = os::env().move_iter()
+        .filter(|&(ref k, _)| *k != ~"HOME" && *k != ~"RUST_PATH")
+        .collect();
+    env.push((~"HOME", home.clone()));
+    env.push((~"RUST_PATH", home));
+    env
+}
+
+/// `env`, with the variable `key` set to `value` in place of any value it had
+pub fn env_with(env: ~[(~str, ~str)], key: &str, value: ~str) -> ~[(~str, ~str)] {
+    let mut env: ~[(~str, ~str)] = env.move_iter().filter(|&(ref k, _)| k.as_slice() != key)
+                                                  .collect();
+    env.push((key.to_owned(), value));
+    env
+}
+
+/// Like `run::process_status`, but gives up on the process if it hasn't
+/// exited after `timeout_secs` seconds. In that case, the process is
+/// killed and None is returned.
+pub fn process_status_with_timeout(prog: &str,
+                                   args: &[~str],
+                                   timeout_secs: u64) -> Option<ProcessExit> {
+    process_status_in_env(prog, args, None, Some(timeout_secs))
+}
+
+/// Like `process_status_with_timeout`, but runs `prog` with the environment
+/// `env` (or rustpkg's own environment, if `env` is None), and only gives up
+/// on it if `timeout_secs` is given. If `prog` can't be run at all, says why
+/// and returns exit status 127, as a shell would.
+///
+/// A process with a timeout is started in a process group of its own, so
+/// that on Unix, anything it started is killed along with it. It doesn't get
+/// signals from the terminal in that case, so if rustpkg is interrupted while
+/// waiting for it, its process group is killed before rustpkg stops. Either
+/// way, `prog` starts with no signals blocked (see
+/// `ProcessConfig::default_signals`).
+pub fn process_status_in_env(prog: &str,
+                             args: &[~str],
+                             env: Option<~[(~str, ~str)]>,
+                             timeout_secs: Option<u64>) -> Option<ProcessExit> {
+    let prog = prog.to_owned();
+    let args = args.to_owned();
+    let detach = timeout_secs.is_some();
+    let (pid_port, pid_chan) = oneshot();
+    let (status_port, status_chan) = oneshot();
+    let sub_prog = prog.clone();
+    do task::spawn {
+        let io = [Ignored, InheritFd(libc::STDOUT_FILENO), InheritFd(libc::STDERR_FILENO)];
+        let spawned = io::result(|| process::Process::new(ProcessConfig {
+            program: sub_prog,
+            args: args,
+            env: env.as_ref().map(|e| e.as_slice()),
+            cwd: None,
+            io: io,
+            detach: detach,
+            niceness: None,
+            default_signals: true
+        }));
+        match spawned {
+            Ok(Some(p)) => {
+                let mut p = p;
+                pid_chan.send(Ok(p.id()));
+                status_chan.send(p.wait());
+            }
+            Ok(None) => pid_chan.send(Err(~"it couldn't be started")),
+            Err(e) => pid_chan.send(Err(e.desc.to_owned()))
+        }
+    }
+
+    let pid = match pid_port.recv() {
+        Ok(pid) => pid,
+        Err(why) => {
+            error(format!("Couldn't run {}: {}", prog, why));
+            return Some(process::ExitStatus(127));
+        }
+    };
+    let timeout_secs = match timeout_secs {
+        Some(secs) => secs,
+        None => return Some(status_port.recv())
+    };
+    let mut listener = Listener::new();
+    listener.register(Interrupt);
+    let mut timer = Timer::new().expect("couldn't create a timer");
+    let timeout_ms = timeout_secs * 1000;
+    let mut waited = 0;
+    while waited < timeout_ms && !status_port.peek() {
+        if listener.port.try_recv().is_some() {
+            debug!("Interrupted while waiting for {}, killing it", prog);
+            kill_process(pid);
+            status_port.recv();
+            listener.unregister(Interrupt);
+            interrupt_self();
+            fail!("interrupted");
+        }
+        timer.sleep(TIMEOUT_POLL_MS);
+        waited += TIMEOUT_POLL_MS;
+    }
+    listener.unregister(Interrupt);
+    if status_port.peek() {
+        Some(status_port.recv())
+    } else {
+        debug!("{} didn't exit after {} seconds, killing it", prog, timeout_secs);
+        kill_process(pid);
+        // Wait for the kill to take effect, so the process gets reaped
+        status_port.recv();
+        None
+    }
+}
+
+/// Once rustpkg's own SIGINT handler has done its part, stops rustpkg the
+/// way SIGINT would have
+#[cfg(not(windows))]
+fn interrupt_self() {
+    unsafe {
+        libc::funcs::posix88::signal::kill(libc::getpid(), libc::SIGINT);
+    }
+}
+
+/// There's no signal to raise again on Windows, so the caller fails instead
+#[cfg(windows)]
+fn interrupt_self() {}
+
+#[cfg(windows)]
+fn kill_process(pid: libc::pid_t) {
+    use std::libc::consts::os::extra::{FALSE, PROCESS_TERMINATE};
+    use std::libc::funcs::extra::kernel32::{OpenProcess, TerminateProcess, CloseHandle};
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, FALSE, pid as libc::DWORD);
+        if handle.is_not_null() {
+            TerminateProcess(handle, 1);
+            CloseHandle(handle);
+        }
+    }
+}
+
+/// Kills the process group led by `pid`, which must have been started
+/// detached
+#[cfg(not(windows))]
+fn kill_process(pid: libc::pid_t) {
+    unsafe {
+        libc::funcs::posix88::signal::kill(-pid, process::MustDieSignal as libc::c_int);
+    }
+}
+
+/// Writes a Makefile-style dependency file to `dep_file`, saying that
+/// `target` depends on each of `prereqs`. Spaces in paths are escaped
+/// with backslashes, as make expects.
+pub fn write_dep_file(dep_file: &Path, target: &Path, prereqs: &[Path]) {
+    fn escape(p: &Path) -> ~str {
+        p.display().to_str().replace(" ", "\\ ")
+    }
+    let mut contents = escape(target) + ":";
+    for p in prereqs.iter() {
+        contents.push_char(' ');
+        contents.push_str(escape(p));
+    }
+    contents.push_char('\n');
+    fs::mkdir_recursive(&dep_file.dir_path(), io::UserRWX);
+    File::create(dep_file).write(contents.as_bytes());
+}
+
+/// Copies `from` to `to` in a way that never leaves a partially written file
+/// at `to`: `to` is either left alone or replaced with a complete copy of `from`.
+pub fn install_file(from: &Path, to: &Path) {
+    install_file_reporting(from, to, &None);
+}
+
+/// Like `install_file`, but if `progress` is Some(c), copies in chunks and
+/// tells c how far it's got after each one (see `BuildContext::copy_progress`)
+pub fn install_file_reporting(from: &Path, to: &Path,
+                              progress: &Option<Callback<~CopyObserver:Send>>) {
+    let staged = stage_install_reporting(from, to, progress);
+    finish_install(&staged, to);
+}
+
+/// Like `install_file_reporting`, but leaves `to` alone if its date digest
+/// already matches `from`'s. Otherwise, gives the new copy `from`'s
+/// modification time so that a later install of the same file is skipped.
+/// Returns true if it copied the file.
+pub fn install_file_if_changed(from: &Path, to: &Path,
+                               progress: &Option<Callback<~CopyObserver:Send>>,
+                               alg: DigestAlgorithm) -> bool {
+    if to.exists() && digest_only_date_using(from, alg) == digest_only_date_using(to, alg) {
+        debug!("{} is unchanged; not installing it again", to.display());
+        return false;
+    }
+    install_file_reporting(from, to, progress);
+    let st = from.stat();
+    fs::change_file_times(to, st.accessed, st.modified);
+    true
+}
+
+/// Like `install_file_if_changed`, but creates `to`'s directory first, and
+/// if either that or the copy fails, reports why (see `describe_copy_error`)
+/// and fails with COPY_FAILED_CODE.
+pub fn install_file_or_fail(from: &Path, to: &Path,
+                            progress: &Option<Callback<~CopyObserver:Send>>,
+                            alg: DigestAlgorithm) -> bool {
+    io::io_error::cond.trap(|e| {
+        error(describe_copy_error(from, to, &e));
+        fail!(COPY_FAILED_CODE)
+    }).inside(|| {
+        fs::mkdir_recursive(&to.dir_path(), io::UserRWX);
+        install_file_if_changed(from, to, progress, alg)
+    })
+}
+
+/// Like `install_file_or_fail`, for an installed executable or dynamic
+/// library: with `strip`, `to` is stripped once it's been copied (see
+/// `strip_or_warn`), and then given `from`'s modification time again, so
+/// that the next install still finds it unchanged. `stripped_before` says
+/// whether the last install left a stripped copy at `to`; if that doesn't
+/// match `strip`, `to` is replaced even though it looks unchanged. Returns
+/// whether `from` was copied.
+pub fn install_binary_or_fail(from: &Path, to: &Path, strip: bool, stripped_before: bool,
+                              progress: &Option<Callback<~CopyObserver:Send>>,
+                              alg: DigestAlgorithm) -> bool {
+    if strip != stripped_before && to.exists() {
+        io::io_error::cond.trap(|e| {
+            error(describe_copy_error(from, to, &e));
+            fail!(COPY_FAILED_CODE)
+        }).inside(|| fs::unlink(to));
+    }
+    let copied = install_file_or_fail(from, to, progress, alg);
+    if copied && strip {
+        strip_or_warn(to);
+        let st = from.stat();
+        fs::change_file_times(to, st.accessed, st.modified);
+    }
+    copied
+}
+
+/// For --strip: removes the debugging symbols from the installed executable
+/// or dynamic library `path`, with `strip -S`. If there's no strip tool to
+/// run, warns and leaves `path` as it is. Fails with COPY_FAILED_CODE if the
+/// strip tool fails.
+pub fn strip_or_warn(path: &Path) {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let args = [~"-S", path.as_str().unwrap().to_owned()];
+    let io = [Ignored, InheritFd(libc::STDOUT_FILENO), InheritFd(libc::STDERR_FILENO)];
+    let result = io::result(|| {
+        process::Process::new(ProcessConfig {
+            program: "strip",
+            args: args,
+            env: None,
+            cwd: None,
+            io: io,
+            detach: false,
+            niceness: None,
+            default_signals: false
+        }).map(|mut p| p.wait())
+    });
+    match result {
+        Ok(Some(ref status)) if status.success() => {}
+        Ok(Some(status)) => {
+            error(format!("Couldn't strip {} (strip failed with {})", path.display(), status));
+            fail!(COPY_FAILED_CODE)
+        }
+        Ok(None) | Err(_) => {
+            warn(format!("Not stripping {}, since there's no strip tool to run",
+                         path.display()));
+        }
+    }
+}
+
+/// Explains why copying `from` to `to` failed with `e`, telling apart the
+/// failures that the user can do something about: not being allowed to write
+/// to the destination, the two being on different devices, and the
+/// destination's disk being full.
+pub fn describe_copy_error(from: &Path, to: &Path, e: &io::IoError) -> ~str {
+    let dir = to.dir_path();
+    let reason = if e.desc.contains("cross-device") {
+        format!("{}; {} and {} are on different devices", e.desc, from.display(),
+                dir.display())
+    } else if e.kind == io::PermissionDenied || e.desc.contains("not permitted")
+        || e.desc.contains("read-only file system") {
+        format!("{}; check that you can write to {}", e.desc, dir.display())
+    } else if e.desc.contains("no space left") || e.desc.contains("quota exceeded") {
+        format!("{}; free up some space on the disk holding {}", e.desc, dir.display())
+    } else {
+        match e.detail {
+            Some(ref detail) => format!("{} ({})", e.desc, *detail),
+            None => e.desc.to_owned()
+        }
+    };
+    format!("Couldn't copy {} to {}: {}", from.display(), to.display(), reason)
+}
+
+/// If files can't be created under `dir` because it's read-only, returns the
+/// read-only directory: `dir` itself if it exists, and otherwise its nearest
+/// ancestor that does. This asks the system whether this process may write to
+/// and search that directory, so a directory owned by another user counts as
+/// read-only, but nothing does for root. Windows ignores the read-only
+/// attribute of directories, so there, this always returns None.
+#[cfg(not(windows))]
+pub fn read_only_ancestor(dir: &Path) -> Option<Path> {
+    let mut dir = dir.clone();
+    while !dir.exists() {
+        if !dir.pop() {
+            return None;
+        }
+    }
+    if can_access(&dir, libc::W_OK | libc::X_OK) { None } else { Some(dir) }
+}
+
+#[cfg(windows)]
+pub fn read_only_ancestor(_dir: &Path) -> Option<Path> {
+    None
+}
+
+/// True if this process can't write to the existing file `path`. Like
+/// `read_only_ancestor`, this asks the system, so a file that another user
+/// owns counts as read-only, but nothing does for root.
+#[cfg(not(windows))]
+pub fn is_read_only_file(path: &Path) -> bool {
+    !can_access(path, libc::W_OK)
+}
+
+#[cfg(windows)]
+pub fn is_read_only_file(path: &Path) -> bool {
+    io::result(|| path.stat()).map_default(false, |st| st.perm & io::UserWrite == 0)
+}
+
+/// Whether `access` allows `mode` (some of W_OK, X_OK and so on) on `path`
+#[cfg(not(windows))]
+fn can_access(path: &Path, mode: libc::c_int) -> bool {
+    path.with_c_str(|p| unsafe { libc::access(p, mode) == 0 })
+}
+
+/// How many files this process has staged, to give each one its own name
+static mut STAGED_FILES: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The first half of `install_file`: copies `from` to a temporary file in
+/// `to`'s directory, and returns the temporary file's path. The file's name
+/// includes the process ID and a counter, so that concurrent installs of the
+/// same file don't write to the same temporary file. If the copy fails, the
+/// temporary file is removed.
+pub fn stage_install(from: &Path, to: &Path) -> Path {
+    stage_install_reporting(from, to, &None)
+}
+
+fn stage_install_reporting(from: &Path, to: &Path,
+                           progress: &Option<Callback<~CopyObserver:Send>>) -> Path {
+    // FIXME (#9639): This needs to handle non-utf8 paths
+    let n = unsafe { STAGED_FILES.fetch_add(1, SeqCst) };
+    let staged = to.with_filename(format!(".{}.{}-{}.rustpkg-tmp", to.filename_str().unwrap(),
+                                          unsafe { libc::getpid() }, n));
+    let copied = io::result(|| match *progress {
+        None => fs::copy(from, &staged),
+        Some(ref c) => copy_reporting(from, &staged, to.as_str().unwrap(), c)
+    });
+    match copied {
+        Ok(()) => staged,
+        Err(e) => {
+            if staged.exists() {
+                io::ignore_io_error(|| fs::unlink(&staged));
+            }
+            io::io_error::cond.raise(e);
+            staged
+        }
+    }
+}
+
+/// The most bytes `copy_reporting` copies between progress reports
+static COPY_CHUNK_SIZE: uint = 64 * 1024;
+
+/// Like `fs::copy`, but tells `progress` `name`, the number of bytes copied
+/// so far and the total before starting and after each chunk
+fn copy_reporting(from: &Path, to: &Path, name: &str, progress: &Callback<~CopyObserver:Send>) {
+    if !from.is_file() {
+        return io::io_error::cond.raise(io::IoError {
+            kind: io::MismatchedFileTypeForOperation,
+            desc: "the source path is not an existing file",
+            detail: None,
+        });
+    }
+    let total = from.stat().size;
+    let mut reader = match File::open(from) { Some(f) => f, None => return };
+    let mut writer = match File::create(to) { Some(f) => f, None => return };
+    let mut buf = vec::from_elem(COPY_CHUNK_SIZE, 0u8);
+    let mut copied = 0u64;
+    unsafe { progress.with(|p| p.copied(name, copied, total)); }
+    loop {
+        match reader.read(buf) {
+            Some(amt) => {
+                writer.write(buf.slice_to(amt));
+                copied += amt as u64;
+                unsafe { progress.with(|p| p.copied(name, copied, total)); }
+            }
+            None => break
+        }
+    }
+    fs::chmod(to, from.stat().perm)
+}
+
+/// The second half of `install_file`: moves the file `stage_install`
+/// returned into place at `to`, or removes it if that fails
+pub fn finish_install(staged: &Path, to: &Path) {
+    match io::result(|| replace_file(staged, to)) {
+        Ok(()) => (),
+        Err(e) => {
+            io::ignore_io_error(|| fs::unlink(staged));
+            io::io_error::cond.raise(e);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn replace_file(from: &Path, to: &Path) {
+    // rename(2) atomically replaces any existing file
+    fs::rename(from, to);
+}
+
+#[cfg(windows)]
+fn replace_file(from: &Path, to: &Path) {
+    // Renaming onto an existing file fails on Windows, so get the old one out
+    // of the way first. This leaves a short window where `to` doesn't exist,
+    // but never one where it's partially written.
+    if to.exists() {
+        fs::unlink(to);
+    }
+    fs::rename(from, to);
+}
+
+/// Parses the contents of a package list file, as given to
+/// `rustpkg install --from-file`: one package ID (optionally with a
+/// version, as in `foo#0.1`) per line. Blank lines are ignored, as is
+/// everything after a `#` that starts a word.
+pub fn parse_pkgid_list(contents: &str) -> ~[~str] {
+    let mut result = ~[];
+    for line in contents.lines() {
+        match line.words().next() {
+            Some(w) if !w.starts_with("#") => result.push(w.to_owned()),
+            _ => ()
+        }
+    }
+    result
+}
+
+/// Reads the package list file `p` (see `parse_pkgid_list`).
+/// Returns why not if the file can't be read, or isn't UTF-8.
+pub fn read_pkgid_list(p: &Path) -> Result<~[~str], ~str> {
+    match io::result(|| File::open(p).read_to_end()) {
+        Ok(bytes) => match str::from_utf8_owned_opt(bytes) {
+            Some(contents) => Ok(parse_pkgid_list(contents)),
+            None => Err(~"it isn't UTF-8")
+        },
+        Err(e) => Err(e.desc.to_owned())
+    }
+}
+
+/// Parses the output of a package script's `binaries` hook: one
+/// `<crate path> <binary name>` pair per line. Lines that don't have
+/// exactly two words are ignored.
+pub fn parse_binary_names(output: &str) -> ~[(Path, ~str)] {
+    let mut result = ~[];
+    for line in output.lines_any() {
+        let words: ~[&str] = line.words().collect();
+        if words.len() == 2 {
+            result.push((Path::new(words[0]), words[1].to_owned()));
+        } else {
+            debug!("parse_binary_names: ignoring line {}", line);
+        }
+    }
+    result
+}
+
+/// Separates the cfgs that a package script's `configs` hook printed from the
+/// crate targets it asked for. A target is `target:<crate path>`, or
+/// `target(<cfg>):<crate path>` to only build the crate when `<cfg>` is one of
+/// the cfgs the package is built with. Crate paths are relative to the
+/// package's source directory. Returns the cfgs, and each target with the cfg
+/// it's gated on, if any.
+pub fn parse_script_configs(words: &[~str]) -> (~[~str], ~[(Path, Option<~str>)]) {
+    let mut cfgs = ~[];
+    let mut targets = ~[];
+    for w in words.iter() {
+        if w.starts_with("target:") {
+            targets.push((Path::new(w.slice_from("target:".len())), None));
+        } else if w.starts_with("target(") && w.contains("):") {
+            let rest = w.slice_from("target(".len());
+            let end = rest.find_str("):").unwrap();
+            targets.push((Path::new(rest.slice_from(end + "):".len())),
+                          Some(rest.slice_to(end).to_owned())));
+        } else {
+            cfgs.push(w.clone());
+        }
+    }
+    (cfgs, targets)
+}
+
+/// Parses the contents of a package script's static configs file (see
+/// `static_configs_path`): whitespace-separated words, each of which is a cfg,
+/// `link-arg:ARG` or `binary:CRATE=NAME` (the static version of the
+/// `binaries` hook), where `#` starts a comment that runs to the end of the
+/// line. Returns the cfgs, the link arguments and the binary names, or an
+/// error message if the file asks for something that can only come from
+/// running the script.
+pub fn parse_static_configs(contents: &str)
+                            -> Result<(~[~str], ~[~str], ~[(Path, ~str)]), ~str> {
+    let mut cfgs = ~[];
+    let mut link_args = ~[];
+    let mut binary_names = ~[];
+    for line in contents.lines_any() {
+        let line = match line.find('#') {
+            Some(i) => line.slice_to(i),
+            None => line
+        };
+        for w in line.words() {
+            if w.starts_with("link-arg:") {
+                link_args.push(w.slice_from("link-arg:".len()).to_owned());
+            } else if w.starts_with("binary:") {
+                let rest = w.slice_from("binary:".len());
+                match rest.find('=') {
+                    Some(i) if i > 0 && i + 1 < rest.len() => {
+                        binary_names.push((Path::new(rest.slice_to(i)),
+                                           rest.slice_from(i + 1).to_owned()));
+                    }
+                    _ => return Err(format!("`{}` should be `binary:CRATE=NAME`", w))
+                }
+            } else if w.starts_with("target:") || w.starts_with("target(") {
+                return Err(format!("`{}` can only be printed by the configs hook", w));
+            } else {
+                cfgs.push(w.to_owned());
+            }
+        }
+    }
+    Ok((cfgs, link_args, binary_names))
+}
+
+/// The static configs file for the package script `script`: the file
+/// next to it with the extension `cfgs` (`pkg.cfgs` for `pkg.rs`)
+pub fn static_configs_path(script: &Path) -> Path {
+    script.with_extension("cfgs")
+}
+
 #[cfg(test)]
 mod test {
-    use super::is_cmd;
+    use super::{is_cmd, parse_pkgid_list, parse_binary_names, parse_script_configs};
+    use super::parse_static_configs;
 
     #[test]
     fn test_is_cmd() {
         assert!(is_cmd("build"));
         assert!(is_cmd("clean"));
+        assert!(is_cmd("clean-cache"));
+        assert!(is_cmd("deps"));
         assert!(is_cmd("do"));
+        assert!(is_cmd("doctor"));
+        assert!(is_cmd("env"));
+        assert!(is_cmd("verify"));
         assert!(is_cmd("info"));
         assert!(is_cmd("install"));
         assert!(is_cmd("prefer"));
+        assert!(is_cmd("reinstall"));
+        assert!(is_cmd("run-example"));
+        assert!(is_cmd("targets"));
         assert!(is_cmd("test"));
         assert!(is_cmd("uninstall"));
         assert!(is_cmd("unprefer"));
+        assert!(is_cmd("update"));
+        assert!(is_cmd("which"));
+    }
+
+    #[test]
+    fn test_parse_pkgid_list() {
+        let contents = "# packages to install\n\
+                        foo\n\
+                        \n\
+                        github.com/mozilla/servo#0.1.2   # pinned\n\
+                        \t  bar  \n\
+                        #baz\n";
+        assert_eq!(parse_pkgid_list(contents),
+                   ~[~"foo", ~"github.com/mozilla/servo#0.1.2", ~"bar"]);
     }
 
+    #[test]
+    fn test_parse_binary_names() {
+        let output = "main.rs frobnicate\n\
+                      \n\
+                      tools/gen/main.rs   gen\r\n\
+                      Warning: I don't know how to binaries\n";
+        assert_eq!(parse_binary_names(output),
+                   ~[(Path::new("main.rs"), ~"frobnicate"),
+                     (Path::new("tools/gen/main.rs"), ~"gen")]);
+    }
+
+    #[test]
+    fn test_parse_script_configs() {
+        let words = [~"quux", ~"target:src/gpu/lib.rs", ~"target(gpu):extra/main.rs",
+                     ~"bar=baz"];
+        let (cfgs, targets) = parse_script_configs(words);
+        assert_eq!(cfgs, ~[~"quux", ~"bar=baz"]);
+        assert_eq!(targets, ~[(Path::new("src/gpu/lib.rs"), None),
+                              (Path::new("extra/main.rs"), Some(~"gpu"))]);
+    }
+
+    #[test]
+    fn test_parse_static_configs() {
+        let contents = "# what to build with\n\
+                        quux bar=baz  # two cfgs\n\
+                        \n\
+                        link-arg:-lm link-arg:-L/opt/lib\n\
+                        binary:main.rs=frobnicate\n";
+        assert_eq!(parse_static_configs(contents),
+                   Ok((~[~"quux", ~"bar=baz"], ~[~"-lm", ~"-L/opt/lib"],
+                       ~[(Path::new("main.rs"), ~"frobnicate")])));
+        assert!(parse_static_configs("binary:main.rs").is_err());
+        assert!(parse_static_configs("binary:=frobnicate").is_err());
+        assert!(parse_static_configs("quux target:src/gpu/lib.rs").is_err());
+        assert!(parse_static_configs("target(gpu):extra/main.rs").is_err());
+    }
+
+    #[test]
+    fn test_env_with() {
+        use super::env_with;
+
+        let env = ~[(~"HOME", ~"/home/me"), (~"RUST_TEST_TASKS", ~"8")];
+        assert_eq!(env_with(env.clone(), "RUST_TEST_TASKS", ~"2"),
+                   ~[(~"HOME", ~"/home/me"), (~"RUST_TEST_TASKS", ~"2")]);
+        assert_eq!(env_with(env, "PATH", ~"/bin"),
+                   ~[(~"HOME", ~"/home/me"), (~"RUST_TEST_TASKS", ~"8"), (~"PATH", ~"/bin")]);
+    }
+
+    #[test]
+    fn test_describe_copy_error() {
+        use std::io;
+        use super::describe_copy_error;
+
+        let error = |kind, desc| io::IoError { kind: kind, desc: desc, detail: None };
+        let from = Path::new("/build/foo");
+        let to = Path::new("/ws/bin/foo");
+        let describe = |e| describe_copy_error(&from, &to, &e);
+
+        let msg = describe(error(io::PermissionDenied, "permission denied"));
+        assert!(msg.starts_with("Couldn't copy /build/foo to /ws/bin/foo: permission denied"));
+        assert!(msg.contains("check that you can write to /ws/bin"));
+        assert!(describe(error(io::OtherIoError, "read-only file system"))
+                .contains("check that you can write to /ws/bin"));
+        assert!(describe(error(io::OtherIoError, "cross-device link not permitted"))
+                .contains("are on different devices"));
+        assert!(describe(error(io::OtherIoError, "no space left on device"))
+                .contains("free up some space on the disk holding /ws/bin"));
+        assert!(describe(error(io::OtherIoError, "i/o error"))
+                .ends_with("/ws/bin/foo: i/o error"));
+    }
 }
 
 pub fn option_to_vec<T>(x: Option<T>) -> ~[T] {
@@ -12,18 +12,79 @@ use std::io;
 use std::io::File;
 use extra::workcache;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
-/// Hashes the file contents along with the last-modified time
+/// The hash functions rustpkg can digest workcache inputs and outputs with
+#[deriving(Eq, Clone)]
+pub enum DigestAlgorithm {
+    Sha1Digest,   // the default
+    Sha256Digest,
+}
+
+impl DigestAlgorithm {
+    /// Parses the argument to --digest
+    pub fn from_str(s: &str) -> Option<DigestAlgorithm> {
+        match s {
+            "sha1"   => Some(Sha1Digest),
+            "sha256" => Some(Sha256Digest),
+            _        => None
+        }
+    }
+
+    /// The name of the algorithm, which prefixes every digest made with it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Sha1Digest   => "sha1",
+            Sha256Digest => "sha256"
+        }
+    }
+
+    fn hasher(&self) -> ~Digest {
+        match *self {
+            Sha1Digest   => ~Sha1::new() as ~Digest,
+            Sha256Digest => ~Sha256::new() as ~Digest
+        }
+    }
+
+    /// Returns the algorithm that `digest` was made with, if it's a digest
+    /// made by this module. Digests without a prefix were made with SHA-1,
+    /// before digests said which algorithm made them.
+    fn of_digest(digest: &str) -> Option<DigestAlgorithm> {
+        match digest.find(':') {
+            Some(i) => DigestAlgorithm::from_str(digest.slice_to(i)),
+            None => Some(Sha1Digest)
+        }
+    }
+}
+
+/// Returns the result of `hasher`, prefixed with the name of `alg`. Since
+/// the algorithm is part of every digest, and digests are part of workcache
+/// keys, switching algorithms makes every cached result stale.
+fn finish(alg: DigestAlgorithm, mut hasher: ~Digest) -> ~str {
+    format!("{}:{}", alg.name(), hasher.result_str())
+}
+
+/// Hashes the file contents along with the last-modified time, with SHA-1
 pub fn digest_file_with_date(path: &Path) -> ~str {
+    digest_file_with_date_using(path, Sha1Digest)
+}
+
+/// Hashes only the last-modified time, with SHA-1
+pub fn digest_only_date(path: &Path) -> ~str {
+    digest_only_date_using(path, Sha1Digest)
+}
+
+/// Like `digest_file_with_date`, but hashes with `alg`
+pub fn digest_file_with_date_using(path: &Path, alg: DigestAlgorithm) -> ~str {
     use conditions::bad_path::cond;
 
     match io::result(|| File::open(path).read_to_end()) {
         Ok(bytes) => {
-            let mut sha = Sha1::new();
-            sha.input(bytes);
+            let mut hasher = alg.hasher();
+            hasher.input(bytes);
             let st = path.stat();
-            sha.input_str(st.modified.to_str());
-            sha.result_str()
+            hasher.input_str(st.modified.to_str());
+            finish(alg, hasher)
         }
         Err(e) => {
             cond.raise((path.clone(), format!("Couldn't read file: {}", e.desc)));
@@ -32,22 +93,78 @@ pub fn digest_file_with_date(path: &Path) -> ~str {
     }
 }
 
-/// Hashes only the last-modified time
-pub fn digest_only_date(path: &Path) -> ~str {
-    let mut sha = Sha1::new();
+/// Hashes only the file contents, with `alg`, so that unlike the digests
+/// above, the digest doesn't change when the file is merely touched
+pub fn digest_file_contents_using(path: &Path, alg: DigestAlgorithm) -> ~str {
+    use conditions::bad_path::cond;
+
+    match io::result(|| File::open(path).read_to_end()) {
+        Ok(bytes) => {
+            let mut hasher = alg.hasher();
+            hasher.input(bytes);
+            finish(alg, hasher)
+        }
+        Err(e) => {
+            cond.raise((path.clone(), format!("Couldn't read file: {}", e.desc)));
+            ~""
+        }
+    }
+}
+
+/// Like `digest_only_date`, but hashes with `alg`
+pub fn digest_only_date_using(path: &Path, alg: DigestAlgorithm) -> ~str {
+    let mut hasher = alg.hasher();
     let st = path.stat();
-    sha.input_str(st.modified.to_str());
-    sha.result_str()
+    hasher.input_str(st.modified.to_str());
+    finish(alg, hasher)
+}
+
+/// True if `path` exists and `digest_file_with_date` gives `digest` for it,
+/// using whichever algorithm `digest` was made with
+pub fn file_digest_matches(path: &Path, digest: &str) -> bool {
+    match DigestAlgorithm::of_digest(digest) {
+        Some(alg) => {
+            path.exists()
+                && without_prefix(digest) == without_prefix(digest_file_with_date_using(path, alg))
+        }
+        None => false
+    }
+}
+
+/// True if `path` exists and `digest_only_date` gives `digest` for it,
+/// using whichever algorithm `digest` was made with
+pub fn date_digest_matches(path: &Path, digest: &str) -> bool {
+    match DigestAlgorithm::of_digest(digest) {
+        Some(alg) => {
+            path.exists()
+                && without_prefix(digest) == without_prefix(digest_only_date_using(path, alg))
+        }
+        None => false
+    }
+}
+
+/// `digest` without the name of the algorithm that made it, if it has one
+fn without_prefix<'a>(digest: &'a str) -> &'a str {
+    match digest.find(':') {
+        Some(i) => digest.slice_from(i + 1),
+        None => digest
+    }
 }
 
 /// Adds multiple discovered outputs
 pub fn discover_outputs(e: &mut workcache::Exec, outputs: ~[Path]) {
+    discover_outputs_using(e, outputs, Sha1Digest)
+}
+
+/// Like `discover_outputs`, but digests the outputs with `alg`
+pub fn discover_outputs_using(e: &mut workcache::Exec, outputs: ~[Path],
+                              alg: DigestAlgorithm) {
     debug!("Discovering {:?} outputs", outputs.len());
     for p in outputs.iter() {
         debug!("Discovering output! {}", p.display());
         // For now, assume that all discovered outputs are binaries
         // FIXME (#9639): This needs to handle non-utf8 paths
-        e.discover_output("binary", p.as_str().unwrap(), digest_only_date(p));
+        e.discover_output("binary", p.as_str().unwrap(), digest_only_date_using(p, alg));
     }
 }
 
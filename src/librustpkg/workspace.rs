@@ -12,9 +12,12 @@
 
 use std::os;
 use std::path::Path;
-use context::Context;
+use context::{Context, FailIfAmbiguous, FirstWorkspace, AllWorkspaces};
 use path_util::{workspace_contains_package_id, find_dir_using_rust_path_hack, default_workspace};
 use path_util::rust_path;
+use rustc::metadata::filesearch::get_rust_path;
+use messages::error;
+use exit_codes::WORKSPACE_PROBLEMS_CODE;
 use util::option_to_vec;
 use package_id::PkgId;
 
@@ -40,6 +43,36 @@ pub fn each_pkg_parent_workspace(cx: &Context,
     return true;
 }
 
+/// Like `each_pkg_parent_workspace`, but only calls `action` on the
+/// workspaces that `cx`'s --ambiguous policy picks (see `choose_workspaces`)
+pub fn each_chosen_pkg_parent_workspace(cx: &Context, pkgid: &PkgId, action: |&Path|) {
+    let mut workspaces = ~[];
+    each_pkg_parent_workspace(cx, pkgid, |ws| { workspaces.push(ws.clone()); false });
+    for ws in choose_workspaces(cx, pkgid, workspaces).iter() {
+        action(ws);
+    }
+}
+
+/// Picks which of `workspaces`, the ones containing `pkgid`, to use,
+/// according to `cx`'s --ambiguous policy. If there's more than one and the
+/// policy is `error`, prints them all and fails with WORKSPACE_PROBLEMS_CODE.
+pub fn choose_workspaces(cx: &Context, pkgid: &PkgId, workspaces: ~[Path]) -> ~[Path] {
+    if workspaces.len() < 2 {
+        return workspaces;
+    }
+    match cx.ambiguous {
+        AllWorkspaces => workspaces,
+        FirstWorkspace => ~[workspaces[0].clone()],
+        FailIfAmbiguous => {
+            let names: ~[~str] = workspaces.iter().map(|ws| ws.display().to_str()).collect();
+            error(format!("Package {} is in more than one workspace: {}. Use \
+                           --ambiguous first or --ambiguous all to say which to use",
+                          pkgid.to_str(), names.connect(", ")));
+            fail!(WORKSPACE_PROBLEMS_CODE)
+        }
+    }
+}
+
 /// Given a package ID, return a vector of all of the workspaces in
 /// the RUST_PATH that contain it
 pub fn pkg_parent_workspaces(cx: &Context, pkgid: &PkgId) -> ~[Path] {
@@ -75,13 +108,46 @@ pub fn cwd_to_workspace() -> Option<(Path, PkgId)> {
     None
 }
 
-/// If `workspace` is the same as `cwd`, and use_rust_path_hack is false,
-/// return `workspace`; otherwise, return the first workspace in the RUST_PATH.
-pub fn determine_destination(cwd: Path, use_rust_path_hack: bool, workspace: &Path) -> Path {
-    if workspace == &cwd && !use_rust_path_hack {
+/// If `workspace` is the same as `cwd`, and `cx` doesn't use the rust path hack,
+/// return `workspace`; otherwise, return the first workspace in the RUST_PATH
+/// (see `fallback_workspace`).
+///
+/// This is where libraries and executables get installed. Tests and benches
+/// are never installed, so `test` looks for them where they were built
+/// instead: in the destination if `workspace` is a package directory that the
+/// rust path hack found (see `is_rust_path_hack_dir`), since that has no
+/// build directory of its own, and otherwise in `workspace`.
+pub fn determine_destination(cwd: Path, cx: &Context, workspace: &Path) -> Path {
+    if workspace == &cwd && !cx.use_rust_path_hack {
         workspace.clone()
     }
     else {
-        default_workspace()
+        fallback_workspace(cx)
+    }
+}
+
+/// True if `dir`, one of the directories `pkg_parent_workspaces` returned for
+/// `pkgid`, is the package's own directory, found with the rust path hack,
+/// rather than a workspace with the package under its `src` directory
+pub fn is_rust_path_hack_dir(cx: &Context, pkgid: &PkgId, dir: &Path) -> bool {
+    cx.use_rust_path_hack && !workspace_contains_package_id(pkgid, dir)
+}
+
+/// Returns the default workspace, for operations that have nowhere better to
+/// put what they build. If RUST_PATH isn't set and `cx` says not to fall back
+/// on the default workspace (with --no-default-workspace), raises
+/// no_rust_path instead; by default, that prints an error and fails with
+/// WORKSPACE_PROBLEMS_CODE.
+pub fn fallback_workspace(cx: &Context) -> Path {
+    use conditions::no_rust_path::cond;
+
+    if cx.no_default_workspace && get_rust_path().is_none() {
+        let msg = ~"there's no workspace to use, and --no-default-workspace was given; \
+                    set RUST_PATH or run rustpkg inside a workspace";
+        return cond.raise_default(msg.clone(), || {
+            error(msg);
+            fail!(WORKSPACE_PROBLEMS_CODE)
+        });
     }
+    default_workspace()
 }
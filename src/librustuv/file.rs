@@ -345,6 +345,9 @@ impl FileWatcher {
         }
     }
 
+    /// The file descriptor this watcher wraps
+    pub fn fd(&self) -> c_int { self.fd }
+
     fn base_read(&mut self, buf: &mut [u8], offset: i64) -> Result<int, IoError> {
         let _m = self.fire_homing_missile();
         let r = FsRequest::read(&self.loop_, self.fd, buf, offset);
@@ -68,7 +68,7 @@ pub use self::file::{FsRequest, FileWatcher};
 pub use self::idle::IdleWatcher;
 pub use self::net::{TcpWatcher, TcpListener, TcpAcceptor, UdpWatcher};
 pub use self::pipe::{PipeWatcher, PipeListener, PipeAcceptor};
-pub use self::process::Process;
+pub use self::process::{Process, TempCwd, spawn_error_to_io_error};
 pub use self::signal::SignalWatcher;
 pub use self::timer::TimerWatcher;
 pub use self::tty::TtyWatcher;
@@ -338,6 +338,7 @@ pub fn uv_error_to_io_error(uverr: UvError) -> IoError {
             OK => OtherIoError,
             EOF => EndOfFile,
             EACCES => PermissionDenied,
+            ENOENT => FileNotFound,
             ECONNREFUSED => ConnectionRefused,
             ECONNRESET => ConnectionReset,
             ENOTCONN => NotConnected,
@@ -8,21 +8,28 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::c_str::CString;
 use std::libc::c_int;
 use std::libc;
+use std::os;
 use std::ptr;
+use std::rand;
 use std::rt::BlockedTask;
+use std::io;
 use std::io::IoError;
 use std::io::process::*;
 use std::rt::local::Local;
 use std::rt::rtio::RtioProcess;
 use std::rt::sched::{Scheduler, SchedHandle};
+use std::unstable::mutex::{Mutex, MUTEX_INIT};
+use std::unstable::sync::atomically;
 use std::vec;
 
 use super::{Loop, UvHandle, UvError, uv_error_to_io_error,
             wait_until_woken_after};
 use uvio::HomingIO;
 use uvll;
+use file::FsRequest;
 use pipe::PipeWatcher;
 
 pub struct Process {
@@ -34,16 +41,37 @@ pub struct Process {
 
     /// Collected from the exit_cb
     exit_status: Option<ProcessExit>,
+
+    /// Whether the uv handle has already been closed (see `close_handle`)
+    closed: bool,
+
+    /// The response file holding the arguments, if the process was spawned
+    /// with one (see `spawn_with_response_file`), and the loop to remove it on
+    response_file: Option<(Loop, Path)>,
 }
 
 impl Process {
     /// Spawn a new process inside the specified event loop.
     ///
     /// Returns either the corresponding process object or an error which
-    /// occurred.
+    /// occurred. An empty `config.program` is rejected with `EINVAL` before
+    /// anything is set up for the child. If `config.niceness` is given, the
+    /// child's priority is set right after it's spawned; failing to set it
+    /// is only noted with `uvdebug!`.
+    ///
+    /// On Unix, libuv forks from the calling thread, and the child keeps that
+    /// thread's signal mask across exec, so if `config.default_signals` is
+    /// set, the mask is emptied around the call to `uv_spawn` and then
+    /// restored. Signals with handlers in this process are reset to their
+    /// defaults by exec anyway. Ignored signals can't be reset: libuv has no
+    /// hook for running code in the child before exec, and changing their
+    /// disposition here would affect every thread in this process.
     pub fn spawn(loop_: &Loop, config: ProcessConfig)
                 -> Result<(~Process, ~[Option<PipeWatcher>]), UvError>
     {
+        if config.program.is_empty() {
+            return Err(UvError(uvll::EINVAL));
+        }
         let cwd = config.cwd.map(|s| s.to_c_str());
         let io = config.io;
         let mut stdio = vec::with_capacity::<uvll::uv_stdio_container_t>(io.len());
@@ -68,7 +96,11 @@ impl Process {
                         Some(ref cwd) => cwd.with_ref(|p| p),
                         None => ptr::null(),
                     },
-                    flags: 0,
+                    flags: if config.detach {
+                        uvll::PROCESS_DETACHED as libc::c_uint
+                    } else {
+                        0
+                    },
                     stdio_count: stdio.len() as libc::c_int,
                     stdio: stdio.as_imm_buf(|p, _| p),
                     uid: 0,
@@ -81,10 +113,21 @@ impl Process {
                     home: get_handle_to_current_scheduler!(),
                     to_wake: None,
                     exit_status: None,
+                    closed: false,
+                    response_file: None,
                 };
-                match unsafe {
+                let old_mask = if config.default_signals {
+                    Some(sigmask::unblock_all())
+                } else {
+                    None
+                };
+                let ret = unsafe {
                     uvll::uv_spawn(loop_.handle, handle, &options)
-                } {
+                };
+                for old_mask in old_mask.iter() {
+                    sigmask::restore(old_mask);
+                }
+                match ret {
                     0 => Ok(process.install()),
                     err => Err(UvError(err)),
                 }
@@ -92,22 +135,699 @@ impl Process {
         });
 
         match ret {
-            Ok(p) => Ok((p, ret_io)),
+            Ok(Ok(p)) => {
+                for &niceness in config.niceness.iter() {
+                    if !priority::set(p.id(), niceness) {
+                        uvdebug!("couldn't set the priority of process {} to {}",
+                                 p.id(), niceness);
+                    }
+                }
+                Ok((p, ret_io))
+            }
+            Ok(Err(e)) | Err(e) => Err(e),
+        }
+    }
+
+    /// Spawn a new process like `spawn`, but if its arguments are more than
+    /// `threshold` bytes long (counting a separator after each one), write
+    /// them to a temporary response file and pass the child `@file` instead.
+    ///
+    /// Only use this for programs that are known to understand response
+    /// files. Each argument is written on a line of its own, in double quotes,
+    /// with backslashes and double quotes escaped. The child may read the file
+    /// at any point while it runs, so it's removed once the child's exit
+    /// status has been collected, or when the process is dropped, rather than
+    /// right after spawning.
+    pub fn spawn_with_response_file(loop_: &Loop, config: ProcessConfig,
+                                    threshold: uint)
+                -> Result<(~Process, ~[Option<PipeWatcher>]), UvError>
+    {
+        let len = config.args.iter().fold(0, |len, arg| len + arg.len() + 1);
+        if len <= threshold {
+            return Process::spawn(loop_, config);
+        }
+
+        let path = os::tmpdir().join(format!("rust-args-{}",
+                                             rand::random::<u32>()));
+        let c_path = path.to_c_str();
+        match write_response_file(loop_, &c_path, config.args) {
+            Ok(()) => {}
+            Err(e) => {
+                let _ = FsRequest::unlink(loop_, &c_path);
+                return Err(e);
+            }
+        }
+
+        let args = [format!("@{}", path.display())];
+        let config = ProcessConfig {
+            program: config.program,
+            args: args,
+            env: config.env,
+            cwd: config.cwd,
+            io: config.io,
+            detach: config.detach,
+            niceness: config.niceness,
+            default_signals: config.default_signals,
+        };
+        match Process::spawn(loop_, config) {
+            Ok((mut p, io)) => {
+                p.response_file = Some((*loop_, path));
+                Ok((p, io))
+            }
+            Err(e) => {
+                let _ = FsRequest::unlink(loop_, &c_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Spawn a new process like `spawn`, but in a new, empty temporary
+    /// directory rather than in `config.cwd`.
+    ///
+    /// The directory is returned as a `TempCwd` along with the process. If
+    /// `remove` is true, dropping the `TempCwd` removes the directory and
+    /// everything the child left in it, so it should be kept until the child
+    /// has been waited on. Otherwise the directory is left in place.
+    pub fn spawn_in_temp_dir(loop_: &Loop, config: ProcessConfig, remove: bool)
+                -> Result<(~Process, ~[Option<PipeWatcher>], TempCwd), UvError>
+    {
+        let path = os::tmpdir().join(format!("rust-cwd-{}",
+                                             rand::random::<u32>()));
+        let c_path = path.to_c_str();
+        match FsRequest::mkdir(loop_, &c_path, libc::S_IRWXU as c_int) {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+
+        let cwd = path.as_str().unwrap().to_owned();
+        let config = ProcessConfig {
+            program: config.program,
+            args: config.args,
+            env: config.env,
+            cwd: Some(cwd.as_slice()),
+            io: config.io,
+            detach: config.detach,
+            niceness: config.niceness,
+            default_signals: config.default_signals,
+        };
+        match Process::spawn(loop_, config) {
+            Ok((p, io)) => {
+                let dir = TempCwd {
+                    home: get_handle_to_current_scheduler!(),
+                    loop_: *loop_,
+                    path: path,
+                    remove: remove,
+                };
+                Ok((p, io, dir))
+            }
+            Err(e) => {
+                let _ = FsRequest::rmdir(loop_, &c_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Spawn a new process like `spawn`, but make sure that the only
+    /// descriptors it inherits are stdin, stdout, stderr and the ones passed
+    /// to it with `InheritFd` in `config.io`.
+    ///
+    /// On Unix, every other open descriptor in this process is marked
+    /// close-on-exec before the child is spawned, and the ones that weren't
+    /// before are unmarked again once it has been, so later children are
+    /// spawned as they would have been. Open descriptors are found by listing
+    /// `/proc/self/fd` where that exists, and otherwise by trying every
+    /// number up to the process's limit.
+    ///
+    /// The close-on-exec flags belong to the whole process, so calls to this
+    /// function are serialized with a lock. A plain `spawn` on another thread
+    /// doesn't take that lock, though, and a child it starts in the middle of
+    /// one of these spawns won't inherit the descriptors that were
+    /// temporarily marked.
+    ///
+    /// On Windows, this is the same as `spawn`: libuv only lets a child inherit
+    /// handles that it was given explicitly.
+    pub fn spawn_restricting_fds(loop_: &Loop, config: ProcessConfig)
+                -> Result<(~Process, ~[Option<PipeWatcher>]), UvError>
+    {
+        let keep: ~[c_int] = config.io.iter().filter_map(|io| {
+            match *io {
+                InheritFd(fd) => Some(fd),
+                _ => None
+            }
+        }).collect();
+        // Listing the descriptors can deschedule this task, so it's done
+        // before taking the lock; nothing between `lock` and `unlock` blocks.
+        let fds = open_fds(loop_);
+        let mut config = Some(config);
+        unsafe {
+            static mut lock: Mutex = MUTEX_INIT;
+            atomically(|| {
+                lock.lock();
+                let marked = close_unlisted_on_exec(fds, keep);
+                let result = Process::spawn(loop_, config.take_unwrap());
+                unmark_on_exec(marked);
+                lock.unlock();
+                result
+            })
+        }
+    }
+
+    /// Spawn a new process like `spawn`, but with a new pseudo-terminal as its
+    /// stdin, stdout and stderr, for interactive tools that behave differently
+    /// when they aren't talking to a terminal.
+    ///
+    /// The `io` in `config` is ignored. Alongside the process, this returns a
+    /// pipe on the pty's master side: whatever the child writes to the
+    /// terminal can be read from it, and whatever is written to it reaches the
+    /// child as terminal input. The terminal's default line discipline
+    /// applies, so for example the child's newlines are read back as `\r\n`.
+    /// Once the child and everything it started have closed the terminal,
+    /// reading gives an error rather than end-of-file on some platforms.
+    ///
+    /// Pseudo-terminals are only available on Unix. Elsewhere this returns an
+    /// `IoUnavailable` error rather than falling back to pipes.
+    pub fn spawn_with_pty(loop_: &Loop, config: ProcessConfig)
+                -> Result<(~Process, PipeWatcher), IoError>
+    {
+        let (master, slave) = match pty::open() {
+            Ok(fds) => fds,
+            Err(e) => return Err(e)
+        };
+        let master = match PipeWatcher::open(loop_, master) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                pty::close(master);
+                pty::close(slave);
+                return Err(uv_error_to_io_error(e));
+            }
+        };
+
+        let program = config.program;
+        let io = [InheritFd(slave), InheritFd(slave), InheritFd(slave)];
+        let config = ProcessConfig {
+            program: program,
+            args: config.args,
+            env: config.env,
+            cwd: config.cwd,
+            io: io,
+            detach: config.detach,
+            niceness: config.niceness,
+            default_signals: config.default_signals,
+        };
+        let ret = Process::spawn(loop_, config);
+        // The child has its own copies of the slave side now, and the master
+        // only sees the terminal close once every copy of it has
+        pty::close(slave);
+        match ret {
+            Ok((p, _)) => Ok((p, master)),
+            Err(e) => Err(spawn_error_to_io_error(program, e)),
+        }
+    }
+
+    /// Removes the process's response file, if it has one
+    fn remove_response_file(&mut self) {
+        let _m = self.fire_homing_missile();
+        match self.response_file.take() {
+            Some((loop_, path)) => {
+                let _ = FsRequest::unlink(&loop_, &path.to_c_str());
+            }
+            None => {}
+        }
+    }
+
+    /// Closes the underlying uv handle without dropping the process.
+    ///
+    /// This is useful for releasing the handle as soon as the process has been
+    /// waited on. Any exit status collected so far is still returned by
+    /// `wait`, but the process can no longer be signaled. Calling this more
+    /// than once (or dropping the process afterwards) is a no-op.
+    pub fn close_handle(&mut self) {
+        let _m = self.fire_homing_missile();
+        assert!(self.to_wake.is_none());
+        if !self.closed {
+            self.closed = true;
+            self.close();
+        }
+    }
+
+    /// Returns the process's exit status if it has already exited, without
+    /// blocking or switching schedulers.
+    ///
+    /// This returns `None` until the exit callback has fired, even if the
+    /// process has in fact exited by then. Unlike `wait`, this only reads
+    /// state that has already been set, so it doesn't fire a homing missile.
+    pub fn finished_status(&self) -> Option<ProcessExit> {
+        self.exit_status
+    }
+
+    /// Collects the exit status of a process that was terminated by someone
+    /// else, for example killed by pid from outside this process.
+    ///
+    /// The exit callback still fires for such a process, so if it already
+    /// has, this returns the status it recorded right away. Otherwise this
+    /// blocks until it fires, exactly like `wait`.
+    pub fn reap(&mut self) -> ProcessExit {
+        let _m = self.fire_homing_missile();
+        match self.exit_status {
+            Some(status) => status,
+            None => self.wait()
+        }
+    }
+}
+
+/// The temporary directory that a process was spawned in by
+/// `Process::spawn_in_temp_dir`
+pub struct TempCwd {
+    home: SchedHandle,
+    loop_: Loop,
+    path: Path,
+
+    /// Whether to remove the directory when this is dropped
+    remove: bool,
+}
+
+impl TempCwd {
+    /// Returns the path of the directory
+    pub fn path<'a>(&'a self) -> &'a Path { &self.path }
+}
+
+impl HomingIO for TempCwd {
+    fn home<'r>(&'r mut self) -> &'r mut SchedHandle { &mut self.home }
+}
+
+impl Drop for TempCwd {
+    fn drop(&mut self) {
+        let _m = self.fire_homing_missile();
+        if self.remove {
+            let _ = remove_dir_all(&self.loop_, &self.path);
+        }
+    }
+}
+
+/// Removes the directory at `path` along with everything in it. Symlinks are
+/// removed rather than followed.
+fn remove_dir_all(loop_: &Loop, path: &Path) -> Result<(), UvError> {
+    let c_path = path.to_c_str();
+    let entries = match FsRequest::readdir(loop_, &c_path, 0) {
+        Ok(entries) => entries,
+        Err(e) => return Err(e),
+    };
+    for entry in entries.iter() {
+        let c_entry = entry.to_c_str();
+        let res = match FsRequest::lstat(loop_, &c_entry) {
+            Ok(ref stat) if stat.kind == io::TypeDirectory => {
+                remove_dir_all(loop_, entry)
+            }
+            Ok(_) => FsRequest::unlink(loop_, &c_entry),
             Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    FsRequest::rmdir(loop_, &c_path)
+}
+
+/// Writes `args` to a new file at `path`, in the format described for
+/// `Process::spawn_with_response_file`
+fn write_response_file(loop_: &Loop, path: &CString,
+                       args: &[~str]) -> Result<(), UvError> {
+    let mut contents = ~"";
+    for arg in args.iter() {
+        contents.push_char('"');
+        for c in arg.chars() {
+            if c == '"' || c == '\\' {
+                contents.push_char('\\');
+            }
+            contents.push_char(c);
+        }
+        contents.push_str("\"\n");
+    }
+
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+    let mode = libc::S_IRUSR | libc::S_IWUSR;
+    match FsRequest::open(loop_, path, flags as int, mode as int) {
+        Ok(file) => FsRequest::write(loop_, file.fd(), contents.as_bytes(), -1),
+        Err(e) => Err(e)
+    }
+}
+
+/// The descriptors that may be open in this process, for
+/// `Process::spawn_restricting_fds`
+#[cfg(unix)]
+fn open_fds(loop_: &Loop) -> ~[c_int] {
+    match FsRequest::readdir(loop_, &"/proc/self/fd".to_c_str(), 0) {
+        Ok(paths) => paths.iter().filter_map(|p| {
+            p.filename_str().and_then(|name| from_str(name))
+        }).collect(),
+        Err(_) => {
+            let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+            range(0, max as c_int).collect()
+        }
+    }
+}
+
+/// Marks every descriptor in `fds` other than stdin, stdout, stderr and those
+/// in `keep` close-on-exec, for `Process::spawn_restricting_fds`. Returns the
+/// descriptors that weren't marked already.
+#[cfg(unix)]
+fn close_unlisted_on_exec(fds: &[c_int], keep: &[c_int]) -> ~[c_int] {
+    let mut marked = ~[];
+    for &fd in fds.iter() {
+        if fd > libc::STDERR_FILENO && !keep.contains(&fd) && cloexec::get(fd) == Some(false) {
+            cloexec::set(fd, true);
+            marked.push(fd);
+        }
+    }
+    marked
+}
+
+/// Clears the close-on-exec flag that `close_unlisted_on_exec` set on `fds`
+#[cfg(unix)]
+fn unmark_on_exec(fds: &[c_int]) {
+    for &fd in fds.iter() {
+        cloexec::set(fd, false);
+    }
+}
+
+// libuv only passes a child the handles in its stdio containers, and all
+// other handles are created non-inheritable, so there's nothing to do
+#[cfg(windows)]
+fn open_fds(_loop: &Loop) -> ~[c_int] { ~[] }
+#[cfg(windows)]
+fn close_unlisted_on_exec(_fds: &[c_int], _keep: &[c_int]) -> ~[c_int] { ~[] }
+#[cfg(windows)]
+fn unmark_on_exec(_fds: &[c_int]) {}
+
+#[cfg(unix)]
+mod cloexec {
+    use std::libc::c_int;
+
+    static F_GETFD: c_int = 1;
+    static F_SETFD: c_int = 2;
+    static FD_CLOEXEC: c_int = 1;
+
+    extern {
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    /// Whether `fd`'s close-on-exec flag is set, or None if `fd` isn't open
+    pub fn get(fd: c_int) -> Option<bool> {
+        let flags = unsafe { fcntl(fd, F_GETFD) };
+        if flags < 0 {
+            None
+        } else {
+            Some(flags & FD_CLOEXEC != 0)
+        }
+    }
+
+    /// Sets or clears `fd`'s close-on-exec flag. Does nothing if `fd` isn't
+    /// open (it may have been the descriptor that listed the others).
+    pub fn set(fd: c_int, on: bool) {
+        unsafe {
+            let flags = fcntl(fd, F_GETFD);
+            if flags < 0 {
+                return;
+            }
+            let flags = if on { flags | FD_CLOEXEC } else { flags & !FD_CLOEXEC };
+            fcntl(fd, F_SETFD, flags);
         }
     }
 }
 
+#[cfg(unix)]
+mod priority {
+    use std::libc::{c_int, pid_t};
+
+    static PRIO_PROCESS: c_int = 0;
+
+    #[cfg(target_os = "freebsd")]
+    type id_t = i64;
+    #[cfg(not(target_os = "freebsd"))]
+    type id_t = u32;
+
+    extern {
+        fn setpriority(which: c_int, who: id_t, prio: c_int) -> c_int;
+        #[cfg(test)]
+        fn getpriority(which: c_int, who: id_t) -> c_int;
+    }
+
+    /// Sets the nice value of process `pid`, returning whether that worked
+    pub fn set(pid: pid_t, niceness: int) -> bool {
+        unsafe { setpriority(PRIO_PROCESS, pid as id_t, niceness as c_int) == 0 }
+    }
+
+    /// Returns the nice value of process `pid`
+    #[cfg(test)]
+    pub fn get(pid: pid_t) -> int {
+        unsafe { getpriority(PRIO_PROCESS, pid as id_t) as int }
+    }
+}
+
+#[cfg(windows)]
+mod priority {
+    use std::libc::{BOOL, DWORD, HANDLE, pid_t};
+    use std::libc;
+
+    static IDLE_PRIORITY_CLASS: DWORD = 0x40;
+    static BELOW_NORMAL_PRIORITY_CLASS: DWORD = 0x4000;
+    static NORMAL_PRIORITY_CLASS: DWORD = 0x20;
+    static ABOVE_NORMAL_PRIORITY_CLASS: DWORD = 0x8000;
+    static HIGH_PRIORITY_CLASS: DWORD = 0x80;
+
+    extern "system" {
+        fn SetPriorityClass(hProcess: HANDLE, dwPriorityClass: DWORD) -> BOOL;
+    }
+
+    /// Sets the priority class of process `pid` to the one closest to the nice
+    /// value `niceness`, returning whether that worked
+    pub fn set(pid: pid_t, niceness: int) -> bool {
+        let class = if niceness >= 10 {
+            IDLE_PRIORITY_CLASS
+        } else if niceness > 0 {
+            BELOW_NORMAL_PRIORITY_CLASS
+        } else if niceness == 0 {
+            NORMAL_PRIORITY_CLASS
+        } else if niceness > -10 {
+            ABOVE_NORMAL_PRIORITY_CLASS
+        } else {
+            HIGH_PRIORITY_CLASS
+        };
+        unsafe {
+            let handle = libc::OpenProcess(libc::PROCESS_SET_INFORMATION, 0,
+                                           pid as DWORD);
+            if handle.is_null() {
+                return false;
+            }
+            let ok = SetPriorityClass(handle, class) != 0;
+            libc::CloseHandle(handle);
+            ok
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sigmask {
+    use std::libc::c_int;
+
+    /// Storage for a `sigset_t`, which is at most this large everywhere
+    pub type SigSet = [u64, ..16];
+
+    #[cfg(target_os = "linux")]
+    #[cfg(target_os = "android")]
+    static SIG_BLOCK: c_int = 0;
+    #[cfg(target_os = "macos")]
+    #[cfg(target_os = "freebsd")]
+    static SIG_BLOCK: c_int = 1;
+    #[cfg(target_os = "linux")]
+    #[cfg(target_os = "android")]
+    static SIG_SETMASK: c_int = 2;
+    #[cfg(target_os = "macos")]
+    #[cfg(target_os = "freebsd")]
+    static SIG_SETMASK: c_int = 3;
+
+    extern {
+        fn sigemptyset(set: *mut SigSet) -> c_int;
+        fn sigaddset(set: *mut SigSet, signum: c_int) -> c_int;
+        fn pthread_sigmask(how: c_int, set: *SigSet, oldset: *mut SigSet) -> c_int;
+    }
+
+    /// Unblocks every signal in the calling thread, returning the mask it
+    /// had before
+    pub fn unblock_all() -> SigSet {
+        let mut empty = [0u64, ..16];
+        let mut old = [0u64, ..16];
+        unsafe {
+            sigemptyset(&mut empty);
+            pthread_sigmask(SIG_SETMASK, &empty, &mut old);
+        }
+        old
+    }
+
+    /// Sets the calling thread's signal mask back to `old`
+    pub fn restore(old: &SigSet) {
+        unsafe { pthread_sigmask(SIG_SETMASK, old, 0 as *mut SigSet); }
+    }
+
+    /// Blocks `signum` in the calling thread, returning the mask it had
+    /// before
+    #[cfg(test)]
+    pub fn block(signum: c_int) -> SigSet {
+        let mut set = [0u64, ..16];
+        let mut old = [0u64, ..16];
+        unsafe {
+            sigemptyset(&mut set);
+            sigaddset(&mut set, signum);
+            pthread_sigmask(SIG_BLOCK, &set, &mut old);
+        }
+        old
+    }
+}
+
+#[cfg(windows)]
+mod sigmask {
+    pub type SigSet = ();
+
+    pub fn unblock_all() -> SigSet {}
+
+    pub fn restore(_old: &SigSet) {}
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::io;
+    use std::io::IoError;
+    use std::libc::{c_char, c_int};
+    use std::libc;
+    use std::os;
+    use super::cloexec;
+
+    #[cfg(target_os = "linux")]
+    #[cfg(target_os = "android")]
+    static O_NOCTTY: c_int = 0o400;
+    #[cfg(target_os = "macos")]
+    static O_NOCTTY: c_int = 0x20000;
+    #[cfg(target_os = "freebsd")]
+    static O_NOCTTY: c_int = 0x8000;
+
+    extern {
+        fn posix_openpt(flags: c_int) -> c_int;
+        fn grantpt(fd: c_int) -> c_int;
+        fn unlockpt(fd: c_int) -> c_int;
+        fn ptsname(fd: c_int) -> *c_char;
+    }
+
+    fn last_error(desc: &'static str) -> IoError {
+        IoError {
+            kind: io::OtherIoError,
+            desc: desc,
+            detail: Some(os::last_os_error()),
+        }
+    }
+
+    /// Opens a new pseudo-terminal, returning its master and slave sides.
+    /// Neither is inherited by children unless it's passed to them, and the
+    /// slave doesn't become this process's controlling terminal.
+    pub fn open() -> Result<(c_int, c_int), IoError> {
+        unsafe {
+            let master = posix_openpt(libc::O_RDWR | O_NOCTTY);
+            if master < 0 {
+                return Err(last_error("couldn't open a pseudo-terminal"));
+            }
+            cloexec::set(master, true);
+            if grantpt(master) != 0 || unlockpt(master) != 0 {
+                let err = last_error("couldn't unlock the pseudo-terminal");
+                close(master);
+                return Err(err);
+            }
+            let name = ptsname(master);
+            if name.is_null() {
+                let err = last_error("couldn't find the pseudo-terminal's slave");
+                close(master);
+                return Err(err);
+            }
+            let slave = libc::open(name, libc::O_RDWR | O_NOCTTY, 0);
+            if slave < 0 {
+                let err = last_error("couldn't open the pseudo-terminal's slave");
+                close(master);
+                return Err(err);
+            }
+            cloexec::set(slave, true);
+            Ok((master, slave))
+        }
+    }
+
+    pub fn close(fd: c_int) {
+        unsafe { libc::close(fd); }
+    }
+}
+
+#[cfg(windows)]
+mod pty {
+    use std::io;
+    use std::io::IoError;
+    use std::libc::c_int;
+
+    pub fn open() -> Result<(c_int, c_int), IoError> {
+        Err(IoError {
+            kind: io::IoUnavailable,
+            desc: "pseudo-terminals aren't supported on this platform",
+            detail: None,
+        })
+    }
+
+    pub fn close(_fd: c_int) {}
+}
+
+/// Converts an error returned by `Process::spawn` into an `IoError`.
+///
+/// Failing to find `program` and finding it but not being allowed to run it
+/// are both common, so the error's detail says which of those happened. It
+/// also says so if `program` was empty.
+pub fn spawn_error_to_io_error(program: &str, err: UvError) -> IoError {
+    let code = *err;
+    let mut ioerr = uv_error_to_io_error(err);
+    ioerr.detail = if program.is_empty() {
+        Some(~"the program to run is an empty string")
+    } else if code == uvll::ENOENT {
+        Some(format!("couldn't find program `{}` (is it on the PATH?)", program))
+    } else if code == uvll::EACCES {
+        Some(format!("program `{}` exists but isn't executable", program))
+    } else {
+        None
+    };
+    ioerr
+}
+
+/// Converts the exit status and terminating signal that libuv reports for a
+/// child into a `ProcessExit`, so that the same outcome always gives the same
+/// value.
+///
+/// On Unix, only the low 8 bits of a status passed to `exit` survive, so the
+/// status is normalized to the range 0-255: a child that calls `exit(256)` or
+/// `exit(-1)` gives `ExitStatus(0)` or `ExitStatus(255)`. Windows exit codes
+/// are 32 bits wide and are kept as they are. Signal numbers are taken as
+/// positive and masked to the 7 bits that a wait status has room for.
+fn exit_from_uv(exit_status: i64, term_signal: libc::c_int) -> ProcessExit {
+    #[cfg(unix)]
+    fn normalize(status: i64) -> int { (status & 0xff) as int }
+    #[cfg(windows)]
+    fn normalize(status: i64) -> int { status as i32 as int }
+
+    match term_signal {
+        0 => ExitStatus(normalize(exit_status)),
+        n => ExitSignal(((n as i64).abs() & 0x7f) as int)
+    }
+}
+
 extern fn on_exit(handle: *uvll::uv_process_t,
                   exit_status: i64,
                   term_signal: libc::c_int) {
     let p: &mut Process = unsafe { UvHandle::from_uv_handle(&handle) };
 
     assert!(p.exit_status.is_none());
-    p.exit_status = Some(match term_signal {
-        0 => ExitStatus(exit_status as int),
-        n => ExitSignal(n as int),
-    });
+    p.exit_status = Some(exit_from_uv(exit_status, term_signal));
 
     match p.to_wake.take() {
         Some(task) => {
@@ -147,8 +867,15 @@ unsafe fn set_stdio(dst: *uvll::uv_stdio_container_t,
     }
 }
 
-/// Converts the program and arguments to the argv array expected by libuv
-fn with_argv<T>(prog: &str, args: &[~str], f: |**libc::c_char| -> T) -> T {
+/// Converts the program and arguments to the argv array expected by libuv.
+/// What libuv does with an empty program is undefined, so that's rejected
+/// with `EINVAL` without calling `f`.
+fn with_argv<T>(prog: &str, args: &[~str],
+                f: |**libc::c_char| -> T) -> Result<T, UvError> {
+    if prog.is_empty() {
+        return Err(UvError(uvll::EINVAL));
+    }
+
     // First, allocation space to put all the C-strings (we need to have
     // ownership of them somewhere
     let mut c_strs = vec::with_capacity(args.len() + 1);
@@ -163,7 +890,7 @@ fn with_argv<T>(prog: &str, args: &[~str], f: |**libc::c_char| -> T) -> T {
         c_args.push(s.with_ref(|p| p));
     }
     c_args.push(ptr::null());
-    c_args.as_imm_buf(|buf, _| f(buf))
+    Ok(c_args.as_imm_buf(|buf, _| f(buf)))
 }
 
 /// Converts the environment to the env array expected by libuv
@@ -200,6 +927,18 @@ impl RtioProcess for Process {
 
     fn kill(&mut self, signal: int) -> Result<(), IoError> {
         let _m = self.fire_homing_missile();
+        // Signalling a process that's known to be dead gives ESRCH on some
+        // platforms and succeeds on others, so don't ask libuv to try
+        if self.exit_status.is_some() {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "process already exited",
+                detail: None
+            });
+        }
+        if self.closed {
+            return Err(uv_error_to_io_error(UvError(uvll::EBADF)));
+        }
         match unsafe {
             uvll::uv_process_kill(self.handle, signal as libc::c_int)
         } {
@@ -213,6 +952,10 @@ impl RtioProcess for Process {
         let _m = self.fire_homing_missile();
         match self.exit_status {
             Some(*) => {}
+            // The exit callback will never be invoked once the handle has
+            // been closed, so there's nothing to wait for
+            None if self.closed => fail!("waiting on a process whose handle \
+                                          was closed before it exited"),
             None => {
                 // If there's no exit code previously listed, then the
                 // process's exit callback has yet to be invoked. We just
@@ -222,14 +965,623 @@ impl RtioProcess for Process {
             }
         }
 
+        self.remove_response_file();
         self.exit_status.unwrap()
     }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        let _m = self.fire_homing_missile();
-        assert!(self.to_wake.is_none());
-        self.close();
+        self.close_handle();
+        self.remove_response_file();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::process::{ProcessConfig, ProcessExit, ExitStatus, ExitSignal};
+    use std::libc;
+    use std::libc::c_void;
+    use std::os;
+    use std::rand;
+    use std::rt::rtio::RtioProcess;
+    use std::task;
+    use super::{Process, exit_from_uv};
+    use super::super::{Loop, local_loop};
+    use file::FsRequest;
+    use uvll;
+
+    /// The number of handles on `loop_`, including ones that are closing
+    fn handle_count(loop_: &Loop) -> uint {
+        extern fn count(_: *uvll::uv_handle_t, arg: *c_void) {
+            unsafe { *(arg as *mut uint) += 1; }
+        }
+        let mut n = 0u;
+        unsafe { uvll::uv_walk(loop_.handle, count, &mut n as *mut uint as *c_void); }
+        n
+    }
+
+    /// For stress tests: spawns `n` children running `program` with `args`
+    /// all at once, so that their exits race each other, and then waits on
+    /// each of them, last first, checking that it exited with `expected`.
+    /// By the time the later children are waited on, most of them will have
+    /// exited already, while the earlier ones may not have yet.
+    pub fn spawn_and_reap_all(n: uint, program: &str, args: &[~str],
+                              expected: ProcessExit) {
+        let mut children = ~[];
+        for _ in range(0, n) {
+            let config = ProcessConfig {
+                program: program,
+                args: args,
+                env: None,
+                cwd: None,
+                io: [],
+                detach: false,
+                niceness: None,
+                default_signals: false,
+            };
+            let (p, _io) = Process::spawn(local_loop(), config).unwrap();
+            children.push(p);
+        }
+        while !children.is_empty() {
+            let mut p = children.pop();
+            assert_eq!(p.wait(), expected);
+        }
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn reap_many_concurrent_children() {
+        let before = handle_count(local_loop());
+        spawn_and_reap_all(100, "/bin/sh", [~"-c", ~"exit 7"], ExitStatus(7));
+        // Every child's handle was closed when it was dropped
+        assert_eq!(handle_count(local_loop()), before);
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn reap_children_from_many_tasks() {
+        use std::comm::oneshot;
+
+        // The tasks' waits interleave, so children exit while other tasks
+        // are blocked waiting on theirs
+        let before = handle_count(local_loop());
+        let mut ports = ~[];
+        for i in range(0, 8) {
+            let (port, chan) = oneshot();
+            do task::spawn {
+                spawn_and_reap_all(10, "/bin/sh", [~"-c", format!("exit {}", i)],
+                                   ExitStatus(i));
+                chan.send(());
+            }
+            ports.push(port);
+        }
+        for port in ports.move_iter() {
+            port.recv();
+        }
+        assert_eq!(handle_count(local_loop()), before);
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn close_handle_keeps_exit_status() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"exit 3"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        assert_eq!(p.wait(), ExitStatus(3));
+        p.close_handle();
+        assert_eq!(p.exit_status, Some(ExitStatus(3)));
+        assert_eq!(p.wait(), ExitStatus(3));
+        assert!(p.kill(0).is_err());
+
+        // closing again, and then dropping, shouldn't double-close
+        p.close_handle();
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn detached_process_leads_its_own_group() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"sleep 30; exit 0"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: true,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        let ret = unsafe {
+            libc::funcs::posix88::signal::kill(-p.id(), libc::SIGKILL)
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(p.wait(), ExitSignal(libc::SIGKILL as int));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_from_uv_normalizes() {
+        assert_eq!(exit_from_uv(0, 0), ExitStatus(0));
+        assert_eq!(exit_from_uv(3, 0), ExitStatus(3));
+        assert_eq!(exit_from_uv(256, 0), ExitStatus(0));
+        assert_eq!(exit_from_uv(257, 0), ExitStatus(1));
+        assert_eq!(exit_from_uv(-1, 0), ExitStatus(255));
+        assert_eq!(exit_from_uv(0, 9), ExitSignal(9));
+        assert_eq!(exit_from_uv(0, -9), ExitSignal(9));
+        assert_eq!(exit_from_uv(0, 64), ExitSignal(64));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn exit_256_is_0() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"exit 256"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        assert_eq!(p.wait(), ExitStatus(0));
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn killed_by_high_signal() {
+        // One of the real-time signals, which terminate a process by default
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"sleep 1000"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        p.kill(40).unwrap();
+        assert_eq!(p.wait(), ExitSignal(40));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn kill_after_exit() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"exit 0"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        assert_eq!(p.wait(), ExitStatus(0));
+        match p.kill(9) {
+            Err(e) => {
+                assert_eq!(e.kind, io::OtherIoError);
+                assert_eq!(e.desc, "process already exited");
+            }
+            Ok(()) => fail!("killing an exited process should fail")
+        }
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn finished_status_matches_wait() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"exit 7"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        let status = p.wait();
+        assert_eq!(status, ExitStatus(7));
+        assert_eq!(p.finished_status(), Some(status));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn reap_after_external_kill() {
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"sleep 1000"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        let pid = p.id();
+        do task::spawn {
+            unsafe { libc::funcs::posix88::signal::kill(pid, 9); }
+        }
+        // This may or may not block, depending on whether the exit callback
+        // has fired yet, but either way it sees the signal
+        assert_eq!(p.reap(), ExitSignal(9));
+        // and once the status is known, reaping again returns it right away
+        assert_eq!(p.reap(), ExitSignal(9));
+        assert_eq!(p.wait(), ExitSignal(9));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_with_response_file_passes_all_args() {
+        // A tool that understands response files, which checks that it was
+        // given just `@file` and that the file holds all of its arguments
+        let tool = os::tmpdir().join(format!("rust-tool-{}", rand::random::<u32>()));
+        let script = "#!/bin/sh\n\
+                      case \"$1\" in @*) ;; *) exit 1 ;; esac\n\
+                      [ $# -eq 1 ] || exit 2\n\
+                      eval set -- $(cat \"${1#@}\")\n\
+                      [ $# -eq 10000 ] || exit 3\n\
+                      [ \"$1\" = arg-0 ] || exit 4\n\
+                      eval last=\\\"\\${$#}\\\"\n\
+                      [ \"$last\" = 'the \"last\" \\ one' ] || exit 5\n";
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+        let mode = libc::S_IRUSR | libc::S_IWUSR | libc::S_IXUSR;
+        {
+            let file = FsRequest::open(local_loop(), &tool.to_c_str(),
+                                       flags as int, mode as int).unwrap();
+            FsRequest::write(local_loop(), file.fd(), script.as_bytes(), -1).unwrap();
+        }
+
+        let mut args: ~[~str] = range(0, 9999).map(|i| format!("arg-{}", i)).collect();
+        args.push(~"the \"last\" \\ one");
+        let config = ProcessConfig {
+            program: tool.as_str().unwrap(),
+            args: args.as_slice(),
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn_with_response_file(local_loop(), config,
+                                                             32768).unwrap();
+        let response_file = match p.response_file {
+            Some((_, ref path)) => path.clone(),
+            None => fail!("the arguments should have been in a response file")
+        };
+        assert_eq!(p.wait(), ExitStatus(0));
+        // the response file is gone once the child has been waited on
+        assert!(FsRequest::stat(local_loop(), &response_file.to_c_str()).is_err());
+        FsRequest::unlink(local_loop(), &tool.to_c_str()).unwrap();
+
+        // short argument lists are passed as usual
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"exit 6"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn_with_response_file(local_loop(), config,
+                                                             32768).unwrap();
+        assert!(p.response_file.is_none());
+        assert_eq!(p.wait(), ExitStatus(6));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_in_temp_dir_runs_child_there() {
+        use std::io::process;
+        use std::rt::rtio::RtioPipe;
+        use std::str;
+
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"touch left-behind; pwd -P"],
+            env: None,
+            cwd: Some("/"),
+            io: [process::Ignored, process::CreatePipe(false, true)],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let path = {
+            let (mut p, mut io, dir) = Process::spawn_in_temp_dir(local_loop(), config,
+                                                                  true).unwrap();
+            let mut out = io[1].take_unwrap();
+            let mut output = ~[];
+            let mut buf = [0u8, ..1024];
+            loop {
+                match out.read(buf) {
+                    Ok(n) => output.push_all(buf.slice_to(n)),
+                    Err(_) => break
+                }
+            }
+            assert_eq!(p.wait(), ExitStatus(0));
+
+            // The child reports the directory's real path, which may differ
+            // from ours if the temporary directory is behind a symlink
+            let reported = Path::new(str::from_utf8(output).trim());
+            let real = FsRequest::stat(local_loop(), &dir.path().to_c_str()).unwrap();
+            let seen = FsRequest::stat(local_loop(), &reported.to_c_str()).unwrap();
+            assert_eq!(real.unstable.inode, seen.unstable.inode);
+            assert_eq!(real.unstable.device, seen.unstable.device);
+            dir.path().clone()
+        };
+        // Dropping the guard removed the directory, and what the child left
+        assert!(FsRequest::stat(local_loop(), &path.to_c_str()).is_err());
+
+        // unless we asked to keep it
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"true"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let path = {
+            let (mut p, _io, dir) = Process::spawn_in_temp_dir(local_loop(), config,
+                                                               false).unwrap();
+            assert_eq!(p.wait(), ExitStatus(0));
+            dir.path().clone()
+        };
+        assert!(FsRequest::stat(local_loop(), &path.to_c_str()).is_ok());
+        FsRequest::rmdir(local_loop(), &path.to_c_str()).unwrap();
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_restricting_fds_closes_unlisted() {
+        use super::cloexec;
+
+        let path = os::tmpdir().join(format!("rust-fd-{}", rand::random::<u32>()));
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let mode = libc::S_IRUSR | libc::S_IWUSR;
+        let file = FsRequest::open(local_loop(), &path.to_c_str(),
+                                   flags as int, mode as int).unwrap();
+        let fd = file.fd();
+        cloexec::set(fd, false);
+        // The child checks whether it can read from the parent's descriptor
+        let args = [~"-c", format!("[ -r /dev/fd/{} ]", fd)];
+
+        // an ordinary spawn passes the descriptor on, since it isn't close-on-exec
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: args.as_slice(),
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        assert_eq!(p.wait(), ExitStatus(0));
+
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: args.as_slice(),
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn_restricting_fds(local_loop(), config).unwrap();
+        assert!(p.wait() != ExitStatus(0));
+        // only that child was kept from the descriptor
+        assert_eq!(cloexec::get(fd), Some(false));
+
+        FsRequest::unlink(local_loop(), &path.to_c_str()).unwrap();
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_with_pty_is_a_terminal() {
+        use std::rt::rtio::RtioPipe;
+        use std::str;
+
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"read line; [ -t 0 ] && [ -t 1 ] && echo \"tty $line\""],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, mut master) = Process::spawn_with_pty(local_loop(),
+                                                          config).unwrap();
+        master.write(bytes!("hello\n")).unwrap();
+
+        // The terminal echoes the input back before the child's own output,
+        // and on some platforms reading fails instead of giving end-of-file
+        // once the child has exited
+        let mut output = ~[];
+        let mut buf = [0u8, ..1024];
+        loop {
+            match master.read(buf) {
+                Ok(n) => output.push_all(buf.slice_to(n)),
+                Err(_) => break
+            }
+            if str::from_utf8(output).contains("tty hello") {
+                break
+            }
+        }
+        assert!(str::from_utf8(output).contains("tty hello"));
+        assert_eq!(p.wait(), ExitStatus(0));
+    }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_sets_nice_value() {
+        // cat doesn't exit until its stdin is closed, so its priority is read
+        // while it's certainly still running
+        let config = ProcessConfig {
+            program: "cat",
+            args: [],
+            env: None,
+            cwd: None,
+            io: [io::process::CreatePipe(true, false)],
+            detach: false,
+            niceness: Some(5),
+            default_signals: false,
+        };
+        let (mut p, mut pipes) = Process::spawn(local_loop(), config).unwrap();
+        let niceness = super::priority::get(p.id());
+        pipes[0].take();
+        assert_eq!(niceness, 5);
+        assert_eq!(p.wait(), ExitStatus(0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn spawn_default_signals_unblocks_sigint() {
+        use std::libc;
+
+        // Exits with 0 if SIGINT is blocked in the child
+        let args = [~"-c", ~"grep -q '^SigBlk:.*[2367abef]$' /proc/self/status"];
+        let old_mask = super::sigmask::block(libc::SIGINT);
+
+        // an ordinary spawn passes the blocked SIGINT on
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: args.as_slice(),
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut inherited, _io) = Process::spawn(local_loop(), config).unwrap();
+
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: args.as_slice(),
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: true,
+        };
+        let (mut defaulted, _io) = Process::spawn(local_loop(), config).unwrap();
+
+        // Restored before waiting, since the task may move to another thread
+        super::sigmask::restore(&old_mask);
+        assert_eq!(inherited.wait(), ExitStatus(0));
+        assert_eq!(defaulted.wait(), ExitStatus(1));
+    }
+
+    #[test]
+    fn with_argv_empty_args() {
+        use std::str;
+        let argv = super::with_argv("prog", [], |argv| unsafe {
+            assert!((*argv.offset(1)).is_null());
+            str::raw::from_c_str(*argv)
+        }).unwrap();
+        assert_eq!(argv, ~"prog");
+    }
+
+    #[test]
+    fn with_argv_program_with_spaces() {
+        use std::str;
+        let args = super::with_argv("/dir with spaces/my prog", [~"an arg"], |argv| unsafe {
+            assert!((*argv.offset(2)).is_null());
+            (str::raw::from_c_str(*argv), str::raw::from_c_str(*argv.offset(1)))
+        }).unwrap();
+        assert_eq!(args, (~"/dir with spaces/my prog", ~"an arg"));
+    }
+
+    #[test]
+    fn with_argv_empty_program() {
+        let called = super::with_argv("", [~"arg"], |_| ());
+        assert!(called.is_err());
+    }
+
+    #[test]
+    fn spawn_empty_program() {
+        let config = ProcessConfig {
+            program: "",
+            args: [],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        match Process::spawn(local_loop(), config) {
+            Ok(*) => fail!("spawning an empty program should fail"),
+            Err(e) => {
+                let e = super::spawn_error_to_io_error("", e);
+                assert_eq!(e.detail, Some(~"the program to run is an empty string"));
+            }
+        }
     }
+
+    #[test]
+    // FIXME(#10380)
+    #[cfg(unix, not(target_os="android"))]
+    fn spawn_program_with_spaces() {
+        let path = os::tmpdir().join(format!("rust uv test {}", rand::random::<u32>()));
+        {
+            let mut file = io::File::create(&path);
+            file.write(bytes!("#!/bin/sh\nexit 7\n"));
+        }
+        io::fs::chmod(&path, io::UserRWX);
+        let config = ProcessConfig {
+            program: path.as_str().unwrap(),
+            args: [],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: false,
+            niceness: None,
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(local_loop(), config).unwrap();
+        assert_eq!(p.wait(), ExitStatus(7));
+        io::fs::unlink(&path);
+    }
+
 }
@@ -321,12 +321,13 @@ impl IoFactory for UvIoFactory {
     fn spawn(&mut self, config: ProcessConfig)
             -> Result<(~RtioProcess, ~[Option<~RtioPipe>]), IoError>
     {
+        let program = config.program.to_owned();
         match Process::spawn(self.uv_loop(), config) {
             Ok((p, io)) => {
                 Ok((p as ~RtioProcess,
                     io.move_iter().map(|i| i.map(|p| ~p as ~RtioPipe)).collect()))
             }
-            Err(e) => Err(uv_error_to_io_error(e)),
+            Err(e) => Err(spawn_error_to_io_error(program, e)),
         }
     }
 
@@ -48,6 +48,7 @@ pub mod errors {
     use std::libc::c_int;
 
     pub static EACCES: c_int = -4092;
+    pub static ENOENT: c_int = -4058;
     pub static ECONNREFUSED: c_int = -4078;
     pub static ECONNRESET: c_int = -4077;
     pub static ENOTCONN: c_int = -4053;
@@ -55,6 +56,7 @@ pub mod errors {
     pub static ECONNABORTED: c_int = -4079;
     pub static ECANCELED: c_int = -4081;
     pub static EBADF: c_int = -4083;
+    pub static EINVAL: c_int = -4071;
 }
 #[cfg(not(windows))]
 pub mod errors {
@@ -62,6 +64,7 @@ pub mod errors {
     use std::libc::c_int;
 
     pub static EACCES: c_int = -libc::EACCES;
+    pub static ENOENT: c_int = -libc::ENOENT;
     pub static ECONNREFUSED: c_int = -libc::ECONNREFUSED;
     pub static ECONNRESET: c_int = -libc::ECONNRESET;
     pub static ENOTCONN: c_int = -libc::ENOTCONN;
@@ -69,6 +72,7 @@ pub mod errors {
     pub static ECONNABORTED: c_int = -libc::ECONNABORTED;
     pub static ECANCELED : c_int = -libc::ECANCELED;
     pub static EBADF : c_int = -libc::EBADF;
+    pub static EINVAL : c_int = -libc::EINVAL;
 }
 
 pub static PROCESS_SETUID: c_int = 1 << 0;
@@ -60,6 +60,10 @@ impl Process {
     ///     these are `None`, then this module will bind the input/output to an
     ///     os pipe instead. This process takes ownership of these file
     ///     descriptors, closing them upon destruction of the process.
+    ///
+    /// `config.detach` and `config.niceness` are applied as they are by the
+    /// libuv runtime. The child always starts with no signals blocked, as if
+    /// `config.default_signals` were set.
     pub fn spawn(config: p::ProcessConfig)
         -> Result<(Process, ~[Option<file::FileDesc>]), io::IoError>
     {
@@ -97,7 +101,8 @@ impl Process {
         let env = config.env.map(|a| a.to_owned());
         let cwd = config.cwd.map(|a| Path::new(a));
         let res = spawn_process_os(config.program, config.args, env,
-                                   cwd.as_ref(), in_fd, out_fd, err_fd);
+                                   cwd.as_ref(), in_fd, out_fd, err_fd,
+                                   config.detach, config.niceness);
 
         unsafe {
             for pipe in in_pipe.iter() { libc::close(pipe.input); }
@@ -177,7 +182,8 @@ struct SpawnProcessResult {
 fn spawn_process_os(prog: &str, args: &[~str],
                     env: Option<~[(~str, ~str)]>,
                     dir: Option<&Path>,
-                    in_fd: c_int, out_fd: c_int, err_fd: c_int) -> SpawnProcessResult {
+                    in_fd: c_int, out_fd: c_int, err_fd: c_int,
+                    detach: bool, niceness: Option<int>) -> SpawnProcessResult {
     use libc::types::os::arch::extra::{DWORD, HANDLE, STARTUPINFO};
     use libc::consts::os::extra::{
         TRUE, FALSE,
@@ -195,6 +201,30 @@ fn spawn_process_os(prog: &str, args: &[~str],
 
     use mem;
 
+    static DETACHED_PROCESS: DWORD = 0x8;
+    static CREATE_NEW_PROCESS_GROUP: DWORD = 0x200;
+    static IDLE_PRIORITY_CLASS: DWORD = 0x40;
+    static BELOW_NORMAL_PRIORITY_CLASS: DWORD = 0x4000;
+    static NORMAL_PRIORITY_CLASS: DWORD = 0x20;
+    static ABOVE_NORMAL_PRIORITY_CLASS: DWORD = 0x8000;
+    static HIGH_PRIORITY_CLASS: DWORD = 0x80;
+
+    let mut flags = if detach { DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP } else { 0 };
+    // The priority class closest to the nice value, as in librustuv
+    for &n in niceness.iter() {
+        flags |= if n >= 10 {
+            IDLE_PRIORITY_CLASS
+        } else if n > 0 {
+            BELOW_NORMAL_PRIORITY_CLASS
+        } else if n == 0 {
+            NORMAL_PRIORITY_CLASS
+        } else if n > -10 {
+            ABOVE_NORMAL_PRIORITY_CLASS
+        } else {
+            HIGH_PRIORITY_CLASS
+        };
+    }
+
     unsafe {
 
         let mut si = zeroed_startupinfo();
@@ -239,7 +269,7 @@ fn spawn_process_os(prog: &str, args: &[~str],
                 cmd.with_c_str(|cmdp| {
                     let created = CreateProcessA(ptr::null(), cast::transmute(cmdp),
                                                  ptr::mut_null(), ptr::mut_null(), TRUE,
-                                                 0, envp, dirp, &mut si, &mut pi);
+                                                 flags, envp, dirp, &mut si, &mut pi);
                     if created == FALSE {
                         create_err = Some(os::last_os_error());
                     }
@@ -360,8 +390,9 @@ pub fn make_command_line(prog: &str, args: &[~str]) -> ~str {
 fn spawn_process_os(prog: &str, args: &[~str],
                     env: Option<~[(~str, ~str)]>,
                     dir: Option<&Path>,
-                    in_fd: c_int, out_fd: c_int, err_fd: c_int) -> SpawnProcessResult {
-    use libc::funcs::posix88::unistd::{fork, dup2, close, chdir, execvp};
+                    in_fd: c_int, out_fd: c_int, err_fd: c_int,
+                    detach: bool, niceness: Option<int>) -> SpawnProcessResult {
+    use libc::funcs::posix88::unistd::{fork, dup2, close, chdir, execvp, setsid};
     use libc::funcs::bsd44::getdtablesize;
 
     mod rustrt {
@@ -370,6 +401,15 @@ fn spawn_process_os(prog: &str, args: &[~str],
         }
     }
 
+    static PRIO_PROCESS: c_int = 0;
+    #[cfg(target_os = "freebsd")]
+    type id_t = i64;
+    #[cfg(not(target_os = "freebsd"))]
+    type id_t = u32;
+    extern {
+        fn setpriority(which: c_int, who: id_t, prio: c_int) -> c_int;
+    }
+
     #[cfg(windows)]
     unsafe fn set_environ(_envp: *c_void) {}
     #[cfg(target_os = "macos")]
@@ -397,6 +437,14 @@ fn spawn_process_os(prog: &str, args: &[~str],
 
         rustrt::rust_unset_sigprocmask();
 
+        if detach && setsid() == -1 {
+            fail!("failure in setsid: {}", os::last_os_error());
+        }
+        // As with libuv, failing to set the priority isn't an error
+        for &n in niceness.iter() {
+            setpriority(PRIO_PROCESS, 0, n as c_int);
+        }
+
         if dup2(in_fd, 0) == -1 {
             fail!("failure in dup2(in_fd, 0): {}", os::last_os_error());
         }
@@ -647,6 +695,44 @@ mod tests {
         );
     }
 
+    #[test] #[cfg(unix)]
+    fn test_spawn_detached_with_niceness() {
+        use io::process::{ProcessConfig, ExitStatus};
+        use libc::{c_int, pid_t};
+        use rt::rtio::RtioProcess;
+        use super::Process;
+
+        extern {
+            fn getpgid(pid: pid_t) -> pid_t;
+            fn getpriority(which: c_int, who: u32) -> c_int;
+            fn usleep(usecs: u32) -> c_int;
+        }
+
+        let config = ProcessConfig {
+            program: "/bin/sh",
+            args: [~"-c", ~"sleep 1"],
+            env: None,
+            cwd: None,
+            io: [],
+            detach: true,
+            niceness: Some(5),
+            default_signals: false,
+        };
+        let (mut p, _io) = Process::spawn(config).unwrap();
+        // The child leads a process group of its own, at the lower priority,
+        // once it's got as far as setting them up
+        let set_up = |p: &Process| unsafe {
+            getpgid(p.id()) == p.id() && getpriority(0, p.id() as u32) == 5
+        };
+        let mut tries = 0;
+        while !set_up(&p) && tries < 50 {
+            unsafe { usleep(10000); }
+            tries += 1;
+        }
+        assert!(set_up(&p));
+        assert_eq!(p.wait(), ExitStatus(0));
+    }
+
     // Currently most of the tests of this functionality live inside std::run,
     // but they may move here eventually as a non-blocking backend is added to
     // std::run
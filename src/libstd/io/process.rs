@@ -59,7 +59,38 @@ pub struct ProcessConfig<'self> {
     ///     0 - stdin
     ///     1 - stdout
     ///     2 - stderr
-    io: &'self [StdioContainer]
+    io: &'self [StdioContainer],
+
+    /// Whether to detach the new process from this one. On Unix, the child
+    /// calls `setsid` before running the program, so it leads a new session
+    /// and process group whose id is its pid, and can be signaled along with
+    /// everything it starts by signaling that group. On Windows, the child
+    /// gets a new process group and no console.
+    detach: bool,
+
+    /// The scheduling priority to give the new process, as a Unix nice value:
+    /// 0 is the normal priority, and values up to 19 lower it, so that for
+    /// example compilers and tests running in the background don't starve
+    /// interactive programs. Raising it with a negative value usually needs
+    /// privileges. On Windows the value picks a priority class instead: idle
+    /// from 10 up, below normal from 1 to 9, above normal from -1 to -9 and
+    /// high from -10 down.
+    ///
+    /// The priority is set as soon as the child has been spawned, before it
+    /// gets to do much work. If it can't be set, the child keeps the priority
+    /// it inherited; that isn't an error. If this is None, the priority isn't
+    /// changed.
+    niceness: Option<int>,
+
+    /// Whether to spawn the new process with no signals blocked, so that for
+    /// example a test binary can be interrupted with Ctrl-C even if the
+    /// thread spawning it has SIGINT blocked. Otherwise the child inherits
+    /// the spawning thread's signal mask, except under the native runtime,
+    /// which always starts children with no signals blocked. Signals that
+    /// this process ignores (such as SIGPIPE) stay ignored in the child
+    /// either way, and on Windows, which has no signal masks, this does
+    /// nothing.
+    default_signals: bool,
 }
 
 /// Describes what to do with a standard io stream for a child process.
@@ -81,11 +112,21 @@ pub enum StdioContainer {
     CreatePipe(bool /* readable */, bool /* writable */),
 }
 
+/// Returns the `io` configuration for a child process that shares this
+/// process's stdin, stdout and stderr, as interactive programs usually should.
+pub fn inherit_stdio() -> ~[StdioContainer] {
+    ~[InheritFd(libc::STDIN_FILENO),
+      InheritFd(libc::STDOUT_FILENO),
+      InheritFd(libc::STDERR_FILENO)]
+}
+
 /// Describes the result of a process after it has terminated.
 /// Note that Windows have no signals, so the result is usually ExitStatus.
 #[deriving(Eq)]
 pub enum ProcessExit {
-    /// Normal termination with an exit status.
+    /// Normal termination with an exit status. On Unix, this is always in
+    /// the range 0-255, since only the low 8 bits of the status a process
+    /// exits with are kept.
     ExitStatus(int),
 
     /// Termination by signal, with the signal number.
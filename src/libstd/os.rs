@@ -818,6 +818,12 @@ fn round_up(from: uint, to: uint) -> uint {
     }
 }
 
+/// Returns the number of logical CPUs the system has
+pub fn num_cpus() -> uint {
+    use rt;
+    rt::num_cpus()
+}
+
 #[cfg(unix)]
 pub fn page_size() -> uint {
     unsafe {
@@ -1325,6 +1331,11 @@ mod tests {
         assert_eq!(getenv(n), option::Some(s));
     }
 
+    #[test]
+    fn test_num_cpus() {
+        assert!(os::num_cpus() > 0);
+    }
+
     #[test]
     fn test_self_exe_path() {
         let path = os::self_exe_path();
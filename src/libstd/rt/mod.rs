@@ -87,6 +87,9 @@ pub use self::util::set_exit_status;
 // method...
 pub use self::util::default_sched_threads;
 
+// the os module needs this to answer `os::num_cpus`
+pub use self::util::num_cpus;
+
 // Re-export of the functionality in the kill module
 pub use self::kill::BlockedTask;
 
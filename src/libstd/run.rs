@@ -13,6 +13,7 @@
 #[allow(missing_doc)];
 
 use cell::Cell;
+use cmp;
 use comm::{stream, SharedChan};
 use io::Reader;
 use io::process::ProcessExit;
@@ -109,6 +110,25 @@ pub struct ProcessOutput {
     error: ~[u8],
 }
 
+/// The output of a finished process, of which at most a fixed number of bytes
+/// per stream was kept (see `Process::finish_with_capped_output`).
+pub struct CappedProcessOutput {
+    /// The status (exit code) of the process.
+    status: ProcessExit,
+
+    /// The start of the data that the process wrote to stdout.
+    output: ~[u8],
+
+    /// The start of the data that the process wrote to stderr.
+    error: ~[u8],
+
+    /// True if the process wrote more to stdout than was kept.
+    output_truncated: bool,
+
+    /// True if the process wrote more to stderr than was kept.
+    error_truncated: bool,
+}
+
 impl Process {
     /**
      * Spawns a new Process.
@@ -138,6 +158,9 @@ impl Process {
             env: env,
             cwd: cwd,
             io: rtio,
+            detach: false,
+            niceness: None,
+            default_signals: false,
         };
         let inner = process::Process::new(rtconfig).unwrap();
         Process { inner: inner }
@@ -208,6 +231,31 @@ impl Process {
      * were redirected to existing file descriptors.
      */
     pub fn finish_with_output(&mut self) -> ProcessOutput {
+        let (status, (outs, _), (errs, _)) = self.finish_reading(None);
+        ProcessOutput {status: status, output: outs, error: errs}
+    }
+
+    /**
+     * Like `finish_with_output`, but keeps at most `cap` bytes of each of
+     * stdout and stderr, so that a child that writes a lot of output can't
+     * exhaust this process's memory. The rest of the output is still read,
+     * so that the child doesn't block writing to a full pipe, but discarded,
+     * and the result says which streams were truncated.
+     */
+    pub fn finish_with_capped_output(&mut self, cap: uint) -> CappedProcessOutput {
+        let (status, (outs, out_truncated), (errs, err_truncated)) =
+            self.finish_reading(Some(cap));
+        CappedProcessOutput {status: status,
+                             output: outs,
+                             error: errs,
+                             output_truncated: out_truncated,
+                             error_truncated: err_truncated}
+    }
+
+    // Waits for the child to exit while reading its stdout and stderr (see
+    // `read_capped`), and returns its status and the two streams' output
+    fn finish_reading(&mut self, cap: Option<uint>)
+                      -> (ProcessExit, (~[u8], bool), (~[u8], bool)) {
         self.close_input();
         let output = Cell::new(self.inner.io[1].take());
         let error = Cell::new(self.inner.io[2].take());
@@ -223,16 +271,16 @@ impl Process {
         do spawn {
             io::ignore_io_error(|| {
                 match error.take() {
-                    Some(ref mut e) => ch.send((2, e.read_to_end())),
-                    None => ch.send((2, ~[]))
+                    Some(ref mut e) => ch.send((2, read_capped(e, cap))),
+                    None => ch.send((2, (~[], false)))
                 }
             })
         }
         do spawn {
             io::ignore_io_error(|| {
                 match output.take() {
-                    Some(ref mut e) => ch_clone.send((1, e.read_to_end())),
-                    None => ch_clone.send((1, ~[]))
+                    Some(ref mut e) => ch_clone.send((1, read_capped(e, cap))),
+                    None => ch_clone.send((1, (~[], false)))
                 }
             })
         }
@@ -247,9 +295,7 @@ impl Process {
             }
         };
 
-        return ProcessOutput {status: status,
-                              output: outs,
-                              error: errs};
+        (status, outs, errs)
     }
 
     /**
@@ -277,6 +323,36 @@ impl Process {
     }
 }
 
+/// Reads `r` to the end, keeping at most `cap` bytes of what it reads (or all
+/// of it, if `cap` is None). Returns what was kept, and whether there was more.
+fn read_capped<R: Reader>(r: &mut R, cap: Option<uint>) -> (~[u8], bool) {
+    let cap = match cap {
+        Some(cap) => cap,
+        None => return (r.read_to_end(), false)
+    };
+    let mut kept: ~[u8] = ~[];
+    let mut truncated = false;
+    let mut buf = [0u8, ..4096];
+    loop {
+        match r.read(buf) {
+            Some(n) => {
+                let keep = cmp::min(n, cap - kept.len());
+                if keep < n {
+                    truncated = true;
+                }
+                let wanted = kept.len() + keep;
+                if kept.capacity() < wanted {
+                    // Grow as usual, but never past `cap`
+                    kept.reserve(cmp::min(cap, cmp::max(wanted, kept.capacity() * 2)));
+                }
+                kept.push_all(buf.slice_to(keep));
+            }
+            None => break
+        }
+    }
+    (kept, truncated)
+}
+
 /**
  * Spawns a process and waits for it to terminate. The process will
  * inherit the current stdin/stdout/stderr file descriptors.
@@ -318,6 +394,27 @@ pub fn process_output(prog: &str, args: &[~str]) -> ProcessOutput {
     prog.finish_with_output()
 }
 
+/**
+ * Spawns a process, records at most `cap` bytes of each of its stdout and
+ * stderr, and waits for it to terminate (see
+ * `Process::finish_with_capped_output`).
+ *
+ * # Arguments
+ *
+ * * prog - The path to an executable
+ * * args - Vector of arguments to pass to the child process
+ * * cap - The most bytes of each stream to keep
+ *
+ * # Return value
+ *
+ * The start of the process's stdout/stderr output, whether either was
+ * truncated, and its exit code.
+ */
+pub fn process_capped_output(prog: &str, args: &[~str], cap: uint) -> CappedProcessOutput {
+    let mut prog = Process::new(prog, args, ProcessOptions::new());
+    prog.finish_with_capped_output(cap)
+}
+
 #[cfg(test)]
 mod tests {
     use libc::c_int;
@@ -449,6 +546,25 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(unix,not(target_os="android"))]
+    fn test_finish_with_capped_output() {
+        // The child writes a megabyte to stdout, of which only 1024 bytes are kept
+        let mut prog = run::Process::new("sh",
+                                         [~"-c", ~"head -c 1048576 /dev/zero; echo hello >&2"],
+                                         run::ProcessOptions::new());
+        let run::CappedProcessOutput {status, output, error, output_truncated, error_truncated}
+            = prog.finish_with_capped_output(1024);
+
+        assert!(status.success());
+        assert_eq!(output.len(), 1024);
+        assert!(output.capacity() <= 1024);
+        assert!(output.iter().all(|&b| b == 0));
+        assert!(output_truncated);
+        assert_eq!(str::from_utf8(error).trim().to_owned(), ~"hello");
+        assert!(!error_truncated);
+    }
+
     #[test]
     #[cfg(not(target_os="android"))] // FIXME(#10380)
     fn test_finish_with_output_twice() {
@@ -32,7 +32,10 @@ fn main () {
         args : [~"child"],
         env : None,
         cwd : None,
-        io : []
+        io : [],
+        detach : false,
+        niceness : None,
+        default_signals : false
     };
 
     let mut p = process::Process::new(config).unwrap();
@@ -23,10 +23,15 @@
 //
 // See #9341
 
+extern mod extra;
+
 use std::io;
+use std::os;
+use std::io::File;
 use std::io::process;
 use std::io::process::{Process, ProcessConfig, CreatePipe, Ignored};
 use std::str;
+use extra::tempfile::TempDir;
 
 #[test]
 // FIXME(#10380)
@@ -39,6 +44,9 @@ fn smoke() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     let p = Process::new(args);
     assert!(p.is_some());
@@ -57,6 +65,9 @@ fn smoke_failure() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     match io::result(|| Process::new(args)) {
         Ok(*) => fail!(),
@@ -64,6 +75,46 @@ fn smoke_failure() {
     }
 }
 
+fn spawn_error(program: &str) -> io::IoError {
+    let args = ProcessConfig {
+        program: program,
+        args: [],
+        env: None,
+        cwd: None,
+        io: [],
+        detach: false,
+        niceness: None,
+        default_signals: false,
+    };
+    match io::result(|| Process::new(args)) {
+        Ok(*) => fail!("spawning {} should have failed", program),
+        Err(e) => e
+    }
+}
+
+#[test]
+// FIXME(#10380)
+#[cfg(unix, not(target_os="android"))]
+fn spawn_missing_program() {
+    let err = spawn_error("if-this-is-a-binary-then-the-world-has-ended");
+    assert_eq!(err.kind, io::FileNotFound);
+    let detail = err.detail.expect("no detail for a missing program");
+    assert!(detail.contains("couldn't find program"));
+}
+
+#[test]
+// FIXME(#10380)
+#[cfg(unix, not(target_os="android"))]
+fn spawn_non_executable_program() {
+    let dir = TempDir::new("spawn_non_executable_program").unwrap();
+    let path = dir.path().join("not-executable");
+    File::create(&path).write(bytes!("#!/bin/sh\n"));
+    let err = spawn_error(path.as_str().unwrap());
+    assert_eq!(err.kind, io::PermissionDenied);
+    let detail = err.detail.expect("no detail for a non-executable program");
+    assert!(detail.contains("isn't executable"));
+}
+
 #[test]
 // FIXME(#10380)
 #[cfg(unix, not(target_os="android"))]
@@ -75,6 +126,9 @@ fn exit_reported_right() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     let p = Process::new(args);
     assert!(p.is_some());
@@ -92,6 +146,9 @@ fn signal_reported_right() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     let p = Process::new(args);
     assert!(p.is_some());
@@ -136,6 +193,9 @@ fn stdout_works() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     assert_eq!(run_output(args), ~"foobar\n");
 }
@@ -152,6 +212,9 @@ fn set_cwd_works() {
         env: None,
         cwd: cwd,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     assert_eq!(run_output(args), ~"/\n");
 }
@@ -168,6 +231,9 @@ fn stdin_works() {
         env: None,
         cwd: None,
         io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
     };
     let mut p = Process::new(args).expect("didn't create a proces?!");
     p.io[0].get_mut_ref().write("foobar".as_bytes());
@@ -176,3 +242,104 @@ fn stdin_works() {
     assert!(p.wait().success());
     assert_eq!(out, ~"foobar\n");
 }
+
+// When run with INHERIT_STDIO_CHILD set, this is the middle process in
+// `inherit_stdio_works`: its child should see the stdin and stdout that
+// `inherit_stdio_works` set up for it
+#[test]
+// FIXME(#10380)
+#[cfg(unix, not(target_os="android"))]
+fn inherit_stdio_child() {
+    if os::getenv("INHERIT_STDIO_CHILD").is_none() { return }
+    let io = process::inherit_stdio();
+    let args = ProcessConfig {
+        program: "/bin/sh",
+        args: [~"-c", ~"read line; echo got $line"],
+        env: None,
+        cwd: None,
+        io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
+    };
+    let mut p = Process::new(args).expect("didn't create a process?!");
+    assert!(p.wait().success());
+}
+
+#[test]
+// FIXME(#10380)
+#[cfg(unix, not(target_os="android"))]
+fn inherit_stdio_works() {
+    let io = ~[CreatePipe(true, false),
+               CreatePipe(false, true),
+               Ignored];
+    let env = os::env() + ~[(~"INHERIT_STDIO_CHILD", ~"1")];
+    let me = os::self_exe_path().expect("couldn't find this test's executable")
+                 .join(Path::new(os::args()[0]).filename_str().unwrap());
+    let args = ProcessConfig {
+        program: me.as_str().unwrap(),
+        args: [~"inherit_stdio_child"],
+        env: Some(env.as_slice()),
+        cwd: None,
+        io: io,
+        detach: false,
+        niceness: None,
+        default_signals: false,
+    };
+    let mut p = Process::new(args).expect("didn't create a process?!");
+    p.io[0].get_mut_ref().write("foobar\n".as_bytes());
+    p.io[0] = None; // close stdin;
+    let out = read_all(p.io[1].get_mut_ref() as &mut Reader);
+    assert!(p.wait().success());
+    assert!(out.contains("got foobar\n"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn default_signals_unblocks_sigint() {
+    use std::libc::{c_int, SIGINT};
+
+    static SIG_BLOCK: c_int = 0;
+    static SIG_SETMASK: c_int = 2;
+    extern {
+        fn sigemptyset(set: *mut [u64, ..16]) -> c_int;
+        fn sigaddset(set: *mut [u64, ..16], signum: c_int) -> c_int;
+        fn pthread_sigmask(how: c_int, set: *[u64, ..16], oldset: *mut [u64, ..16]) -> c_int;
+    }
+
+    // Exits with 0 if SIGINT is blocked in the child
+    let args = [~"-c", ~"grep -q '^SigBlk:.*[2367abef]$' /proc/self/status"];
+
+    let mut set = [0u64, ..16];
+    let mut old = [0u64, ..16];
+    unsafe {
+        sigemptyset(&mut set);
+        sigaddset(&mut set, SIGINT);
+        pthread_sigmask(SIG_BLOCK, &set, &mut old);
+    }
+    let mut inherited = Process::new(ProcessConfig {
+        program: "/bin/sh",
+        args: args,
+        env: None,
+        cwd: None,
+        io: [],
+        detach: false,
+        niceness: None,
+        default_signals: false,
+    }).unwrap();
+    let mut defaulted = Process::new(ProcessConfig {
+        program: "/bin/sh",
+        args: args,
+        env: None,
+        cwd: None,
+        io: [],
+        detach: false,
+        niceness: None,
+        default_signals: true,
+    }).unwrap();
+    // Restored before waiting, since the task may move to another thread
+    unsafe { pthread_sigmask(SIG_SETMASK, &old, 0 as *mut [u64, ..16]); }
+
+    assert_eq!(inherited.wait(), process::ExitStatus(0));
+    assert_eq!(defaulted.wait(), process::ExitStatus(1));
+}